@@ -0,0 +1,186 @@
+//! Measurement of how fast each cube takes the writes of sounds and lights, to pick a
+//! `--unit` and a `--max-ops` that the cubes keep up with.
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use toio::{LightOp, Note, SoundOp};
+use tokio::time::{delay_for, Duration, Instant};
+
+use crate::{cubes, link::Link, midi::Time, Opt};
+
+/// How long each operation written lasts.
+const OP: Duration = Duration::from_millis(10);
+/// The most light operations in a write.
+const MAX_LIGHT_OPS: usize = 29;
+/// The pause between the bursts, letting the cube settle.
+const PAUSE: Duration = Duration::from_millis(500);
+
+/// What is written to the cube in a burst.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Sound,
+    Light,
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Kind::Sound => write!(f, "sound"),
+            Kind::Light => write!(f, "light"),
+        }
+    }
+}
+
+/// The writes of a burst, with how long each took to be acknowledged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Burst {
+    ops: usize,
+    trips: Vec<Duration>,
+    elapsed: Duration,
+}
+
+impl Burst {
+    fn per_sec(&self, n: usize) -> f64 {
+        n as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// The round trip within which the given percent of the writes were acknowledged, by
+    /// the nearest rank.
+    fn percentile(&self, percent: usize) -> Time {
+        let mut trips = self.trips.clone();
+        trips.sort();
+        match trips.len() {
+            0 => 0,
+            n => {
+                let rank = (n * percent).div_ceil(100).saturating_sub(1);
+                trips[rank.min(n - 1)].as_millis() as Time
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Burst {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.1} writes/s, {:.0} ops/s, latency p50 {} ms, p90 {} ms, p99 {} ms, max {} ms",
+            self.per_sec(self.trips.len()),
+            self.per_sec(self.trips.len() * self.ops),
+            self.percentile(50),
+            self.percentile(90),
+            self.percentile(99),
+            self.percentile(100),
+        )
+    }
+}
+
+/// The shortest unit with which the play sets of `ops` operations last as long as the
+/// writes take, rounded up to the 10 msec of the operations.
+fn unit(trip: Time, ops: usize) -> Time {
+    let unit = trip.div_ceil(ops.max(1) as Time);
+    unit.div_ceil(10).max(1) * 10
+}
+
+/// Writes operations of the kind to the cube back to back for the duration.
+async fn burst(link: &mut Link, kind: Kind, ops: usize, secs: Duration) -> Result<Burst> {
+    let sounds = vec![SoundOp::new(Note::NoSound, OP); ops];
+    let lights: Vec<_> = (0..ops.min(MAX_LIGHT_OPS))
+        .map(|i| match i % 2 {
+            0 => LightOp::new(255, 255, 255, Some(OP)),
+            _ => LightOp::new(0, 0, 0, Some(OP)),
+        })
+        .collect();
+
+    let mut burst = Burst {
+        ops: match kind {
+            Kind::Sound => sounds.len(),
+            Kind::Light => lights.len(),
+        },
+        ..Burst::default()
+    };
+    let start = Instant::now();
+    while start.elapsed() < secs {
+        let sent = Instant::now();
+        match kind {
+            Kind::Sound => link.play(1, &sounds).await?,
+            Kind::Light => link.light(1, &lights).await?,
+        }
+        burst.trips.push(sent.elapsed());
+    }
+    burst.elapsed = start.elapsed();
+    Ok(burst)
+}
+
+/// Writes bursts of sounds and then of lights to every cube for `secs` each, as many
+/// operations at once as the play sets, printing how many each cube took and how long
+/// they took.
+pub async fn run(opt: &Opt, secs: u64) -> Result<()> {
+    let ops = opt.max_ops;
+    if ops == 0 || ops >= 60 {
+        return Err(anyhow!("The number of operations must be from 1 to 59"));
+    }
+    let secs = Duration::from_secs(secs.max(1));
+
+    let mut links = crate::search(opt).await?;
+    let registry = cubes::Registry::local();
+    for link in &mut links {
+        let i = link.index;
+        link.cube()?
+            .connect()
+            .await
+            .context(format!("Couldn't connect to cube {}", i))?;
+        let id = link.cube()?.id().to_string();
+        let label = registry.label(i, &id);
+
+        for kind in [Kind::Sound, Kind::Light] {
+            info!("Benchmarking the {} writes of cube {}", kind, label);
+            let burst = burst(link, kind, ops, secs).await?;
+            info!("Cube {} {}: {}", label, kind, burst);
+            if kind == Kind::Sound {
+                info!(
+                    "Cube {} keeps up with play sets of {} operations from --unit {}",
+                    label,
+                    ops,
+                    unit(burst.percentile(90), ops)
+                );
+            }
+            delay_for(PAUSE).await;
+        }
+        link.stop_sound().await?;
+        link.light_off().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn burst() {
+        let ms = Duration::from_millis;
+        let burst = Burst {
+            ops: 10,
+            trips: [40, 30, 35, 200, 50, 45, 38, 42, 41, 39]
+                .iter()
+                .map(|t| ms(*t))
+                .collect(),
+            elapsed: ms(2000),
+        };
+        assert_eq!(burst.percentile(0), 30);
+        assert_eq!(burst.percentile(50), 40);
+        assert_eq!(burst.percentile(90), 50);
+        assert_eq!(burst.percentile(99), 200);
+        assert_eq!(burst.percentile(100), 200);
+        assert_eq!(Burst::default().percentile(90), 0);
+        assert_eq!(
+            burst.to_string(),
+            "5.0 writes/s, 50 ops/s, latency p50 40 ms, p90 50 ms, p99 200 ms, max 200 ms"
+        );
+
+        assert_eq!(unit(45, 10), 10);
+        assert_eq!(unit(120, 10), 20);
+        assert_eq!(unit(0, 10), 10);
+        assert_eq!(unit(95, 1), 100);
+    }
+}
@@ -0,0 +1,99 @@
+//! On-disk cache of the plans converted from the files, so that playing a song again
+//! with the same options skips parsing and mixing it.
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+use crate::{export, json, midi::Plan};
+
+/// The directory of the cached plans.
+pub fn dir() -> Result<PathBuf> {
+    if let Some(path) = std::env::var_os("TOIO_MIDI_CACHE") {
+        return Ok(path.into());
+    }
+    let cache = match (std::env::var_os("XDG_CACHE_HOME"), std::env::var_os("HOME")) {
+        (Some(cache), _) => PathBuf::from(cache),
+        (None, Some(home)) => Path::new(&home).join(".cache"),
+        (None, None) => return Err(anyhow!("No home directory to cache the plans in")),
+    };
+    Ok(cache.join("toio-midi"))
+}
+
+/// The key of the plan of the files converted with the options, changing with the
+/// contents of the files and with the version converting them.
+pub fn key(files: &[&Path], options: &str) -> Result<String> {
+    let mut hasher = DefaultHasher::new();
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    for file in files {
+        std::fs::read(file)
+            .context(format!("Couldn't read {}", file.display()))?
+            .hash(&mut hasher);
+    }
+    options.hash(&mut hasher);
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+fn path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.json", key))
+}
+
+/// Reads the plan cached under the key, if any; a broken one is left to be replaced.
+pub fn load(dir: &Path, key: &str) -> Option<Vec<Plan>> {
+    let path = path(dir, key);
+    let text = std::fs::read_to_string(&path).ok()?;
+    match json::parse(&text).and_then(|json| export::read_plan(&json)) {
+        Ok(segments) => Some(segments),
+        Err(e) => {
+            debug!("Ignored the broken cache {}: {:#}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Keeps the plan under the key.
+pub fn save(dir: &Path, key: &str, segments: &[Plan]) -> Result<()> {
+    std::fs::create_dir_all(dir).context(format!("Couldn't create {}", dir.display()))?;
+    let path = path(dir, key);
+    std::fs::write(&path, format!("{}\n", export::plan(segments)))
+        .context(format!("Couldn't write {}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::midi::{Play, PlaySet};
+    use toio::Note;
+
+    #[test]
+    fn cache() {
+        let dir = std::env::temp_dir().join(format!("toio-midi-cache-{}", std::process::id()));
+        let file = dir.join("song.mid");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&file, b"one").unwrap();
+
+        let key = super::key(&[&file], "speed 100").unwrap();
+        assert_eq!(super::key(&[&file], "speed 100").unwrap(), key);
+        assert_ne!(super::key(&[&file], "speed 120").unwrap(), key);
+        std::fs::write(&file, b"two").unwrap();
+        assert_ne!(super::key(&[&file], "speed 100").unwrap(), key);
+
+        assert_eq!(load(&dir, &key), None);
+        let mut plan = Plan::new();
+        let mut set = PlaySet::new(1, 500);
+        set.plays.push(Play::new(1, 500, 250, Note::C4));
+        set.len = 250;
+        plan.insert((500, 1), set);
+        let segments = vec![plan, Plan::new()];
+        save(&dir, &key, &segments).unwrap();
+        assert_eq!(load(&dir, &key), Some(segments));
+
+        std::fs::write(path(&dir, &key), "{").unwrap();
+        assert_eq!(load(&dir, &key), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+//! Calibration of the delay from sending a command to the sound of each cube.
+
+use anyhow::{anyhow, Context, Error, Result};
+use log::*;
+use toio::{Note, SoundOp};
+use tokio::time::{delay_for, Duration, Instant};
+
+use crate::{cubes, midi::Time, Opt};
+
+/// How many clicks are played on each cube.
+const CLICKS: usize = 8;
+/// The click played.
+const CLICK: Duration = Duration::from_millis(50);
+/// Interval between the clicks.
+const INTERVAL: Duration = Duration::from_millis(250);
+
+/// The delay from sending a command to the sound of a cube, overriding the calibrated one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Latency {
+    pub cube: usize,
+    pub ms: Time,
+}
+
+impl std::str::FromStr for Latency {
+    type Err = Error;
+
+    /// Parses `cube1=35` in msec, where the `cube` prefix is optional.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid offset: {} (expected such as cube1=35)", s);
+        let (cube, ms) = s.split_once('=').ok_or_else(invalid)?;
+        let cube = cube.strip_prefix("cube").unwrap_or(cube);
+        Ok(Latency {
+            cube: cube.parse().map_err(|_| invalid())?,
+            ms: ms.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Estimates the latency as half the median round trip of the clicks acknowledged.
+fn latency(mut trips: Vec<Duration>) -> Time {
+    trips.sort();
+    trips
+        .get(trips.len() / 2)
+        .map_or(0, |d| d.as_millis() as Time / 2)
+}
+
+/// Plays the reference clicks on every cube, timing them to keep the latency of each
+/// in the cubes file.
+pub async fn run(opt: &Opt) -> Result<()> {
+    let path = cubes::path()?;
+    let mut registry = cubes::Registry::load(&path)?;
+
+    let mut links = crate::search(opt).await?;
+    for link in &mut links {
+        let i = link.index;
+        link.cube()?
+            .connect()
+            .await
+            .context(format!("Couldn't connect to cube {}", i))?;
+        let id = link.cube()?.id().to_string();
+
+        let mut trips = vec![];
+        for _ in 0..CLICKS {
+            let sent = Instant::now();
+            link.play(1, &[SoundOp::new(Note::C6, CLICK)]).await?;
+            trips.push(sent.elapsed());
+            delay_for(INTERVAL).await;
+        }
+        let ms = latency(trips);
+        info!("Cube {} latency: {} ms", registry.label(i, &id), ms);
+        registry.set_latency(&id, ms);
+    }
+
+    registry.save(&path)?;
+    info!("Saved the latencies to {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latency() {
+        assert_eq!(
+            "cube1=35".parse::<Latency>().unwrap(),
+            Latency { cube: 1, ms: 35 }
+        );
+        assert_eq!("2=0".parse::<Latency>().unwrap().cube, 2);
+        assert!("cube1:35".parse::<Latency>().is_err());
+        assert!("cube1=-5".parse::<Latency>().is_err());
+
+        let ms = |v: &[u64]| v.iter().map(|m| Duration::from_millis(*m)).collect();
+        assert_eq!(super::latency(ms(&[80, 60, 300, 70, 64])), 35);
+        assert_eq!(super::latency(vec![]), 0);
+    }
+}
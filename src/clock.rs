@@ -0,0 +1,888 @@
+//! Following an external sync input instead of the timeline of the host, so that the cubes
+//! keep in time with a DAW or a drum machine: the MIDI clock of 24 ticks a quarter note, or
+//! the MIDI time code chased to its position in the song. Or the timeline of the host, paused
+//! and moved by the MIDI Machine Control of a studio.
+
+use anyhow::{anyhow, Context, Error, Result};
+use log::*;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    time::{timeout_at, Duration, Instant},
+};
+
+use crate::midi::Time;
+
+/// Ticks of the clock a quarter note.
+const PPQN: f64 = 24.0;
+/// Ticks of the clock a beat of the song position pointer, a sixteenth note.
+const BEAT: f64 = 6.0;
+/// The msec the time code may be off from where it's expected before it's taken as a jump.
+const JUMP: f64 = 200.0;
+/// How long before a time comes the input is watched for where it goes meanwhile.
+const WATCH: Duration = Duration::from_millis(50);
+
+/// The tempo map of the song as (quarter notes, msec, msec a quarter note) from the start.
+#[derive(Clone, Debug, Default)]
+pub struct Tempos(pub Vec<(f64, f64, f64)>);
+
+impl Tempos {
+    /// The tempo change last before the point, by the key of the point.
+    fn at<F: Fn(&(f64, f64, f64)) -> f64>(&self, key: F, point: f64) -> (f64, f64, f64) {
+        match self.0.partition_point(|t| key(t) <= point) {
+            // 120 bpm until told.
+            0 => (0.0, 0.0, 500.0),
+            i => self.0[i - 1],
+        }
+    }
+
+    /// The msec of the song at the quarter notes from the start.
+    fn ms(&self, quarters: f64) -> f64 {
+        let (q, ms, len) = self.at(|t| t.0, quarters);
+        ms + (quarters - q) * len
+    }
+
+    /// The quarter notes from the start at the msec of the song.
+    fn quarters(&self, at: f64) -> f64 {
+        let (q, ms, len) = self.at(|t| t.1, at);
+        q + (at - ms) / len.max(f64::EPSILON)
+    }
+}
+
+/// The frame rates of the time code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Rate {
+    Fps24,
+    Fps25,
+    /// 29.97 frames a second, dropping the labels of two frames most minutes.
+    Drop30,
+    Fps30,
+}
+
+impl Rate {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 3 {
+            0 => Rate::Fps24,
+            1 => Rate::Fps25,
+            2 => Rate::Drop30,
+            _ => Rate::Fps30,
+        }
+    }
+
+    /// Msec of a frame.
+    fn frame(self) -> f64 {
+        match self {
+            Rate::Fps24 => 1000.0 / 24.0,
+            Rate::Fps25 => 1000.0 / 25.0,
+            Rate::Drop30 => 1001.0 / 30.0,
+            Rate::Fps30 => 1000.0 / 30.0,
+        }
+    }
+}
+
+/// The time code in hours, minutes, seconds and frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timecode {
+    hours: u8,
+    minutes: u8,
+    seconds: u8,
+    frames: u8,
+}
+
+impl Timecode {
+    /// The msec since the time code of zero, at the rate.
+    fn ms(&self, rate: Rate) -> f64 {
+        let secs = self.hours as u64 * 3600 + self.minutes as u64 * 60 + self.seconds as u64;
+        let frames = match rate {
+            Rate::Fps24 => secs * 24,
+            Rate::Fps25 => secs * 25,
+            Rate::Drop30 | Rate::Fps30 => secs * 30,
+        } + self.frames as u64;
+        let frames = match rate {
+            Rate::Drop30 => {
+                let minutes = self.hours as u64 * 60 + self.minutes as u64;
+                frames - 2 * (minutes - minutes / 10)
+            }
+            _ => frames,
+        };
+        frames as f64 * rate.frame()
+    }
+}
+
+impl std::str::FromStr for Timecode {
+    type Err = Error;
+
+    /// Parses such as `01:00:00:00`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid time code: {} (expected such as 01:00:00:00)", s);
+        let parts = s
+            .split(':')
+            .map(|p| p.parse().map_err(|_| invalid()))
+            .collect::<Result<Vec<u8>>>()?;
+        match parts[..] {
+            [hours, minutes, seconds, frames] if hours < 24 && minutes < 60 && seconds < 60 => {
+                Ok(Timecode {
+                    hours,
+                    minutes,
+                    seconds,
+                    frames,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for Timecode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}:{:02}:{:02}",
+            self.hours, self.minutes, self.seconds, self.frames
+        )
+    }
+}
+
+/// The time code put together from the eight quarter frames.
+#[derive(Clone, Debug, Default)]
+struct Quarters {
+    pieces: [u8; 8],
+    /// The pieces come since the first.
+    seen: u8,
+}
+
+impl Quarters {
+    /// Takes the quarter frame, telling the time code once the last piece comes after the
+    /// others.
+    fn push(&mut self, b: u8) -> Option<(Timecode, Rate)> {
+        let piece = ((b >> 4) & 7) as usize;
+        if piece == 0 {
+            self.seen = 0;
+        }
+        self.pieces[piece] = b & 0x0f;
+        self.seen |= 1 << piece;
+        if piece != 7 || self.seen != 0xff {
+            return None;
+        }
+        let p = &self.pieces;
+        let code = Timecode {
+            hours: p[6] | (p[7] & 1) << 4,
+            minutes: p[4] | (p[5] & 3) << 4,
+            seconds: p[2] | (p[3] & 3) << 4,
+            frames: p[0] | (p[1] & 1) << 4,
+        };
+        Some((code, Rate::from_bits(p[7] >> 1)))
+    }
+}
+
+/// The messages of the sync input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Message {
+    Tick,
+    Start,
+    Continue,
+    Stop,
+    /// The song position pointer in sixteenth notes, to continue from.
+    Position(u16),
+    /// A quarter frame of the time code.
+    Quarter(u8),
+    /// The full time code, sent on jumping to it.
+    Full(Timecode, Rate),
+    /// The play command of the machine control.
+    Play,
+    /// The stop or pause command of the machine control.
+    Pause,
+    /// The locate command of the machine control, to go to the time code.
+    Locate(Timecode, Rate),
+}
+
+/// Puts the messages of the sync input together from its bytes.
+#[derive(Clone, Debug, Default)]
+struct Parser {
+    /// The status awaiting its data bytes, with the first of two.
+    status: Option<(u8, Option<u8>)>,
+    /// The system exclusive message being read.
+    sysex: Option<Vec<u8>>,
+}
+
+impl Parser {
+    fn push(&mut self, b: u8) -> Option<Message> {
+        match b {
+            0xf8 => Some(Message::Tick),
+            0xfa => Some(Message::Start),
+            0xfb => Some(Message::Continue),
+            0xfc => Some(Message::Stop),
+            // The other real-time messages may come anywhere without breaking the others.
+            0xf9..=0xff => None,
+            0xf0 => {
+                self.status = None;
+                self.sysex = Some(vec![]);
+                None
+            }
+            0xf7 => sysex(&self.sysex.take()?),
+            0x80..=0xf6 => {
+                self.status = Some((b, None));
+                self.sysex = None;
+                None
+            }
+            _ => {
+                if let Some(sysex) = &mut self.sysex {
+                    sysex.push(b);
+                    return None;
+                }
+                match self.status.take() {
+                    Some((0xf1, _)) => Some(Message::Quarter(b)),
+                    Some((0xf2, None)) => {
+                        self.status = Some((0xf2, Some(b)));
+                        None
+                    }
+                    Some((0xf2, Some(lsb))) => {
+                        Some(Message::Position(lsb as u16 | (b as u16) << 7))
+                    }
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+/// The time code and its rate in the hours, minutes, seconds and frames of a message.
+fn timecode(hours: u8, minutes: u8, seconds: u8, frames: u8) -> (Timecode, Rate) {
+    let code = Timecode {
+        hours: hours & 0x1f,
+        minutes,
+        seconds,
+        frames,
+    };
+    (code, Rate::from_bits(hours >> 5))
+}
+
+/// The full time code or the machine control in the system exclusive message, without its
+/// first and last bytes.
+fn sysex(sysex: &[u8]) -> Option<Message> {
+    match *sysex {
+        [0x7f, _, 0x01, 0x01, h, m, s, f] => {
+            let (code, rate) = timecode(h, m, s, f);
+            Some(Message::Full(code, rate))
+        }
+        [0x7f, _, 0x06, 0x44, 0x06, 0x01, h, m, s, f, ..] => {
+            let (code, rate) = timecode(h, m, s, f);
+            Some(Message::Locate(code, rate))
+        }
+        // Deferred play is as good as play as the cubes start at once.
+        [0x7f, _, 0x06, 0x02 | 0x03] => Some(Message::Play),
+        [0x7f, _, 0x06, 0x01 | 0x09] => Some(Message::Pause),
+        _ => None,
+    }
+}
+
+/// When a time of the song comes by the sync input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cue {
+    /// The time comes at the instant.
+    At(Instant),
+    /// The input went to the time of the song, to play on from.
+    Locate(Time),
+    /// The input stopped, to be silent until it goes on.
+    Halt,
+}
+
+/// Where the sync input is in the song.
+#[derive(Clone, Debug, Default)]
+struct Follow {
+    /// The tempo map the ticks of the clock are laid on.
+    tempos: Tempos,
+    running: bool,
+    /// Ticks since the start and when the last came, from the first after the start.
+    ticks: Option<(u64, Instant)>,
+    /// The time between the ticks, smoothed over them.
+    interval: Option<Duration>,
+    /// The tick the segment is played from, and the time of the song it's at.
+    base: (u64, Time),
+    /// The song position pointer to continue from.
+    pointer: Option<u16>,
+    /// The time of the song the clock stopped at, to continue from without a pointer.
+    held: Option<Time>,
+    /// The time code of the start of the song, if chased.
+    zero: Option<Timecode>,
+    quarters: Quarters,
+    /// The time of the song by the last time code, when it came and at which rate.
+    code: Option<(f64, Instant, Rate)>,
+    /// The time of the song the input went to, to be taken up.
+    located: Option<Time>,
+    /// The input stopped, to be taken up.
+    halted: bool,
+    /// The timeline of the host is followed, moved by the machine control.
+    transport: bool,
+    /// The time of the song the transport played from and when, unless paused.
+    played: Option<(f64, Instant)>,
+}
+
+impl Follow {
+    /// Follows the clock, a quarter note of which is one of the song along its tempo map.
+    fn clock(tempos: Tempos) -> Self {
+        Self {
+            tempos,
+            ..Self::default()
+        }
+    }
+
+    /// Chases the time code, the song starting at `zero`.
+    fn chase(zero: Timecode) -> Self {
+        Self {
+            zero: Some(zero),
+            ..Self::default()
+        }
+    }
+
+    /// Follows the timeline of the host, paused and moved by the machine control, the song
+    /// starting at `zero`.
+    fn transport(zero: Timecode) -> Self {
+        Self {
+            transport: true,
+            zero: Some(zero),
+            ..Self::default()
+        }
+    }
+
+    fn apply(&mut self, message: Message, now: Instant) {
+        match message {
+            Message::Play | Message::Pause | Message::Locate(..) if !self.transport => {}
+            Message::Play => {
+                if self.played.is_none() {
+                    let at = self.held.take().unwrap_or(0);
+                    self.played = Some((at as f64, now));
+                    self.located = Some(at);
+                }
+            }
+            Message::Pause => {
+                if let Some((at, since)) = self.played.take() {
+                    let played = now.saturating_duration_since(since).as_secs_f64() * 1000.0;
+                    self.held = Some((at + played) as Time);
+                    self.halted = true;
+                }
+            }
+            Message::Locate(code, rate) => {
+                let zero = self.zero.unwrap_or_default();
+                let at = (code.ms(rate) - zero.ms(rate)).max(0.0) as Time;
+                if self.played.is_some() {
+                    self.played = Some((at as f64, now));
+                    self.located = Some(at);
+                } else {
+                    self.held = Some(at);
+                }
+            }
+            _ if self.transport => {}
+            // The time code tells the time of the song by itself.
+            Message::Start | Message::Continue | Message::Position(_) if self.zero.is_some() => {}
+            Message::Start => {
+                // Started over while playing.
+                if self.ticks.is_some() {
+                    self.located = Some(0);
+                }
+                self.running = true;
+                self.ticks = None;
+                self.base = (0, 0);
+                self.pointer = None;
+                self.held = None;
+            }
+            Message::Continue => {
+                let at = match (self.pointer.take(), self.held.take()) {
+                    (Some(beats), _) => self.tempos.ms(beats as f64 * BEAT / PPQN) as Time,
+                    (None, Some(at)) => at,
+                    (None, None) => self.position(),
+                };
+                self.running = true;
+                self.ticks = None;
+                self.base = (0, at);
+                self.located = Some(at);
+            }
+            Message::Position(beats) => self.pointer = Some(beats),
+            Message::Stop => {
+                if self.zero.is_none() && self.running {
+                    self.held = Some(self.position());
+                }
+                self.running = false;
+                self.halted = true;
+            }
+            Message::Tick if self.running => {
+                self.ticks = Some(match self.ticks {
+                    None => (0, now),
+                    Some((n, last)) => {
+                        let took = now.saturating_duration_since(last);
+                        self.interval = Some(match self.interval {
+                            Some(interval) => (interval * 3 + took) / 4,
+                            None => took,
+                        });
+                        (n + 1, now)
+                    }
+                });
+            }
+            Message::Tick => {}
+            Message::Quarter(b) => {
+                let zero = match self.zero {
+                    Some(zero) => zero,
+                    None => return,
+                };
+                if let Some((code, rate)) = self.quarters.push(b) {
+                    // The last quarter frame comes seven quarters of a frame after the frame.
+                    let at = code.ms(rate) - zero.ms(rate) + rate.frame() * 7.0 / 4.0;
+                    let expected = self.code.map(|(code, since, _)| {
+                        code + now.saturating_duration_since(since).as_secs_f64() * 1000.0
+                    });
+                    if expected.is_none_or(|e| (e - at).abs() > JUMP) {
+                        self.located = Some(at.max(0.0) as Time);
+                    }
+                    self.code = Some((at, now, rate));
+                }
+            }
+            Message::Full(code, rate) => {
+                if let Some(zero) = self.zero {
+                    self.located = Some((code.ms(rate) - zero.ms(rate)).max(0.0) as Time);
+                    self.code = None;
+                }
+            }
+        }
+    }
+
+    /// The quarter notes of the song at the last tick of the clock.
+    fn quarters(&self) -> f64 {
+        let (base, from) = self.base;
+        let n = self.ticks.map_or(base, |(n, _)| n);
+        self.tempos.quarters(from as f64) + n.saturating_sub(base) as f64 / PPQN
+    }
+
+    /// The time of the song at the last tick of the clock.
+    fn position(&self) -> Time {
+        self.tempos.ms(self.quarters()).round() as Time
+    }
+
+    /// Plays the segment from the time of the song on from the last tick, or from the start
+    /// of the clock if not started, or now unless the transport is paused.
+    fn begin(&mut self, at: Time, now: Instant) {
+        self.base = (self.ticks.map_or(0, |(n, _)| n), at);
+        if self.transport && self.held.is_none() {
+            self.played = Some((at as f64, now));
+        }
+    }
+
+    /// When the last news of the input came, the msec of real time from then until the time
+    /// of the song, and how long until the next news if expected.
+    fn ahead(&self, at: Time) -> Option<(Instant, f64, Option<Duration>)> {
+        if self.transport {
+            let (from, since) = self.played?;
+            return Some((since, at as f64 - from, None));
+        }
+        if self.zero.is_some() {
+            let (from, since, rate) = self.code?;
+            let step = Duration::from_secs_f64(rate.frame() * 2.0 / 1000.0);
+            return Some((since, at as f64 - from, Some(step)));
+        }
+        if !self.running {
+            return None;
+        }
+        let (_, last) = self.ticks?;
+        let interval = self.interval?;
+        // Ticks until then along the tempo map.
+        let ticks = (self.tempos.quarters(at as f64) - self.quarters()) * PPQN;
+        Some((
+            last,
+            ticks * interval.as_secs_f64() * 1000.0,
+            Some(interval),
+        ))
+    }
+
+    /// When the time of the song comes by the input so far, once it's no more than `early`
+    /// after the next news.
+    fn when(&self, at: Time, early: Duration) -> Option<Instant> {
+        let (since, ahead, step) = self.ahead(at)?;
+        let due = since + Duration::from_micros((ahead.max(0.0) * 1000.0).round() as u64);
+        Some(due).filter(|due| step.is_none_or(|step| *due <= since + step + early))
+    }
+
+    /// Keeps the timeline of the host where it was over a pause of the playback by itself.
+    fn delay(&mut self, paused: Duration) {
+        if let Some((at, since)) = self.played.filter(|_| self.transport) {
+            self.played = Some((at, since + paused));
+        }
+    }
+
+    /// The tempo of the clock, once it ticked twice.
+    fn bpm(&self) -> Option<f64> {
+        self.interval
+            .map(|i| 60.0 / (i.as_secs_f64() * PPQN).max(f64::EPSILON))
+    }
+}
+
+/// The raw MIDI device read for the time code or the machine control, with the time code
+/// the song starts at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chase {
+    /// Raw MIDI device such as `/dev/midi1`, or `virtual`.
+    pub path: PathBuf,
+    /// The time code of the start of the song.
+    pub zero: Timecode,
+}
+
+impl std::str::FromStr for Chase {
+    type Err = Error;
+
+    /// Parses the device, with the time code of the start of the song after `@` if not zero.
+    fn from_str(s: &str) -> Result<Self> {
+        let (path, zero) = match s.rfind('@') {
+            Some(i) => (&s[..i], s[i + 1..].parse()?),
+            None => (s, Timecode::default()),
+        };
+        if path.is_empty() {
+            return Err(anyhow!("Invalid time code input: {}", s));
+        }
+        Ok(Chase {
+            path: path.into(),
+            zero,
+        })
+    }
+}
+
+/// The sync input read from a raw MIDI device.
+pub struct Clock {
+    rx: UnboundedReceiver<(Instant, Message)>,
+    follow: Follow,
+}
+
+impl Clock {
+    /// Follows the MIDI clock, a quarter note of which is one of the song along its tempo map.
+    pub fn midi(path: &Path, tempos: Tempos) -> Result<Self> {
+        Self::open(path, Follow::clock(tempos))
+    }
+
+    /// Chases the time code, the song starting at `zero`.
+    pub fn mtc(path: &Path, zero: Timecode) -> Result<Self> {
+        Self::open(path, Follow::chase(zero))
+    }
+
+    /// Follows the timeline of the host, paused and moved by the machine control, the song
+    /// starting at `zero`.
+    pub fn mmc(path: &Path, zero: Timecode) -> Result<Self> {
+        Self::open(path, Follow::transport(zero))
+    }
+
+    /// Reads the input on a thread as the device only supports blocking reads.
+    fn open(path: &Path, follow: Follow) -> Result<Self> {
+        let mut file = std::fs::File::open(path)
+            .context(format!("Couldn't open sync input {}", path.display()))?;
+        let path = path.to_path_buf();
+        let (tx, rx) = unbounded_channel();
+
+        std::thread::spawn(move || {
+            let mut parser = Parser::default();
+            let mut buf = [0; 64];
+            loop {
+                match file.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        for m in buf[..n].iter().filter_map(|b| parser.push(*b)) {
+                            if tx.send((Instant::now(), m)).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("Couldn't read sync input {}: {}", path.display(), e);
+                        break;
+                    }
+                }
+            }
+            info!("Sync input {} closed", path.display());
+        });
+
+        Ok(Self { rx, follow })
+    }
+
+    fn apply(&mut self, message: Message, now: Instant) {
+        match message {
+            Message::Start => info!("The MIDI clock started"),
+            Message::Continue => info!("The MIDI clock continued"),
+            Message::Position(beats) => debug!("The song position is at {}/16", beats),
+            Message::Play => info!("Machine control: play"),
+            Message::Pause => info!("Machine control: stop"),
+            Message::Locate(code, _) => info!("Machine control: locate {}", code),
+            Message::Stop => match self.follow.bpm() {
+                Some(bpm) => info!("The MIDI clock stopped at {:.1} bpm", bpm),
+                None => info!("The MIDI clock stopped"),
+            },
+            _ => {}
+        }
+        self.follow.apply(message, now);
+    }
+
+    fn drain(&mut self) {
+        while let Ok((now, message)) = self.rx.try_recv() {
+            self.apply(message, now);
+        }
+    }
+
+    /// Keeps the timeline of the host where it was over a pause of the playback by itself,
+    /// as the sync inputs from outside go on their own.
+    pub fn delay(&mut self, paused: Duration) {
+        self.follow.delay(paused);
+    }
+
+    /// Plays the segment, starting at the time of the song, from the current position of the
+    /// clock.
+    pub fn begin(&mut self, at: Time) {
+        self.drain();
+        self.follow.begin(at, Instant::now());
+    }
+
+    /// Waits until the input tells when the time of the song comes, `early` before it comes
+    /// at the latest, or where it went or that it stopped instead.
+    pub async fn when(&mut self, at: Time, early: Duration) -> Result<Cue> {
+        loop {
+            self.drain();
+            if std::mem::take(&mut self.follow.halted) {
+                return Ok(Cue::Halt);
+            }
+            if let Some(to) = self.follow.located.take() {
+                return Ok(Cue::Locate(to));
+            }
+            if let Some(due) = self.follow.when(at, early) {
+                let watched = due.checked_sub(WATCH + early).unwrap_or(due);
+                if watched <= Instant::now() {
+                    return Ok(Cue::At(due));
+                }
+                match timeout_at(watched, self.rx.recv()).await {
+                    Ok(Some((now, message))) => self.apply(message, now),
+                    Ok(None) => return Err(anyhow!("The sync input closed")),
+                    Err(_) => return Ok(Cue::At(due)),
+                }
+                continue;
+            }
+            match self.rx.recv().await {
+                Some((now, message)) => self.apply(message, now),
+                None => return Err(anyhow!("The sync input closed")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn follow() {
+        let mut parser = Parser::default();
+        assert_eq!(parser.push(0xf8), Some(Message::Tick));
+        assert_eq!(parser.push(0x90), None);
+        assert_eq!(parser.push(0x3c), None);
+
+        let ms = Duration::from_millis;
+        let early = ms(100);
+        let zero = Instant::now();
+        // 24 ticks of 25 msec make a quarter note of 100 bpm, and a tick of the song at
+        // 125 bpm is 20 msec.
+        let mut follow = Follow::clock(Tempos(vec![(0.0, 0.0, 480.0)]));
+        follow.apply(Message::Tick, zero);
+        assert_eq!(follow.when(0, early), None);
+
+        follow.apply(Message::Start, zero);
+        for n in 0..5 {
+            follow.apply(Message::Tick, zero + ms(25) * n);
+        }
+        assert_eq!(follow.bpm().map(|b| b.round()), Some(100.0));
+        let last = zero + ms(100);
+        assert_eq!(follow.when(0, early), Some(last));
+        assert_eq!(follow.when(140, early), Some(last + ms(75)));
+        assert_eq!(follow.when(180, early), Some(last + ms(125)));
+        assert_eq!(follow.when(200, early), None);
+
+        // Without a pointer it goes on from where it stopped.
+        follow.apply(Message::Stop, zero + ms(110));
+        assert!(follow.halted);
+        assert_eq!(follow.when(140, early), None);
+        follow.apply(Message::Continue, zero + ms(1000));
+        assert_eq!(follow.located.take(), Some(80));
+        follow.apply(Message::Tick, zero + ms(1000));
+        assert_eq!(follow.bpm().map(|b| b.round()), Some(100.0));
+        assert_eq!(follow.when(80, early), Some(zero + ms(1000)));
+
+        // The next segment goes on from the tick.
+        follow.begin(5000, zero);
+        assert_eq!(follow.when(5000, early), Some(zero + ms(1000)));
+        assert_eq!(follow.when(5040, early), Some(zero + ms(1050)));
+
+        // Two quarter notes in, and back to the start.
+        follow.apply(Message::Stop, zero + ms(1100));
+        for b in [0xf2, 8, 0] {
+            if let Some(m) = parser.push(b) {
+                follow.apply(m, zero + ms(1100));
+            }
+        }
+        follow.apply(Message::Continue, zero + ms(1200));
+        assert_eq!(follow.located.take(), Some(960));
+        follow.apply(Message::Tick, zero + ms(1200));
+        follow.apply(Message::Start, zero + ms(1300));
+        assert_eq!(follow.located.take(), Some(0));
+    }
+
+    #[test]
+    fn tempo_map() {
+        // A quarter note of 500 msec, and of 250 msec from the second on.
+        let tempos = Tempos(vec![(0.0, 0.0, 500.0), (1.0, 500.0, 250.0)]);
+        assert_eq!(tempos.ms(1.5), 625.0);
+        assert_eq!(tempos.quarters(625.0), 1.5);
+        assert_eq!(tempos.quarters(250.0), 0.5);
+
+        let ms = Duration::from_millis;
+        let zero = Instant::now();
+        let mut follow = Follow::clock(tempos);
+        follow.apply(Message::Start, zero);
+        for n in 0..25 {
+            follow.apply(Message::Tick, zero + ms(20) * n);
+        }
+        // A quarter note in, the ticks go on at the tempo after the change.
+        assert_eq!(follow.position(), 500);
+        assert_eq!(follow.when(625, ms(1000)), Some(zero + ms(480 + 240)));
+
+        // The song position pointer of six sixteenths, after the change.
+        follow.apply(Message::Stop, zero + ms(500));
+        follow.apply(Message::Position(6), zero + ms(500));
+        follow.apply(Message::Continue, zero + ms(600));
+        assert_eq!(follow.located.take(), Some(625));
+    }
+
+    /// The quarter frames of the time code at 25 frames a second.
+    fn quarters(code: &Timecode) -> Vec<u8> {
+        let pieces = [
+            code.frames & 0xf,
+            code.frames >> 4,
+            code.seconds & 0xf,
+            code.seconds >> 4,
+            code.minutes & 0xf,
+            code.minutes >> 4,
+            code.hours & 0xf,
+            (code.hours >> 4) | 1 << 1,
+        ];
+        pieces
+            .iter()
+            .enumerate()
+            .flat_map(|(i, p)| vec![0xf1, (i as u8) << 4 | p])
+            .collect()
+    }
+
+    #[test]
+    fn chase() {
+        let one: Timecode = "01:00:00:00".parse().unwrap();
+        assert!("01:00:00".parse::<Timecode>().is_err());
+        assert!("01:60:00:00".parse::<Timecode>().is_err());
+        assert_eq!(one.ms(Rate::Fps25), 3_600_000.0);
+        let code: Timecode = "00:10:00:02".parse().unwrap();
+        assert_eq!(code.ms(Rate::Drop30), 17984.0 * 1001.0 / 30.0);
+        let chase: Chase = "/dev/midi1@01:00:00:00".parse().unwrap();
+        assert_eq!(chase.zero, one);
+        assert_eq!(one.to_string(), "01:00:00:00");
+        assert_eq!(
+            "virtual".parse::<Chase>().unwrap().zero,
+            Timecode::default()
+        );
+
+        let ms = Duration::from_millis;
+        let early = ms(0);
+        let zero = Instant::now();
+        let mut parser = Parser::default();
+        let mut follow = Follow::chase(one);
+        let mut feed = |follow: &mut Follow, bytes: &[u8], now| {
+            for b in bytes {
+                if let Some(m) = parser.push(*b) {
+                    follow.apply(m, now);
+                }
+            }
+        };
+
+        // Two seconds into the song, seven quarters of 40 msec on.
+        let code: Timecode = "01:00:02:00".parse().unwrap();
+        feed(&mut follow, &quarters(&code), zero);
+        assert_eq!(follow.located.take(), Some(2070));
+        assert_eq!(follow.when(2070, early), Some(zero));
+        assert_eq!(follow.when(2100, early), Some(zero + ms(30)));
+        assert_eq!(follow.when(2200, early), None);
+
+        // Two frames later it's where it's expected.
+        let code: Timecode = "01:00:02:02".parse().unwrap();
+        feed(&mut follow, &quarters(&code), zero + ms(80));
+        assert_eq!(follow.located, None);
+
+        // The full time code jumps.
+        let full = [0xf0, 0x7f, 0x7f, 0x01, 0x01, 1 << 5 | 1, 0, 30, 0, 0xf7];
+        feed(&mut follow, &full, zero + ms(100));
+        assert_eq!(follow.located.take(), Some(30_000));
+        assert_eq!(follow.when(30_000, early), None);
+    }
+
+    #[test]
+    fn transport() {
+        let ms = Duration::from_millis;
+        let early = ms(0);
+        let zero = Instant::now();
+        let mut parser = Parser::default();
+        let mut follow = Follow::transport(Timecode::default());
+        let mut feed = |follow: &mut Follow, bytes: &[u8], now| {
+            for b in bytes {
+                if let Some(m) = parser.push(*b) {
+                    follow.apply(m, now);
+                }
+            }
+        };
+        let command = |c| [0xf0, 0x7f, 0x7f, 0x06, c, 0xf7];
+
+        follow.begin(0, zero);
+        assert_eq!(follow.when(5000, early), Some(zero + ms(5000)));
+        feed(&mut follow, &[0xf8, 0xfc], zero + ms(10));
+        assert!(!follow.halted);
+
+        feed(&mut follow, &command(0x01), zero + ms(200));
+        assert!(follow.halted);
+        assert_eq!(follow.when(5000, early), None);
+        // Ten seconds at 25 frames a second.
+        let locate = [
+            0xf0,
+            0x7f,
+            0x7f,
+            0x06,
+            0x44,
+            0x06,
+            0x01,
+            1 << 5,
+            0,
+            10,
+            0,
+            0,
+            0xf7,
+        ];
+        feed(&mut follow, &locate, zero + ms(300));
+        assert_eq!(follow.located, None);
+        feed(&mut follow, &command(0x02), zero + ms(1000));
+        assert_eq!(follow.located.take(), Some(10_000));
+        assert_eq!(follow.when(10_500, early), Some(zero + ms(1500)));
+
+        feed(&mut follow, &command(0x09), zero + ms(1100));
+        feed(&mut follow, &command(0x02), zero + ms(2000));
+        assert_eq!(follow.located.take(), Some(10_100));
+        feed(&mut follow, &locate, zero + ms(2500));
+        assert_eq!(follow.located.take(), Some(10_000));
+        assert_eq!(follow.when(10_000, early), Some(zero + ms(2500)));
+
+        // A pause of the playback by itself holds the timeline.
+        follow.delay(ms(400));
+        assert_eq!(follow.when(10_100, early), Some(zero + ms(3000)));
+
+        // The song starting at eight seconds.
+        let mut follow = Follow::transport("00:00:08:00".parse().unwrap());
+        follow.begin(0, zero);
+        feed(&mut follow, &locate, zero + ms(100));
+        assert_eq!(follow.located.take(), Some(2_000));
+    }
+}
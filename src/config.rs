@@ -0,0 +1,529 @@
+//! Parser for the subset of TOML used by show files.
+//!
+//! Supports comments, `[table]` and `[[array-of-tables]]` headers, and
+//! `key = value` pairs whose values are strings, integers, floats, booleans
+//! or (possibly multi-line) arrays of them. Every value remembers where it was
+//! written so that errors can point at the offending line and column.
+
+use anyhow::{anyhow, Error, Result};
+use derive_new::new;
+use std::{collections::BTreeMap, fmt};
+
+/// Position in the source, 1-based.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, new)]
+pub struct Pos {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.col)
+    }
+}
+
+pub type Table = BTreeMap<String, Item>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Array(Vec<Item>),
+    Table(Table),
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "a string",
+            Value::Int(_) => "an integer",
+            Value::Float(_) => "a float",
+            Value::Bool(_) => "a boolean",
+            Value::Array(_) => "an array",
+            Value::Table(_) => "a table",
+        }
+    }
+}
+
+/// A value with its position.
+#[derive(Clone, Debug, PartialEq, new)]
+pub struct Item {
+    pub pos: Pos,
+    pub value: Value,
+}
+
+impl Item {
+    /// Creates an error pointing at this item.
+    pub fn error<S: fmt::Display>(&self, msg: S) -> Error {
+        anyhow!("{}: {}", self.pos, msg)
+    }
+
+    fn expected(&self, what: &str) -> Error {
+        self.error(format!("expected {}, found {}", what, self.value.kind()))
+    }
+
+    pub fn str(&self) -> Result<&str> {
+        match &self.value {
+            Value::Str(s) => Ok(s),
+            _ => Err(self.expected("a string")),
+        }
+    }
+
+    pub fn int(&self) -> Result<i64> {
+        match &self.value {
+            Value::Int(v) => Ok(*v),
+            _ => Err(self.expected("an integer")),
+        }
+    }
+
+    /// Reads a non-negative integer fitting in `T`.
+    pub fn uint<T: std::convert::TryFrom<i64>>(&self) -> Result<T> {
+        let v = self.int()?;
+        T::try_from(v).map_err(|_| self.error(format!("{} is out of range", v)))
+    }
+
+    pub fn float(&self) -> Result<f64> {
+        match &self.value {
+            Value::Float(v) => Ok(*v),
+            Value::Int(v) => Ok(*v as f64),
+            _ => Err(self.expected("a number")),
+        }
+    }
+
+    pub fn bool(&self) -> Result<bool> {
+        match &self.value {
+            Value::Bool(v) => Ok(*v),
+            _ => Err(self.expected("a boolean")),
+        }
+    }
+
+    pub fn array(&self) -> Result<&[Item]> {
+        match &self.value {
+            Value::Array(v) => Ok(v),
+            _ => Err(self.expected("an array")),
+        }
+    }
+
+    pub fn table(&self) -> Result<&Table> {
+        match &self.value {
+            Value::Table(v) => Ok(v),
+            _ => Err(self.expected("a table")),
+        }
+    }
+}
+
+/// Parses the document into the root table.
+pub fn parse(s: &str) -> Result<Table> {
+    Parser::new(s).document()
+}
+
+struct Parser {
+    chars: Vec<char>,
+    i: usize,
+    line: usize,
+    col: usize,
+}
+
+impl Parser {
+    fn new(s: &str) -> Self {
+        Self {
+            chars: s.chars().collect(),
+            i: 0,
+            line: 1,
+            col: 1,
+        }
+    }
+
+    fn pos(&self) -> Pos {
+        Pos::new(self.line, self.col)
+    }
+
+    fn error<S: fmt::Display>(&self, msg: S) -> Error {
+        anyhow!("{}: {}", self.pos(), msg)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.i).cloned()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.i += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        match self.peek() {
+            Some(p) if p == c => {
+                self.bump();
+                Ok(())
+            }
+            Some(p) => Err(self.error(format!("expected '{}', found '{}'", c, p))),
+            None => Err(self.error(format!("expected '{}', found end of file", c))),
+        }
+    }
+
+    /// Skips spaces and comments on the current line.
+    fn blank(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => {
+                    self.bump();
+                }
+                '#' => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.bump();
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Skips spaces, comments and newlines.
+    fn blank_lines(&mut self) {
+        loop {
+            self.blank();
+            if self.peek() == Some('\n') {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<()> {
+        self.blank();
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!("unexpected '{}' at the end of line", c))),
+        }
+    }
+
+    fn document(&mut self) -> Result<Table> {
+        let mut root = Table::new();
+        let mut current = vec![];
+
+        loop {
+            self.blank_lines();
+            let pos = self.pos();
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    self.bump();
+                    let array = self.peek() == Some('[');
+                    if array {
+                        self.bump();
+                    }
+                    let path = self.path()?;
+                    self.expect(']')?;
+                    if array {
+                        self.expect(']')?;
+                    }
+                    self.end_of_line()?;
+                    open(&mut root, &path, array, pos)?;
+                    current = path;
+                }
+                Some(_) => {
+                    let key = self.key()?;
+                    self.blank();
+                    self.expect('=')?;
+                    self.blank();
+                    let value = self.value()?;
+                    self.end_of_line()?;
+
+                    let table = lookup(&mut root, &current, pos)?;
+                    if table.contains_key(&key) {
+                        return Err(anyhow!("{}: duplicate key '{}'", pos, key));
+                    }
+                    table.insert(key, value);
+                }
+            }
+        }
+    }
+
+    fn path(&mut self) -> Result<Vec<String>> {
+        let mut path = vec![];
+        loop {
+            self.blank();
+            path.push(self.key()?);
+            self.blank();
+            if self.peek() == Some('.') {
+                self.bump();
+            } else {
+                return Ok(path);
+            }
+        }
+    }
+
+    fn key(&mut self) -> Result<String> {
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            _ => {
+                let mut key = String::new();
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                        key.push(c);
+                        self.bump();
+                    } else {
+                        break;
+                    }
+                }
+                if key.is_empty() {
+                    Err(self.error("expected a key"))
+                } else {
+                    Ok(key)
+                }
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Item> {
+        let pos = self.pos();
+        let value = match self.peek() {
+            Some('"') => Value::Str(self.basic_string()?),
+            Some('\'') => Value::Str(self.literal_string()?),
+            Some('[') => Value::Array(self.array()?),
+            Some(c) if c == '+' || c == '-' || c.is_ascii_digit() => self.number()?,
+            Some(c) if c.is_ascii_alphabetic() => {
+                let word = self.key()?;
+                match word.as_str() {
+                    "true" => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    _ => {
+                        return Err(anyhow!(
+                            "{}: invalid value '{}' (strings must be quoted)",
+                            pos,
+                            word
+                        ))
+                    }
+                }
+            }
+            Some(c) => return Err(self.error(format!("unexpected '{}'", c))),
+            None => return Err(self.error("expected a value, found end of file")),
+        };
+        Ok(Item::new(pos, value))
+    }
+
+    fn number(&mut self) -> Result<Value> {
+        let pos = self.pos();
+        let mut s = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || "+-._".contains(c) {
+                if c != '_' {
+                    s.push(c);
+                }
+                self.bump();
+            } else {
+                break;
+            }
+        }
+
+        if let Ok(v) = s.parse::<i64>() {
+            Ok(Value::Int(v))
+        } else if let Ok(v) = s.parse::<f64>() {
+            Ok(Value::Float(v))
+        } else {
+            Err(anyhow!("{}: invalid number '{}'", pos, s))
+        }
+    }
+
+    fn array(&mut self) -> Result<Vec<Item>> {
+        self.expect('[')?;
+        let mut items = vec![];
+        loop {
+            self.blank_lines();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(items);
+            }
+            items.push(self.value()?);
+            self.blank_lines();
+            match self.peek() {
+                Some(',') => {
+                    self.bump();
+                }
+                Some(']') => {}
+                _ => return Err(self.error("expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.bump() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some(c) => return Err(self.error(format!("invalid escape '\\{}'", c))),
+                    None => return Err(self.error("unterminated string")),
+                },
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String> {
+        self.expect('\'')?;
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(s),
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+}
+
+/// Creates the table (or a new element of the array of tables) at the path.
+fn open(root: &mut Table, path: &[String], array: bool, pos: Pos) -> Result<()> {
+    let (last, parents) = path.split_last().expect("empty path");
+    let parent = lookup(root, parents, pos)?;
+
+    match parent.get_mut(last) {
+        None if array => {
+            let table = Item::new(pos, Value::Table(Table::new()));
+            parent.insert(last.clone(), Item::new(pos, Value::Array(vec![table])));
+        }
+        None => {
+            parent.insert(last.clone(), Item::new(pos, Value::Table(Table::new())));
+        }
+        Some(Item {
+            value: Value::Array(items),
+            ..
+        }) if array => items.push(Item::new(pos, Value::Table(Table::new()))),
+        Some(_) => {
+            return Err(anyhow!(
+                "{}: table '{}' is defined twice",
+                pos,
+                path.join(".")
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the table at the path, creating intermediate tables.
+///
+/// For arrays of tables, the last element is used.
+fn lookup<'a>(root: &'a mut Table, path: &[String], pos: Pos) -> Result<&'a mut Table> {
+    let mut table = root;
+    for key in path {
+        let item = table
+            .entry(key.clone())
+            .or_insert_with(|| Item::new(pos, Value::Table(Table::new())));
+        table = match &mut item.value {
+            Value::Table(t) => t,
+            Value::Array(items) => match items.last_mut() {
+                Some(Item {
+                    value: Value::Table(t),
+                    ..
+                }) => t,
+                _ => return Err(anyhow!("{}: '{}' is not a table", pos, key)),
+            },
+            _ => return Err(anyhow!("{}: '{}' is not a table", pos, key)),
+        };
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn table(item: &Item) -> &Table {
+        match &item.value {
+            Value::Table(t) => t,
+            v => panic!("not a table: {:?}", v),
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let t = super::parse(
+            r#"
+# Show
+file = "battle.mid" # comment
+speed = 120
+segment = 2.5
+strict = true
+rules = [
+    "0=2,4",
+    '1=3',
+]
+
+[profile.stage]
+unit = 20
+
+[[cue]]
+at = 1000
+
+[[cue]]
+at = 2000
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(t["file"].str().unwrap(), "battle.mid");
+        assert_eq!(t["file"].pos, Pos::new(3, 8));
+        assert_eq!(t["speed"].uint::<u64>().unwrap(), 120);
+        assert_eq!(t["segment"].float().unwrap(), 2.5);
+        assert!(t["strict"].bool().unwrap());
+        let rules: Vec<_> = t["rules"]
+            .array()
+            .unwrap()
+            .iter()
+            .map(|i| i.str().unwrap())
+            .collect();
+        assert_eq!(rules, vec!["0=2,4", "1=3"]);
+        let stage = table(&table(&t["profile"])["stage"]);
+        assert_eq!(stage["unit"].int().unwrap(), 20);
+        let cues: Vec<_> = t["cue"]
+            .array()
+            .unwrap()
+            .iter()
+            .map(|c| table(c)["at"].int().unwrap())
+            .collect();
+        assert_eq!(cues, vec![1000, 2000]);
+    }
+
+    #[test]
+    fn errors() {
+        let err = |s: &str| super::parse(s).unwrap_err().to_string();
+
+        assert_eq!(err("a = 1\na = 2"), "line 2, column 1: duplicate key 'a'");
+        assert_eq!(
+            err("a = text"),
+            "line 1, column 5: invalid value 'text' (strings must be quoted)"
+        );
+        assert_eq!(err("a = \"x"), "line 1, column 7: unterminated string");
+        assert_eq!(
+            super::parse("a = 1").unwrap()["a"]
+                .str()
+                .unwrap_err()
+                .to_string(),
+            "line 1, column 5: expected a string, found an integer"
+        );
+    }
+}
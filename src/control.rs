@@ -0,0 +1,370 @@
+use anyhow::{Context, Result};
+use futures::{future, StreamExt};
+use log::*;
+use std::{
+    io::{BufRead, Read},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+
+/// Signals of the panic button and the playback keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    /// Silences every cube and pauses the playback.
+    Panic,
+    /// Resumes the playback.
+    Resume,
+    /// Panics while playing, resumes while paused.
+    Toggle,
+    /// Goes on to the next segment.
+    Skip,
+    /// Stops playing.
+    Quit,
+    /// Plays the song again from the start, as it changed on disk.
+    Reload,
+    /// Stops at once and turns the cubes off, on Ctrl-C.
+    Interrupt,
+}
+
+/// Interval of checking the watched file.
+const WATCH: Duration = Duration::from_millis(500);
+
+/// The modified time and the size of a file.
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &Path) -> Stamp {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Tells when a file changed, once it has stayed the same since, not to read it half written.
+struct Watch {
+    last: Stamp,
+    changed: bool,
+}
+
+impl Watch {
+    fn new(stamp: Stamp) -> Self {
+        Self {
+            last: stamp,
+            changed: false,
+        }
+    }
+
+    /// Takes the stamp of a check, returning true if the file is ready to reload.
+    fn check(&mut self, stamp: Stamp) -> bool {
+        if stamp != self.last {
+            self.last = stamp;
+            self.changed = true;
+            return false;
+        }
+        let ready = self.changed && stamp.is_some();
+        if ready {
+            self.changed = false;
+        }
+        ready
+    }
+}
+
+impl Signal {
+    /// Reads the signal from the path of HTTP or the address of OSC.
+    fn from_path(path: &str) -> Option<Signal> {
+        match path.trim_end_matches('/') {
+            "/panic" => Some(Signal::Panic),
+            "/resume" => Some(Signal::Resume),
+            _ => None,
+        }
+    }
+}
+
+/// The terminal reading each key without Enter, restored when dropped.
+struct Cbreak(String);
+
+impl Cbreak {
+    /// Switches the terminal on stdin, unless stdin isn't a terminal.
+    fn enter() -> Option<Self> {
+        let saved = stty(&["-g"])?;
+        stty(&["-icanon", "-echo", "min", "1"])?;
+        Some(Self(saved.trim().into()))
+    }
+}
+
+impl Drop for Cbreak {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.0]);
+    }
+}
+
+/// Runs `stty` on the terminal of stdin.
+fn stty(args: &[&str]) -> Option<String> {
+    let out = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if out.status.success() {
+        String::from_utf8(out.stdout).ok()
+    } else {
+        None
+    }
+}
+
+/// Receives the signals from every source of the panic button.
+pub struct Control {
+    tx: UnboundedSender<Signal>,
+    rx: UnboundedReceiver<Signal>,
+    /// Shared with the interrupt to restore the terminal before exiting.
+    terminal: Arc<Mutex<Option<Cbreak>>>,
+}
+
+impl Control {
+    pub fn new() -> Self {
+        let (tx, rx) = unbounded_channel();
+        Self {
+            tx,
+            rx,
+            terminal: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Waits for the next signal; never returns if there's no source.
+    pub async fn next(&mut self) -> Signal {
+        match self.rx.next().await {
+            Some(s) => s,
+            // Unreachable as the sender is kept.
+            None => future::pending().await,
+        }
+    }
+
+    /// Toggles on the Enter key.
+    pub fn key(&self) {
+        let tx = self.tx.clone();
+        std::thread::spawn(move || {
+            let stdin = std::io::stdin();
+            for line in stdin.lock().lines() {
+                if line.is_err() || tx.send(Signal::Toggle).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Takes the playback keys as they're pressed, or each followed by Enter
+    /// if stdin isn't a terminal.
+    pub fn keys(&mut self) {
+        let raw = {
+            let mut terminal = self.terminal.lock().unwrap();
+            *terminal = Cbreak::enter();
+            terminal.is_some()
+        };
+        let tx = self.tx.clone();
+        if raw {
+            std::thread::spawn(move || {
+                for byte in std::io::stdin().lock().bytes() {
+                    match byte {
+                        Ok(b) => {
+                            if let Some(s) = key(&(b as char).to_string()) {
+                                if tx.send(s).is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                }
+            });
+            return;
+        }
+
+        std::thread::spawn(move || {
+            let stdin = std::io::stdin();
+            for line in stdin.lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                if let Some(s) = key(&line) {
+                    if tx.send(s).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Interrupts on Ctrl-C; another one restores the terminal and exits without waiting for
+    /// the cubes.
+    pub fn interrupt(&self) {
+        let (tx, terminal) = (self.tx.clone(), self.terminal.clone());
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok()
+                && tx.send(Signal::Interrupt).is_ok()
+                && tokio::signal::ctrl_c().await.is_ok()
+            {
+                drop(terminal.lock().unwrap().take());
+                crate::journal::flush();
+                std::process::exit(130);
+            }
+        });
+    }
+
+    /// Reloads when the file changes on disk.
+    pub fn watch(&self, path: PathBuf) {
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let mut watch = Watch::new(stamp(&path));
+            let mut ticks = tokio::time::interval(WATCH);
+            loop {
+                ticks.tick().await;
+                if watch.check(stamp(&path)) && tx.send(Signal::Reload).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Accepts `/panic` and `/resume` over HTTP.
+    pub async fn http(&self, addr: SocketAddr) -> Result<()> {
+        let mut listener = TcpListener::bind(addr)
+            .await
+            .context(format!("Couldn't listen on {}", addr))?;
+        info!("Panic button on http://{}/panic and /resume", addr);
+
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("Panic button over HTTP stopped: {}", e);
+                        return;
+                    }
+                };
+
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]);
+                let path = request.lines().next().and_then(|l| l.split(' ').nth(1));
+                let res = match path.and_then(Signal::from_path) {
+                    Some(s) => {
+                        let _ = tx.send(s);
+                        "200 OK"
+                    }
+                    None => "404 Not Found",
+                };
+                let res = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    res
+                );
+                let _ = stream.write_all(res.as_bytes()).await;
+            }
+        });
+        Ok(())
+    }
+
+    /// Accepts the OSC messages `/panic` and `/resume`.
+    pub async fn osc(&self, addr: SocketAddr) -> Result<()> {
+        let mut socket = UdpSocket::bind(addr)
+            .await
+            .context(format!("Couldn't listen on {}", addr))?;
+        info!("Panic button on OSC {} /panic and /resume", addr);
+
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            loop {
+                let n = match socket.recv_from(&mut buf).await {
+                    Ok((n, _)) => n,
+                    Err(e) => {
+                        error!("Panic button over OSC stopped: {}", e);
+                        return;
+                    }
+                };
+                match osc_address(&buf[..n]).and_then(Signal::from_path) {
+                    Some(s) => {
+                        let _ = tx.send(s);
+                    }
+                    None => debug!("Unknown OSC message: {:?}", &buf[..n]),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        // The interrupt keeps a hold of it.
+        drop(self.terminal.lock().unwrap().take());
+    }
+}
+
+/// Reads the playback key, where a line of spaces is the space key.
+fn key(line: &str) -> Option<Signal> {
+    let line = line.trim_end_matches(['\n', '\r']);
+    if !line.is_empty() && line.trim().is_empty() {
+        return Some(Signal::Toggle);
+    }
+    match line.trim() {
+        "p" => Some(Signal::Toggle),
+        "s" => Some(Signal::Skip),
+        "q" => Some(Signal::Quit),
+        _ => None,
+    }
+}
+
+/// Returns the address pattern of the OSC message.
+fn osc_address(packet: &[u8]) -> Option<&str> {
+    let end = packet.iter().position(|b| *b == 0).unwrap_or(packet.len());
+    std::str::from_utf8(&packet[..end])
+        .ok()
+        .filter(|a| a.starts_with('/'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn signals() {
+        assert_eq!(Signal::from_path("/panic"), Some(Signal::Panic));
+        assert_eq!(Signal::from_path("/resume/"), Some(Signal::Resume));
+        assert_eq!(Signal::from_path("/"), None);
+
+        assert_eq!(key("p\n"), Some(Signal::Toggle));
+        assert_eq!(key("q"), Some(Signal::Quit));
+        assert_eq!(key(" "), Some(Signal::Toggle));
+        assert_eq!(key(" \n"), Some(Signal::Toggle));
+        assert_eq!(key(""), None);
+        assert_eq!(key("x"), None);
+
+        assert_eq!(osc_address(b"/panic\0\0,\0\0\0"), Some("/panic"));
+        assert_eq!(osc_address(b"#bundle\0"), None);
+    }
+
+    #[test]
+    fn watch() {
+        let t = |s| Some((SystemTime::UNIX_EPOCH + Duration::from_secs(s), 100));
+        let mut watch = Watch::new(t(1));
+        assert!(!watch.check(t(1)));
+        // Reloads once the file stays the same after the change.
+        assert!(!watch.check(t(2)));
+        assert!(!watch.check(t(3)));
+        assert!(watch.check(t(3)));
+        assert!(!watch.check(t(3)));
+        // Not while the file is missing as it's being saved.
+        assert!(!watch.check(None));
+        assert!(!watch.check(None));
+        assert!(!watch.check(t(4)));
+        assert!(watch.check(t(4)));
+    }
+}
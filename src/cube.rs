@@ -0,0 +1,198 @@
+//! The commands of the task playing on a cube, and how it queues them.
+
+use std::collections::VecDeque;
+use tokio::time::{Duration, Instant};
+
+use crate::midi::{PlaySet, Time};
+
+pub enum Command {
+    /// Plays the set from the time, which may be ahead of when it's sent.
+    Play(PlaySet, Instant),
+    Park,
+    Idle,
+    /// Fades out the sound and the light over the time and silences the cube.
+    WindDown(Duration),
+    /// Stops the sound at once, dropping the queued commands.
+    Silence,
+    /// Gets ready to play from the start again.
+    Restart,
+}
+
+/// Trims the commands waiting behind `cmd`, which is to run next, for a cube that fell
+/// behind: those before the last silence are dropped, then the oldest play sets while
+/// more than `limit` wait.
+///
+/// Returns the command to run and how many play sets were dropped for the limit.
+pub fn trim(cmd: Command, queue: &mut VecDeque<Command>, limit: usize) -> (Command, usize) {
+    let mut cmd = cmd;
+    if let Some(i) = queue.iter().rposition(|c| matches!(c, Command::Silence)) {
+        queue.drain(..i);
+        cmd = queue.pop_front().unwrap();
+    }
+    let mut dropped = 0;
+    while queue.len() > limit {
+        match queue.iter().position(|c| matches!(c, Command::Play(..))) {
+            Some(i) => drop(queue.remove(i)),
+            None => break,
+        }
+        dropped += 1;
+    }
+    (cmd, dropped)
+}
+
+/// Takes the command sent while there's no cube to play it, returning false if the
+/// playback ended.
+///
+/// A play set makes what was sent before it out of date.
+pub fn take(
+    cmd: Option<Command>,
+    last: &mut Option<(PlaySet, Instant)>,
+    queue: &mut VecDeque<Command>,
+) -> bool {
+    match cmd {
+        None | Some(Command::WindDown(_)) => return false,
+        Some(Command::Play(p, due)) => {
+            queue.clear();
+            *last = Some((p, due));
+        }
+        Some(cmd) => {
+            *last = None;
+            queue.push_back(cmd);
+        }
+    }
+    true
+}
+
+/// Returns the commands taken to carry on with, starting with the rest of the play set
+/// that should be sounding at `now`.
+pub fn carry_on(
+    last: &mut Option<(PlaySet, Instant)>,
+    mut queue: VecDeque<Command>,
+    now: Instant,
+) -> VecDeque<Command> {
+    if let Some((p, start)) = last.take() {
+        let from = now.saturating_duration_since(start).as_millis() as Time;
+        if from < p.len {
+            queue.push_front(Command::Play(p.rest(from), start.max(now)));
+        }
+    }
+    queue
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::midi::Play;
+    use toio::Note;
+
+    fn play(at: u64) -> Command {
+        Command::Play(PlaySet::new(0, at), Instant::now())
+    }
+
+    fn names(queue: &VecDeque<Command>) -> Vec<String> {
+        queue.iter().map(name).collect()
+    }
+
+    fn name(cmd: &Command) -> String {
+        match cmd {
+            Command::Play(p, _) => format!("play {}", p.at),
+            Command::Park => "park".into(),
+            Command::Idle => "idle".into(),
+            Command::WindDown(_) => "wind down".into(),
+            Command::Silence => "silence".into(),
+            Command::Restart => "restart".into(),
+        }
+    }
+
+    #[test]
+    fn trim() {
+        // Within the limit, nothing is dropped.
+        let mut queue: VecDeque<_> = vec![play(100), play(200)].into();
+        let (cmd, dropped) = super::trim(play(0), &mut queue, 2);
+        assert_eq!((name(&cmd), dropped), ("play 0".into(), 0));
+        assert_eq!(names(&queue), vec!["play 100", "play 200"]);
+
+        // Behind, the oldest play sets go first, and the other commands stay.
+        let mut queue: VecDeque<_> = vec![play(100), Command::Park, play(200), play(300)].into();
+        let (cmd, dropped) = super::trim(play(0), &mut queue, 2);
+        assert_eq!((name(&cmd), dropped), ("play 0".into(), 2));
+        assert_eq!(names(&queue), vec!["park", "play 300"]);
+
+        // Only play sets are dropped, even if more wait than the limit.
+        let mut queue: VecDeque<_> = vec![Command::Park, Command::Idle, play(100)].into();
+        let (_, dropped) = super::trim(play(0), &mut queue, 1);
+        assert_eq!(dropped, 1);
+        assert_eq!(names(&queue), vec!["park", "idle"]);
+
+        // A silence drops what was sent before it, and runs first.
+        let mut queue: VecDeque<_> = vec![play(100), Command::Silence, play(200)].into();
+        let (cmd, dropped) = super::trim(play(0), &mut queue, 2);
+        assert_eq!((name(&cmd), dropped), ("silence".into(), 0));
+        assert_eq!(names(&queue), vec!["play 200"]);
+    }
+
+    #[test]
+    fn carry_on() {
+        let mut set = PlaySet::new(0, 0);
+        set.plays = vec![
+            Play::new(0, 0, 400, Note::C4),
+            Play::new(0, 400, 600, Note::E4),
+        ];
+        set.len = 1000;
+        let start = Instant::now();
+
+        // While the cube is away, a play set makes what was queued before it out of date.
+        let (mut last, mut queue) = (None, VecDeque::new());
+        assert!(take(Some(Command::Park), &mut last, &mut queue));
+        assert!(take(
+            Some(Command::Play(set.clone(), start)),
+            &mut last,
+            &mut queue
+        ));
+        assert!(queue.is_empty());
+        assert!(take(Some(Command::Idle), &mut last, &mut queue));
+        assert!(last.is_none());
+        assert_eq!(names(&queue), vec!["idle"]);
+        assert!(take(
+            Some(Command::Play(set.clone(), start)),
+            &mut last,
+            &mut queue
+        ));
+        assert!(queue.is_empty());
+
+        // Back 500 msec later, the cube carries on from the middle of the set.
+        let now = start + Duration::from_millis(500);
+        let queue = super::carry_on(&mut last, queue, now);
+        assert!(last.is_none());
+        match queue.front() {
+            Some(Command::Play(p, due)) => {
+                assert_eq!(p.plays, vec![Play::new(0, 500, 500, Note::E4)]);
+                assert_eq!((p.at, p.len, *due), (500, 500, now));
+            }
+            _ => panic!("The rest of the set isn't carried on"),
+        }
+
+        // A set taken before its time is carried on as it is, when it's due.
+        let mut last = Some((set.clone(), now));
+        let queue = super::carry_on(&mut last, VecDeque::new(), start);
+        match queue.front() {
+            Some(Command::Play(p, due)) => assert_eq!((p.plays.len(), *due), (2, now)),
+            _ => panic!("The set isn't carried on"),
+        }
+
+        // A set already over isn't, while the queued commands are.
+        let mut last = Some((set, start));
+        let queue: VecDeque<_> = vec![Command::Park].into();
+        let later = start + Duration::from_millis(1000);
+        assert_eq!(
+            names(&super::carry_on(&mut last, queue, later)),
+            vec!["park"]
+        );
+
+        // The playback ends without a cube too.
+        let mut queue = VecDeque::new();
+        assert!(!take(None, &mut None, &mut queue));
+        let wind_down = Command::WindDown(Duration::from_millis(100));
+        assert!(!take(Some(wind_down), &mut None, &mut queue));
+    }
+}
@@ -0,0 +1,412 @@
+//! Registry of cubes by their Bluetooth identifiers with nicknames and latencies, kept in a
+//! state file.
+
+use anyhow::{anyhow, Context, Result};
+use derive_new::new;
+use log::*;
+use std::{
+    collections::BTreeMap,
+    io::BufRead,
+    path::{Path, PathBuf},
+};
+use toio::Cube;
+
+use crate::{config, link::Link, midi::Time, Opt};
+
+/// The table of the latencies in the state file.
+const LATENCY: &str = "latency";
+
+/// A cube known by its nickname.
+#[derive(Clone, Debug, PartialEq, Eq, new)]
+pub struct Registered {
+    pub name: String,
+    /// The Bluetooth identifier, such as the address.
+    pub id: String,
+}
+
+/// The cubes registered so far.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Registry {
+    cubes: Vec<Registered>,
+    /// Delays from sending a command to the sound of the cubes by identifier in msec,
+    /// found with the calibrate command.
+    latency: BTreeMap<String, Time>,
+}
+
+/// The state file, `$TOIO_MIDI_CUBES` or `cubes.toml` in the state directory of the user.
+pub fn path() -> Result<PathBuf> {
+    if let Some(path) = std::env::var_os("TOIO_MIDI_CUBES") {
+        return Ok(path.into());
+    }
+    let state = match (std::env::var_os("XDG_STATE_HOME"), std::env::var_os("HOME")) {
+        (Some(state), _) => PathBuf::from(state),
+        (None, Some(home)) => Path::new(&home).join(".local/state"),
+        (None, None) => return Err(anyhow!("No home directory to keep the cubes in")),
+    };
+    Ok(state.join("toio-midi/cubes.toml"))
+}
+
+/// Checks that the nickname can't be taken for a cube number.
+fn check(name: &str) -> Result<()> {
+    let chars = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty()
+        || !name.chars().all(chars)
+        || name.chars().all(|c| c.is_ascii_digit())
+        || name == LATENCY
+    {
+        return Err(anyhow!(
+            "Invalid nickname: {} (expected letters, digits, - and _, such as bass-cube)",
+            name
+        ));
+    }
+    Ok(())
+}
+
+impl Registry {
+    /// Reads the state file; there's no cube registered without one.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                Self::parse(&text).context(format!("Invalid cubes file {}", path.display()))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).context(format!("Couldn't read cubes file {}", path.display())),
+        }
+    }
+
+    /// Reads the state file of the user, warning of what's wrong with it.
+    pub fn local() -> Self {
+        match path().and_then(|p| Self::load(&p)) {
+            Ok(registry) => registry,
+            Err(e) => {
+                warn!("No cube nicknames: {:#}", e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Parses the lines of `name = "id"`, followed by the `[latency]` table of `"id" = msec`.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut registry = Self::default();
+        for (name, item) in config::parse(text)? {
+            if name == LATENCY {
+                for (id, ms) in item.table()? {
+                    registry.latency.insert(id.clone(), ms.uint()?);
+                }
+                continue;
+            }
+            check(&name).map_err(|e| item.error(e))?;
+            registry
+                .cubes
+                .push(Registered::new(name, item.str()?.into()));
+        }
+        Ok(registry)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let write = || format!("Couldn't write cubes file {}", path.display());
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context(write())?;
+        }
+        std::fs::write(path, self.to_string()).context(write())
+    }
+
+    pub fn cubes(&self) -> &[Registered] {
+        &self.cubes
+    }
+
+    /// The nickname of the cube of the identifier.
+    pub fn name(&self, id: &str) -> Option<&str> {
+        self.cubes
+            .iter()
+            .find(|c| c.id == id)
+            .map(|c| c.name.as_str())
+    }
+
+    /// Tells if the cube of the identifier is the cube of the nickname or identifier.
+    pub fn is(&self, id: &str, cube: &str) -> bool {
+        id.eq_ignore_ascii_case(cube) || self.name(id) == Some(cube)
+    }
+
+    /// The delay from sending a command to the sound of the cube of the identifier.
+    pub fn latency(&self, id: &str) -> Option<Time> {
+        self.latency.get(id).copied()
+    }
+
+    pub fn set_latency(&mut self, id: &str, ms: Time) {
+        self.latency.insert(id.into(), ms);
+    }
+
+    /// Names the cube, replacing its old nickname.
+    pub fn register(&mut self, name: &str, id: &str) -> Result<()> {
+        check(name)?;
+        if let Some(other) = self.cubes.iter().find(|c| c.name == name && c.id != id) {
+            return Err(anyhow!("{} is already the nickname of {}", name, other.id));
+        }
+        self.cubes.retain(|c| c.id != id);
+        self.cubes.push(Registered::new(name.into(), id.into()));
+        self.cubes.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(())
+    }
+
+    /// Labels the cube of the index for logs, such as `1 (bass-cube)`.
+    pub fn label(&self, index: usize, id: &str) -> String {
+        match self.name(id) {
+            Some(name) => format!("{} ({})", index, name),
+            None => index.to_string(),
+        }
+    }
+}
+
+/// Picks the cubes of the nicknames or identifiers out of those found as (identifier, cube),
+/// in the order given.
+pub fn pick<T>(
+    found: Vec<(String, T)>,
+    cubes: &[String],
+    registry: &Registry,
+) -> Result<Vec<(String, T)>> {
+    let mut found: Vec<_> = found.into_iter().map(Some).collect();
+    cubes
+        .iter()
+        .map(|cube| {
+            found
+                .iter_mut()
+                .find(|c| c.as_ref().is_some_and(|(id, _)| registry.is(id, cube)))
+                .and_then(|c| c.take())
+                .ok_or_else(|| anyhow!("Cube {} isn't found", cube))
+        })
+        .collect()
+}
+
+/// Keeps the `max` cubes of the strongest signals out of those found as (RSSI, cube), in
+/// the order they were found in.
+pub fn nearest<T>(found: Vec<(i32, T)>, max: usize) -> Vec<T> {
+    let mut found: Vec<_> = found.into_iter().enumerate().collect();
+    found.sort_by_key(|(i, (rssi, _))| (std::cmp::Reverse(*rssi), *i));
+    found.truncate(max);
+    found.sort_by_key(|(i, _)| *i);
+    found.into_iter().map(|(_, (_, cube))| cube).collect()
+}
+
+/// Keeps the cubes found as (identifier, cube) that are named, and the nearest of the others
+/// by `rssi` up to `max` in all, in the order they were found in.
+pub fn capped<T>(
+    found: Vec<(String, T)>,
+    named: &[&str],
+    max: usize,
+    registry: &Registry,
+    rssi: impl Fn(&T) -> i32,
+) -> Vec<(String, T)> {
+    let (named, others): (Vec<_>, Vec<_>) = found
+        .into_iter()
+        .enumerate()
+        .partition(|(_, (id, _))| named.iter().any(|n| registry.is(id, n)));
+    let left = max.saturating_sub(named.len());
+    let others = others.into_iter().map(|c| (rssi(&(c.1).1), c)).collect();
+    let mut kept: Vec<_> = named.into_iter().chain(nearest(others, left)).collect();
+    kept.sort_by_key(|(i, _)| *i);
+    kept.into_iter().map(|(_, c)| c).collect()
+}
+
+/// Orders the cubes found as (identifier, cube) so that those the rules name as
+/// (number, nickname or identifier) get the numbers of the rules; the others keep the
+/// order they were found in.
+pub fn arrange<T>(
+    found: Vec<(String, T)>,
+    targets: &[(usize, &str)],
+    registry: &Registry,
+) -> Result<Vec<T>> {
+    let n = found.len();
+    let mut found: Vec<_> = found.into_iter().map(Some).collect();
+    let mut cubes: Vec<Option<T>> = (0..n).map(|_| None).collect();
+    for &(index, target) in targets {
+        let i = found
+            .iter()
+            .position(|c| c.as_ref().is_some_and(|(id, _)| registry.is(id, target)))
+            .ok_or_else(|| anyhow!("Cube {} isn't found", target))?;
+        if index >= n {
+            return Err(anyhow!(
+                "Cube {} plays part {}, but only {} cubes are found",
+                target,
+                index,
+                n
+            ));
+        }
+        cubes[index] = found[i].take().map(|(_, cube)| cube);
+    }
+    let mut rest = found.into_iter().flatten().map(|(_, cube)| cube);
+    Ok(cubes
+        .into_iter()
+        .filter_map(|c| c.or_else(|| rest.next()))
+        .collect())
+}
+
+impl std::fmt::Display for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "# Cubes named by toio-midi cubes register")?;
+        for c in &self.cubes {
+            writeln!(f, "{} = {:?}", c.name, c.id)?;
+        }
+        if !self.latency.is_empty() {
+            writeln!(f, "\n[{}]", LATENCY)?;
+            for (id, ms) in &self.latency {
+                writeln!(f, "{:?} = {}", id, ms)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads a line of the standard input, empty at its end.
+pub(crate) async fn ask() -> Result<String> {
+    Ok(tokio::task::spawn_blocking(|| {
+        let stdin = std::io::stdin();
+        let line = stdin.lock().lines().next();
+        line.unwrap_or_else(|| Ok(String::new()))
+    })
+    .await??)
+}
+
+/// Lights up each cube found in turn and asks for its nickname, keeping them in the state file.
+pub async fn register(opt: &Opt) -> Result<()> {
+    let path = path()?;
+    let mut registry = Registry::load(&path)?;
+
+    let cubes = Cube::search().all().await?;
+    if cubes.is_empty() {
+        return Err(anyhow!("No cube found"));
+    }
+    let tracer = crate::tracer(opt);
+    for (i, cube) in cubes.into_iter().enumerate() {
+        let mut link = Link::new(i, cube, tracer.clone());
+        let id = link.cube()?.id().to_string();
+        link.cube()?
+            .connect()
+            .await
+            .context(format!("Couldn't connect to cube {}", i))?;
+        link.light_on(255, 255, 255).await?;
+        loop {
+            match registry.name(&id) {
+                Some(name) => info!(
+                    "Nickname of the lit cube {}, now {}? [Enter to keep]",
+                    id, name
+                ),
+                None => info!("Nickname of the lit cube {}? [Enter to skip]", id),
+            }
+            let name = ask().await?;
+            let name = name.trim();
+            if name.is_empty() {
+                break;
+            }
+            match registry.register(name, &id) {
+                Ok(()) => break,
+                Err(e) => warn!("{}", e),
+            }
+        }
+        link.light_off().await?;
+    }
+
+    registry.save(&path)?;
+    info!(
+        "Saved {} cubes to {}",
+        registry.cubes().len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Prints the registered cubes.
+pub fn list() -> Result<()> {
+    let path = path()?;
+    let registry = Registry::load(&path)?;
+    if registry.cubes().is_empty() {
+        info!("No cube registered in {}", path.display());
+    }
+    for c in registry.cubes() {
+        match registry.latency(&c.id) {
+            Some(ms) => println!("{} {} {}ms", c.name, c.id, ms),
+            None => println!("{} {}", c.name, c.id),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn registry() {
+        let mut registry = Registry::default();
+        registry.register("bass-cube", "d1:5e:01").unwrap();
+        registry.register("lead", "c0:ff:02").unwrap();
+        assert!(registry.register("lead", "d1:5e:01").is_err());
+        assert!(registry.register("2", "d1:5e:01").is_err());
+        assert!(registry.register("bass cube", "d1:5e:01").is_err());
+        assert_eq!(registry.name("d1:5e:01"), Some("bass-cube"));
+        assert_eq!(registry.label(1, "d1:5e:01"), "1 (bass-cube)");
+        assert_eq!(registry.label(2, "aa:bb:cc"), "2");
+
+        // Renaming replaces the old nickname.
+        registry.register("bass", "d1:5e:01").unwrap();
+        assert_eq!(registry.cubes().len(), 2);
+
+        let text = registry.to_string();
+        assert_eq!(
+            text,
+            "# Cubes named by toio-midi cubes register\nbass = \"d1:5e:01\"\nlead = \"c0:ff:02\"\n"
+        );
+        assert_eq!(Registry::parse(&text).unwrap(), registry);
+        assert!(Registry::parse("lead = 1").is_err());
+        assert!(registry.register("latency", "aa:bb:cc").is_err());
+
+        let mut timed = registry.clone();
+        timed.set_latency("c0:ff:02", 35);
+        let text = timed.to_string();
+        assert!(text.ends_with("\n[latency]\n\"c0:ff:02\" = 35\n"));
+        let parsed = Registry::parse(&text).unwrap();
+        assert_eq!(parsed.latency("c0:ff:02"), Some(35));
+        assert_eq!(parsed, timed);
+
+        let found = || {
+            vec![
+                ("c0:ff:02".to_string(), 'a'),
+                ("d1:5e:01".to_string(), 'b'),
+                ("aa:bb:cc".to_string(), 'c'),
+            ]
+        };
+        assert_eq!(
+            arrange(found(), &[], &registry).unwrap(),
+            vec!['a', 'b', 'c']
+        );
+        assert_eq!(
+            arrange(found(), &[(0, "bass"), (2, "C0:FF:02")], &registry).unwrap(),
+            vec!['b', 'c', 'a']
+        );
+        assert!(arrange(found(), &[(0, "drums")], &registry).is_err());
+        assert!(arrange(found(), &[(3, "bass")], &registry).is_err());
+
+        let cubes = |list: &[&str]| -> Vec<String> { list.iter().map(|c| c.to_string()).collect() };
+        let picked = pick(found(), &cubes(&["aa:bb:cc", "lead"]), &registry).unwrap();
+        assert_eq!(
+            picked.into_iter().map(|(_, c)| c).collect::<Vec<_>>(),
+            vec!['c', 'a']
+        );
+        assert!(pick(found(), &cubes(&["lead", "lead"]), &registry).is_err());
+
+        let rssi = vec![(-80, 'a'), (-40, 'b'), (-60, 'c'), (-40, 'd')];
+        assert_eq!(nearest(rssi.clone(), 3), vec!['b', 'c', 'd']);
+        assert_eq!(nearest(rssi, 5).len(), 4);
+
+        let strength = |c: &char| if *c == 'b' { -80 } else { -40 };
+        let kept = |named: &[&str], max| -> Vec<_> {
+            capped(found(), named, max, &registry, strength)
+                .into_iter()
+                .map(|(_, c)| c)
+                .collect()
+        };
+        assert_eq!(kept(&[], 2), vec!['a', 'c']);
+        assert_eq!(kept(&["bass"], 2), vec!['a', 'b']);
+        assert_eq!(kept(&["bass", "aa:bb:cc"], 1), vec!['b', 'c']);
+    }
+}
@@ -0,0 +1,189 @@
+//! Short demo songs in the public domain, built into the binary.
+
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+
+use crate::smf::{self, Track};
+
+/// Ticks per quarter note of the demos.
+const TIME_BASE: u16 = 480;
+/// Ticks per eighth note, the unit of the lengths in the scores.
+const EIGHTH: u32 = TIME_BASE as u32 / 2;
+
+struct Demo {
+    name: &'static str,
+    bpm: u32,
+    /// Parts as (name, score).
+    parts: &'static [(&'static str, &'static str)],
+    /// Sings the first part twice as a round of voices, each entering the eighth notes later.
+    round: Option<(usize, u32)>,
+}
+
+const FRERE_JACQUES: &str = "C4 D4 E4 C4 C4 D4 E4 C4 E4 F4 G4:4 E4 F4 G4:4 \
+    G4:1 A4:1 G4:1 F4:1 E4 C4 G4:1 A4:1 G4:1 F4:1 E4 C4 C4 G3 C4:4 C4 G3 C4:4";
+
+/// The scores are notes such as `C#4` or rests `r`, with the length in eighth notes
+/// after a colon if it isn't a quarter note.
+const DEMOS: &[Demo] = &[
+    Demo {
+        name: "ode-to-joy",
+        bpm: 120,
+        parts: &[
+            (
+                "Melody",
+                "E4 E4 F4 G4 G4 F4 E4 D4 C4 C4 D4 E4 E4:3 D4:1 D4:4 \
+                 E4 E4 F4 G4 G4 F4 E4 D4 C4 C4 D4 E4 D4:3 C4:1 C4:4",
+            ),
+            ("Bass", "C3:8 G2:8 C3:8 G2:8 C3:8 G2:8 C3:8 G2:4 C3:4"),
+        ],
+        round: None,
+    },
+    Demo {
+        name: "twinkle",
+        bpm: 100,
+        parts: &[
+            (
+                "Melody",
+                "C4 C4 G4 G4 A4 A4 G4:4 F4 F4 E4 E4 D4 D4 C4:4 \
+                 G4 G4 F4 F4 E4 E4 D4:4 G4 G4 F4 F4 E4 E4 D4:4 \
+                 C4 C4 G4 G4 A4 A4 G4:4 F4 F4 E4 E4 D4 D4 C4:4",
+            ),
+            (
+                "Bass",
+                "C3:8 F2:4 C3:4 F2:4 C3:4 G2:4 C3:4 \
+                 C3:4 G2:4 C3:4 G2:4 C3:4 G2:4 C3:4 G2:4 \
+                 C3:8 F2:4 C3:4 F2:4 C3:4 G2:4 C3:4",
+            ),
+        ],
+        round: None,
+    },
+    Demo {
+        name: "frere-jacques",
+        bpm: 110,
+        parts: &[("Voice", FRERE_JACQUES)],
+        round: Some((3, 16)),
+    },
+];
+
+/// Names of the demos.
+pub fn names() -> Vec<&'static str> {
+    DEMOS.iter().map(|d| d.name).collect()
+}
+
+/// Parses a note such as `C4`, `F#3` or `Bb2` into its MIDI note number.
+fn note(s: &str) -> Option<u8> {
+    let mut chars = s.chars();
+    let pc = match chars.next()? {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (shift, octave) = match rest.strip_prefix('#') {
+        Some(o) => (1, o),
+        None => match rest.strip_prefix('b') {
+            Some(o) => (-1, o),
+            None => (0, rest),
+        },
+    };
+    let octave: i32 = octave.parse().ok()?;
+    let n = (octave + 1) * 12 + pc + shift;
+    if (0..128).contains(&n) {
+        Some(n as u8)
+    } else {
+        None
+    }
+}
+
+/// Converts the score into notes as (tick, length, note, velocity).
+fn score(s: &str) -> Result<Vec<(u32, u32, u8, u8)>> {
+    let mut at = 0;
+    let mut notes = vec![];
+    for token in s.split_whitespace() {
+        let invalid = || anyhow!("Invalid note in demo: {}", token);
+        let (name, len) = match token.split_once(':') {
+            Some((name, len)) => (name, len.parse::<u32>().map_err(|_| invalid())?),
+            None => (token, 2),
+        };
+        let len = len * EIGHTH;
+        if name != "r" {
+            notes.push((at, len, note(name).ok_or_else(invalid)?, 100));
+        }
+        at += len;
+    }
+    Ok(notes)
+}
+
+/// Writes the demo into a MIDI file in the temporary directory.
+///
+/// Returns the file and the number of parts, which are on tracks 1 and later.
+pub fn write(name: &str) -> Result<(PathBuf, usize)> {
+    let demo = DEMOS
+        .iter()
+        .find(|d| d.name == name)
+        .ok_or_else(|| anyhow!("Unknown demo: {} (expected {})", name, names().join(", ")))?;
+
+    let mut tracks = demo
+        .parts
+        .iter()
+        .map(|(part, notes)| {
+            let mut track = Track::new(part.to_string());
+            track.notes = score(notes)?;
+            Ok(track)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if let Some((voices, delay)) = demo.round {
+        let theme = tracks.remove(0);
+        let len = theme.notes.last().map_or(0, |(at, len, _, _)| at + len);
+        tracks = (0..voices as u32)
+            .map(|v| {
+                let mut track = Track::new(format!("{} {}", theme.name, v + 1));
+                let start = v * delay * EIGHTH;
+                track.notes = (0..2)
+                    .flat_map(|r| theme.notes.iter().map(move |n| (start + r * len, n)))
+                    .map(|(offset, &(at, l, n, vel))| (offset + at, l, n, vel))
+                    .collect();
+                track
+            })
+            .collect();
+    }
+
+    let path = std::env::temp_dir().join(format!("toio-midi-{}.mid", demo.name));
+    smf::write(&path, TIME_BASE, 60_000_000 / demo.bpm, &tracks)?;
+    Ok((path, tracks.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn demos() {
+        assert_eq!(note("C4"), Some(60));
+        assert_eq!(note("F#3"), Some(54));
+        assert_eq!(note("Bb2"), Some(46));
+        assert_eq!(note("H4"), None);
+        assert_eq!(
+            score("C4 r:1 A4:4").unwrap(),
+            vec![(0, 480, 60, 100), (720, 960, 69, 100)]
+        );
+        assert!(score("C4:x").is_err());
+
+        for demo in DEMOS {
+            for (_, notes) in demo.parts {
+                score(notes).unwrap();
+            }
+        }
+        assert!(write("unknown").is_err());
+
+        let (path, parts) = write("frere-jacques").unwrap();
+        assert_eq!(parts, 3);
+        std::fs::remove_file(path).unwrap();
+    }
+}
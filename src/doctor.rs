@@ -0,0 +1,119 @@
+//! Diagnostics of the Bluetooth stack and the cubes, telling what to do about each problem.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use tokio::time::{Duration, Instant};
+
+use crate::{
+    cubes,
+    link::Link,
+    preflight::{self, Report},
+    Opt,
+};
+
+/// How many commands are sent to measure the latency of a cube.
+const PINGS: u32 = 10;
+/// The latency that makes the cubes fall behind the song.
+const SLOW: Duration = Duration::from_millis(100);
+
+async fn connect(link: &mut Link) -> Result<((), String)> {
+    link.cube()?.connect().await?;
+    Ok(((), "connected".into()))
+}
+
+async fn firmware(link: &mut Link) -> Result<(String, String)> {
+    let version = link.cube()?.version().await?;
+    Ok((version.clone(), format!("protocol version {}", version)))
+}
+
+async fn battery(link: &mut Link) -> Result<((), String)> {
+    let level = link.cube()?.battery().await?;
+    if level < crate::LOW_BATTERY {
+        return Err(anyhow!("{}% left", level));
+    }
+    Ok(((), format!("{}%", level)))
+}
+
+/// Measures the round trip of the commands acknowledged by the cube.
+async fn latency(link: &mut Link) -> Result<((), String)> {
+    let mut max = Duration::from_millis(0);
+    let mut total = Duration::from_millis(0);
+    for _ in 0..PINGS {
+        let start = Instant::now();
+        link.stop_sound().await?;
+        let took = start.elapsed();
+        max = max.max(took);
+        total += took;
+    }
+    let detail = format!("{:?} on average, up to {:?}", total / PINGS, max);
+    if total / PINGS > SLOW {
+        return Err(anyhow!("{}", detail));
+    }
+    Ok(((), detail))
+}
+
+/// Checks the Bluetooth stack and every cube in range, failing if any check fails.
+pub async fn run(opt: &Opt) -> Result<()> {
+    let mut report = Report::default();
+
+    let cubes = match report.check("Bluetooth stack", preflight::adapter().await) {
+        Some(cubes) => cubes,
+        None => {
+            report.advise("Turn Bluetooth on, let the terminal use it, and power the cubes on");
+            return Err(anyhow!("Doctor failed: {} checks failed", report.failed));
+        }
+    };
+
+    let registry = cubes::Registry::local();
+    let tracer = crate::tracer(opt);
+    let mut versions = vec![];
+    for (i, cube) in cubes.into_iter().enumerate() {
+        let mut link = Link::new(i, cube, tracer.clone());
+        let name = registry.label(i, link.cube()?.id());
+        if report
+            .check(
+                &format!("Cube {} connection", name),
+                connect(&mut link).await,
+            )
+            .is_none()
+        {
+            report.advise("Put the cube closer, or press its button to turn it on again");
+            continue;
+        }
+        if let Some(version) = report.check(
+            &format!("Cube {} firmware", name),
+            firmware(&mut link).await,
+        ) {
+            versions.push(version);
+        }
+        if report
+            .check(&format!("Cube {} battery", name), battery(&mut link).await)
+            .is_none()
+        {
+            report.advise("Charge the cube, or give --reassign-battery to play on with it");
+        }
+        if report
+            .check(&format!("Cube {} latency", name), latency(&mut link).await)
+            .is_none()
+        {
+            report.advise("Put the cube closer, or play on fewer cubes with --max-cubes");
+        }
+    }
+
+    versions.sort();
+    versions.dedup();
+    if versions.len() > 1 {
+        report.check::<()>(
+            "Firmware",
+            Err(anyhow!("the cubes run {}", versions.join(", "))),
+        );
+        report.advise("Update the cubes with the toio app, or correct the octaves with tune");
+    }
+
+    if report.failed > 0 {
+        Err(anyhow!("Doctor failed: {} checks failed", report.failed))
+    } else {
+        info!("No problem found");
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+//! Re-anchoring of the schedule when the play sets keep going out late over long pieces.
+
+use tokio::time::{Duration, Instant};
+
+/// How often the schedule may be re-anchored.
+const PERIOD: Duration = Duration::from_secs(10);
+/// The lag left alone.
+const THRESHOLD: Duration = Duration::from_millis(5);
+/// The most the schedule is moved at once, not to stretch a gap audibly.
+const MAX_STEP: Duration = Duration::from_millis(20);
+
+/// The most the schedule is moved in all, not to fall behind the song for good.
+const MAX_OFFSET: Duration = Duration::from_millis(200);
+
+/// Which way to move the rest of the schedule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Correction {
+    Later(Duration),
+    Earlier(Duration),
+}
+
+/// Keeps track of how late the play sets go out.
+pub struct Drift {
+    lags: Vec<Duration>,
+    since: Instant,
+    /// How much later the schedule has been moved in all.
+    moved: Duration,
+}
+
+impl Drift {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            lags: vec![],
+            since: now,
+            moved: Duration::from_millis(0),
+        }
+    }
+
+    /// Records how late a play set went out.
+    pub fn record(&mut self, late: Duration) {
+        self.lags.push(late);
+    }
+
+    /// Once a period, returns how much to move the rest of the schedule by.
+    ///
+    /// The schedule goes later by the median lag of the period, so that the gaps after a
+    /// late set aren't cut short, and back earlier once the sets go out on time again.
+    pub fn correction(&mut self, now: Instant) -> Option<Correction> {
+        if now < self.since + PERIOD || self.lags.is_empty() {
+            return None;
+        }
+        self.since = now;
+        self.lags.sort();
+        let median = self.lags[self.lags.len() / 2];
+        self.lags.clear();
+
+        let step = if median < THRESHOLD {
+            let step = self.moved.min(MAX_STEP);
+            self.moved -= step;
+            Correction::Earlier(step)
+        } else {
+            let step = median.min(MAX_STEP).min(MAX_OFFSET - self.moved);
+            self.moved += step;
+            Correction::Later(step)
+        };
+        match step {
+            Correction::Later(d) | Correction::Earlier(d) if d.as_nanos() == 0 => None,
+            step => Some(step),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn correction() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut drift = Drift::new(start);
+        for late in &[8, 12, 9, 300, 10] {
+            drift.record(ms(*late));
+        }
+        assert_eq!(drift.correction(start + ms(1000)), None);
+        assert_eq!(
+            drift.correction(start + PERIOD),
+            Some(Correction::Later(ms(10)))
+        );
+        // The lags are counted again from then.
+        assert_eq!(drift.correction(start + PERIOD * 2), None);
+
+        drift.record(ms(80));
+        assert_eq!(
+            drift.correction(start + PERIOD * 3),
+            Some(Correction::Later(MAX_STEP))
+        );
+
+        // On time again, the schedule goes back as far as it was moved.
+        drift.record(ms(2));
+        assert_eq!(
+            drift.correction(start + PERIOD * 4),
+            Some(Correction::Earlier(MAX_STEP))
+        );
+        drift.record(ms(2));
+        assert_eq!(
+            drift.correction(start + PERIOD * 5),
+            Some(Correction::Earlier(ms(10)))
+        );
+        drift.record(ms(2));
+        assert_eq!(drift.correction(start + PERIOD * 6), None);
+    }
+
+    #[test]
+    fn max_offset() {
+        let start = Instant::now();
+        let mut drift = Drift::new(start);
+        let mut moved = Duration::from_millis(0);
+        for i in 1..20 {
+            drift.record(Duration::from_millis(100));
+            if let Some(Correction::Later(d)) = drift.correction(start + PERIOD * i) {
+                moved += d;
+            }
+        }
+        assert_eq!(moved, MAX_OFFSET);
+    }
+}
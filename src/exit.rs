@@ -0,0 +1,115 @@
+use log::{Level, Log, Metadata, Record};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::span;
+
+/// Categories of failures, each exiting with its own code so that scripts can
+/// branch on them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The MIDI file couldn't be read.
+    Parse,
+    /// Bluetooth or the cubes failed.
+    Bluetooth,
+    /// The conversion warned more than allowed.
+    Warnings,
+    /// The file uses features the cubes can't play.
+    Unsupported,
+}
+
+impl Failure {
+    pub fn code(self) -> i32 {
+        match self {
+            Failure::Parse => 2,
+            Failure::Bluetooth => 3,
+            Failure::Warnings => 4,
+            Failure::Unsupported => 5,
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Failure::Parse => write!(f, "Couldn't read the MIDI file"),
+            Failure::Bluetooth => write!(f, "Bluetooth error"),
+            Failure::Warnings => write!(f, "Too many warnings on conversion"),
+            Failure::Unsupported => write!(f, "Unsupported MIDI features"),
+        }
+    }
+}
+
+/// Returns the exit code for the error; 1 for uncategorized ones.
+pub fn code(e: &anyhow::Error) -> i32 {
+    e.downcast_ref::<Failure>().map(|f| f.code()).unwrap_or(1)
+}
+
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+/// Modules whose warnings are about the conversion of the file.
+const CONVERSION: &[&str] = &["toio_midi::midi", "toio_midi::smf"];
+
+/// Counts the warnings on conversion on top of the logger.
+struct Counter(env_logger::Logger);
+
+impl Log for Counter {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Warn && CONVERSION.contains(&record.target()) {
+            WARNINGS.fetch_add(1, Ordering::Relaxed);
+        }
+        if span::hidden() {
+            return;
+        }
+        let spans = span::current();
+        if spans.is_empty() {
+            return self.0.log(record);
+        }
+        self.0.log(
+            &Record::builder()
+                .args(format_args!("[{}] {}", spans, record.args()))
+                .level(record.level())
+                .target(record.target())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        )
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// Installs the logger counting the warnings on conversion.
+pub fn init(logger: env_logger::Logger) {
+    // Warnings are counted even if they are filtered out.
+    log::set_max_level(logger.filter().max(log::LevelFilter::Warn));
+    log::set_boxed_logger(Box::new(Counter(logger))).expect("Logger is already set");
+}
+
+/// Returns the number of warnings on conversion so far.
+pub fn warnings() -> usize {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::{anyhow, Context, Result};
+
+    #[test]
+    fn codes() {
+        let e: Result<()> = Err(anyhow!("Bad header")).context(Failure::Parse);
+        assert_eq!(code(&e.unwrap_err()), 2);
+        let e: Result<()> = Err(anyhow!("Lost")).context(Failure::Bluetooth);
+        assert_eq!(code(&e.context("while playing").unwrap_err()), 3);
+        assert_eq!(code(&anyhow!("Other")), 1);
+        let e: Result<()> = Err(anyhow!("Pitch bends")).context(Failure::Unsupported);
+        assert_eq!(code(&e.unwrap_err()), 5);
+    }
+}
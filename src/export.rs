@@ -0,0 +1,423 @@
+//! Exports of the schedule of each cube for other toio tools and DAWs.
+
+use anyhow::{anyhow, Context, Result};
+use std::{collections::BTreeMap, convert::TryFrom, path::Path};
+use toio::{Note, SoundOp};
+
+use crate::{
+    json::Json,
+    midi::{Channel, Plan, Play, PlaySet, Time},
+    route::Router,
+    smf::{self, Track},
+    song,
+};
+
+/// Ticks per quarter note of exported MIDI files, at 60 bpm so that a tick is a msec.
+const TIME_BASE: u16 = 1000;
+/// Semitones between the MIDI note numbers and the notes of the cubes.
+const MIDI_OFFSET: u8 = 12;
+
+/// Name of the note in toio.js such as `C#4`.
+fn note_name(note: Note) -> String {
+    match note {
+        Note::NoSound => "NO_SOUND".into(),
+        n => song::note_name(n),
+    }
+}
+
+fn call(at: Time, method: &str, args: Vec<Json>) -> Json {
+    Json::object(vec![
+        ("at", at.into()),
+        ("method", method.into()),
+        ("args", Json::Array(args)),
+    ])
+}
+
+fn play_sound(at: Time, ops: &[SoundOp], repeat: u8) -> Json {
+    let ops = ops
+        .iter()
+        .map(|op| {
+            Json::object(vec![
+                ("durationMs", (op.duration.as_millis() as u64).into()),
+                ("noteName", note_name(op.note).into()),
+                ("note", (op.note as u8).into()),
+            ])
+        })
+        .collect();
+    call(at, "playSound", vec![Json::Array(ops), repeat.into()])
+}
+
+/// The cubes playing the set with what each plays, the mirrors transposed.
+pub fn targets(set: &PlaySet, router: &Router) -> Vec<(usize, PlaySet)> {
+    let mut targets: Vec<(usize, PlaySet)> = router
+        .mirrors(set.ch)
+        .into_iter()
+        .map(|(cube, interval)| (cube, set.transposed(interval)))
+        .collect();
+    if let Some(cube) = router.cube(set.ch) {
+        targets.push((cube, set.clone()));
+    }
+    targets
+}
+
+/// Converts the plan into the calls of toio.js on each cube, with the time in msec
+/// from the start at which each call is made.
+///
+/// Each cube is `{"cube": 0, "calls": [{"at": 0, "method": "playSound", "args": [...]}]}`
+/// so that a script can replay it with `cube[call.method](...call.args)`.
+pub fn toio<F: Fn(usize) -> (u8, u8, u8)>(plan: &Plan, router: &Router, color: F) -> Json {
+    let mut cubes: BTreeMap<usize, (Vec<(Time, Json)>, Time)> = BTreeMap::new();
+
+    for set in plan.values() {
+        for (cube, set) in targets(set, router) {
+            let (calls, end) = cubes.entry(cube).or_default();
+            let mut at = set.at;
+            for chunk in set.chunks() {
+                calls.push((at, play_sound(at, &chunk, set.repeat)));
+                let len: Time = chunk.iter().map(|op| op.duration.as_millis() as Time).sum();
+                at += len * set.repeat as Time;
+            }
+            *end = (*end).max(set.at + set.len);
+        }
+    }
+
+    let cubes: Vec<_> = cubes
+        .into_iter()
+        .map(|(cube, (mut calls, end))| {
+            calls.sort_by_key(|(at, _)| *at);
+
+            let (r, g, b) = color(cube);
+            let light = Json::object(vec![
+                ("durationMs", 0u64.into()),
+                ("red", r.into()),
+                ("green", g.into()),
+                ("blue", b.into()),
+            ]);
+            let calls = std::iter::once(call(0, "turnOnLight", vec![light]))
+                .chain(calls.into_iter().map(|(_, c)| c))
+                .chain(std::iter::once(call(end, "stopSound", vec![])))
+                .collect();
+
+            Json::object(vec![("cube", cube.into()), ("calls", Json::Array(calls))])
+        })
+        .collect();
+
+    Json::object(vec![("cubes", Json::Array(cubes))])
+}
+
+/// Converts the segments of the plan into JSON as they're sent to the cubes.
+///
+/// Each play set is `{"part": 0, "at": 0, "len": 500, "repeat": 1, "plays": [...]}`
+/// with the times in msec from the start of the song, not of the segment.
+pub fn plan(segments: &[Plan]) -> Json {
+    let segments = segments
+        .iter()
+        .map(|plan| {
+            let sets = plan
+                .values()
+                .map(|set| {
+                    let plays = set
+                        .plays
+                        .iter()
+                        .map(|play| {
+                            Json::object(vec![
+                                ("at", play.at.into()),
+                                ("len", play.len.into()),
+                                ("note", (play.note as u8).into()),
+                                ("noteName", note_name(play.note).into()),
+                                ("velocity", play.vel.into()),
+                            ])
+                        })
+                        .collect();
+                    Json::object(vec![
+                        ("part", set.ch.into()),
+                        ("at", set.at.into()),
+                        ("len", set.len.into()),
+                        ("repeat", set.repeat.into()),
+                        ("plays", Json::Array(plays)),
+                    ])
+                })
+                .collect();
+            Json::object(vec![("sets", Json::Array(sets))])
+        })
+        .collect();
+
+    Json::object(vec![("segments", Json::Array(segments))])
+}
+
+/// Converts the plan into a track per part with the notes as the cubes play them,
+/// repetitions unrolled.
+fn tracks(plan: &Plan) -> Vec<Track> {
+    let mut parts: BTreeMap<Channel, Track> = BTreeMap::new();
+    for set in plan.values() {
+        let track = parts
+            .entry(set.ch)
+            .or_insert_with(|| Track::new(format!("Part {}", set.ch)));
+        for play in set.unrolled() {
+            if play.note != Note::NoSound {
+                let note = (play.note as u8).saturating_add(MIDI_OFFSET).min(127);
+                track
+                    .notes
+                    .push((play.at as u32, play.len as u32, note, play.vel));
+            }
+        }
+    }
+    parts.into_values().collect()
+}
+
+/// Writes the plan into a standard MIDI file with a track per part.
+pub fn midi(plan: &Plan, path: &Path) -> Result<()> {
+    smf::write(path, TIME_BASE, 1_000_000, &tracks(plan))
+}
+
+fn field(json: &Json, key: &str) -> Result<u64> {
+    json.get(key)
+        .ok_or_else(|| anyhow!("missing '{}'", key))?
+        .as_u64()
+        .ok_or_else(|| anyhow!("'{}' must be a non-negative integer", key))
+}
+
+fn play_set(json: &Json) -> Result<PlaySet> {
+    let ch = Channel::try_from(field(json, "part")?).map_err(|_| anyhow!("invalid part"))?;
+    let repeat = match json.get("repeat") {
+        Some(_) => u8::try_from(field(json, "repeat")?)
+            .ok()
+            .filter(|r| *r > 0)
+            .ok_or_else(|| anyhow!("'repeat' must be from 1 to 255"))?,
+        None => 1,
+    };
+
+    let mut plays = vec![];
+    for (i, play) in json
+        .get("plays")
+        .and_then(|p| p.as_array())
+        .ok_or_else(|| anyhow!("missing the array 'plays'"))?
+        .iter()
+        .enumerate()
+    {
+        let play = (|| -> Result<Play> {
+            let note = field(play, "note")?;
+            let note = u8::try_from(note)
+                .ok()
+                .and_then(|n| Note::try_from(n).ok())
+                .ok_or_else(|| anyhow!("invalid note {}", note))?;
+            let mut p = Play::new(ch, field(play, "at")?, field(play, "len")?, note);
+            if play.get("velocity").is_some() {
+                p.vel = u8::try_from(field(play, "velocity")?)
+                    .map_err(|_| anyhow!("'velocity' must be up to 255"))?;
+            }
+            Ok(p)
+        })()
+        .context(format!("play {}", i))?;
+        plays.push(play);
+    }
+    plays.sort_by_key(|p| p.at);
+
+    let at = match json.get("at") {
+        Some(_) => field(json, "at")?,
+        None => plays.first().map_or(0, |p| p.at),
+    };
+    let mut set = PlaySet::new(ch, at);
+    let mut end = at;
+    for play in plays {
+        if play.at < end {
+            return Err(anyhow!("play at {} overlaps the previous one", play.at));
+        }
+        // The cube plays the operations back to back; fill the gaps with rests.
+        if play.at > end {
+            set.plays
+                .push(Play::new(ch, end, play.at - end, Note::NoSound));
+        }
+        end = play
+            .at
+            .checked_add(play.len)
+            .ok_or_else(|| anyhow!("play at {} is too long", play.at))?;
+        set.plays.push(play);
+    }
+    set.len = (end - at)
+        .checked_mul(repeat as Time)
+        .filter(|len| at.checked_add(*len).is_some())
+        .ok_or_else(|| anyhow!("the set is too long to repeat {} times", repeat))?;
+    set.repeat = repeat;
+    Ok(set)
+}
+
+/// Reads the segments of the plan written by [`plan`], possibly edited by hand.
+///
+/// Gaps between plays become rests, missing velocities are the default and the lengths
+/// of play sets are taken from their plays.
+pub fn read_plan(json: &Json) -> Result<Vec<Plan>> {
+    let segments = json
+        .get("segments")
+        .and_then(|s| s.as_array())
+        .ok_or_else(|| anyhow!("missing the array 'segments'"))?;
+
+    segments
+        .iter()
+        .enumerate()
+        .map(|(i, segment)| {
+            let sets = segment
+                .get("sets")
+                .and_then(|s| s.as_array())
+                .ok_or_else(|| anyhow!("segment {}: missing the array 'sets'", i))?;
+            let mut plan = Plan::new();
+            for (j, set) in sets.iter().enumerate() {
+                let set = play_set(set).context(format!("segment {}, set {}", i, j))?;
+                if plan.contains_key(&(set.at, set.ch)) {
+                    return Err(anyhow!(
+                        "segment {}, set {}: part {} has another set at {}",
+                        i,
+                        j,
+                        set.ch,
+                        set.at
+                    ));
+                }
+                plan.insert((set.at, set.ch), set);
+            }
+            Ok(plan)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::route::Mirror;
+
+    #[test]
+    fn toio() {
+        let mut set = PlaySet::new(0, 100);
+        set.plays = vec![
+            Play::new(0, 100, 300, Note::A4),
+            Play::new(0, 400, 200, Note::NoSound),
+        ];
+        set.len = 500;
+        let plan: Plan = vec![((100, 0), set)].into_iter().collect();
+
+        let mut router = Router::new(2, vec![0]);
+        router
+            .mirror(&"cube1=cube0:+12".parse::<Mirror>().unwrap())
+            .unwrap();
+
+        let json = super::toio(&plan, &router, |i| {
+            if i == 0 {
+                (255, 0, 0)
+            } else {
+                (0, 255, 0)
+            }
+        });
+        let cube = |i: usize| match &json {
+            Json::Object(root) => match &root[0].1 {
+                Json::Array(cubes) => cubes[i].to_string(),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+
+        assert_eq!(
+            cube(0),
+            concat!(
+                r#"{"cube":0,"calls":["#,
+                r#"{"at":0,"method":"turnOnLight","args":[{"durationMs":0,"red":255,"green":0,"blue":0}]},"#,
+                r#"{"at":100,"method":"playSound","args":[["#,
+                r#"{"durationMs":300,"noteName":"A4","note":57},"#,
+                r#"{"durationMs":200,"noteName":"NO_SOUND","note":128}],1]},"#,
+                r#"{"at":600,"method":"stopSound","args":[]}]}"#
+            )
+        );
+        assert!(cube(1).contains(r#""noteName":"A5","note":69"#));
+    }
+
+    #[test]
+    fn plan() {
+        let mut set = PlaySet::new(1, 100);
+        set.plays = vec![Play::new(1, 100, 300, Note::A4)];
+        set.len = 300;
+        let plan: Plan = vec![((100, 1), set)].into_iter().collect();
+
+        assert_eq!(
+            super::plan(&[plan]).to_string(),
+            concat!(
+                r#"{"segments":[{"sets":[{"part":1,"at":100,"len":300,"repeat":1,"plays":["#,
+                r#"{"at":100,"len":300,"note":57,"noteName":"A4","velocity":100}]}]}]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn read_plan() {
+        let mut set = PlaySet::new(1, 100);
+        set.plays = vec![
+            Play::new(1, 100, 300, Note::A4),
+            Play::new(1, 400, 200, Note::NoSound),
+            Play::new(1, 600, 100, Note::C4),
+        ];
+        set.len = 1200;
+        set.repeat = 2;
+        let plan: Plan = vec![((100, 1), set)].into_iter().collect();
+        let json =
+            crate::json::parse(&super::plan(std::slice::from_ref(&plan)).to_string()).unwrap();
+        assert_eq!(super::read_plan(&json).unwrap(), vec![plan]);
+
+        // Hand-written gaps become rests.
+        let json = crate::json::parse(
+            r#"{"segments": [{"sets": [{"part": 0, "plays": [
+                {"at": 500, "len": 100, "note": 57},
+                {"at": 0, "len": 100, "note": 48}
+            ]}]}]}"#,
+        )
+        .unwrap();
+        let set = &super::read_plan(&json).unwrap()[0][&(0, 0)];
+        let notes: Vec<_> = set.plays.iter().map(|p| (p.at, p.len, p.note)).collect();
+        assert_eq!(
+            notes,
+            vec![
+                (0, 100, Note::C4),
+                (100, 400, Note::NoSound),
+                (500, 100, Note::A4)
+            ]
+        );
+        assert_eq!(set.len, 600);
+
+        let err = |s: &str| {
+            let json = crate::json::parse(s).unwrap();
+            format!("{:#}", super::read_plan(&json).unwrap_err())
+        };
+        assert_eq!(
+            err(
+                r#"{"segments": [{"sets": [{"part": 0, "plays": [{"at": 0, "len": 1, "note": 200}]}]}]}"#
+            ),
+            "segment 0, set 0: play 0: invalid note 200"
+        );
+        assert_eq!(
+            err(r#"{"segments": [{"sets": [{"plays": []}]}]}"#),
+            "segment 0, set 0: missing 'part'"
+        );
+        assert_eq!(
+            err(
+                r#"{"segments": [{"sets": [{"part": 0, "repeat": 2, "plays": [{"at": 9223372036854775807, "len": 9223372036854775807, "note": 60}]}]}]}"#
+            ),
+            "segment 0, set 0: the set is too long to repeat 2 times"
+        );
+    }
+
+    #[test]
+    fn tracks() {
+        let mut set = PlaySet::new(1, 100);
+        set.plays = vec![
+            Play::new(1, 100, 300, Note::A4),
+            Play::new(1, 400, 200, Note::NoSound),
+        ];
+        set.len = 1000;
+        set.repeat = 2;
+        let plan: Plan = vec![((100, 1), set)].into_iter().collect();
+
+        let tracks = super::tracks(&plan);
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].name, "Part 1");
+        assert_eq!(
+            tracks[0].notes,
+            vec![(100, 300, 69, 100), (600, 300, 69, 100)]
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Fade-out over the end of the song so that it doesn't stop abruptly.
+
+use toio::Note;
+
+use crate::midi::{self, Plan, Play, Time, OP_LEN_UNIT};
+
+/// Shortens and softens the notes over the last `len` msec until the last note ends.
+///
+/// The notes keep their starts; what they lose is played as rests, so they thin out
+/// towards the end.
+pub fn fade_out(plan: Plan, len: Time) -> Plan {
+    let end = midi::last_note(&plan);
+    let from = end.saturating_sub(len);
+    let len = end - from;
+
+    plan.into_iter()
+        .map(|(key, mut set)| {
+            if set.at + set.len <= from || len == 0 {
+                return (key, set);
+            }
+            // The repetitions fade one by one.
+            let plays = set.unrolled();
+            set.repeat = 1;
+            set.plays = plays
+                .into_iter()
+                .flat_map(|play| {
+                    if play.note == Note::NoSound || play.at < from {
+                        return vec![play];
+                    }
+                    let left = end.saturating_sub(play.at);
+                    let mut note = play.clone();
+                    note.len = (play.len * left / len / OP_LEN_UNIT * OP_LEN_UNIT)
+                        .clamp(OP_LEN_UNIT.min(play.len), play.len);
+                    note.vel = (play.vel as Time * left / len).max(1) as u8;
+                    if note.len == play.len {
+                        return vec![note];
+                    }
+                    let rest = Play::new(
+                        play.ch,
+                        play.at + note.len,
+                        play.len - note.len,
+                        Note::NoSound,
+                    );
+                    vec![note, rest]
+                })
+                .collect();
+            (key, set)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::midi::PlaySet;
+
+    #[test]
+    fn fade_out() {
+        let mut set = PlaySet::new(0, 0);
+        set.plays = (0..4)
+            .map(|i| Play::new(0, i * 500, 500, Note::C4))
+            .collect();
+        set.len = 2000;
+        let mut plan = Plan::new();
+        plan.insert((0, 0), set);
+
+        let faded = super::fade_out(plan, 1000);
+        let plays: Vec<_> = faded[&(0, 0)]
+            .plays
+            .iter()
+            .map(|p| (p.at, p.len, p.note, p.vel))
+            .collect();
+        assert_eq!(
+            plays,
+            vec![
+                (0, 500, Note::C4, 100),
+                (500, 500, Note::C4, 100),
+                (1000, 500, Note::C4, 100),
+                (1500, 250, Note::C4, 50),
+                (1750, 250, Note::NoSound, 100),
+            ]
+        );
+        assert_eq!(faded[&(0, 0)].len, 2000);
+    }
+}
@@ -0,0 +1,66 @@
+//! Identification of the cubes one by one so that they can be put in place before a show.
+
+use anyhow::{Context, Result};
+use log::*;
+use toio::{LightOp, Note, SoundOp};
+use tokio::time::{delay_for, Duration};
+
+use crate::{cubes, link::Link, Opt};
+
+/// The arpeggio each cube plays in turn.
+const ARPEGGIO: [Note; 4] = [Note::C5, Note::E5, Note::G5, Note::C6];
+/// How long each note of the arpeggio sounds.
+const NOTE: Duration = Duration::from_millis(150);
+/// How many times the cube blinks.
+const BLINKS: usize = 3;
+/// How long the light is on and off on a blink.
+const BLINK: Duration = Duration::from_millis(200);
+
+/// Blinks the cube in its color and plays the arpeggio, then keeps it lit.
+async fn identify(link: &mut Link, (r, g, b): (u8, u8, u8)) -> Result<()> {
+    let blink = [
+        LightOp::new(r, g, b, Some(BLINK)),
+        LightOp::new(0, 0, 0, Some(BLINK)),
+    ];
+    link.light(BLINKS, &blink).await?;
+    let ops: Vec<_> = ARPEGGIO.iter().map(|n| SoundOp::new(*n, NOTE)).collect();
+    link.play(1, &ops).await?;
+    delay_for(BLINK * 2 * BLINKS as u32).await;
+    link.light_on(r, g, b).await
+}
+
+/// Connects the cubes numbered as in the show, and has each in turn blink in its color
+/// and play an arpeggio while printing its number, until Enter.
+pub async fn run(opt: &Opt) -> Result<()> {
+    let mut links = crate::search(opt).await?;
+    let registry = cubes::Registry::local();
+    for link in &mut links {
+        let i = link.index;
+        link.cube()?
+            .connect()
+            .await
+            .context(format!("Couldn't connect to cube {}", i))?;
+    }
+
+    for link in &mut links {
+        let i = link.index;
+        let id = link.cube()?.id().to_string();
+        match opt.rules.iter().find(|r| r.as_ch as usize == i) {
+            Some(rule) => info!(
+                "Cube {} is {}, playing {}",
+                registry.label(i, &id),
+                id,
+                rule
+            ),
+            None => info!("Cube {} is {}", registry.label(i, &id), id),
+        }
+        identify(link, crate::color(opt, i)).await?;
+    }
+
+    info!("Press Enter when the cubes are in place");
+    cubes::ask().await?;
+    for link in &mut links {
+        link.light_off().await?;
+    }
+    Ok(())
+}
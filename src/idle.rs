@@ -0,0 +1,101 @@
+use anyhow::{anyhow, Error, Result};
+use toio::{LightOp, Note, SoundOp};
+use tokio::time::Duration;
+
+use crate::link::Link;
+
+/// Gentle behavior of cubes while no song is playing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Idle {
+    /// Slowly breathes the light of the cube.
+    pub breathe: bool,
+    /// Plays a soft chime now and then.
+    pub chime: bool,
+}
+
+impl Idle {
+    pub fn enabled(&self) -> bool {
+        self.breathe || self.chime
+    }
+}
+
+impl std::str::FromStr for Idle {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut idle = Idle::default();
+        for b in s.split(',') {
+            match b {
+                "breathe" => idle.breathe = true,
+                "chime" => idle.chime = true,
+                "off" => {}
+                _ => {
+                    return Err(anyhow!(
+                        "Invalid idle behavior: {} (expected breathe, chime or off)",
+                        b
+                    ))
+                }
+            }
+        }
+        Ok(idle)
+    }
+}
+
+/// Light program fading the color in and out.
+fn breath((r, g, b): (u8, u8, u8)) -> Vec<LightOp> {
+    let steps = 14;
+    (0..steps)
+        .chain((0..steps).rev())
+        .map(|i| {
+            // Keep a glow at the bottom so the cube never looks turned off.
+            let level = |c: u8| (c as u32 * (i + 2) / (steps + 1)) as u8;
+            LightOp::new(
+                level(r),
+                level(g),
+                level(b),
+                Some(Duration::from_millis(150)),
+            )
+        })
+        .collect()
+}
+
+/// Starts the idle behavior on the cube; the breathing runs on the cube itself.
+pub async fn start(link: &mut Link, idle: &Idle, color: (u8, u8, u8)) -> Result<()> {
+    if idle.breathe {
+        link.light(0, &breath(color)).await?;
+    }
+    Ok(())
+}
+
+pub async fn chime(link: &mut Link) -> Result<()> {
+    link.play_with_volume(
+        1,
+        &[
+            SoundOp::new(Note::E6, Duration::from_millis(150)),
+            SoundOp::new(Note::B6, Duration::from_millis(400)),
+        ],
+        24,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("off".parse::<Idle>().unwrap(), Idle::default());
+        let idle: Idle = "breathe,chime".parse().unwrap();
+        assert!(idle.breathe && idle.chime);
+        assert!("dance".parse::<Idle>().is_err());
+    }
+
+    #[test]
+    fn breath_fits_light_program() {
+        let ops = breath((255, 0, 0));
+        assert!(ops.len() < 30);
+        assert!(ops.iter().all(|op| op.red > 0 && op.green == 0));
+        assert_eq!(ops.first().unwrap().red, ops.last().unwrap().red);
+    }
+}
@@ -0,0 +1,485 @@
+use anyhow::{anyhow, Context, Error, Result};
+use futures::prelude::*;
+use log::*;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+use toio::{Cube, Note, SoundOp};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    time::{delay_until, Duration, Instant},
+};
+
+use crate::{
+    link::Link,
+    midi::{self, Channel, MAX_OP_LEN},
+    sim::Sim,
+    span, Opt,
+};
+
+/// Live MIDI input played by its own cube.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Input {
+    /// Raw MIDI device such as `/dev/midi1`, or a named pipe fed by another program, or
+    /// `virtual` for the virtual port that sequencers can send to.
+    pub path: PathBuf,
+    /// Delay added to the input to line it up with the slower players.
+    pub delay: Duration,
+}
+
+impl std::str::FromStr for Input {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (path, delay) = match s.rfind('@') {
+            Some(i) => {
+                let ms = s[i + 1..]
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid delay of input: {}", s))?;
+                (&s[..i], ms)
+            }
+            None => (s, 0),
+        };
+        if path.is_empty() {
+            return Err(anyhow!("Invalid input: {}", s));
+        }
+        Ok(Input {
+            path: path.into(),
+            delay: Duration::from_millis(delay),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LiveEvent {
+    On(u8),
+    Off(u8),
+}
+
+/// Parses a raw MIDI byte stream, handling running status.
+#[derive(Default)]
+struct Parser {
+    status: Option<u8>,
+    data: Vec<u8>,
+}
+
+impl Parser {
+    /// The channel of the last message, counted from 0.
+    fn channel(&self) -> u8 {
+        self.status.map_or(0, |s| s & 0x0f)
+    }
+
+    fn push(&mut self, b: u8) -> Option<LiveEvent> {
+        if b >= 0xf8 {
+            // Real-time messages may appear anywhere without breaking running status.
+            return None;
+        }
+        if b >= 0x80 {
+            self.status = if b < 0xf0 { Some(b) } else { None };
+            self.data.clear();
+            return None;
+        }
+
+        let status = self.status?;
+        self.data.push(b);
+        let len = match status & 0xf0 {
+            0xc0 | 0xd0 => 1,
+            _ => 2,
+        };
+        if self.data.len() < len {
+            return None;
+        }
+        let data = std::mem::take(&mut self.data);
+
+        match status & 0xf0 {
+            0x90 if data[1] > 0 => Some(LiveEvent::On(data[0])),
+            0x80 | 0x90 => Some(LiveEvent::Off(data[0])),
+            _ => None,
+        }
+    }
+}
+
+/// Notes held on a monophonic cube; the last pressed note sounds.
+#[derive(Default)]
+struct Held {
+    notes: Vec<u8>,
+}
+
+impl Held {
+    /// Returns the note to play.
+    fn on(&mut self, note: u8) -> u8 {
+        self.notes.retain(|n| *n != note);
+        self.notes.push(note);
+        note
+    }
+
+    /// Returns `None` if the sounding note doesn't change, otherwise the note to
+    /// fall back to, or `Some(None)` to stop.
+    fn off(&mut self, note: u8) -> Option<Option<u8>> {
+        let last = self.notes.last().cloned();
+        self.notes.retain(|n| *n != note);
+        if last == Some(note) {
+            Some(self.notes.last().cloned())
+        } else {
+            None
+        }
+    }
+}
+
+/// The input naming the virtual port.
+const VIRTUAL: &str = "virtual";
+
+/// The card of the first virtual MIDI port of snd-virmidi in the list of the sound cards.
+fn virmidi(cards: &str) -> Option<u32> {
+    cards.lines().find_map(|line| {
+        let (card, rest) = line.trim_start().split_once(' ')?;
+        if rest.trim_start().starts_with("[VirMIDI") {
+            card.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Finds the raw device of the virtual port sequencers send to, telling its name.
+fn virtual_port() -> Result<PathBuf> {
+    let cards = std::fs::read_to_string("/proc/asound/cards").unwrap_or_default();
+    let card = virmidi(&cards).ok_or_else(|| {
+        anyhow!("No virtual MIDI port is found; create one with `modprobe snd-virmidi`")
+    })?;
+    info!(
+        "Sequencers can send to the virtual port VirMIDI {}-0 to play the cubes",
+        card
+    );
+    Ok(PathBuf::from(format!("/dev/snd/midiC{}D0", card)))
+}
+
+/// The raw device of the input, with `virtual` for the virtual port.
+pub fn device(path: &Path) -> Result<PathBuf> {
+    if path == Path::new(VIRTUAL) {
+        virtual_port()
+    } else {
+        Ok(path.to_path_buf())
+    }
+}
+
+/// A key pressed or released, with when and on which channel.
+type Key = (Instant, u8, LiveEvent);
+
+/// Reads the input on a thread as the device only supports blocking reads.
+fn listen(input: &Input) -> Result<UnboundedReceiver<Key>> {
+    let mut file = std::fs::File::open(&input.path)
+        .context(format!("Couldn't open input {}", input.path.display()))?;
+    let path = input.path.clone();
+    let (tx, rx) = unbounded_channel();
+
+    std::thread::spawn(move || {
+        let mut parser = Parser::default();
+        let mut buf = [0; 64];
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    for b in &buf[..n] {
+                        let e = match parser.push(*b) {
+                            Some(e) => e,
+                            None => continue,
+                        };
+                        if tx.send((Instant::now(), parser.channel(), e)).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Couldn't read input {}: {}", path.display(), e);
+                    break;
+                }
+            }
+        }
+        info!("Input {} closed", path.display());
+    });
+
+    Ok(rx)
+}
+
+async fn play(link: &mut Link, note: u8) -> Result<()> {
+    let op = SoundOp::new(midi::cube_note(note), Duration::from_millis(MAX_OP_LEN));
+    // Repeats until the key is released.
+    link.play(0, &[op]).await
+}
+
+/// Plays the keys on the cube, the delay after they're pressed or released.
+async fn player(mut link: Link, mut events: UnboundedReceiver<Key>, delay: Duration) -> Result<()> {
+    let mut held = Held::default();
+
+    while let Some((at, _, e)) = events.next().await {
+        delay_until(at + delay).await;
+        match e {
+            LiveEvent::On(n) => play(&mut link, held.on(n)).await?,
+            LiveEvent::Off(n) => match held.off(n) {
+                Some(Some(n)) => play(&mut link, n).await?,
+                Some(None) => link.stop_sound().await?,
+                None => {}
+            },
+        }
+    }
+
+    link.stop_sound().await
+}
+
+/// Ticks every beat on the cube, accenting the first beat of bars.
+async fn metronome(mut link: Link, bpm: u64, beats: u64) -> Result<()> {
+    let beat = Duration::from_millis(60000 / bpm);
+    let mut at = Instant::now();
+
+    for i in 0.. {
+        delay_until(at).await;
+        let note = if i % beats == 0 { Note::A6 } else { Note::E6 };
+        link.play(1, &[SoundOp::new(note, Duration::from_millis(50))])
+            .await?;
+        at += beat;
+    }
+
+    Ok(())
+}
+
+/// Fails on real cubes unless on macOS, the only platform the BLE backend of the toio crate
+/// works on, while the inputs are raw MIDI devices of Linux.
+fn real_cubes(opt: &Opt) -> Result<()> {
+    if opt.simulate || cfg!(target_os = "macos") {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Real cubes can't be played live here: the BLE backend of the toio crate works only \
+         on macOS, while the inputs are raw MIDI devices of Linux; play on virtual cubes with \
+         --simulate"
+    ))
+}
+
+/// Plays each input on its own cube, with the metronome on the next cube if any.
+pub async fn run(opt: &Opt, inputs: Vec<Input>, bpm: Option<u64>, beats: u64) -> Result<()> {
+    if bpm == Some(0) || beats == 0 {
+        return Err(anyhow!("Tempo and beats of the metronome must be non-zero"));
+    }
+
+    real_cubes(opt)?;
+
+    let needed = inputs.len() + bpm.map(|_| 1).unwrap_or(0);
+    let tracer = crate::tracer(opt);
+    let mut links = Vec::new();
+    if opt.simulate {
+        info!("Simulating {} cubes", needed);
+        let sim = Sim::new(opt.simulate_beep);
+        links.extend((0..needed).map(|i| Link::simulated(i, sim, tracer.clone())));
+    } else {
+        let cubes = Cube::search().all().await?;
+        if cubes.len() < needed {
+            return Err(anyhow!(
+                "{} cubes are needed but {} found",
+                needed,
+                cubes.len()
+            ));
+        }
+        for (i, cube) in cubes.into_iter().take(needed).enumerate() {
+            let mut link = Link::new(i, cube, tracer.clone());
+            link.cube()?.connect().await?;
+            let (r, g, b) = crate::color(opt, i);
+            link.light_on(r, g, b).await?;
+            links.push(link);
+        }
+    }
+
+    let mut tasks = Vec::new();
+    let mut links = links.into_iter();
+    for (input, link) in inputs.into_iter().zip(&mut links) {
+        info!(
+            "Cube {} plays {} (delay {:?})",
+            link.index,
+            input.path.display(),
+            input.delay
+        );
+        let events = listen(&input)?;
+        tasks.push(tokio::spawn(player(link, events, input.delay)).boxed());
+    }
+    if let (Some(bpm), Some(link)) = (bpm, links.next()) {
+        info!("Cube {} ticks at {} bpm", link.index, bpm);
+        tasks.push(tokio::spawn(metronome(link, bpm, beats)).boxed());
+    }
+
+    info!("Jam session started");
+    // Stops the session when any player fails or all the inputs are closed.
+    while !tasks.is_empty() {
+        let (res, _, rest) = future::select_all(tasks).await;
+        res??;
+        tasks = rest;
+        if bpm.is_some() && tasks.len() == 1 {
+            break;
+        }
+    }
+
+    info!("Jam session done");
+    Ok(())
+}
+
+/// The cubes playing the channel by the rules as (cube, channels), or the cube of the
+/// same number without rules.
+fn cubes_of(rules: &[(usize, Vec<Channel>)], ch: u8) -> Vec<usize> {
+    if rules.is_empty() {
+        return vec![ch as usize];
+    }
+    rules
+        .iter()
+        .filter(|(_, chs)| chs.contains(&(ch as Channel)))
+        .map(|(cube, _)| *cube)
+        .collect()
+}
+
+/// Plays the channels of the input on the cubes by the rules, as the notes come.
+pub async fn live(opt: &Opt, mut input: Input) -> Result<()> {
+    real_cubes(opt)?;
+    input.path = device(&input.path)?;
+    let rules: Vec<_> = opt
+        .rules
+        .iter()
+        .map(|r| (r.as_ch as usize, r.chs.clone()))
+        .collect();
+    let mut links = if opt.simulate {
+        let cubes = rules.iter().map(|(cube, _)| cube + 1).max().unwrap_or(16);
+        info!("Simulating {} cubes", cubes);
+        let (sim, tracer) = (Sim::new(opt.simulate_beep), crate::tracer(opt));
+        (0..cubes)
+            .map(|i| Link::simulated(i, sim, tracer.clone()))
+            .collect()
+    } else {
+        crate::search(opt).await?
+    };
+    for link in links.iter_mut().filter(|l| !l.is_simulated()) {
+        let i = link.index;
+        link.cube()?
+            .connect()
+            .await
+            .context(format!("Couldn't connect to cube {}", i))?;
+        let (r, g, b) = crate::color(opt, i);
+        link.light_on(r, g, b).await?;
+    }
+    for (cube, chs) in &rules {
+        if *cube >= links.len() {
+            warn!(
+                "Cube {} isn't found; channels {:?} aren't played on it",
+                cube, chs
+            );
+        }
+    }
+
+    let mut voices = vec![];
+    let mut tasks = vec![];
+    for link in links {
+        let (tx, rx) = unbounded_channel();
+        voices.push(tx);
+        let index = link.index;
+        tasks.push(tokio::spawn(span::cube(
+            index,
+            player(link, rx, input.delay),
+        )));
+    }
+
+    let mut events = listen(&input)?;
+    info!("Playing {} live", input.path.display());
+    'keys: while let Some(key) = events.next().await {
+        for cube in cubes_of(&rules, key.1) {
+            // The player failed; told below.
+            if voices.get(cube).is_some_and(|tx| tx.send(key).is_err()) {
+                break 'keys;
+            }
+        }
+    }
+
+    drop(voices);
+    for task in tasks {
+        task.await??;
+    }
+    info!("Live session done");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn input_from_str() {
+        let i: Input = "/dev/midi2@30".parse().unwrap();
+        assert_eq!(i.path, PathBuf::from("/dev/midi2"));
+        assert_eq!(i.delay, Duration::from_millis(30));
+        let i: Input = "/dev/midi1".parse().unwrap();
+        assert_eq!(i.delay, Duration::from_millis(0));
+        assert!("/dev/midi1@x".parse::<Input>().is_err());
+        assert!("@10".parse::<Input>().is_err());
+    }
+
+    #[test]
+    fn parser() {
+        let mut p = Parser::default();
+        let events: Vec<_> = [
+            0x90, 60, 100,  // note on
+            0xf8, // clock in between
+            62, 90, // running status
+            0xb0, 7, 100, // control change
+            0x80, 60, 0, // note off
+            0x90, 62, 0, // note on with zero velocity
+        ]
+        .iter()
+        .filter_map(|b| p.push(*b))
+        .collect();
+
+        assert_eq!(
+            events,
+            vec![
+                LiveEvent::On(60),
+                LiveEvent::On(62),
+                LiveEvent::Off(60),
+                LiveEvent::Off(62)
+            ]
+        );
+
+        p.push(0x93);
+        p.push(64);
+        assert_eq!(p.push(100), Some(LiveEvent::On(64)));
+        assert_eq!(p.channel(), 3);
+    }
+
+    #[test]
+    fn virmidi() {
+        let cards = [
+            " 0 [PCH            ]: HDA-Intel - HDA Intel PCH",
+            "                      HDA Intel PCH at 0xf7f10000 irq 32",
+            " 1 [VirMIDI        ]: VirMIDI - VirMIDI",
+            "                      Virtual MIDI Card 1",
+        ]
+        .join("\n");
+        assert_eq!(super::virmidi(&cards), Some(1));
+        assert_eq!(super::virmidi(" 0 [PCH            ]: HDA-Intel\n"), None);
+    }
+
+    #[test]
+    fn cubes_of() {
+        assert_eq!(super::cubes_of(&[], 2), vec![2]);
+        let rules = [(0, vec![0, 1]), (1, vec![1, 9])];
+        assert_eq!(super::cubes_of(&rules, 0), vec![0]);
+        assert_eq!(super::cubes_of(&rules, 1), vec![0, 1]);
+        assert_eq!(super::cubes_of(&rules, 5), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn held() {
+        let mut h = Held::default();
+        assert_eq!(h.on(60), 60);
+        assert_eq!(h.on(64), 64);
+        // Releasing a note in the background doesn't change the sound.
+        assert_eq!(h.on(67), 67);
+        assert_eq!(h.off(64), None);
+        assert_eq!(h.off(67), Some(Some(60)));
+        assert_eq!(h.off(60), Some(None));
+    }
+}
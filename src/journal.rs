@@ -0,0 +1,120 @@
+//! The journal of what happens during a performance as lines of JSON, for other tools
+//! such as a video synced to the song to follow.
+
+use anyhow::{Context, Result};
+use log::*;
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::json::Json;
+
+/// What the writer thread is asked to do.
+enum Job {
+    Line(Arc<File>, String),
+    /// Tells once the lines before are written.
+    Flush(mpsc::Sender<()>),
+}
+
+/// The thread writing the lines of every file in turn, started on the first line.
+static WRITER: Mutex<Option<mpsc::Sender<Job>>> = Mutex::new(None);
+
+static JOURNAL: Mutex<Option<Lines>> = Mutex::new(None);
+
+fn send(job: Job) {
+    let mut writer = WRITER.lock().unwrap();
+    let tx = writer.get_or_insert_with(|| {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for job in rx {
+                match job {
+                    Job::Line(file, line) => {
+                        if let Err(e) = (&*file).write_all(line.as_bytes()) {
+                            debug!("Couldn't write the lines: {}", e);
+                        }
+                    }
+                    Job::Flush(done) => drop(done.send(())),
+                }
+            }
+        });
+        tx
+    });
+    let _ = tx.send(job);
+}
+
+/// A file of lines of JSON, the trace or the journal, written on a thread of its own not to
+/// block the runtime.
+#[derive(Clone, Debug)]
+pub struct Lines(Arc<File>);
+
+impl Lines {
+    pub fn new(file: File) -> Self {
+        Self(Arc::new(file))
+    }
+
+    /// Writes the JSON as a line, at once not to mix with the other lines.
+    pub fn write(&self, json: &Json) {
+        send(Job::Line(self.0.clone(), format!("{}\n", json)));
+    }
+}
+
+/// Waits until the lines written so far are in their files, before exiting.
+pub fn flush() {
+    if WRITER.lock().unwrap().is_none() {
+        return;
+    }
+    let (tx, rx) = mpsc::channel();
+    send(Job::Flush(tx));
+    let _ = rx.recv();
+}
+
+/// Starts writing the events into the file afresh.
+pub fn open(path: &Path) -> Result<()> {
+    let file =
+        File::create(path).context(format!("Couldn't create the journal {}", path.display()))?;
+    *JOURNAL.lock().unwrap() = Some(Lines::new(file));
+    Ok(())
+}
+
+/// The line of the event at the time in msec since 1970.
+fn line(time: f64, event: &str, fields: Vec<(&str, Json)>) -> Json {
+    let mut json = Json::object(vec![("time", Json::from(time)), ("event", event.into())]);
+    for (key, value) in fields {
+        json = json.with(key, value);
+    }
+    json
+}
+
+/// Writes the event with its fields, if the journal is kept.
+pub fn record(event: &str, fields: Vec<(&str, Json)>) {
+    let journal = match JOURNAL.lock().unwrap().clone() {
+        Some(journal) => journal,
+        None => return,
+    };
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    journal.write(&line(time.as_micros() as f64 / 1000.0, event, fields));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn line() {
+        assert_eq!(
+            super::line(
+                1500.5,
+                "song-position",
+                vec![("song", "twinkle".into()), ("at", Json::from(250u64))]
+            )
+            .to_string(),
+            "{\"time\":1500.5,\"event\":\"song-position\",\"song\":\"twinkle\",\"at\":250}"
+        );
+    }
+}
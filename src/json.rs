@@ -0,0 +1,452 @@
+//! Minimal JSON reader and writer for the exports.
+
+use anyhow::{anyhow, Error, Result};
+use std::fmt;
+
+use crate::config::Pos;
+
+/// Output format of the listing and analyses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Lines for humans.
+    #[default]
+    Text,
+    /// JSON for scripts and front-ends.
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(anyhow!("Invalid format: {} (expected text or json)", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Creates an object keeping the order of the keys.
+    pub fn object<K: Into<String>>(pairs: Vec<(K, Json)>) -> Self {
+        Json::Object(pairs.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    /// Appends the key to the object.
+    pub fn with<K: Into<String>>(mut self, key: K, value: Json) -> Self {
+        if let Json::Object(pairs) = &mut self {
+            pairs.push((key.into(), value));
+        }
+        self
+    }
+
+    /// Returns the value of the key if this is an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Int(i) if *i >= 0 => Some(*i as u64),
+            _ => None,
+        }
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        let pretty = f.alternate();
+        let newline = |f: &mut fmt::Formatter, indent: usize| -> fmt::Result {
+            if pretty {
+                write!(f, "\n{:1$}", "", indent * 2)?;
+            }
+            Ok(())
+        };
+
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(i) => write!(f, "{}", i),
+            Json::Float(x) if x.is_finite() => write!(f, "{}", x),
+            Json::Float(_) => write!(f, "null"),
+            Json::Str(s) => string(f, s),
+            Json::Array(items) if items.is_empty() => write!(f, "[]"),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    newline(f, indent + 1)?;
+                    item.write(f, indent + 1)?;
+                }
+                newline(f, indent)?;
+                write!(f, "]")
+            }
+            Json::Object(pairs) if pairs.is_empty() => write!(f, "{{}}"),
+            Json::Object(pairs) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    newline(f, indent + 1)?;
+                    string(f, k)?;
+                    write!(f, "{}", if pretty { ": " } else { ":" })?;
+                    v.write(f, indent + 1)?;
+                }
+                newline(f, indent)?;
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Writes compact JSON, or indented JSON with `{:#}`.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(i: u64) -> Self {
+        Json::Int(i as i64)
+    }
+}
+
+impl From<u16> for Json {
+    fn from(i: u16) -> Self {
+        Json::Int(i as i64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(i: usize) -> Self {
+        Json::Int(i as i64)
+    }
+}
+
+impl From<u8> for Json {
+    fn from(i: u8) -> Self {
+        Json::Int(i as i64)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(x: f64) -> Self {
+        Json::Float(x)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::Str(s.into())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::Str(s)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Json::Null, Into::into)
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(items: Vec<T>) -> Self {
+        Json::Array(items.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Parses the document.
+pub fn parse(s: &str) -> Result<Json> {
+    let mut p = Parser::new(s);
+    p.blank();
+    let json = p.value()?;
+    p.blank();
+    match p.peek() {
+        None => Ok(json),
+        Some(c) => Err(p.error(format!("unexpected '{}' after the document", c))),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    i: usize,
+    line: usize,
+    col: usize,
+}
+
+impl Parser {
+    fn new(s: &str) -> Self {
+        Self {
+            chars: s.chars().collect(),
+            i: 0,
+            line: 1,
+            col: 1,
+        }
+    }
+
+    fn error<S: fmt::Display>(&self, msg: S) -> Error {
+        anyhow!("{}: {}", Pos::new(self.line, self.col), msg)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.i).cloned()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.i += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        match self.peek() {
+            Some(p) if p == c => {
+                self.bump();
+                Ok(())
+            }
+            Some(p) => Err(self.error(format!("expected '{}', found '{}'", c, p))),
+            None => Err(self.error(format!("expected '{}', found end of file", c))),
+        }
+    }
+
+    fn blank(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+            self.bump();
+        }
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => Ok(Json::Str(self.string()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => {
+                let mut word = String::new();
+                while let Some(c) = self.peek().filter(|c| c.is_ascii_alphabetic()) {
+                    word.push(c);
+                    self.bump();
+                }
+                match word.as_str() {
+                    "true" => Ok(Json::Bool(true)),
+                    "false" => Ok(Json::Bool(false)),
+                    "null" => Ok(Json::Null),
+                    _ => Err(self.error(format!("invalid value '{}'", word))),
+                }
+            }
+            Some(c) => Err(self.error(format!("unexpected '{}'", c))),
+            None => Err(self.error("expected a value, found end of file")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let mut s = String::new();
+        while let Some(c) = self
+            .peek()
+            .filter(|c| c.is_ascii_digit() || "+-.eE".contains(*c))
+        {
+            s.push(c);
+            self.bump();
+        }
+        if let Ok(i) = s.parse() {
+            Ok(Json::Int(i))
+        } else if let Ok(x) = s.parse() {
+            Ok(Json::Float(x))
+        } else {
+            Err(self.error(format!("invalid number '{}'", s)))
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.bump() {
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = (0..4).filter_map(|_| self.bump()).collect();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(std::char::from_u32);
+                        s.push(
+                            c.ok_or_else(|| self.error(format!("invalid escape '\\u{}'", hex)))?,
+                        );
+                    }
+                    Some(c) if "\"\\/".contains(c) => s.push(c),
+                    Some(c) => return Err(self.error(format!("invalid escape '\\{}'", c))),
+                    None => return Err(self.error("unterminated string")),
+                },
+                Some(c) if c != '\n' => s.push(c),
+                _ => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    /// Parses the items separated by commas up to the closing character.
+    fn items<T, F: FnMut(&mut Self) -> Result<T>>(
+        &mut self,
+        close: char,
+        mut item: F,
+    ) -> Result<Vec<T>> {
+        let mut items = vec![];
+        self.bump();
+        self.blank();
+        if self.peek() == Some(close) {
+            self.bump();
+            return Ok(items);
+        }
+        loop {
+            self.blank();
+            items.push(item(self)?);
+            self.blank();
+            match self.peek() {
+                Some(',') => {}
+                Some(c) if c == close => {
+                    self.bump();
+                    return Ok(items);
+                }
+                _ => return Err(self.error(format!("expected ',' or '{}'", close))),
+            }
+            self.bump();
+        }
+    }
+
+    fn array(&mut self) -> Result<Json> {
+        Ok(Json::Array(self.items(']', |p| p.value())?))
+    }
+
+    fn object(&mut self) -> Result<Json> {
+        let pairs = self.items('}', |p| {
+            let key = p.string()?;
+            p.blank();
+            p.expect(':')?;
+            p.blank();
+            Ok((key, p.value()?))
+        })?;
+        Ok(Json::Object(pairs))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn write() {
+        let json = Json::object(vec![
+            ("name", "say \"hi\"\n".into()),
+            ("notes", vec![60u8, 62].into()),
+            ("empty", Json::Array(vec![])),
+            ("ok", true.into()),
+            ("nan", f64::NAN.into()),
+        ])
+        .with("none", None::<u8>.into());
+        assert_eq!(
+            json.to_string(),
+            r#"{"name":"say \"hi\"\n","notes":[60,62],"empty":[],"ok":true,"nan":null,"none":null}"#
+        );
+        assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+        assert!("xml".parse::<Format>().is_err());
+        assert_eq!(
+            format!("{:#}", Json::object(vec![("a", vec![1u8].into())])),
+            "{\n  \"a\": [\n    1\n  ]\n}"
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let json = Json::object(vec![
+            ("name", "say \"hi\"\n".into()),
+            ("notes", vec![60u8, 62].into()),
+            ("empty", Json::Object(vec![])),
+            ("x", (-1.5).into()),
+            ("none", Json::Null),
+        ]);
+        assert_eq!(super::parse(&format!("{:#}", json)).unwrap(), json);
+        assert_eq!(
+            super::parse("\"\\u00e9\\/\"").unwrap(),
+            Json::Str("é/".into())
+        );
+        assert_eq!(
+            json.get("notes").and_then(|n| n.as_array()).unwrap().len(),
+            2
+        );
+        assert_eq!(
+            json.get("notes").unwrap().as_array().unwrap()[1].as_u64(),
+            Some(62)
+        );
+
+        let err = |s: &str| super::parse(s).unwrap_err().to_string();
+        assert_eq!(
+            err("{\n  \"a\": 1,\n}"),
+            "line 3, column 1: expected '\"', found '}'"
+        );
+        assert_eq!(err("[1 2]"), "line 1, column 4: expected ',' or ']'");
+        assert_eq!(
+            err("[1] x"),
+            "line 1, column 5: unexpected 'x' after the document"
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use anyhow::{anyhow, Result};
+use toio::{LightOp, Note};
+use tokio::time::Duration;
+
+use crate::midi::{PlaySet, Time, MAX_OP_LEN, OP_LEN_UNIT};
+
+/// The maximum number of operations in a light program.
+const MAX_LIGHT_OPS: usize = 29;
+/// The shortest step of a crossfade in msec.
+const MIN_STEP: Time = 50;
+/// Brightness kept on the softest notes, in percent.
+const FLOOR: u32 = 15;
+/// The longest flash on a note in msec.
+const FLASH: Time = 100;
+
+/// How the light of a cube follows its part.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Lit in the color of the cube.
+    #[default]
+    Steady,
+    /// Crossfades along with the velocity of the notes.
+    Dynamics,
+    /// Flashes on each note in the color of its pitch.
+    NoteFollow,
+    /// Flashes white on the start of each set, which is a bar on the metronome cube.
+    Downbeat,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "steady" => Ok(Mode::Steady),
+            "dynamics" => Ok(Mode::Dynamics),
+            "note-follow" => Ok(Mode::NoteFollow),
+            _ => Err(anyhow!(
+                "Invalid light mode: {} (expected steady, dynamics or note-follow)",
+                s
+            )),
+        }
+    }
+}
+
+/// Brightness in percent for the velocity.
+fn level(vel: u8) -> u32 {
+    FLOOR + (100 - FLOOR) * vel.min(127) as u32 / 127
+}
+
+/// Cue points of the play set as (msec from the start, brightness).
+///
+/// Rests are skipped so that the light fades through them.
+fn cues(set: &PlaySet) -> Vec<(Time, u32)> {
+    set.plays
+        .iter()
+        .filter(|p| p.note != Note::NoSound)
+        .map(|p| (p.at.saturating_sub(set.at), level(p.vel)))
+        .collect()
+}
+
+/// Brightness at the time, interpolated linearly between the cue points.
+fn at(cues: &[(Time, u32)], t: Time) -> u32 {
+    match cues.iter().position(|(at, _)| *at > t) {
+        Some(0) => cues[0].1,
+        Some(i) => {
+            let (t0, l0) = cues[i - 1];
+            let (t1, l1) = cues[i];
+            let (l0, l1) = (l0 as i64, l1 as i64);
+            (l0 + (l1 - l0) * (t - t0) as i64 / (t1 - t0) as i64) as u32
+        }
+        None => cues.last().map(|(_, l)| *l).unwrap_or(FLOOR),
+    }
+}
+
+/// Light program crossfading the color along with the dynamics of the play set.
+///
+/// The gradient is stepped to fit the program into a single write to the cube.
+pub fn crossfade(set: &PlaySet, (r, g, b): (u8, u8, u8)) -> Vec<LightOp> {
+    let cues = cues(set);
+    if cues.is_empty() || set.len == 0 {
+        return vec![];
+    }
+
+    let len = set.len;
+    let step = (len.div_ceil(MAX_LIGHT_OPS as Time))
+        .div_ceil(OP_LEN_UNIT)
+        .saturating_mul(OP_LEN_UNIT)
+        .clamp(MIN_STEP, MAX_OP_LEN);
+
+    (0..len)
+        .step_by(step as usize)
+        .take(MAX_LIGHT_OPS)
+        .map(|t| {
+            let l = at(&cues, t);
+            let c = |c: u8| (c as u32 * l / 100) as u8;
+            let d = step.min(len - t);
+            LightOp::new(c(r), c(g), c(b), Some(Duration::from_millis(d)))
+        })
+        .collect()
+}
+
+/// Color of the pitch class around the hue circle from red on C, dimmed by the velocity.
+fn pitch_color(note: Note, vel: u8) -> (u8, u8, u8) {
+    let hue = (note as u32 % 12) * 360 / 12;
+    let x = |h: u32| match h % 360 {
+        h if h < 60 => 255 * h / 60,
+        h if h < 180 => 255,
+        h if h < 240 => 255 * (240 - h) / 60,
+        _ => 0,
+    };
+    let l = level(vel);
+    let c = |c: u32| (c * l / 100) as u8;
+    (c(x(hue + 120)), c(x(hue)), c(x(hue + 240)))
+}
+
+/// Light programs flashing on the start of each note of the play set in the color of its pitch,
+/// as (msec from the start, program).
+///
+/// A program runs until a rest too long for a single operation or until it's full;
+/// the next one starts on the next note.
+pub fn flashes(set: &PlaySet) -> Vec<(Time, Vec<LightOp>)> {
+    let repeat = set.repeat.max(1) as Time;
+    let pattern = set.len / repeat;
+    let mut onsets: Vec<_> = (0..repeat)
+        .flat_map(|r| {
+            cues(set)
+                .into_iter()
+                .zip(set.plays.iter().filter(|p| p.note != Note::NoSound))
+                .map(move |((at, _), p)| (at + r * pattern, p.len, p.note, p.vel))
+        })
+        .collect();
+    onsets.sort_by_key(|(at, _, _, _)| *at);
+    onsets.dedup_by_key(|(at, _, _, _)| *at);
+
+    let dark = |d: Time| LightOp::new(0, 0, 0, Some(Duration::from_millis(d)));
+    let mut programs = vec![];
+    let mut current: Option<(Time, Vec<LightOp>, Time)> = None;
+    for (i, &(t, len, note, vel)) in onsets.iter().enumerate() {
+        let next = onsets.get(i + 1).map(|(at, _, _, _)| *at);
+        let flash = next.map_or(len, |n| (n - t).min(len)).min(FLASH) / OP_LEN_UNIT * OP_LEN_UNIT;
+        if flash == 0 {
+            continue;
+        }
+
+        let (start, ops, elapsed) = current.get_or_insert_with(|| (t, vec![], 0));
+        let (r, g, b) = pitch_color(note, vel);
+        ops.push(LightOp::new(r, g, b, Some(Duration::from_millis(flash))));
+        *elapsed += flash;
+
+        // The rest to the next note, kept in step with the start of the program.
+        let rest = next.map(|n| (n - *start - *elapsed) / OP_LEN_UNIT * OP_LEN_UNIT);
+        match rest {
+            Some(0) if ops.len() < MAX_LIGHT_OPS => {}
+            Some(d) if d <= MAX_OP_LEN && ops.len() + 2 <= MAX_LIGHT_OPS => {
+                ops.push(dark(d));
+                *elapsed += d;
+            }
+            _ => programs.extend(current.take().map(|(start, ops, _)| (start, ops))),
+        }
+    }
+    programs.extend(current.map(|(start, ops, _)| (start, ops)));
+    programs
+}
+
+/// Light program flashing white at the start of the play set, then lit in the color.
+pub fn downbeat(set: &PlaySet, (r, g, b): (u8, u8, u8)) -> Vec<LightOp> {
+    if set.len == 0 {
+        return vec![];
+    }
+    let flash = FLASH.min(set.len);
+    let rest = (set.len - flash).min(MAX_OP_LEN);
+    let op = |r, g, b, d| LightOp::new(r, g, b, Some(Duration::from_millis(d)));
+    let mut ops = vec![op(255, 255, 255, flash)];
+    if rest > 0 {
+        ops.push(op(r, g, b, rest));
+    }
+    ops
+}
+
+/// Light program fading the color out to black over the time in msec.
+pub fn fade_out((r, g, b): (u8, u8, u8), len: Time) -> Vec<LightOp> {
+    let step = (len / MAX_LIGHT_OPS as Time).clamp(MIN_STEP, MAX_OP_LEN);
+    let steps = (len / step).max(1);
+
+    (0..steps)
+        .map(|i| {
+            let c = |c: u8| (c as Time * (steps - i) / (steps + 1)) as u8;
+            LightOp::new(c(r), c(g), c(b), Some(Duration::from_millis(step)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::midi::Play;
+
+    #[test]
+    fn crossfade() {
+        // Crescendo from pp to ff over a rest.
+        let mut set = PlaySet::new(0, 1000);
+        let mut soft = Play::new(0, 1000, 500, Note::C3);
+        soft.vel = 0;
+        let mut loud = Play::new(0, 2000, 1000, Note::C3);
+        loud.vel = 127;
+        set.plays = vec![soft, Play::new(0, 1500, 500, Note::NoSound), loud];
+        set.len = 2000;
+
+        let ops = super::crossfade(&set, (255, 0, 0));
+        assert!(ops.len() <= MAX_LIGHT_OPS);
+        let total: Duration = ops.iter().map(|op| op.duration.unwrap()).sum();
+        assert_eq!(total, Duration::from_millis(2000));
+
+        let reds: Vec<_> = ops.iter().map(|op| op.red).collect();
+        assert_eq!(reds[0], (255 * FLOOR / 100) as u8);
+        assert!(reds.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(*reds.last().unwrap(), 255);
+
+        // Nothing to light in rests.
+        let mut rest = PlaySet::new(0, 0);
+        rest.plays = vec![Play::new(0, 0, 500, Note::NoSound)];
+        rest.len = 500;
+        assert!(super::crossfade(&rest, (255, 0, 0)).is_empty());
+    }
+
+    #[test]
+    fn fade_out() {
+        let ops = super::fade_out((0, 255, 0), 3000);
+        assert!(ops.len() <= MAX_LIGHT_OPS);
+        let total: Duration = ops.iter().map(|op| op.duration.unwrap()).sum();
+        assert!(total <= Duration::from_millis(3000));
+        let greens: Vec<_> = ops.iter().map(|op| op.green).collect();
+        assert!(greens[0] < 255);
+        assert!(greens.windows(2).all(|w| w[0] > w[1]));
+        assert!(*greens.last().unwrap() < 20);
+    }
+
+    #[test]
+    fn downbeat() {
+        let mut set = PlaySet::new(0, 0);
+        set.len = 2000;
+        let ops = super::downbeat(&set, (0, 0, 255));
+        assert_eq!(ops.len(), 2);
+        assert_eq!((ops[0].red, ops[0].blue), (255, 255));
+        assert_eq!(ops[0].duration, Some(Duration::from_millis(FLASH)));
+        assert_eq!((ops[1].red, ops[1].blue), (0, 255));
+        assert_eq!(ops[1].duration, Some(Duration::from_millis(1900)));
+        set.len = 0;
+        assert!(super::downbeat(&set, (0, 0, 255)).is_empty());
+    }
+
+    #[test]
+    fn flashes() {
+        let mut set = PlaySet::new(0, 1000);
+        set.plays = vec![
+            Play::new(0, 1000, 50, Note::C4),
+            Play::new(0, 1050, 450, Note::NoSound),
+            Play::new(0, 1500, 500, Note::A4),
+            Play::new(0, 2000, 4000, Note::NoSound),
+            Play::new(0, 6000, 500, Note::C4),
+        ];
+        set.len = 5500;
+
+        let programs = super::flashes(&set);
+        let durations = |ops: &[LightOp]| -> Vec<_> {
+            ops.iter()
+                .map(|op| op.duration.unwrap().as_millis())
+                .collect()
+        };
+        // The long rest starts another program.
+        assert_eq!(programs.len(), 2);
+        assert_eq!(programs[0].0, 0);
+        assert_eq!(durations(&programs[0].1), vec![50, 450, 100]);
+        assert_eq!(programs[1].0, 5000);
+        assert_eq!(durations(&programs[1].1), vec![100]);
+
+        let c = &programs[0].1[0];
+        assert!(c.red > 0 && c.green == 0 && c.blue == 0);
+        assert_eq!(programs[0].1[1].red, 0);
+
+        // Repeated patterns flash on every repetition.
+        set.plays.truncate(3);
+        set.len = 2000;
+        set.repeat = 2;
+        let programs = super::flashes(&set);
+        assert_eq!(programs.len(), 1);
+        assert_eq!(
+            durations(&programs[0].1),
+            vec![50, 450, 100, 400, 50, 450, 100]
+        );
+        assert_eq!("note-follow".parse::<Mode>().unwrap(), Mode::NoteFollow);
+    }
+}
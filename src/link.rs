@@ -0,0 +1,266 @@
+use anyhow::{anyhow, Result};
+use log::*;
+use std::{
+    convert::TryInto,
+    fs::File,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use toio::{
+    ble::Uuid,
+    proto::{self, Light, LightCtrl, LightOff, LightOn, Message, Sound, SoundPlay},
+    Cube, LightOp, SoundOp,
+};
+use tokio::time::Duration;
+
+use crate::{
+    journal::Lines,
+    json::Json,
+    sim::{self, Sim},
+};
+
+/// Logs BLE writes, limiting the number of lines per second, and keeps every one of them
+/// in the trace file if any.
+#[derive(Clone, Debug)]
+pub struct Tracer {
+    /// The lines logged a second, unless the writes aren't logged.
+    limit: Option<usize>,
+    state: Arc<Mutex<TraceState>>,
+    file: Option<Lines>,
+}
+
+#[derive(Debug)]
+struct TraceState {
+    window: Instant,
+    count: usize,
+    suppressed: usize,
+}
+
+impl Tracer {
+    pub fn new(limit: Option<usize>, file: Option<File>) -> Self {
+        Self {
+            limit,
+            state: Arc::new(Mutex::new(TraceState {
+                window: Instant::now(),
+                count: 0,
+                suppressed: 0,
+            })),
+            file: file.map(Lines::new),
+        }
+    }
+
+    /// Keeps the write in the trace file as a line of JSON.
+    fn keep(&self, index: usize, msg: &Message, payload: &[u8], latency: Duration, ok: bool) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = Json::object(vec![
+            ("time", Json::from(time.as_micros() as f64 / 1000.0)),
+            ("cube", Json::from(index)),
+            ("message", Json::from(sim::describe(msg))),
+            ("payload", Json::from(hex(payload))),
+            ("latency", Json::from(latency.as_micros() as f64 / 1000.0)),
+            ("ok", Json::from(ok)),
+        ]);
+        file.write(&line);
+    }
+
+    fn log(&self, index: usize, uuid: &Uuid, payload: &[u8], latency: Duration) {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        let mut s = self.state.lock().unwrap();
+
+        if s.window.elapsed() >= Duration::from_secs(1) {
+            if s.suppressed > 0 {
+                info!("BLE trace: {} writes not shown", s.suppressed);
+            }
+            s.window = Instant::now();
+            s.count = 0;
+            s.suppressed = 0;
+        }
+
+        if s.count < limit {
+            s.count += 1;
+            info!(
+                "BLE cube {} {}: {} bytes in {:?}: {}",
+                index,
+                uuid,
+                payload.len(),
+                latency,
+                hex(payload)
+            );
+        } else {
+            s.suppressed += 1;
+        }
+    }
+}
+
+fn hex(payload: &[u8]) -> String {
+    payload
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Where a link sends the messages.
+enum Target {
+    Cube(Cube),
+    Sim(Sim),
+}
+
+/// A connection to a cube sending the protocol messages.
+pub struct Link {
+    pub index: usize,
+    target: Target,
+    tracer: Option<Tracer>,
+}
+
+impl Link {
+    pub fn new(index: usize, cube: Cube, tracer: Option<Tracer>) -> Self {
+        Self {
+            index,
+            target: Target::Cube(cube),
+            tracer,
+        }
+    }
+
+    /// A link to a virtual cube, which only logs the messages, keeping them in the trace
+    /// file if any.
+    pub fn simulated(index: usize, sim: Sim, tracer: Option<Tracer>) -> Self {
+        Self {
+            index,
+            target: Target::Sim(sim),
+            tracer,
+        }
+    }
+
+    /// The cube of the link, which virtual cubes don't have.
+    pub fn cube(&mut self) -> Result<&mut Cube> {
+        match &mut self.target {
+            Target::Cube(cube) => Ok(cube),
+            Target::Sim(_) => Err(anyhow!("Cube {} is simulated", self.index)),
+        }
+    }
+
+    pub fn is_simulated(&self) -> bool {
+        matches!(self.target, Target::Sim(_))
+    }
+
+    async fn send(&mut self, msg: Message) -> Result<()> {
+        match &mut self.target {
+            Target::Cube(cube) => cube.write_msg(msg, true).await,
+            Target::Sim(sim) => {
+                sim.receive(self.index, &msg);
+                Ok(())
+            }
+        }
+    }
+
+    async fn write(&mut self, msg: Message) -> Result<()> {
+        let tracer = match &self.tracer {
+            Some(tracer) => tracer.clone(),
+            None => return self.send(msg).await,
+        };
+
+        let (uuid, payload): (Uuid, Vec<u8>) = msg.clone().try_into()?;
+        let start = Instant::now();
+        let res = self.send(msg.clone()).await;
+        let latency = start.elapsed();
+        tracer.keep(self.index, &msg, &payload, latency, res.is_ok());
+        // The virtual cubes log the messages themselves.
+        if !self.is_simulated() {
+            tracer.log(self.index, &uuid, &payload, latency);
+        }
+        res
+    }
+
+    pub async fn play(&mut self, repeat: usize, ops: &[SoundOp]) -> Result<()> {
+        self.play_with_volume(repeat, ops, 255).await
+    }
+
+    pub async fn play_with_volume(
+        &mut self,
+        repeat: usize,
+        ops: &[SoundOp],
+        volume: u8,
+    ) -> Result<()> {
+        if ops.is_empty() || ops.len() >= 60 {
+            return Err(anyhow!("The number of operations must be from 1 to 59"));
+        }
+        if repeat > 255 {
+            return Err(anyhow!("The repeat count must be less than 256"));
+        }
+
+        let ops: Result<Vec<_>> = ops
+            .iter()
+            .map(|op| {
+                let d = (op.duration.as_millis() / 10).max(1);
+                if d > 255 {
+                    return Err(anyhow!("The duration must be less than 2560 milliseconds"));
+                }
+                Ok(proto::SoundOp::new(d as u8, op.note, volume))
+            })
+            .collect();
+        let ops = ops?;
+
+        self.write(Message::Sound(Sound::Play(SoundPlay::new(
+            repeat as u8,
+            ops.len() as u8,
+            ops,
+        ))))
+        .await
+    }
+
+    pub async fn stop_sound(&mut self) -> Result<()> {
+        self.write(Message::Sound(Sound::Stop)).await
+    }
+
+    pub async fn light_on(&mut self, red: u8, green: u8, blue: u8) -> Result<()> {
+        self.write(Message::Light(Light::On(LightOn::new(0, red, green, blue))))
+            .await
+    }
+
+    /// Runs the light program on the cube; it repeats forever if `repeat` is 0.
+    pub async fn light(&mut self, repeat: usize, ops: &[LightOp]) -> Result<()> {
+        if ops.is_empty() || ops.len() >= 30 {
+            return Err(anyhow!("The number of operations must be from 1 to 29"));
+        }
+        if repeat > 255 {
+            return Err(anyhow!("The repeat count must be less than 256"));
+        }
+
+        let ops: Result<Vec<_>> = ops
+            .iter()
+            .map(|op| {
+                let d = op
+                    .duration
+                    .map(|d| (d.as_millis() / 10).max(1))
+                    .unwrap_or(0);
+                if d > 255 {
+                    return Err(anyhow!("The duration must be less than 2560 milliseconds"));
+                }
+                Ok(LightOn::new(d as u8, op.red, op.green, op.blue))
+            })
+            .collect();
+        let ops = ops?;
+
+        self.write(Message::Light(Light::Ctrl(LightCtrl::new(
+            repeat as u8,
+            ops.len() as u8,
+            ops,
+        ))))
+        .await
+    }
+
+    pub async fn light_off(&mut self) -> Result<()> {
+        self.write(Message::Light(Light::Off(LightOff::new())))
+            .await
+    }
+}
@@ -0,0 +1,225 @@
+//! Lyrics of karaoke files printed line by line in time with the playback.
+
+use anyhow::Result;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    midi::{Mark, MarkKind, PlaySet, Time},
+    sink::SoundSink,
+};
+
+/// Interval of checking the next line.
+const REFRESH: Duration = Duration::from_millis(100);
+
+/// Joins the syllables into lines as (at, line).
+///
+/// The lyric events are read if any, or else the text events as in `.kar` files, where
+/// `/` and `\` start a line and the texts starting with `@` tell about the song.
+pub fn lines(marks: &[Mark]) -> Vec<(Time, String)> {
+    let kind = if marks.iter().any(|m| m.kind == MarkKind::Lyric) {
+        MarkKind::Lyric
+    } else {
+        MarkKind::Text
+    };
+
+    let mut lines: Vec<(Time, String)> = vec![];
+    let mut open = false;
+    for mark in marks.iter().filter(|m| m.kind == kind) {
+        if kind == MarkKind::Text && mark.text.starts_with('@') {
+            continue;
+        }
+        let (text, start) = match mark.text.strip_prefix(['/', '\\']) {
+            Some(text) => (text, true),
+            None => (mark.text.as_str(), false),
+        };
+        if start || !open {
+            lines.push((mark.at, String::new()));
+        }
+        open = !text.ends_with(['\r', '\n']);
+        if let Some((_, line)) = lines.last_mut() {
+            *line += text.trim_end_matches(['\r', '\n']);
+        }
+    }
+    lines
+        .into_iter()
+        .map(|(at, line)| (at, line.trim().to_string()))
+        .filter(|(_, line)| !line.is_empty())
+        .collect()
+}
+
+/// Cuts the lines to those from `from` until `to`, moved to start at `from`.
+pub fn cut(lines: Vec<(Time, String)>, from: Time, to: Option<Time>) -> Vec<(Time, String)> {
+    lines
+        .into_iter()
+        .filter(|(at, _)| *at >= from && to.is_none_or(|to| *at < to))
+        .map(|(at, line)| (at - from, line))
+        .collect()
+}
+
+struct State {
+    lines: Vec<(Time, String)>,
+    /// The line to print next.
+    next: usize,
+    /// The position in the song and when it was reached, kept while silenced.
+    position: Option<(Time, Instant)>,
+    silenced: bool,
+}
+
+impl State {
+    fn position(&self, now: Instant) -> Time {
+        match self.position {
+            Some((at, _)) if self.silenced => at,
+            Some((at, since)) => at + now.saturating_duration_since(since).as_millis() as Time,
+            None => 0,
+        }
+    }
+
+    /// Returns the lines come up to the position.
+    fn due(&mut self, position: Time) -> Vec<String> {
+        let from = self.next;
+        while self
+            .lines
+            .get(self.next)
+            .is_some_and(|(at, _)| *at <= position)
+        {
+            self.next += 1;
+        }
+        self.lines[from..self.next]
+            .iter()
+            .map(|(_, line)| line.clone())
+            .collect()
+    }
+}
+
+/// Follows the song with the sets sent to the cubes, printing the lyrics.
+pub struct Lyrics {
+    state: Arc<Mutex<State>>,
+}
+
+impl Lyrics {
+    /// Starts printing the lines, a sink per cube.
+    pub fn start(lines: Vec<(Time, String)>, cubes: usize) -> Vec<Lyrics> {
+        let state = Arc::new(Mutex::new(State {
+            lines,
+            next: 0,
+            position: None,
+            silenced: false,
+        }));
+
+        let shared = state.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(REFRESH);
+            while Arc::strong_count(&shared) > 1 {
+                ticks.tick().await;
+                let mut s = shared.lock().unwrap();
+                if s.position.is_some() {
+                    let position = s.position(Instant::now());
+                    for line in s.due(position) {
+                        println!("{}", line);
+                    }
+                }
+            }
+        });
+
+        (0..cubes)
+            .map(|_| Lyrics {
+                state: state.clone(),
+            })
+            .collect()
+    }
+}
+
+impl SoundSink for Lyrics {
+    async fn play(&mut self, set: PlaySet) -> Result<()> {
+        let mut s = self.state.lock().unwrap();
+        let at = s.position.map_or(set.at, |(at, _)| at.max(set.at));
+        s.position = Some((at, Instant::now()));
+        s.silenced = false;
+        Ok(())
+    }
+
+    async fn silence(&mut self) -> Result<()> {
+        let mut s = self.state.lock().unwrap();
+        if !s.silenced {
+            let now = Instant::now();
+            s.position = Some((s.position(now), now));
+            s.silenced = true;
+        }
+        Ok(())
+    }
+
+    async fn restart(&mut self) -> Result<()> {
+        let mut s = self.state.lock().unwrap();
+        s.position = None;
+        s.next = 0;
+        Ok(())
+    }
+
+    async fn resume(&mut self) -> Result<()> {
+        let mut s = self.state.lock().unwrap();
+        s.position = s.position.map(|(at, _)| (at, Instant::now()));
+        s.silenced = false;
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lyrics() {
+        let mark = |at, kind, text: &str| Mark::new(at, kind, text.into());
+        let kar = vec![
+            mark(0, MarkKind::Text, "@KMIDI KARAOKE FILE"),
+            mark(0, MarkKind::Text, "@TTwinkle"),
+            mark(100, MarkKind::Text, "/Twin"),
+            mark(200, MarkKind::Text, "kle "),
+            mark(300, MarkKind::Text, "twin"),
+            mark(400, MarkKind::Text, "kle"),
+            mark(500, MarkKind::Text, "\\Lit"),
+            mark(600, MarkKind::Text, "tle star"),
+        ];
+        let lines = lines(&kar);
+        assert_eq!(
+            lines,
+            vec![
+                (100, "Twinkle twinkle".to_string()),
+                (500, "Little star".to_string())
+            ]
+        );
+        assert_eq!(
+            cut(lines, 200, Some(800)),
+            vec![(300, "Little star".into())]
+        );
+
+        let lyric = vec![
+            mark(0, MarkKind::Text, "Words and music"),
+            mark(100, MarkKind::Lyric, "Frè"),
+            mark(200, MarkKind::Lyric, "re "),
+            mark(300, MarkKind::Lyric, "Jacques\r"),
+            mark(400, MarkKind::Lyric, "Dor"),
+        ];
+        assert_eq!(
+            super::lines(&lyric),
+            vec![(100, "Frère Jacques".into()), (400, "Dor".into())]
+        );
+
+        let mut s = State {
+            lines: super::lines(&lyric),
+            next: 0,
+            position: None,
+            silenced: false,
+        };
+        assert!(s.due(50).is_empty());
+        assert_eq!(s.due(450), vec!["Frère Jacques", "Dor"]);
+        assert!(s.due(1000).is_empty());
+    }
+}
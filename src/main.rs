@@ -1,24 +1,203 @@
+mod bench;
+mod cache;
+mod calibrate;
+mod clock;
+mod config;
+mod control;
+mod cube;
+mod cubes;
+mod demo;
+mod doctor;
+mod drift;
+mod exit;
+mod export;
+mod fade;
+mod identify;
+mod idle;
+mod jam;
+mod journal;
+mod json;
+mod lights;
+mod link;
+mod lyrics;
+mod melody;
 mod midi;
+mod packet;
+mod playlist;
+mod preflight;
+mod preview;
+mod progress;
+mod report;
+mod retry;
+mod roll;
+mod route;
+mod section;
+mod show;
+mod sim;
+mod sink;
+mod smf;
+mod song;
+mod span;
+mod stats;
+mod stress;
+mod tap;
+mod timeline;
+mod timer;
+mod tui;
+mod tune;
+mod unsupported;
 
 use anyhow::{anyhow, Context, Error, Result};
-use futures::prelude::*;
+use derive_new::new;
+use futures::{prelude::*, stream};
 use log::*;
-use std::path::PathBuf;
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+};
 use structopt::StructOpt;
-use toio::{Cube, SoundOp};
-use tokio::time::{delay_for, delay_until, Duration, Instant};
+use toio::{Cube, Event as CubeEvent};
+use tokio::time::{delay_for, Duration, Instant};
 
-use crate::midi::PlaySet;
+use crate::{
+    control::{Control, Signal},
+    cube::Command,
+    exit::Failure,
+    json::{Format, Json},
+    link::{Link, Tracer},
+    lyrics::Lyrics,
+    midi::{Channel, PlaySet, Time},
+    packet::PacketSize,
+    progress::Progress,
+    report::Timing,
+    roll::Roll,
+    route::{Mirror, Playing, Router},
+    section::{Offset, Repeat},
+    sim::Sim,
+    sink::{MidiOut, PlayLog, SoundSink},
+    timer::Timer,
+    tui::Tui,
+};
 
 #[derive(Clone, Debug)]
 pub struct Rule {
-    chs: Vec<u8>,
-    as_ch: u8,
+    chs: Vec<Channel>,
+    as_ch: Channel,
+    /// The cube to play on by its nickname or Bluetooth identifier, numbered by `number`.
+    cube: Option<String>,
 }
 
 impl Rule {
-    fn new(chs: Vec<u8>, as_ch: u8) -> Self {
-        Self { chs, as_ch }
+    fn new(chs: Vec<Channel>, as_ch: Channel) -> Self {
+        Self {
+            chs,
+            as_ch,
+            cube: None,
+        }
+    }
+
+    /// The cube the rule plays on, by its name if given.
+    fn target(&self) -> String {
+        self.cube.clone().unwrap_or_else(|| self.as_ch.to_string())
+    }
+}
+
+/// Gives the rules on cubes by name the lowest cube numbers free, in order.
+fn number(rules: &mut [Rule]) {
+    let mut taken: Vec<_> = rules
+        .iter()
+        .filter(|r| r.cube.is_none())
+        .map(|r| r.as_ch)
+        .collect();
+    for rule in rules.iter_mut().filter(|r| r.cube.is_some()) {
+        let free = (0..).find(|ch| !taken.contains(ch)).unwrap_or(0);
+        rule.as_ch = free;
+        taken.push(free);
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let chs: Vec<_> = self
+            .chs
+            .iter()
+            .map(|ch| match ch / midi::LAYER {
+                0 => ch.to_string(),
+                file => format!("{}:{}", file, ch % midi::LAYER),
+            })
+            .collect();
+        write!(f, "{}={}", self.target(), chs.join(","))
+    }
+}
+
+/// Finds two rules playing on the same cube, which would overlap their notes.
+fn conflict(rules: &[Rule]) -> Option<(usize, usize)> {
+    let same = |a: &Rule, b: &Rule| match (&a.cube, &b.cube) {
+        (None, None) => a.as_ch == b.as_ch,
+        (a, b) => a.is_some() && a == b,
+    };
+    (0..rules.len()).find_map(|b| (0..b).find(|a| same(&rules[*a], &rules[b])).map(|a| (a, b)))
+}
+
+/// Settings contradicting each other, told at the key of show files of the later one
+/// and its item if the key is an array.
+struct Conflict {
+    key: &'static str,
+    item: Option<usize>,
+    msg: String,
+}
+
+/// Finds the settings that contradict each other.
+fn conflicts(opt: &Opt) -> Vec<Conflict> {
+    let mut conflicts = vec![];
+    if let Some((a, b)) = conflict(&opt.rules) {
+        conflicts.push(Conflict {
+            key: "rules",
+            item: Some(b),
+            msg: format!(
+                "rule '{}' plays on cube {} as well as rule '{}'; merge them into one rule",
+                opt.rules[b],
+                opt.rules[b].target(),
+                opt.rules[a]
+            ),
+        });
+    }
+    if let Some(ch) = opt.solo.iter().find(|ch| opt.mute.contains(ch)) {
+        conflicts.push(Conflict {
+            key: "solo",
+            item: None,
+            msg: format!("track {} is both muted and soloed", ch),
+        });
+    }
+    if opt.dynamics && opt.light_mode == lights::Mode::NoteFollow {
+        conflicts.push(Conflict {
+            key: "light-mode",
+            item: None,
+            msg: "dynamics conflicts with the light mode note-follow".into(),
+        });
+    }
+    conflicts
+}
+
+/// Reads a channel, or `file:channel` of the layered files.
+fn layer_channel(s: &str) -> Result<Channel> {
+    match s.split_once(':') {
+        Some((file, ch)) => {
+            let (file, ch): (Channel, Channel) = (file.parse()?, ch.parse()?);
+            if ch >= midi::LAYER {
+                return Err(anyhow!("Channel {} is out of range", ch));
+            }
+            file.checked_mul(midi::LAYER)
+                .and_then(|f| f.checked_add(ch))
+                .ok_or_else(|| anyhow!("File {} is out of range", file))
+        }
+        None => Ok(s.parse()?),
     }
 }
 
@@ -31,13 +210,22 @@ impl std::str::FromStr for Rule {
             let as_ch = iter.next().ok_or_else(|| anyhow!("Invalid rule: {}", s))?;
             let chs = iter.next().ok_or_else(|| anyhow!("Invalid rule: {}", s))?;
 
-            let as_ch = as_ch.parse().context(format!("Invalid rule: {}", s))?;
             let chs: Result<Vec<_>> = chs
                 .split(",")
-                .map(|ch| Ok(ch.parse().context(format!("Invalid rule: {}", s))?))
+                .map(|ch| layer_channel(ch).context(format!("Invalid rule: {}", s)))
                 .collect();
 
-            Ok(Rule::new(chs?, as_ch))
+            // A cube by name or by identifier unless a number.
+            let cube = |c: char| c.is_ascii_alphanumeric() || "-_:".contains(c);
+            match as_ch.parse() {
+                Ok(as_ch) => Ok(Rule::new(chs?, as_ch)),
+                Err(_) if !as_ch.is_empty() && as_ch.chars().all(cube) => {
+                    let mut rule = Rule::new(chs?, 0);
+                    rule.cube = Some(as_ch.into());
+                    Ok(rule)
+                }
+                Err(e) => Err(e).context(format!("Invalid rule: {}", s)),
+            }
         } else {
             Err(anyhow!("Invalid rule: {}", s))
         }
@@ -45,136 +233,3434 @@ impl std::str::FromStr for Rule {
 }
 
 #[derive(StructOpt)]
-struct Opt {
-    /// MIDI file name
+enum Cmd {
+    /// Play the MIDI files, the same as giving them without a command
+    Play {
+        /// MIDI file name, or show file name (.toml)
+        #[structopt(name = "file")]
+        file: Option<PathBuf>,
+        /// More MIDI files to play after it, as a playlist
+        #[structopt(name = "more")]
+        more: Vec<PathBuf>,
+        #[structopt(flatten)]
+        opt: Opt,
+    },
+    /// List the tracks of a MIDI file
+    List {
+        /// MIDI file name
+        #[structopt(name = "file")]
+        file: PathBuf,
+        #[structopt(flatten)]
+        read: Read,
+    },
+    /// Check the adapter, cubes, show file and schedule of a show
+    Preflight {
+        /// Show file name
+        #[structopt(name = "show")]
+        show: PathBuf,
+        /// Seconds of the show to run in the dry-run
+        #[structopt(long = "dry-run-secs", default_value = "10")]
+        dry_run_secs: u64,
+        #[structopt(flatten)]
+        opt: Opt,
+    },
+    /// Check the Bluetooth stack and the cubes, telling what to do about each problem
+    Doctor {
+        #[structopt(flatten)]
+        trace: Trace,
+    },
+    /// Play live MIDI inputs, each on its own cube
+    ///
+    /// The inputs are raw MIDI devices of Linux, where the toio crate can't connect real
+    /// cubes, so the cubes are the virtual ones of --simulate.
+    Jam {
+        /// Raw MIDI devices such as /dev/midi1, each with an optional delay in msec such as /dev/midi2@30
+        #[structopt(name = "input", required = true)]
+        inputs: Vec<jam::Input>,
+        /// Tempo of the metronome played on the next cube after the inputs
+        #[structopt(long = "metronome")]
+        metronome: Option<u64>,
+        /// Beats per bar of the metronome
+        #[structopt(long = "beats", default_value = "4")]
+        beats: u64,
+        /// Play on virtual cubes logging what they receive instead of real cubes
+        #[structopt(long = "simulate")]
+        simulate: bool,
+        /// Ring the terminal bell on each sound received by the virtual cubes
+        #[structopt(long = "simulate-beep")]
+        simulate_beep: bool,
+        #[structopt(flatten)]
+        trace: Trace,
+    },
+    /// Play the notes of a live MIDI input on the cubes as they come, each channel on the
+    /// cubes given by the rules
+    ///
+    /// The input is a raw MIDI device of Linux, where the toio crate can't connect real
+    /// cubes, so the cubes are the virtual ones of --simulate.
+    Live {
+        /// Raw MIDI device such as /dev/midi1, or virtual for the port of snd-virmidi that
+        /// sequencers can send to, with an optional delay in msec such as /dev/midi1@30
+        #[structopt(long = "input")]
+        input: jam::Input,
+        /// Rules to assign channels to cube
+        #[structopt(short = "r", long = "rule", parse(try_from_str))]
+        rules: Vec<Rule>,
+        /// Play on virtual cubes logging what they receive instead of real cubes
+        #[structopt(long = "simulate")]
+        simulate: bool,
+        /// Ring the terminal bell on each sound received by the virtual cubes
+        #[structopt(long = "simulate-beep")]
+        simulate_beep: bool,
+        #[structopt(flatten)]
+        connect: Connect,
+        #[structopt(flatten)]
+        trace: Trace,
+    },
+    /// Play a reference note on every cube to find and correct octave mismatches
+    Tune {
+        /// Offset of the notes of a cube in semitones such as cube1:-12, to start from
+        #[structopt(long = "tuning", number_of_values = 1)]
+        tunings: Vec<tune::Tuning>,
+        #[structopt(flatten)]
+        trace: Trace,
+    },
+    /// Play clicks on every cube to measure the delay until each sounds, kept in the cubes file
+    Calibrate {
+        #[structopt(flatten)]
+        connect: Connect,
+        #[structopt(flatten)]
+        trace: Trace,
+    },
+    /// Write sounds and lights to every cube as fast as it takes them, printing the writes a
+    /// second and how long they take
+    Bench {
+        /// Seconds of the writes of each kind to each cube
+        #[structopt(long = "secs", default_value = "5")]
+        secs: u64,
+        /// Maximum number of sound operations sent to a cube at once (1-59)
+        #[structopt(long = "max-ops", default_value = "59")]
+        max_ops: usize,
+        #[structopt(flatten)]
+        connect: Connect,
+        #[structopt(flatten)]
+        trace: Trace,
+    },
+    /// Play a generated pattern of notes instead of a file, to test the timing at its limits
+    Stress {
+        /// Parts, each played on its own cube unless the rules tell otherwise
+        #[structopt(long = "parts", default_value = "2")]
+        parts: usize,
+        /// Notes a second on each part
+        #[structopt(long = "density", default_value = "8")]
+        density: u64,
+        /// Length of the notes in msec, or a range such as 20-200 to pick from
+        #[structopt(long = "note-len", default_value = "100")]
+        lengths: stress::Lengths,
+        /// Seconds of the pattern
+        #[structopt(long = "secs", default_value = "30")]
+        secs: u64,
+        /// Seed of the notes picked, the same one giving the same pattern
+        #[structopt(long = "seed", default_value = "1")]
+        seed: u64,
+        #[structopt(flatten)]
+        opt: Opt,
+    },
+    /// Write the plan of play sets sent to each part as JSON instead of playing
+    Export {
+        /// Output file name
+        #[structopt(name = "output")]
+        output: PathBuf,
+        /// MIDI file name, or show file name (.toml)
+        #[structopt(name = "file")]
+        file: Option<PathBuf>,
+        #[structopt(flatten)]
+        opt: Opt,
+    },
+    /// Render the arrangement with square waves instead of playing on cubes
+    Preview {
+        /// Output WAV file name
+        #[structopt(long = "wav")]
+        wav: PathBuf,
+        /// MIDI file name, or show file name (.toml)
+        #[structopt(name = "file")]
+        file: Option<PathBuf>,
+        #[structopt(flatten)]
+        opt: Opt,
+    },
+    /// Play a plan written by export, skipping the MIDI file
+    PlayPlan {
+        /// Plan file name (.json)
+        #[structopt(name = "plan")]
+        plan: PathBuf,
+        #[structopt(flatten)]
+        opt: Opt,
+    },
+    /// Print the notes of each track and the tempo map of a MIDI file
+    Stats {
+        /// MIDI file name
+        #[structopt(name = "file")]
+        file: PathBuf,
+        #[structopt(flatten)]
+        read: Read,
+    },
+    /// Blink each cube in its color and play an arpeggio on it in turn to put the cubes in place
+    Identify {
+        /// Show file name (.toml) numbering the cubes
+        #[structopt(name = "show")]
+        show: Option<PathBuf>,
+        /// Rules to assign tracks to cube
+        #[structopt(short = "r", long = "rule", parse(try_from_str))]
+        rules: Vec<Rule>,
+        #[structopt(flatten)]
+        connect: Connect,
+        #[structopt(flatten)]
+        trace: Trace,
+    },
+    /// Name the cubes to tell them apart
+    Cubes {
+        #[structopt(subcommand)]
+        cmd: CubesCmd,
+    },
+}
+
+#[derive(StructOpt)]
+enum CubesCmd {
+    /// Light up each cube found in turn and ask for its nickname
+    Register {
+        #[structopt(flatten)]
+        trace: Trace,
+    },
+    /// Print the nicknames of the cubes
+    List,
+}
+
+#[derive(StructOpt)]
+#[structopt(setting = structopt::clap::AppSettings::ArgsNegateSubcommands)]
+struct Cli {
+    #[structopt(subcommand)]
+    cmd: Option<Cmd>,
+    /// MIDI file name, or show file name (.toml)
     #[structopt(name = "file")]
-    file: PathBuf,
-    /// List tracks
-    #[structopt(short = "l", long = "list")]
-    list: bool,
+    file: Option<PathBuf>,
+    /// More MIDI files to play after it, as a playlist
+    #[structopt(name = "more")]
+    more: Vec<PathBuf>,
+    #[structopt(flatten)]
+    opt: Opt,
+    /// The show keys given on the command line, which the show file doesn't override.
+    #[structopt(skip)]
+    given: Vec<&'static str>,
+}
+
+/// Options to find and connect the cubes.
+#[derive(StructOpt)]
+struct Connect {
+    /// Connect only to the cube of the Bluetooth identifier or nickname, numbered in the order
+    /// given
+    #[structopt(long = "cube")]
+    cubes: Vec<String>,
+    /// Keep searching until the number of cubes are found, of those named with --cube if any
+    #[structopt(long = "require-cubes")]
+    require_cubes: Option<usize>,
+    /// How long to keep searching for the cubes required, such as 30s
+    #[structopt(long = "search-timeout", default_value = "30s")]
+    search_timeout: Offset,
+    /// Connect at most the number of cubes, the nearest ones by signal strength
+    #[structopt(long = "max-cubes")]
+    max_cubes: Option<usize>,
+    /// Leave the cubes of a weaker signal than the RSSI in dBm alone, such as -70
+    #[structopt(long = "min-rssi", allow_hyphen_values = true)]
+    min_rssi: Option<i32>,
+}
+
+/// Options to trace what's written to the cubes.
+#[derive(StructOpt)]
+struct Trace {
+    /// Log every BLE write with its payload and latency
+    #[structopt(long = "trace-ble")]
+    ble: bool,
+    /// Keep every command sent to the cubes in the file as JSON lines, with its time and payload
+    #[structopt(name = "trace", long = "trace")]
+    file: Option<PathBuf>,
+}
+
+/// Options to read MIDI files and print what's read.
+#[derive(StructOpt)]
+struct Read {
+    /// Split parts by track or by MIDI channel; auto splits format 0 files by channel
+    #[structopt(long = "group-by", default_value = "auto")]
+    group_by: midi::GroupBy,
+    /// Output format of list, stats and --markers: text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: Format,
+    /// Reader of MIDI files: ghakuf, or builtin for files ghakuf can't read; broken files fail either way unless --lenient
+    #[structopt(long = "parser", default_value = "ghakuf")]
+    parser: midi::Parser,
+    /// Take the tracks read so far from broken files instead of failing
+    #[structopt(long = "lenient")]
+    lenient: bool,
+}
+
+/// Options to play songs.
+#[derive(StructOpt)]
+struct Opt {
+    /// The file to play, given before the options or in the show.
+    #[structopt(skip)]
+    file: Option<PathBuf>,
+    /// The files to play after it.
+    #[structopt(skip)]
+    more: Vec<PathBuf>,
+    /// More MIDI files layered onto one timeline after the file, if any, each referenced as
+    /// file:channel in the rules counting from 0, such as 0=0:1,1:10
+    #[structopt(name = "layer", long = "file")]
+    layers: Vec<PathBuf>,
+    /// Play the MIDI files listed in the playlist file (.m3u) after the files given
+    #[structopt(long = "playlist")]
+    playlist: Option<PathBuf>,
+    /// Play the MIDI files in the directory after the files given
+    #[structopt(long = "dir")]
+    dir: Option<PathBuf>,
+    /// Play the playlist in random order over and over
+    #[structopt(long = "shuffle")]
+    shuffle: bool,
+    /// Apply the settings of the profile of the name in the show file over the others
+    #[structopt(long = "profile")]
+    profile: Option<String>,
+    /// Play the file again from the start whenever it changes on disk
+    #[structopt(long = "watch")]
+    watch: bool,
+    /// Play a built-in demo song instead of a file: ode-to-joy, twinkle or frere-jacques
+    #[structopt(long = "demo")]
+    demo: Option<String>,
     /// Rules to assign tracks to cube
     #[structopt(short = "r", long = "rule", parse(try_from_str))]
     rules: Vec<Rule>,
-    /// Speed
+    /// Speed in percent of the tempo of the file
     #[structopt(short = "s", long = "speed", default_value = "100")]
     speed: u64,
+    /// Make a cube mirror the part of another transposed by semitones, such as cube2=cube0:+12
+    #[structopt(long = "link")]
+    links: Vec<Mirror>,
+    /// Set the speed by tapping the tempo on the Enter key before playing
+    #[structopt(long = "tap-tempo")]
+    tap_tempo: bool,
     /// Time-slice size used on merge
     #[structopt(short = "u", long = "unit", default_value = "40")]
     unit: u64,
+    /// Play the section between the two times over and over, such as 0:30 0:45
+    #[structopt(long = "loop-between", number_of_values = 2)]
+    loop_between: Vec<Offset>,
+    /// Play the song the given times over, or infinite
+    #[structopt(long = "repeat")]
+    repeat: Option<Repeat>,
+    /// Play from the time in the song, such as 1m30s or 1:30
+    #[structopt(long = "from")]
+    from: Option<Offset>,
+    /// Play until the time in the song
+    #[structopt(long = "to")]
+    to: Option<Offset>,
+    /// Play from the marker or the cue point of the name in the song
+    #[structopt(long = "from-marker")]
+    from_marker: Option<String>,
+    /// List the markers and the cue points of the song
+    #[structopt(long = "markers")]
+    markers: bool,
+    /// Count in the bars with clicks on cube 0 at the tempo of the song, instead of waiting
+    /// 3 seconds to start
+    #[structopt(long = "count-in")]
+    count_in: Option<u64>,
+    /// Keep the cube to click on every beat of the song, flashing on the first beat of bars
+    #[structopt(long = "metronome")]
+    metronome: Option<usize>,
+    /// Fade out over the last seconds of the song, shortening and softening the notes
+    #[structopt(long = "fade-out")]
+    fade_out: Option<f64>,
+    /// Print the lyrics in time with the song, such as of .kar files
+    #[structopt(long = "lyrics")]
+    lyrics: bool,
+    /// Split the song into segments of about the given minutes, pausing for a button press in between
+    #[structopt(long = "segment")]
+    segment: Option<f64>,
+    /// Refuse to play when the battery of a cube is below the given percentage
+    #[structopt(long = "min-battery")]
+    min_battery: Option<usize>,
+    /// Move the part of a cube to another cube when its battery falls below the given percentage
+    #[structopt(long = "reassign-battery")]
+    reassign_battery: Option<usize>,
+    #[structopt(flatten)]
+    connect: Connect,
+    /// Connect to a lost cube again and carry on from where the song is
+    #[structopt(long = "reconnect")]
+    reconnect: bool,
+    /// Keep places for the number of cubes powered on while playing, which join to play the
+    /// parts left over or mirror a cube
+    #[structopt(long = "hot-plug", default_value = "0")]
+    hot_plug: usize,
+    /// Mix the parts meant for the cubes not found into the other cubes instead of failing
+    #[structopt(long = "collapse")]
+    collapse: bool,
+    /// Move the parts of a lost cube to another cube, mixed into what it plays
+    #[structopt(long = "failover")]
+    failover: bool,
+    /// Keep playing on the other cubes when a cube fails, instead of stopping
+    #[structopt(long = "drop-failed")]
+    drop_failed: bool,
+    /// Tracks to mute, or file:channel of the layered files as in the rules
+    #[structopt(long = "mute", use_delimiter = true, parse(try_from_str = layer_channel))]
+    mute: Vec<Channel>,
+    /// Tracks to play exclusively, or file:channel of the layered files as in the rules
+    #[structopt(long = "solo", use_delimiter = true, parse(try_from_str = layer_channel))]
+    solo: Vec<Channel>,
+    #[structopt(flatten)]
+    trace: Trace,
+    /// Write what happens during the performance into the file as JSON lines, such as the
+    /// connections, the play sets sent, the errors and the position in the song
+    #[structopt(long = "event-log")]
+    event_log: Option<PathBuf>,
+    /// Log only the lines about the cube, besides those about no cube in particular
+    #[structopt(long = "log-cube")]
+    log_cube: Option<usize>,
+    /// How to handle gaps between notes: rest, legato or staccato[:msec]
+    #[structopt(long = "gap", default_value = "rest")]
+    gap: midi::Gap,
+    /// Maximum number of sound operations sent to a cube at once (1-59)
+    #[structopt(long = "max-ops", default_value = "59")]
+    max_ops: usize,
+    /// Maximum length of a sound operation in msec (10-2550)
+    #[structopt(long = "max-op-len", default_value = "2550")]
+    max_op_len: u64,
+    #[structopt(flatten)]
+    read: Read,
+    /// Fail with exit code 5 if the file uses MIDI features the cubes can't play
+    #[structopt(long = "strict")]
+    strict: bool,
+    /// Fail with exit code 4 if the conversion warns more than this
+    #[structopt(long = "max-warnings")]
+    max_warnings: Option<usize>,
+    /// Play only this track of format 2 files, counting from 1, instead of all the sequences in turn
+    #[structopt(long = "sequence")]
+    sequence: Option<Channel>,
+    /// Play the most likely melody on cube 0 when no rules are given
+    #[structopt(long = "auto-assign")]
+    auto_assign: bool,
+    /// Crossfade the light of cubes along with the dynamics of their parts;
+    /// the same as --light-mode dynamics
+    #[structopt(long = "dynamics")]
+    dynamics: bool,
+    /// How the light of cubes follows their parts: steady, dynamics or note-follow
+    #[structopt(long = "light-mode", default_value = "steady")]
+    light_mode: lights::Mode,
+    /// Send repeated patterns once with a repeat count
+    #[structopt(long = "fold-repeats")]
+    fold_repeats: bool,
+    /// Behavior of cubes while waiting between songs: breathe, chime or off
+    #[structopt(long = "idle", default_value = "off")]
+    idle: idle::Idle,
+    /// Seconds between the chimes while idle
+    #[structopt(long = "idle-chime-secs", default_value = "30")]
+    idle_chime_secs: u64,
+    /// Wind down the playback after this many seconds, however long the song is
+    #[structopt(long = "max-duration")]
+    max_duration: Option<u64>,
+    /// Write the schedule of each cube as toio.js calls in JSON instead of playing
+    #[structopt(long = "export-toio")]
+    export_toio: Option<PathBuf>,
+    /// Write the arrangement the cubes play as a MIDI file instead of playing
+    #[structopt(long = "export-midi")]
+    export_midi: Option<PathBuf>,
+    /// Play on the raw MIDI output such as /dev/snd/midiC1D0 instead of cubes
+    #[structopt(long = "preview-midi")]
+    preview_midi: Option<PathBuf>,
+    /// Play on virtual cubes logging what they receive instead of real cubes
+    #[structopt(long = "simulate")]
+    simulate: bool,
+    /// Ring the terminal bell on each sound received by the virtual cubes
+    #[structopt(long = "simulate-beep")]
+    simulate_beep: bool,
+    /// Print the batches sent to each cube in the order of time instead of playing
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+    /// Pause and resume with the space key, skip the segment with s and quit with q
+    #[structopt(long = "keys")]
+    keys: bool,
+    /// Show the status of the cubes full-screen with keys to pause, skip and quit
+    #[structopt(long = "tui")]
+    tui: bool,
+    /// Show a progress bar of the position in the song with the time left
+    #[structopt(long = "progress")]
+    progress: bool,
+    /// Draw a scrolling piano roll of what each cube should be playing
+    #[structopt(long = "piano-roll")]
+    piano_roll: bool,
+    /// Record the play sets sent to each cube into the file, a line per set
+    #[structopt(long = "play-log")]
+    play_log: Option<PathBuf>,
+    /// Always send the most sound operations at once instead of adapting to the latency
+    #[structopt(long = "fixed-packets")]
+    fixed_packets: bool,
+    /// Try a failed write to a cube again this many times, waiting longer each time
+    #[structopt(long = "retries", default_value = "0")]
+    retries: u32,
+    /// Skip up to this many play sets in a row that still fail to write, instead of failing the cube
+    #[structopt(long = "max-skips", default_value = "0")]
+    max_skips: usize,
+    /// Keep the converted plans on disk to play the same song again without converting it
+    #[structopt(long = "cache")]
+    cache: bool,
+    /// Start playing the first seconds of the file while the rest is converted
+    #[structopt(long = "progressive")]
+    progressive: bool,
+    /// Time the play sets on a thread that sleeps and then spins, closer than the async timers
+    #[structopt(long = "precise-timing")]
+    precise_timing: bool,
+    /// Send each play set this many msec ahead of its time, for the cube to start it on time
+    #[structopt(long = "lookahead", default_value = "0")]
+    lookahead: u64,
+    /// Follow the MIDI clock of the raw device such as /dev/midi1, or `virtual`, starting and
+    /// stopping with it at its tempo
+    #[structopt(long = "midi-clock")]
+    midi_clock: Option<PathBuf>,
+    /// Chase the MIDI time code of the raw device, such as /dev/midi1@01:00:00:00 for the song
+    /// starting at an hour
+    #[structopt(long = "mtc")]
+    mtc: Option<clock::Chase>,
+    /// Pause, play and locate the song by the MIDI Machine Control from the raw device such as
+    /// /dev/midi1, or `virtual`, with /dev/midi1@01:00:00:00 for the song starting at an hour
+    #[structopt(long = "mmc")]
+    mmc: Option<clock::Chase>,
+    /// Latency of a cube in msec such as cube1=35, instead of the one found with calibrate
+    #[structopt(long = "offset", number_of_values = 1)]
+    latencies: Vec<calibrate::Latency>,
+    /// Offset of the notes of a cube in semitones such as cube1:-12, found with the tune command
+    #[structopt(long = "tuning", number_of_values = 1)]
+    tunings: Vec<tune::Tuning>,
+    /// Silence every cube and pause on the Enter key, and resume on it again
+    #[structopt(long = "panic-key")]
+    panic_key: bool,
+    /// Address such as 127.0.0.1:8000 to accept /panic and /resume over HTTP
+    #[structopt(long = "panic-http")]
+    panic_http: Option<std::net::SocketAddr>,
+    /// Address such as 127.0.0.1:9000 to accept the OSC messages /panic and /resume
+    #[structopt(long = "panic-osc")]
+    panic_osc: Option<std::net::SocketAddr>,
+}
+
+/// How long to keep trying to connect to a lost cube again.
+const RECONNECT: Duration = Duration::from_secs(30);
+/// How long a try to connect to a lost cube again takes at most.
+const RECONNECT_TRY: Duration = Duration::from_secs(3);
+/// Interval of searching for the cubes powered on later, doubled while none joins.
+const HOT_PLUG: Duration = Duration::from_secs(2);
+/// The longest interval of searching for the cubes powered on later.
+const HOT_PLUG_MAX: Duration = Duration::from_secs(60);
+/// How many commands wait for a cube at most; play sets beyond are dropped.
+const QUEUE: usize = 32;
+/// How much of the song is converted at once with --progressive, in msec.
+const PROGRESSIVE: Time = 10_000;
+/// Battery percentage warned of on connecting.
+const LOW_BATTERY: usize = 20;
+/// How long the playback winds down at the time limit.
+const WIND_DOWN: Duration = Duration::from_secs(3);
+/// Steps of the volume on winding down.
+const WIND_DOWN_STEPS: u32 = 6;
+/// Length of a click of the count-in and the metronome in msec.
+const CLICK: Time = 50;
+
+/// The commands sent to a cube task and not taken yet, counted for the sink to tell how
+/// far behind the cube is, with how the task wrote them.
+struct Inbox {
+    rx: tokio::sync::mpsc::Receiver<Command>,
+    depth: Arc<AtomicUsize>,
+    /// How many commands taken from the channel wait in the task.
+    queued: Arc<AtomicUsize>,
+    timing: Arc<Mutex<Timing>>,
+}
+
+impl Stream for Inbox {
+    type Item = Command;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<Option<Command>> {
+        let polled = Pin::new(&mut self.rx).poll_next(cx);
+        if let Poll::Ready(Some(_)) = polled {
+            self.depth.fetch_sub(1, Ordering::SeqCst);
+        }
+        polled
+    }
+}
+
+/// Writes of a play set to a cube.
+enum Step {
+    Sound(Vec<toio::SoundOp>),
+    Light(Vec<toio::LightOp>),
 }
 
-fn ops(set: &PlaySet) -> Vec<SoundOp> {
-    assert!(set.plays.len() <= 59);
-    set.plays
+/// The light color of the cube, derived from the tracks it plays.
+fn color(opt: &Opt, i: usize) -> (u8, u8, u8) {
+    let p: usize = opt
+        .rules
         .iter()
-        .map(|p| {
-            assert!(p.len <= 2550);
-            SoundOp::new(p.note, Duration::from_millis(p.len))
-        })
-        .collect()
+        .find(|p| p.as_ch as usize == i)
+        .map(|r| r.chs.iter().map(|ch| *ch as usize).sum())
+        .unwrap_or(i);
+    let c = (p % 7 + 1) as u8;
+    ((c & 1) * 255, (c >> 1 & 1) * 255, (c >> 2 & 1) * 255)
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let opt = Opt::from_args();
+/// Settings of a cube task.
+#[derive(Clone)]
+struct CubeConfig {
+    color: (u8, u8, u8),
+    idle: idle::Idle,
+    chime: Duration,
+    light: lights::Mode,
+    fixed_packets: bool,
+    /// Ops a packet takes at most.
+    max_ops: usize,
+    /// Offset of the notes in semitones.
+    offset: i8,
+    /// Connects again if the cube is lost.
+    reconnect: bool,
+    retry: retry::Policy,
+    timer: Timer,
+}
 
-    env_logger::from_env(
-        env_logger::Env::default().default_filter_or(format!("{}=info", module_path!())),
-    )
-    .init();
+/// Fades out what the cube is playing and turns it off.
+async fn wind_down(
+    link: &mut Link,
+    playing: Option<(PlaySet, Instant)>,
+    color: (u8, u8, u8),
+    len: Duration,
+) -> Result<()> {
+    if len == Duration::from_secs(0) {
+        link.stop_sound().await?;
+        return link.light_off().await;
+    }
+    let ops = lights::fade_out(color, len.as_millis() as Time);
+    if !ops.is_empty() {
+        link.light(1, &ops).await?;
+    }
 
-    if opt.speed == 0 {
-        return Err(anyhow!("Speed must be non-zero"));
+    let step = len / WIND_DOWN_STEPS;
+    let ms = step.as_millis() as Time;
+    let from = playing
+        .as_ref()
+        .map(|(_, start)| start.elapsed().as_millis() as Time);
+    for i in 0..WIND_DOWN_STEPS {
+        let chunk = match (&playing, from) {
+            (Some((p, _)), Some(from)) => p.window(from + i as Time * ms, ms).chunks(),
+            _ => vec![],
+        };
+        if let Some(chunk) = chunk.first() {
+            let volume = 255 * (WIND_DOWN_STEPS - i) / (WIND_DOWN_STEPS + 1);
+            link.play_with_volume(1, chunk, volume as u8).await?;
+        }
+        delay_for(step).await;
     }
 
-    if opt.list {
-        let events = midi::load(&opt.file)?;
+    link.stop_sound().await?;
+    link.light_off().await
+}
 
-        let mut set = vec![];
-        for ((_, ch), _) in events {
-            set.push(ch);
+/// Waits until the time of the next step of a play set, returning false if a command
+/// arriving meanwhile cuts the set short.
+///
+/// A play set sent ahead cuts it short only at its own time, kept in `next`.
+async fn until(
+    at: Instant,
+    timer: &Timer,
+    rx: &mut Inbox,
+    queue: &mut VecDeque<Command>,
+    next: &mut Option<Instant>,
+) -> bool {
+    loop {
+        match future::select(timer.until(at).boxed(), rx.next()).await {
+            future::Either::Left(_) => return true,
+            future::Either::Right((Some(Command::Play(p, due)), _))
+                if next.is_none() && due > at =>
+            {
+                *next = Some(due);
+                queue.push_back(Command::Play(p, due));
+            }
+            future::Either::Right((cmd, _)) => {
+                queue.extend(cmd);
+                return false;
+            }
         }
-        set.sort();
-        set.dedup();
-        info!("Available tracks: {:?}", set);
-        return Ok(());
     }
+}
 
-    let events = if opt.rules.is_empty() {
-        midi::load(&opt.file)?
-    } else {
-        info!("Parsing file {}...", opt.file.display());
-        let rules: Vec<_> = opt.rules.iter().map(|r| (r.as_ch, r.chs.clone())).collect();
-        midi::load_mixed(&opt.file, opt.unit, &rules)?
+/// Plays the queued commands, then those of the channel on the cube until it's closed.
+///
+/// A command arriving while a play set is sent in chunks cuts it short, or a play set
+/// sent ahead at its time; any other command cancels the sets sent ahead. The play set
+/// received last is kept in `last` with when it started, until the cube stops playing it.
+async fn run_cube(
+    link: &mut Link,
+    rx: &mut Inbox,
+    config: CubeConfig,
+    last: &mut Option<(PlaySet, Instant)>,
+    mut queue: VecDeque<Command>,
+) -> Result<()> {
+    let CubeConfig {
+        color,
+        idle,
+        chime,
+        light,
+        fixed_packets,
+        max_ops,
+        offset,
+        reconnect: _,
+        retry,
+        timer,
+    } = config;
+    let mut packets = PacketSize::new(link.index, max_ops);
+    // Play sets skipped in a row.
+    let mut skipped = 0;
+    let mut idling = false;
+    let mut playing = None;
+
+    loop {
+        let cmd = if !queue.is_empty() {
+            queue.pop_front()
+        } else if idling && idle.chime {
+            match tokio::time::timeout(chime, rx.next()).await {
+                Ok(cmd) => cmd,
+                Err(_) => {
+                    idle::chime(link).await?;
+                    continue;
+                }
+            }
+        } else {
+            rx.next().await
+        };
+        let cmd = match cmd {
+            Some(cmd) => cmd,
+            None => return Ok(()),
+        };
+
+        // As many wait here as in the channel at most, the oldest play sets dropped first.
+        while let Some(Some(queued)) = rx.next().now_or_never() {
+            queue.push_back(queued);
+        }
+        let (cmd, dropped) = cube::trim(cmd, &mut queue, QUEUE);
+        for _ in 0..dropped {
+            rx.timing.lock().unwrap().drop_set();
+        }
+        // Told the first time.
+        if dropped > 0 && rx.timing.lock().unwrap().dropped() == dropped {
+            warn!(
+                "Cube {} fell {} play sets behind; dropping them",
+                link.index, dropped
+            );
+        }
+        rx.queued.store(queue.len(), Ordering::SeqCst);
+
+        let wake = idling && !matches!(cmd, Command::Idle);
+        if !matches!(cmd, Command::Play(..)) {
+            *last = None;
+        }
+        match cmd {
+            Command::Play(p, due) => {
+                // Sent ahead; wait for its time unless another command comes first.
+                if due > Instant::now() {
+                    match future::select(timer.until(due).boxed(), rx.next()).await {
+                        future::Either::Left(_) => {}
+                        // A play set waits its turn; another command cancels those waiting.
+                        future::Either::Right((Some(cmd), _)) => {
+                            if matches!(cmd, Command::Play(..)) {
+                                queue.push_front(Command::Play(p, due));
+                            } else {
+                                queue.retain(|c| !matches!(c, Command::Play(..)));
+                            }
+                            queue.push_back(cmd);
+                            continue;
+                        }
+                        future::Either::Right((None, _)) => timer.until(due).await,
+                    }
+                }
+                let start = Instant::now();
+                *last = Some((p.clone(), start));
+                let p = if offset != 0 { p.transposed(offset) } else { p };
+                let chunks = if fixed_packets {
+                    p.chunks()
+                } else {
+                    p.chunks_of(packets.size())
+                };
+                let sounds: usize = chunks.iter().map(Vec::len).sum();
+
+                let mut steps = vec![];
+                let mut at = start;
+                for chunk in chunks {
+                    let len = chunk.iter().map(|op| op.duration).sum::<Duration>();
+                    steps.push((at, Step::Sound(chunk)));
+                    at += len * p.repeat as u32;
+                }
+                // Lights go after the sounds at the same time not to delay them.
+                let ops = match light {
+                    lights::Mode::Dynamics if p.repeat == 1 => lights::crossfade(&p, color),
+                    lights::Mode::Downbeat => lights::downbeat(&p, color),
+                    _ => vec![],
+                };
+                if !ops.is_empty() {
+                    steps.push((start, Step::Light(ops)));
+                }
+                if light == lights::Mode::NoteFollow {
+                    for (at, ops) in lights::flashes(&p) {
+                        steps.push((start + Duration::from_millis(at), Step::Light(ops)));
+                    }
+                }
+                steps.sort_by_key(|(at, _)| *at);
+
+                let mut next = match queue.front() {
+                    Some(Command::Play(_, due)) => Some(*due),
+                    _ => None,
+                };
+                let mut first = true;
+                for (i, (at, step)) in steps.into_iter().enumerate() {
+                    if next.is_some_and(|due| at >= due) {
+                        break;
+                    }
+                    if i > 0 && !until(at, &timer, rx, &mut queue, &mut next).await {
+                        break;
+                    }
+                    match step {
+                        Step::Sound(chunk) => {
+                            let sent = Instant::now();
+                            if first {
+                                first = false;
+                                let late = sent.saturating_duration_since(due);
+                                rx.timing.lock().unwrap().sent(late);
+                                journal::record(
+                                    "play-set-sent",
+                                    vec![
+                                        ("cube", link.index.into()),
+                                        ("at", p.at.into()),
+                                        ("part", p.ch.into()),
+                                        ("ops", sounds.into()),
+                                        ("late", (late.as_micros() as f64 / 1000.0).into()),
+                                    ],
+                                );
+                            }
+                            let end = start + Duration::from_millis(p.len);
+                            match retry.play(link, p.repeat as usize, &chunk, end).await {
+                                Ok(()) => skipped = 0,
+                                Err(e) if retry.skips(skipped) => {
+                                    skipped += 1;
+                                    rx.timing.lock().unwrap().skip();
+                                    warn!(
+                                        "Cube {} skipped the play set at {} of the song: {:#}",
+                                        link.index,
+                                        Offset(p.at),
+                                        e
+                                    );
+                                    journal::record(
+                                        "error",
+                                        vec![
+                                            ("cube", link.index.into()),
+                                            ("at", p.at.into()),
+                                            ("message", format!("{:#}", e).into()),
+                                            ("skipped", true.into()),
+                                        ],
+                                    );
+                                    break;
+                                }
+                                Err(e) => {
+                                    return Err(e).context(format!("error on cube {}", link.index))
+                                }
+                            }
+                            packets.record(sent.elapsed());
+                            rx.timing.lock().unwrap().written(sent.elapsed());
+                        }
+                        Step::Light(ops) => link.light(1, &ops).await?,
+                    }
+                }
+                playing = Some((p, start));
+            }
+            Command::Park => {
+                link.stop_sound().await?;
+                link.light_on(32, 0, 0).await?;
+            }
+            Command::Silence => {
+                playing = None;
+                link.stop_sound().await?;
+            }
+            Command::Restart => {
+                playing = None;
+                link.stop_sound().await?;
+                link.light_on(color.0, color.1, color.2).await?;
+            }
+            Command::Idle => {
+                idling = true;
+                idle::start(link, &idle, color).await?;
+            }
+            Command::WindDown(len) => {
+                wind_down(link, playing.take(), color, len).await?;
+                return Ok(());
+            }
+        }
+
+        // Restore the light after the music has resumed not to delay it.
+        if wake {
+            idling = false;
+            if idle.breathe {
+                link.light_on(color.0, color.1, color.2).await?;
+            }
+        }
+    }
+}
+
+/// Takes the commands already sent, returning false if the playback ended.
+fn drain(
+    rx: &mut Inbox,
+    last: &mut Option<(PlaySet, Instant)>,
+    queue: &mut VecDeque<Command>,
+) -> bool {
+    while let Some(cmd) = rx.next().now_or_never() {
+        if !cube::take(cmd, last, queue) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Takes the commands sent while there's no cube to play them until the playback ends.
+async fn taking(
+    rx: &mut Inbox,
+    last: &mut Option<(PlaySet, Instant)>,
+    queue: &mut VecDeque<Command>,
+) {
+    while cube::take(rx.next().await, last, queue) {}
+}
+
+/// Connects to the lost cube again, taking the commands sent meanwhile.
+///
+/// Returns the commands to carry on with, or `None` if the playback ended meanwhile.
+async fn reconnect(
+    link: &mut Link,
+    rx: &mut Inbox,
+    last: &mut Option<(PlaySet, Instant)>,
+    color: (u8, u8, u8),
+) -> Result<Option<VecDeque<Command>>> {
+    let deadline = Instant::now() + RECONNECT;
+    let mut queue = VecDeque::new();
+    loop {
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "Couldn't connect to cube {} again in {}s",
+                link.index,
+                RECONNECT.as_secs()
+            ))
+            .context(Failure::Bluetooth);
+        }
+
+        // The commands are taken meanwhile not to fill the queue.
+        let connect = Box::pin(tokio::time::timeout(RECONNECT_TRY, link.cube()?.connect()));
+        let connect = match future::select(connect, Box::pin(taking(rx, last, &mut queue))).await {
+            future::Either::Left((connect, _)) => connect,
+            future::Either::Right(_) => return Ok(None),
+        };
+        match connect {
+            Ok(Ok(())) => break,
+            Ok(Err(e)) => debug!("Cube {} isn't back yet: {:#}", link.index, e),
+            Err(_) => debug!("Cube {} isn't back yet", link.index),
+        }
+        let retry = Box::pin(delay_for(RECONNECT_TRY));
+        if let future::Either::Right(_) =
+            future::select(retry, Box::pin(taking(rx, last, &mut queue))).await
+        {
+            return Ok(None);
+        }
+    }
+
+    link.light_on(color.0, color.1, color.2).await?;
+    // Those sent while the try connected.
+    if !drain(rx, last, &mut queue) {
+        return Ok(None);
+    }
+    Ok(Some(cube::carry_on(last, queue, Instant::now())))
+}
+
+/// Runs the cube task, trying to stop the sound and the light of the cube if it fails.
+///
+/// If asked, a lost cube is connected again to carry on from where the song is.
+async fn supervise(
+    mut link: Link,
+    mut rx: Inbox,
+    config: CubeConfig,
+    mut queue: VecDeque<Command>,
+) -> Result<()> {
+    let mut last = None;
+    let e = loop {
+        let e = match run_cube(&mut link, &mut rx, config.clone(), &mut last, queue).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        if !config.reconnect || link.is_simulated() {
+            break e;
+        }
+        warn!("Cube {} is lost: {:#}; connecting again...", link.index, e);
+        journal::record(
+            "reconnect",
+            vec![
+                ("cube", link.index.into()),
+                ("state", "lost".into()),
+                ("message", format!("{:#}", e).into()),
+            ],
+        );
+        queue = match reconnect(&mut link, &mut rx, &mut last, config.color).await {
+            Ok(Some(queue)) => queue,
+            Ok(None) => return Ok(()),
+            Err(e) => break e,
+        };
+        info!("Cube {} is back", link.index);
+        journal::record(
+            "reconnect",
+            vec![("cube", link.index.into()), ("state", "back".into())],
+        );
     };
 
-    let mut cubes = Cube::search().all().await?;
+    error!("Cube {} failed: {:#}", link.index, e);
+    journal::record(
+        "error",
+        vec![
+            ("cube", link.index.into()),
+            ("message", format!("{:#}", e).into()),
+        ],
+    );
+    // The cube may be gone already.
+    let _ = link.stop_sound().await;
+    let _ = link.light_off().await;
+    Err(e)
+}
 
-    if cubes.is_empty() {
-        return Err(anyhow!("No cube found"));
+/// Waits for a cube to join, taking the commands sent meanwhile, then plays on it from
+/// where the song is.
+async fn vacant(
+    joins: tokio::sync::oneshot::Receiver<Link>,
+    mut rx: Inbox,
+    config: CubeConfig,
+) -> Result<()> {
+    let mut last = None;
+    let mut queue = VecDeque::new();
+    let mut joins = joins;
+    let mut link = loop {
+        match future::select(&mut joins, rx.next()).await {
+            future::Either::Left((Ok(link), _)) => break link,
+            // Nobody comes any more.
+            future::Either::Left((Err(_), _)) => return Ok(()),
+            future::Either::Right((cmd, _)) => {
+                if !cube::take(cmd, &mut last, &mut queue) {
+                    return Ok(());
+                }
+            }
+        }
+    };
+    let (r, g, b) = config.color;
+    link.light_on(r, g, b).await?;
+    if !drain(&mut rx, &mut last, &mut queue) {
+        return Ok(());
     }
+    supervise(
+        link,
+        rx,
+        config,
+        cube::carry_on(&mut last, queue, Instant::now()),
+    )
+    .await
+}
 
-    for (i, cube) in cubes.iter_mut().enumerate() {
-        cube.connect().await?;
-        info!("Cube {} connected", i);
+/// Plays on a real or virtual cube through its task.
+struct CubeSink {
+    index: usize,
+    tx: tokio::sync::mpsc::Sender<Command>,
+    /// How many commands wait for the task.
+    depth: Arc<AtomicUsize>,
+    /// How many of them wait in the task.
+    queued: Arc<AtomicUsize>,
+    /// The most commands that waited at once.
+    most: usize,
+    /// How many play sets are dropped as the queue is full.
+    skipped: usize,
+    timing: Arc<Mutex<Timing>>,
+    task: tokio::task::JoinHandle<Result<()>>,
+    /// Keeps the others playing if the cube fails.
+    drop_failed: bool,
+    dropped: bool,
+}
 
-        let p = opt
-            .rules
-            .iter()
-            .find(|p| p.as_ch == i as u8)
-            .map(|r| r.chs.iter().sum())
-            .unwrap_or(i as u8);
-        cube.light_on(
-            ((p % 7 + 1) & 1u8) * 255,
-            ((p % 7 + 1) >> 1u8 & 1u8) * 255,
-            ((p % 7 + 1) >> 2u8 & 1u8) * 255,
-            None,
-        )
-        .await?;
+/// Opens the queue of a cube task.
+fn queue() -> (tokio::sync::mpsc::Sender<Command>, Inbox) {
+    let (tx, rx) = tokio::sync::mpsc::channel(QUEUE);
+    let (depth, queued, timing) = (Arc::default(), Arc::default(), Arc::default());
+    (
+        tx,
+        Inbox {
+            rx,
+            depth,
+            queued,
+            timing,
+        },
+    )
+}
+
+impl CubeSink {
+    fn new(
+        index: usize,
+        tx: tokio::sync::mpsc::Sender<Command>,
+        depth: Arc<AtomicUsize>,
+        queued: Arc<AtomicUsize>,
+        timing: Arc<Mutex<Timing>>,
+        task: tokio::task::JoinHandle<Result<()>>,
+        drop_failed: bool,
+    ) -> Self {
+        Self {
+            index,
+            tx,
+            depth,
+            queued,
+            most: 0,
+            skipped: 0,
+            timing,
+            task,
+            drop_failed,
+            dropped: false,
+        }
+    }
+
+    fn spawn(link: Link, config: CubeConfig, drop_failed: bool) -> Self {
+        let index = link.index;
+        let (tx, rx) = queue();
+        let (depth, queued, timing) = (rx.depth.clone(), rx.queued.clone(), rx.timing.clone());
+        let task = tokio::spawn(span::cube(
+            index,
+            supervise(link, rx, config, VecDeque::new()),
+        ));
+        Self::new(index, tx, depth, queued, timing, task, drop_failed)
+    }
+
+    /// Keeps the place of a cube joining later through the sender.
+    fn vacant(
+        index: usize,
+        config: CubeConfig,
+        drop_failed: bool,
+    ) -> (Self, tokio::sync::oneshot::Sender<Link>) {
+        let (tx, rx) = queue();
+        let (depth, queued, timing) = (rx.depth.clone(), rx.queued.clone(), rx.timing.clone());
+        let (join, joins) = tokio::sync::oneshot::channel();
+        let task = tokio::spawn(span::cube(index, vacant(joins, rx, config)));
+        let sink = Self::new(index, tx, depth, queued, timing, task, drop_failed);
+        (sink, join)
+    }
+
+    /// Sends the command to the task, failing if the task is gone unless the cube is dropped.
+    ///
+    /// A play set is dropped if the queue is full, as the cube can't keep up; the other
+    /// commands wait for their place.
+    async fn send(&mut self, cmd: Command) -> Result<()> {
+        // Counted before it's sent not to be taken before counted.
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        let sent = match cmd {
+            Command::Play(..) => match self.tx.try_send(cmd) {
+                Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+                    self.depth.fetch_sub(1, Ordering::SeqCst);
+                    self.skipped += 1;
+                    if self.skipped == 1 {
+                        warn!(
+                            "Cube {} can't keep up with {} commands waiting; dropping play sets",
+                            self.index, QUEUE
+                        );
+                    }
+                    return Ok(());
+                }
+                sent => sent.is_ok(),
+            },
+            cmd => self.tx.send(cmd).await.is_ok(),
+        };
+        if sent {
+            let waiting = self.depth.load(Ordering::SeqCst) + self.queued.load(Ordering::SeqCst);
+            self.most = self.most.max(waiting);
+        } else {
+            self.depth.fetch_sub(1, Ordering::SeqCst);
+        }
+        if sent || self.dropped {
+            return Ok(());
+        }
+        if self.drop_failed {
+            warn!("Cube {} is dropped; the others keep playing", self.index);
+            self.dropped = true;
+            return Ok(());
+        }
+        Err(anyhow!("Cube {} stopped playing", self.index)).context(Failure::Bluetooth)
+    }
+}
+
+impl SoundSink for CubeSink {
+    async fn play(&mut self, set: PlaySet) -> Result<()> {
+        self.send(Command::Play(set, Instant::now())).await
+    }
+
+    async fn play_at(&mut self, set: PlaySet, due: Instant) -> Result<()> {
+        self.send(Command::Play(set, due)).await
+    }
+
+    async fn silence(&mut self) -> Result<()> {
+        self.send(Command::Silence).await
+    }
+
+    async fn idle(&mut self) -> Result<()> {
+        self.send(Command::Idle).await
+    }
+
+    async fn park(&mut self) -> Result<()> {
+        self.send(Command::Park).await
+    }
+
+    async fn restart(&mut self) -> Result<()> {
+        self.send(Command::Restart).await
+    }
+
+    async fn wind_down(&mut self, fade: Duration) -> Result<()> {
+        self.send(Command::WindDown(fade)).await
+    }
+
+    fn lost(&self) -> bool {
+        self.dropped
+    }
+
+    async fn finish(self) -> Result<()> {
+        drop(self.tx);
+        let result = self.task.await?;
+        let timing = self.timing.lock().unwrap().clone();
+        let dropped = self.skipped + timing.dropped();
+        if dropped > 0 {
+            warn!(
+                "Cube {} dropped {} play sets, with up to {} commands waiting; \
+                 try a larger --unit or --fold-repeats",
+                self.index, dropped, self.most
+            );
+        } else {
+            debug!(
+                "Cube {} had up to {} commands waiting",
+                self.index, self.most
+            );
+        }
+        info!(
+            "Cube {} timing: {}, {} dropped as the queue was full",
+            self.index, timing, dropped
+        );
+        match result {
+            // Told when it failed.
+            Err(_) if self.drop_failed => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Moves the parts of the lost cube to the cube taking over.
+fn fail_over(router: &mut Router, cube: usize) {
+    let (moves, dropped) = router.fail(cube);
+    for m in moves {
+        warn!(
+            "Cube {} is lost; moving part {} to cube {}",
+            m.from, m.part, m.to
+        );
+    }
+    for part in dropped {
+        warn!(
+            "Cube {} is lost; no cube is left to play part {}",
+            cube, part
+        );
+    }
+}
+
+async fn wait_button<S: Stream<Item = (usize, CubeEvent)> + Unpin>(events: &mut S) -> Result<()> {
+    while let Some((_, event)) = events.next().await {
+        if let CubeEvent::Button(true) = event {
+            return Ok(());
+        }
+    }
+    Err(anyhow!("Lost cube events while waiting for the button"))
+}
+
+async fn rebalance<E: Stream<Item = (usize, CubeEvent)> + Unpin, S: SoundSink>(
+    router: &mut Router,
+    events: &mut E,
+    sinks: &mut [S],
+    threshold: usize,
+) -> Result<()> {
+    while let Some(Some((i, event))) = events.next().now_or_never() {
+        if let CubeEvent::Battery(level) = event {
+            debug!("Cube {} battery: {}%", i, level);
+            router.battery(i, level);
+        }
+    }
+
+    let (moves, parked) = router.rebalance(threshold);
+    for m in moves {
+        warn!(
+            "Cube {} is low on battery; moving part {} to cube {}",
+            m.from, m.part, m.to
+        );
+    }
+    for i in parked {
+        warn!("Cube {} is parked due to low battery", i);
+        sinks[i].park().await?;
+    }
+    Ok(())
+}
+
+fn file(opt: &Opt) -> Result<&Path> {
+    opt.file
+        .as_deref()
+        .ok_or_else(|| anyhow!("No MIDI file given"))
+}
+
+fn validate(opt: &Opt) -> Result<()> {
+    if let Some(m) = opt.segment {
+        if m.is_nan() || m <= 0.0 {
+            return Err(anyhow!("Segment length must be positive"));
+        }
+    }
+    if let (Some(from), Some(to)) = (opt.from, opt.to) {
+        if from >= to {
+            return Err(anyhow!("--from {} must be before --to {}", from, to));
+        }
+    }
+    if let [a, b] = opt.loop_between[..] {
+        if a >= b {
+            return Err(anyhow!(
+                "The loop from {} must end after it, not at {}",
+                a,
+                b
+            ));
+        }
+        if opt.from.is_some() || opt.to.is_some() {
+            return Err(anyhow!("--loop-between conflicts with --from and --to"));
+        }
+    }
+    if !matches!(opt.loop_between.len(), 0 | 2) {
+        return Err(anyhow!("--loop-between takes the start and the end"));
+    }
+    if opt.repeat == Some(Repeat(Some(0))) {
+        return Err(anyhow!("Repeat must be non-zero"));
+    }
+    if !opt.layers.is_empty() && opt.rules.is_empty() {
+        return Err(anyhow!(
+            "Layering files needs rules on their channels such as -r 0=0:1 -r 1=1:10"
+        ));
+    }
+    if playlisted(opt) {
+        if opt.tap_tempo || opt.export_toio.is_some() || opt.export_midi.is_some() {
+            return Err(anyhow!(
+                "A playlist can only be played, not with --tap-tempo or the exports"
+            ));
+        }
+        if !opt.loop_between.is_empty() {
+            return Err(anyhow!("--loop-between conflicts with a playlist"));
+        }
+        if opt.watch {
+            return Err(anyhow!("--watch conflicts with a playlist"));
+        }
+        if opt.lyrics {
+            return Err(anyhow!("--lyrics conflicts with a playlist"));
+        }
+        if opt.progressive {
+            return Err(anyhow!("--progressive conflicts with a playlist"));
+        }
+        if opt.midi_clock.is_some() || opt.mtc.is_some() {
+            return Err(anyhow!("--midi-clock and --mtc conflict with a playlist"));
+        }
+        if !opt.layers.is_empty() {
+            return Err(anyhow!(
+                "Layering files with --file conflicts with a playlist"
+            ));
+        }
+    } else if opt.shuffle {
+        return Err(anyhow!(
+            "--shuffle needs files to play such as from --playlist or --dir"
+        ));
+    }
+    if opt.from_marker.is_some() && (opt.from.is_some() || !opt.loop_between.is_empty()) {
+        return Err(anyhow!(
+            "--from-marker conflicts with --from and --loop-between"
+        ));
+    }
+    if let Some(secs) = opt.fade_out {
+        if secs.is_nan() || secs <= 0.0 {
+            return Err(anyhow!("Fade-out must be positive"));
+        }
+    }
+    if opt.count_in == Some(0) {
+        return Err(anyhow!("Count-in must be non-zero"));
+    }
+    if opt.count_in.is_some() && (opt.midi_clock.is_some() || opt.mtc.is_some()) {
+        return Err(anyhow!("--count-in conflicts with --midi-clock and --mtc"));
+    }
+    if opt.mmc.is_some() {
+        if opt.midi_clock.is_some() || opt.mtc.is_some() {
+            return Err(anyhow!("--mmc conflicts with --midi-clock and --mtc"));
+        }
+        if opt.progressive {
+            return Err(anyhow!("--mmc conflicts with --progressive"));
+        }
+    }
+    if opt.mtc.is_some() {
+        if opt.midi_clock.is_some() {
+            return Err(anyhow!("--mtc conflicts with --midi-clock"));
+        }
+        if opt.segment.is_some()
+            || !opt.loop_between.is_empty()
+            || opt.repeat.is_some()
+            || opt.progressive
+        {
+            return Err(anyhow!(
+                "The time code plays the song once as it is; --mtc conflicts with --segment, \
+                 --loop-between, --repeat and --progressive"
+            ));
+        }
+    }
+    if opt.max_duration == Some(0) {
+        return Err(anyhow!("Max duration must be non-zero"));
+    }
+    if let Some(Conflict { mut msg, .. }) = conflicts(opt).into_iter().next() {
+        msg[..1].make_ascii_uppercase();
+        return Err(anyhow!(msg));
+    }
+    if opt.tui && opt.piano_roll {
+        return Err(anyhow!("--tui conflicts with --piano-roll"));
+    }
+    if (opt.keys || opt.tui) && opt.panic_key {
+        return Err(anyhow!(
+            "--panic-key conflicts with the keys of --keys and --tui"
+        ));
+    }
+    if opt.tui && opt.progress {
+        return Err(anyhow!("--tui conflicts with --progress"));
+    }
+    if opt.tui && opt.lyrics {
+        return Err(anyhow!("--tui conflicts with --lyrics"));
+    }
+    if opt.simulate_beep && !opt.simulate {
+        return Err(anyhow!("--simulate-beep needs --simulate"));
+    }
+    midi_options(opt).validate()
+}
+
+/// Assigns the most likely melody to cube 0, keeping the order of the other tracks.
+fn auto_assign(opt: &mut Opt) -> Result<()> {
+    if !opt.auto_assign || !opt.rules.is_empty() {
+        return Ok(());
     }
 
-    let cubes: Vec<_> = cubes
+    let plays = midi::load_plays(file(opt)?, &midi_options(opt)).context(Failure::Parse)?;
+    let scores = melody::scores(&plays);
+    let melody = match scores.first() {
+        Some(s) => s.ch,
+        None => return Ok(()),
+    };
+    let mut chs: Vec<_> = scores
+        .iter()
+        .map(|s| s.ch)
+        .filter(|ch| *ch != melody)
+        .collect();
+    chs.sort_unstable();
+    chs.insert(0, melody);
+
+    info!("Playing track {} as the melody on cube 0", melody);
+    opt.rules = chs
         .into_iter()
         .enumerate()
-        .map(|(i, mut cube)| {
-            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-            tokio::spawn(async move {
-                while let Some(p) = rx.next().await {
-                    cube.play(1, ops(&p))
-                        .await
-                        .context(format!("error on cube {}", i))?;
-                }
-                Ok::<_, Error>(())
-            });
-            tx
-        })
+        .map(|(i, ch)| Rule::new(vec![ch], i as Channel))
         .collect();
+    Ok(())
+}
 
-    info!("Start playing in 3 seconds...");
-    delay_for(Duration::from_secs(3)).await;
-    info!("Started");
+fn midi_options(opt: &Opt) -> midi::Options {
+    let mut midi_opts = midi::Options::new();
+    midi_opts.mute = opt.mute.clone();
+    midi_opts.solo = opt.solo.clone();
+    midi_opts.gap = opt.gap;
+    midi_opts.max_ops = opt.max_ops;
+    midi_opts.max_op_len = opt.max_op_len;
+    midi_opts.group_by = opt.read.group_by;
+    midi_opts.speed = opt.speed;
+    midi_opts.sequence = opt.sequence;
+    midi_opts.parser = opt.read.parser;
+    midi_opts.lenient = opt.read.lenient;
+    midi_opts
+}
+
+/// Options to read every track of the file.
+fn list_options(read: &Read) -> midi::Options {
+    let mut list_opts = midi::Options::new();
+    list_opts.group_by = read.group_by;
+    list_opts.parser = read.parser;
+    list_opts.lenient = read.lenient;
+    list_opts
+}
+
+fn tracer(opt: &Opt) -> Option<Tracer> {
+    // Created at the start, and written by every tracer from then.
+    let file = opt.trace.file.as_ref().and_then(|path| {
+        match std::fs::OpenOptions::new().append(true).open(path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                warn!("Couldn't open the trace {}: {}", path.display(), e);
+                None
+            }
+        }
+    });
+    if !opt.trace.ble && file.is_none() {
+        return None;
+    }
+    Some(Tracer::new(opt.trace.ble.then_some(20), file))
+}
 
-    let start = Instant::now();
-    let mut last_at = 0;
-    let mut tail = 0;
-    for ((at, _), playset) in events {
-        debug!("At {}: {:?}", at, playset);
+/// Runs the future unless the time to wind down comes first.
+async fn before<F: Future>(wind: Option<Instant>, f: F) -> Option<F::Output> {
+    match wind {
+        Some(wind) => tokio::time::timeout_at(wind, f).await.ok(),
+        None => Some(f.await),
+    }
+}
+
+/// What the scheduler does after waiting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Next {
+    /// Goes on, having paused for the time.
+    Play(Duration),
+    /// Goes on to the next segment at once.
+    Skip,
+    /// Winds down as the time limit has come.
+    Wind,
+    /// Stops playing at once.
+    Quit,
+    /// Plays the song again, reloaded from the file.
+    Reload,
+    /// Turns the cubes off at once, on Ctrl-C.
+    Interrupt,
+}
 
-        if last_at != at {
-            delay_until(start + Duration::from_millis(at)).await;
+/// Waits until the time, pausing on the panic button.
+///
+/// The sets sounding when paused go on from where they were on resuming.
+async fn wait<S: SoundSink>(
+    mut at: Instant,
+    timer: &Timer,
+    wind: Option<Instant>,
+    control: &mut Control,
+    sinks: &mut [S],
+    playing: &mut Playing,
+) -> Result<Next> {
+    let mut paused = Duration::from_secs(0);
+    loop {
+        let signal = match before(
+            wind,
+            future::select(timer.until(at).boxed(), control.next().boxed()),
+        )
+        .await
+        {
+            None => return Ok(Next::Wind),
+            Some(future::Either::Left(_)) => return Ok(Next::Play(paused)),
+            Some(future::Either::Right((signal, _))) => signal,
+        };
+        match signal {
+            Signal::Resume => continue,
+            Signal::Skip => return Ok(Next::Skip),
+            Signal::Quit => return Ok(Next::Quit),
+            Signal::Reload => return Ok(Next::Reload),
+            Signal::Interrupt => return Ok(Next::Interrupt),
+            Signal::Panic | Signal::Toggle => {}
         }
-        last_at = at;
 
-        if let Some(cube) = cubes.get(playset.ch as usize) {
-            tail = playset.len;
-            let _ = cube.send(playset);
+        if signal == Signal::Panic {
+            warn!("Panic! Every cube is silenced until resumed");
+        } else {
+            info!("Paused");
+        }
+        for sink in sinks.iter_mut() {
+            sink.silence().await?;
+        }
+        let since = Instant::now();
+        let rests: Vec<_> = playing
+            .drain(..)
+            .filter_map(|(cube, set, sent)| {
+                let from = since.duration_since(sent).as_millis() as Time;
+                Some((cube, set.rest(from))).filter(|(_, rest)| !rest.plays.is_empty())
+            })
+            .collect();
+        loop {
+            match before(wind, control.next()).await {
+                None => return Ok(Next::Wind),
+                Some(Signal::Panic) => {}
+                Some(Signal::Skip) => return Ok(Next::Skip),
+                Some(Signal::Quit) => return Ok(Next::Quit),
+                Some(Signal::Reload) => return Ok(Next::Reload),
+                Some(Signal::Interrupt) => return Ok(Next::Interrupt),
+                Some(Signal::Resume) | Some(Signal::Toggle) => break,
+            }
         }
+        info!("Resumed");
+        for sink in sinks.iter_mut() {
+            sink.resume().await?;
+        }
+        for (cube, rest) in rests {
+            playing.push((cube, rest.clone(), Instant::now()));
+            sinks[cube].play(rest).await?;
+        }
+        paused += since.elapsed();
+        at += since.elapsed();
     }
-    delay_for(Duration::from_millis(tail)).await;
+}
 
-    info!("Shutting down in 3 seconds...");
-    delay_for(Duration::from_secs(3)).await;
-    info!("Done");
+/// Converts the file into the segments to play, or takes them from the cache if asked.
+fn plan(opt: &Opt) -> Result<Vec<midi::Plan>> {
+    if !opt.cache {
+        return convert(opt);
+    }
+    let dir = cache::dir()?;
+    let files: Vec<_> = std::iter::once(file(opt)?)
+        .chain(opt.layers.iter().map(|f| f.as_path()))
+        .collect();
+    // Everything the conversion depends on besides the files.
+    let options = format!(
+        "{:?}",
+        (
+            midi_options(opt),
+            &opt.rules,
+            opt.unit,
+            (
+                opt.strict,
+                opt.max_warnings,
+                opt.fold_repeats,
+                opt.metronome
+            ),
+            (&opt.from, &opt.to, &opt.from_marker, &opt.loop_between),
+            (opt.fade_out, opt.segment),
+        )
+    );
+    let key = cache::key(&files, &options)?;
+    if let Some(segments) = cache::load(&dir, &key) {
+        info!("Playing the plan cached in {}", dir.display());
+        return Ok(segments);
+    }
+    let segments = convert(opt)?;
+    if let Err(e) = cache::save(&dir, &key, &segments) {
+        warn!("Couldn't cache the plan: {:#}", e);
+    }
+    Ok(segments)
+}
 
-    Ok(())
+fn convert(opt: &Opt) -> Result<Vec<midi::Plan>> {
+    let file = file(opt)?;
+    let midi_opts = midi_options(opt);
+
+    if opt.strict {
+        let mut ignored = midi::ignored(file, &midi_opts).context(Failure::Parse)?;
+        for layer in &opt.layers {
+            ignored.extend(midi::ignored(layer, &midi_opts).context(Failure::Parse)?);
+        }
+        for line in &ignored {
+            error!("Unsupported: {}", line);
+        }
+        if !ignored.is_empty() {
+            return Err(anyhow!("The file uses MIDI features the cubes can't play"))
+                .context(Failure::Unsupported);
+        }
+    }
+
+    let events = if !opt.layers.is_empty() {
+        let files: Vec<_> = std::iter::once(file)
+            .chain(opt.layers.iter().map(|f| f.as_path()))
+            .collect();
+        info!("Layering {} files...", files.len());
+        let rules: Vec<_> = opt.rules.iter().map(|r| (r.as_ch, r.chs.clone())).collect();
+        midi::load_layered(&files, &midi_opts, opt.unit, &rules).context(Failure::Parse)?
+    } else if opt.rules.is_empty() {
+        midi::load(file, &midi_opts).context(Failure::Parse)?
+    } else {
+        info!("Parsing file {}...", file.display());
+        let rules: Vec<_> = opt.rules.iter().map(|r| (r.as_ch, r.chs.clone())).collect();
+        midi::load_mixed(file, &midi_opts, opt.unit, &rules).context(Failure::Parse)?
+    };
+
+    if let Some(max) = opt.max_warnings {
+        let n = exit::warnings();
+        if n > max {
+            return Err(anyhow!("{} warnings, more than {}", n, max)).context(Failure::Warnings);
+        }
+    }
+
+    let mut events = if opt.fold_repeats {
+        midi::folded(events, opt.max_ops)
+    } else {
+        events
+    };
+    if let Some(cube) = opt.metronome {
+        let ch = metronome_part(&events, cube)?;
+        let beats = midi::beats(file, &midi_opts, midi::end(&events)).context(Failure::Parse)?;
+        events.extend(metronome(&beats, ch));
+    }
+    let (from, to) = section(opt, file, &midi_opts)?;
+    let events = if from.is_some() || to.is_some() {
+        let from = from.map_or(0, |f| f.0);
+        info!(
+            "Playing from {} to {}",
+            Offset(from),
+            to.map_or("the end".into(), |t| t.to_string())
+        );
+        section::cut(events, from, to.map(|t| t.0))
+    } else {
+        events
+    };
+    let events = match opt.fade_out {
+        Some(secs) => fade::fade_out(events, (secs * 1000.0) as Time),
+        None => events,
+    };
+
+    Ok(match opt.segment {
+        Some(m) => midi::segments(events, (m * 60000.0) as u64),
+        None => vec![events],
+    })
+}
+
+/// The part of the metronome on the cube, which must be given no other part.
+fn metronome_part(events: &midi::Plan, cube: usize) -> Result<Channel> {
+    let ch = cube as Channel;
+    if events.keys().any(|(_, c)| *c == ch) {
+        return Err(anyhow!(
+            "Cube {} plays part {}; give it no part with --rule to keep it for the metronome",
+            cube,
+            ch
+        ));
+    }
+    Ok(ch)
+}
+
+/// Play sets of the metronome clicking on the beats, a set per bar.
+fn metronome(beats: &[(Time, bool)], ch: Channel) -> midi::Plan {
+    let mut plan = midi::Plan::new();
+    let mut bar: Option<PlaySet> = None;
+    for (i, &(at, downbeat)) in beats.iter().enumerate() {
+        if downbeat || bar.is_none() {
+            if let Some(set) = bar.replace(PlaySet::new(ch, at)) {
+                plan.insert((set.at, ch), set);
+            }
+        }
+        let next = beats.get(i + 1).map_or(at + CLICK * 2, |(next, _)| *next);
+        let len = CLICK.min(next - at);
+        let note = if downbeat {
+            toio::Note::A6
+        } else {
+            toio::Note::E6
+        };
+        if let Some(set) = &mut bar {
+            set.plays.push(midi::Play::new(ch, at, len, note));
+            if next > at + len {
+                let rest = midi::Play::new(ch, at + len, next - at - len, toio::Note::NoSound);
+                set.plays.push(rest);
+            }
+            set.len = next - set.at;
+        }
+    }
+    if let Some(set) = bar {
+        plan.insert((set.at, ch), set);
+    }
+    plan
+}
+
+/// The section of the song to play, from the times or the marker given.
+fn section(
+    opt: &Opt,
+    file: &Path,
+    midi_opts: &midi::Options,
+) -> Result<(Option<Offset>, Option<Offset>)> {
+    let (from, to) = match opt.loop_between[..] {
+        [a, b] => (Some(a), Some(b)),
+        _ => (opt.from, opt.to),
+    };
+    let from = match &opt.from_marker {
+        Some(name) => Some(marker(file, midi_opts, name)?),
+        None => from,
+    };
+    if let (Some(from), Some(to)) = (from, to) {
+        if from >= to {
+            return Err(anyhow!("--to {} must be after the start at {}", to, from));
+        }
+    }
+    Ok((from, to))
+}
+
+/// Finds the time of the marker or the cue point of the name.
+fn marker(file: &Path, opts: &midi::Options, name: &str) -> Result<Offset> {
+    let marks = midi::marks(file, opts).context(Failure::Parse)?;
+    match marks.iter().find(|m| m.text == name) {
+        Some(m) => Ok(Offset(m.at)),
+        None if marks.is_empty() => Err(anyhow!("No marker in {}", file.display())),
+        None => {
+            let names: Vec<_> = marks.iter().map(|m| m.text.as_str()).collect();
+            Err(anyhow!(
+                "No marker named {}; the markers are {}",
+                name,
+                names.join(", ")
+            ))
+        }
+    }
+}
+
+/// Number of cubes to play every part and link.
+fn cubes_needed(opt: &Opt, parts: &std::collections::BTreeSet<midi::Channel>) -> usize {
+    parts
+        .iter()
+        .map(|p| *p as usize)
+        .chain(opt.links.iter().flat_map(|m| vec![m.follower, m.leader]))
+        .max()
+        .map_or(0, |c| c + 1)
+}
+
+/// Writes the schedule of each cube, assuming as many cubes as the parts.
+fn export_toio(opt: &Opt, segments: Vec<midi::Plan>, path: &Path) -> Result<()> {
+    let plan: midi::Plan = segments.into_iter().flatten().collect();
+    let parts = plan
+        .keys()
+        .map(|(_, ch)| *ch)
+        .collect::<std::collections::BTreeSet<_>>();
+    let cubes = cubes_needed(opt, &parts);
+
+    let mut router = Router::new(cubes, parts);
+    for m in &opt.links {
+        router.mirror(m)?;
+    }
+
+    let json = export::toio(&plan, &router, |i| color(opt, i));
+    std::fs::write(path, format!("{:#}\n", json))
+        .context(format!("Couldn't write {}", path.display()))?;
+    info!("Exported the schedule to {}", path.display());
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    exit::init(
+        env_logger::Builder::from_env(
+            env_logger::Env::default().default_filter_or(format!("{}=info", module_path!())),
+        )
+        .build(),
+    );
+
+    let matches = Cli::clap().get_matches();
+    let mut cli = Cli::from_clap(&matches);
+    cli.given = show::given(&matches);
+    let res = run(cli).await;
+    if let Err(e) = &res {
+        journal::record("error", vec![("message", format!("{:#}", e).into())]);
+    }
+    journal::flush();
+    if let Err(e) = res {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(exit::code(&e));
+    }
+}
+
+/// Creates the trace file afresh for the tracers to write to and the event log, and keeps
+/// the log to the cube asked.
+fn start_logs(opt: &Opt) -> Result<()> {
+    if let Some(path) = &opt.trace.file {
+        std::fs::File::create(path)
+            .context(format!("Couldn't create the trace {}", path.display()))?;
+    }
+    if let Some(path) = &opt.event_log {
+        journal::open(path)?;
+    }
+    if let Some(cube) = opt.log_cube {
+        span::only(cube);
+    }
+    Ok(())
+}
+
+/// The rules playing each part written from track 1 on a cube of its own.
+fn each_part(parts: usize) -> Vec<Rule> {
+    (0..parts as Channel)
+        .map(|p| Rule::new(vec![p + 1], p))
+        .collect()
+}
+
+/// Runs the command, or plays the files given without one.
+async fn run(cli: Cli) -> Result<()> {
+    // The options before a command are the defaults, as they end the commands.
+    let mut opt = cli.opt;
+    let mut export = None;
+    let mut preview = None;
+    let mut identify = false;
+    match cli.cmd {
+        Some(Cmd::Preflight {
+            show,
+            dry_run_secs,
+            opt,
+        }) => {
+            start_logs(&opt)?;
+            return preflight::run(&show, opt, &cli.given, Duration::from_secs(dry_run_secs)).await;
+        }
+        Some(Cmd::Jam {
+            inputs,
+            metronome,
+            beats,
+            simulate,
+            simulate_beep,
+            trace,
+        }) => {
+            opt.simulate = simulate;
+            opt.simulate_beep = simulate_beep;
+            opt.trace = trace;
+            start_logs(&opt)?;
+            return jam::run(&opt, inputs, metronome, beats).await;
+        }
+        Some(Cmd::Live {
+            input,
+            rules,
+            simulate,
+            simulate_beep,
+            connect,
+            trace,
+        }) => {
+            opt.rules = rules;
+            opt.simulate = simulate;
+            opt.simulate_beep = simulate_beep;
+            opt.connect = connect;
+            opt.trace = trace;
+            start_logs(&opt)?;
+            number(&mut opt.rules);
+            return jam::live(&opt, input).await;
+        }
+        Some(Cmd::Tune { tunings, trace }) => {
+            opt.tunings = tunings;
+            opt.trace = trace;
+            start_logs(&opt)?;
+            return tune::run(&opt).await;
+        }
+        Some(Cmd::Doctor { trace }) => {
+            opt.trace = trace;
+            start_logs(&opt)?;
+            return doctor::run(&opt).await;
+        }
+        Some(Cmd::Calibrate { connect, trace }) => {
+            opt.connect = connect;
+            opt.trace = trace;
+            start_logs(&opt)?;
+            return calibrate::run(&opt).await;
+        }
+        Some(Cmd::Bench {
+            secs,
+            max_ops,
+            connect,
+            trace,
+        }) => {
+            opt.max_ops = max_ops;
+            opt.connect = connect;
+            opt.trace = trace;
+            start_logs(&opt)?;
+            return bench::run(&opt, secs).await;
+        }
+        Some(Cmd::Cubes { cmd }) => {
+            return match cmd {
+                CubesCmd::Register { trace } => {
+                    opt.trace = trace;
+                    start_logs(&opt)?;
+                    cubes::register(&opt).await
+                }
+                CubesCmd::List => cubes::list(),
+            }
+        }
+        Some(Cmd::Stats { file, read }) => {
+            let stats = midi::stats(&file, &list_options(&read)).context(Failure::Parse)?;
+            match read.format {
+                Format::Text => print!("{}", stats),
+                Format::Json => println!("{:#}", stats.json()),
+            }
+            return Ok(());
+        }
+        Some(Cmd::List { file, read }) => return list(&file, &read),
+        Some(Cmd::Play { file, more, opt: o }) => {
+            opt = o;
+            opt.file = file;
+            opt.more = more;
+        }
+        Some(Cmd::Export {
+            output,
+            file,
+            opt: o,
+        }) => {
+            opt = o;
+            opt.file = file;
+            export = Some(output);
+        }
+        Some(Cmd::Preview { wav, file, opt: o }) => {
+            opt = o;
+            opt.file = file;
+            preview = Some(wav);
+        }
+        Some(Cmd::Identify {
+            show,
+            rules,
+            connect,
+            trace,
+        }) => {
+            opt.file = show;
+            opt.rules = rules;
+            opt.connect = connect;
+            opt.trace = trace;
+            identify = true;
+        }
+        Some(Cmd::Stress {
+            parts,
+            density,
+            lengths,
+            secs,
+            seed,
+            opt: o,
+        }) => {
+            opt = o;
+            let pattern = stress::Pattern::new(parts, density, lengths, secs, seed);
+            let (path, parts) = stress::write(&pattern, &std::env::temp_dir())?;
+            info!("Playing {}", pattern);
+            opt.file = Some(path);
+            if opt.rules.is_empty() {
+                opt.rules = each_part(parts);
+            }
+        }
+        Some(Cmd::PlayPlan { plan, opt }) => {
+            start_logs(&opt)?;
+            validate(&opt)?;
+            let text = std::fs::read_to_string(&plan)
+                .context(format!("Couldn't read plan file {}", plan.display()))?;
+            let segments = json::parse(&text)
+                .and_then(|json| export::read_plan(&json))
+                .context(format!("Invalid plan file {}", plan.display()))?;
+            let song = Song::new(plan.display().to_string(), segments);
+            return perform(&opt, vec![song]).await;
+        }
+        None => {
+            opt.file = cli.file;
+            opt.more = cli.more;
+        }
+    }
+    start_logs(&opt)?;
+
+    if let Some(path) = opt.file.clone().filter(|f| show::is_show(f)) {
+        show::load(&path, &mut opt, &cli.given)?;
+        // The show may give the files.
+        start_logs(&opt)?;
+    } else if let Some(name) = &opt.profile {
+        return Err(anyhow!(
+            "--profile {} needs a show file (.toml) with the profiles",
+            name
+        ));
+    }
+    demo(&mut opt)?;
+    if opt.file.is_none() && !opt.layers.is_empty() {
+        opt.file = Some(opt.layers.remove(0));
+    }
+    number(&mut opt.rules);
+
+    validate(&opt)?;
+
+    if identify {
+        return identify::run(&opt).await;
+    }
+
+    if playlisted(&opt) {
+        if export.is_some() || preview.is_some() {
+            return Err(anyhow!("A playlist can only be played"));
+        }
+        let songs = playlist(&mut opt)?;
+        return perform(&opt, songs).await;
+    }
+
+    if opt.markers {
+        let marks = midi::marks(file(&opt)?, &midi_options(&opt)).context(Failure::Parse)?;
+        if opt.read.format == Format::Json {
+            let marks = marks
+                .into_iter()
+                .map(|m| {
+                    Json::object(vec![
+                        ("at", m.at.into()),
+                        ("kind", m.kind.to_string().into()),
+                        ("text", m.text.into()),
+                    ])
+                })
+                .collect();
+            println!("{:#}", Json::Array(marks));
+            return Ok(());
+        }
+        for m in marks {
+            println!("{} {}: {}", Offset(m.at), m.kind, m.text);
+        }
+        return Ok(());
+    }
+
+    auto_assign(&mut opt)?;
+
+    if opt.tap_tempo {
+        let notated = midi::bpm(file(&opt)?, &midi_options(&opt)).context(Failure::Parse)?;
+        let tapped = tap::tempo().await?;
+        opt.speed = tap::speed(tapped, notated);
+        info!(
+            "Playing at {:.0} bpm, {}% of {:.0} bpm in the file",
+            tapped, opt.speed, notated
+        );
+    }
+
+    let playing = export.is_none()
+        && preview.is_none()
+        && opt.export_toio.is_none()
+        && opt.export_midi.is_none();
+    if opt.progressive && playing {
+        return perform(&opt, vec![progressive(&opt)?]).await;
+    }
+
+    let segments = plan(&opt)?;
+    if let Some(path) = export {
+        std::fs::write(&path, format!("{:#}\n", export::plan(&segments)))
+            .context(format!("Couldn't write {}", path.display()))?;
+        info!("Exported the plan to {}", path.display());
+        return Ok(());
+    }
+
+    if let Some(path) = preview {
+        let plan: midi::Plan = segments.into_iter().flatten().collect();
+        preview::write(&plan, &path)?;
+        info!("Rendered the preview to {}", path.display());
+        return Ok(());
+    }
+
+    if let Some(path) = &opt.export_toio {
+        return export_toio(&opt, segments, path);
+    }
+
+    if let Some(path) = &opt.export_midi {
+        let plan: midi::Plan = segments.into_iter().flatten().collect();
+        export::midi(&plan, path)?;
+        info!("Exported the arrangement to {}", path.display());
+        return Ok(());
+    }
+
+    let song = Song::of(file(&opt)?, segments);
+    perform(&opt, vec![song]).await
+}
+
+/// Lists the tracks of the file with how likely each is the melody, and the features the
+/// cubes can't play.
+fn list(file: &Path, read: &Read) -> Result<()> {
+    let list_opts = list_options(read);
+    let midi::Listing {
+        parts: set,
+        plays,
+        stats,
+        ignored,
+    } = midi::listing(file, &list_opts).context(Failure::Parse)?;
+
+    let scores = melody::scores(&plays);
+    let parts = stats.parts.iter().filter(|(ch, _, _)| set.contains(ch));
+
+    if read.format == Format::Json {
+        let tracks = parts
+            .map(|(ch, name, part)| {
+                let score = scores.iter().find(|s| s.ch == *ch).map(|s| {
+                    Json::object(vec![
+                        ("total", s.total().into()),
+                        ("pitch", s.pitch.into()),
+                        ("density", s.density.into()),
+                        ("activity", s.activity.into()),
+                    ])
+                });
+                part.json(*ch, name).with("melody", score.into())
+            })
+            .collect();
+        let json = Json::object(vec![
+            ("tracks", Json::Array(tracks)),
+            ("ignored", ignored.into()),
+        ]);
+        println!("{:#}", json);
+        return Ok(());
+    }
+
+    info!("Available tracks: {:?}", set);
+    for (ch, name, part) in parts {
+        let mut line = name.clone();
+        line[..1].make_ascii_uppercase();
+        line += ":";
+        if let Some(instrument) = part.instrument() {
+            line += &format!(" {},", instrument);
+        }
+        line += &format!(" {} notes", part.notes);
+        if let Some(range) = part.range_name() {
+            line += &format!(" in {}", range);
+        }
+        if let Some(s) = scores.iter().find(|s| s.ch == *ch) {
+            line += &format!(
+                ", melody score {:.2} (pitch {:.2}, density {:.2}, activity {:.2})",
+                s.total(),
+                s.pitch,
+                s.density,
+                s.activity
+            );
+        }
+        info!("{}", line);
+    }
+
+    for line in ignored {
+        info!("Ignored {}", line);
+    }
+    Ok(())
+}
+
+/// A song to play, split into segments.
+#[derive(new)]
+struct Song {
+    name: String,
+    segments: Vec<midi::Plan>,
+    /// The MIDI file of the song, unless it's played from a plan.
+    #[new(default)]
+    file: Option<PathBuf>,
+    /// The rest of the only segment, converted while it's played.
+    #[new(default)]
+    rest: Option<Rest>,
+}
+
+impl Song {
+    /// The song of the MIDI file.
+    fn of(file: &Path, segments: Vec<midi::Plan>) -> Self {
+        let mut song = Self::new(file.display().to_string(), segments);
+        song.file = Some(file.to_path_buf());
+        song
+    }
+}
+
+/// The plans of the rest of a song coming on as they're converted, with the parts of them.
+struct Rest {
+    parts: std::collections::BTreeSet<Channel>,
+    plans: tokio::sync::mpsc::UnboundedReceiver<Result<midi::Plan>>,
+}
+
+/// Leaves reading and converting the song to play to a thread, a window after another.
+fn progressive(opt: &Opt) -> Result<Song> {
+    let unsupported = [
+        ("--file", !opt.layers.is_empty()),
+        ("--strict", opt.strict),
+        ("--max-warnings", opt.max_warnings.is_some()),
+        ("--metronome", opt.metronome.is_some()),
+        ("--fade-out", opt.fade_out.is_some()),
+        ("--segment", opt.segment.is_some()),
+        ("--repeat", opt.repeat.is_some()),
+        ("--loop-between", !opt.loop_between.is_empty()),
+        ("--tui", opt.tui),
+        ("--progress", opt.progress),
+        ("--dry-run", opt.dry_run),
+    ];
+    if let Some((name, _)) = unsupported.iter().find(|(_, given)| *given) {
+        return Err(anyhow!(
+            "--progressive needs the whole song converted for {}",
+            name
+        ));
+    }
+
+    let file = file(opt)?;
+    let midi_opts = midi_options(opt);
+    let rules: Vec<_> = opt.rules.iter().map(|r| (r.as_ch, r.chs.clone())).collect();
+    let (from, to) = section(opt, file, &midi_opts)?;
+    if from.is_some() || to.is_some() {
+        info!(
+            "Playing from {} to {}",
+            Offset(from.map_or(0, |f| f.0)),
+            to.map_or("the end".into(), |t| t.to_string())
+        );
+    }
+    let (fold, max_ops) = (opt.fold_repeats, opt.max_ops);
+    let cut = move |plan| {
+        let plan = if fold {
+            midi::folded(plan, max_ops)
+        } else {
+            plan
+        };
+        if from.is_some() || to.is_some() {
+            section::cut(plan, from.map_or(0, |f| f.0), to.map(|t| t.0))
+        } else {
+            plan
+        }
+    };
+
+    // The parts come from the rules, or from the file once it's read.
+    let waited = rules.is_empty();
+    let (parsed, parts) = std::sync::mpsc::channel();
+    let (tx, plans) = tokio::sync::mpsc::unbounded_channel();
+    let (path, unit) = (file.to_path_buf(), opt.unit);
+    info!("Parsing file {}...", file.display());
+    std::thread::spawn(move || {
+        let windows = match midi::windows(&path, &midi_opts, unit, &rules, PROGRESSIVE) {
+            Ok(windows) => windows,
+            Err(e) if waited => {
+                let _ = parsed.send(Err(e));
+                return;
+            }
+            Err(e) => {
+                let _ = tx.send(Err(e));
+                return;
+            }
+        };
+        if waited {
+            let _ = parsed.send(Ok(windows.parts()));
+        }
+        for plan in windows {
+            if tx.send(Ok(cut(plan))).is_err() {
+                return;
+            }
+        }
+        debug!("Converted the rest of the song");
+    });
+    let parts = if waited {
+        parts
+            .recv()
+            .map_err(|_| anyhow!("Couldn't parse {}", file.display()))?
+            .context(Failure::Parse)?
+    } else {
+        opt.rules.iter().map(|r| r.as_ch).collect()
+    };
+    info!(
+        "Converting the song a {}s window after another while playing",
+        PROGRESSIVE / 1000
+    );
+
+    // The song starts with nothing converted, taking the plans as they're ready.
+    let mut song = Song::of(file, vec![midi::Plan::new()]);
+    song.rest = Some(Rest { parts, plans });
+    Ok(song)
+}
+
+/// Plays the demo song asked, from the command line or the show, instead of the file.
+fn demo(opt: &mut Opt) -> Result<()> {
+    if let Some(name) = &opt.demo {
+        let (path, parts) = demo::write(name)?;
+        info!("Playing the demo {}", name);
+        opt.file = Some(path);
+        if opt.rules.is_empty() {
+            opt.rules = each_part(parts);
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if playing more than a file.
+fn playlisted(opt: &Opt) -> bool {
+    !opt.more.is_empty() || opt.playlist.is_some() || opt.dir.is_some()
+}
+
+/// Plans the files given and those of the playlist, each with its own rules if any.
+fn playlist(opt: &mut Opt) -> Result<Vec<Song>> {
+    let mut entries: Vec<_> = opt
+        .file
+        .iter()
+        .chain(&opt.more)
+        .map(|f| playlist::Entry::new(f.clone(), vec![]))
+        .collect();
+    if let Some(path) = &opt.playlist {
+        entries.extend(playlist::load(path)?);
+    }
+    if let Some(dir) = &opt.dir {
+        entries.extend(playlist::scan(dir)?);
+    }
+    if entries.is_empty() {
+        return Err(anyhow!("The playlist is empty"));
+    }
+
+    let rules = opt.rules.clone();
+    let mut songs = vec![];
+    for entry in entries {
+        opt.file = Some(entry.file);
+        opt.rules = if entry.rules.is_empty() {
+            rules.clone()
+        } else {
+            entry.rules
+        };
+        number(&mut opt.rules);
+        auto_assign(opt)?;
+        songs.push(Song::of(file(opt)?, plan(opt)?));
+    }
+    info!("Playing {} songs", songs.len());
+    Ok(songs)
+}
+
+/// Searches the cubes in range, again and again until `--require-cubes` are found.
+///
+/// The cubes are always searched through the default Bluetooth adapter of the system.
+async fn scan(opt: &Opt) -> Result<Vec<Cube>> {
+    let deadline = Instant::now() + Duration::from_millis(opt.connect.search_timeout.0);
+    let registry = cubes::Registry::local();
+    let mut cubes: Vec<Cube> = vec![];
+    loop {
+        // The BLE backend panics on unsupported platforms; report it as a Bluetooth error.
+        let found = tokio::spawn(async { Cube::search().all().await })
+            .await
+            .map_err(|e| anyhow!("Bluetooth is unavailable: {}", e))
+            .and_then(|r| r)
+            .context(Failure::Bluetooth)?;
+        for cube in found {
+            let near = opt.connect.min_rssi.is_none_or(|min| cube.rssi() >= min);
+            if near && cubes.iter().all(|c| c.id() != cube.id()) {
+                cubes.push(cube);
+            }
+        }
+
+        let required = match opt.connect.require_cubes {
+            Some(n) => n,
+            None => return Ok(cubes),
+        };
+        // With --cube, only the cubes named count.
+        let present = if opt.connect.cubes.is_empty() {
+            cubes.len()
+        } else {
+            let ids: Vec<_> = cubes.iter().map(|c| c.id().to_string()).collect();
+            let named = opt.connect.cubes.iter();
+            named
+                .filter(|n| ids.iter().any(|id| registry.is(id, n)))
+                .count()
+        };
+        if present >= required {
+            return Ok(cubes);
+        }
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "Found only {} of the {} cubes required in {}",
+                present,
+                required,
+                opt.connect.search_timeout
+            ))
+            .context(Failure::Bluetooth);
+        }
+        info!(
+            "Found {} of the {} cubes required; searching again...",
+            present, required
+        );
+    }
+}
+
+/// Searches the cubes to play.
+async fn search(opt: &Opt) -> Result<Vec<Link>> {
+    let cubes = scan(opt).await?;
+    if cubes.is_empty() {
+        return Err(anyhow!("No cube found")).context(Failure::Bluetooth);
+    }
+    let targets: Vec<_> = opt
+        .rules
+        .iter()
+        .filter_map(|r| r.cube.as_deref().map(|c| (r.as_ch as usize, c)))
+        .collect();
+    let registry = cubes::Registry::local();
+    let mut found = cubes.into_iter().map(|c| (c.id().to_string(), c)).collect();
+    if !opt.connect.cubes.is_empty() {
+        found = cubes::pick(found, &opt.connect.cubes, &registry).context(Failure::Bluetooth)?;
+    }
+    // The cubes named are kept, the cap taking the nearest of the others.
+    if let Some(max) = opt.connect.max_cubes {
+        let asked = opt.connect.cubes.iter().map(|c| c.as_str());
+        let named: Vec<_> = asked.chain(targets.iter().map(|(_, c)| *c)).collect();
+        found = cubes::capped(found, &named, max, &registry, Cube::rssi);
+    }
+    let cubes = cubes::arrange(found, &targets, &registry).context(Failure::Bluetooth)?;
+
+    let tracer = tracer(opt);
+    Ok(cubes
+        .into_iter()
+        .enumerate()
+        .map(|(i, cube)| Link::new(i, cube, tracer.clone()))
+        .collect())
+}
+
+/// Plays the songs on the cubes one after another, or on the MIDI output instead.
+///
+/// A dry run only prints what the cubes would be sent.
+async fn perform(opt: &Opt, songs: Vec<Song>) -> Result<()> {
+    let parts = songs
+        .iter()
+        .flat_map(|s| &s.segments)
+        .flat_map(|s| s.keys().map(|(_, ch)| *ch))
+        .chain(
+            songs
+                .iter()
+                .flat_map(|s| &s.rest)
+                .flat_map(|r| r.parts.clone()),
+        )
+        .collect::<std::collections::BTreeSet<_>>();
+
+    if opt.dry_run {
+        let router = route(opt, cubes_needed(opt, &parts), parts)?;
+        for song in &songs {
+            if songs.len() > 1 {
+                println!("Song {}", song.name);
+            }
+            let segments = &song.segments;
+            for (i, plan) in segments.iter().enumerate() {
+                if segments.len() > 1 {
+                    println!("Segment {}/{}", i + 1, segments.len());
+                }
+                for line in timeline::lines(plan, &router) {
+                    println!("{}", line);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let timer = if opt.precise_timing {
+        Timer::precise()
+    } else {
+        Timer::default()
+    };
+    if let Some(path) = &opt.preview_midi {
+        let sinks = MidiOut::open(path, cubes_needed(opt, &parts))?;
+        info!("Playing on {}", path.display());
+        let router = route(opt, sinks.len(), parts)?;
+        let status = (0..sinks.len())
+            .map(|i| tui::Cube::new(&format!("MIDI ch {}", smf::channel(i) + 1), None))
+            .collect();
+        return logged(opt, songs, router, vec![], sinks, status, timer).await;
+    }
+
+    let mut cubes: Vec<Link> = if opt.simulate {
+        let sim = Sim::new(opt.simulate_beep);
+        let cubes = cubes_needed(opt, &parts);
+        info!("Simulating {} cubes", cubes);
+        let tracer = tracer(opt);
+        (0..cubes)
+            .map(|i| Link::simulated(i, sim, tracer.clone()))
+            .collect()
+    } else {
+        search(opt).await?
+    };
+    // Places kept for the cubes joining later play the parts left over, or else mirror.
+    let connected = cubes.len();
+    let spares = if opt.simulate { 0 } else { opt.hot_plug };
+    let mut router = route(opt, connected + spares, parts.clone())?;
+    collapse(opt, &mut router, &parts, connected + spares)?;
+    for i in connected..connected + spares {
+        if !parts.iter().any(|p| router.cube(*p) == Some(i)) {
+            router.mirror(&Mirror::new(i, i % connected, 0))?;
+        }
+    }
+
+    let registry = cubes::Registry::local();
+    let mut known = vec![];
+    let mut events = vec![];
+    let mut status: Vec<_> = cubes
+        .iter()
+        .map(|_| tui::Cube::new("simulated", None))
+        .chain((0..spares).map(|_| tui::Cube::new("vacant", None)))
+        .collect();
+    for (i, link) in cubes
+        .iter_mut()
+        .enumerate()
+        .filter(|(_, l)| !l.is_simulated())
+    {
+        link.cube()?.connect().await.context(Failure::Bluetooth)?;
+        info!("Cube {} connected", registry.label(i, link.cube()?.id()));
+        journal::record(
+            "connect",
+            vec![("cube", i.into()), ("id", link.cube()?.id().into())],
+        );
+        known.push(link.cube()?.id().to_string());
+        status[i].state = "connected".into();
+
+        if songs.iter().any(|s| s.segments.len() > 1) || opt.reassign_battery.is_some() {
+            events.push(link.cube()?.events().await?.map(move |e| (i, e)).boxed());
+        }
+        let level = link.cube()?.battery().await.context(Failure::Bluetooth)?;
+        info!("Cube {} battery: {}%", i, level);
+        match opt.min_battery {
+            Some(min) if level < min => {
+                return Err(anyhow!(
+                    "Cube {} has {}% of battery, less than --min-battery {}%",
+                    i,
+                    level,
+                    min
+                ))
+                .context(Failure::Bluetooth);
+            }
+            _ if level < LOW_BATTERY => {
+                warn!("Cube {} is low on battery and may not last the song", i)
+            }
+            _ => {}
+        }
+        router.battery(i, level);
+        status[i].battery = Some(level);
+        if let Some(ms) = registry.latency(link.cube()?.id()) {
+            router.latency(i, ms);
+        }
+
+        let (r, g, b) = color(opt, i);
+        link.light_on(r, g, b).await.context(Failure::Bluetooth)?;
+    }
+
+    for l in &opt.latencies {
+        router.latency(l.cube, l.ms);
+    }
+
+    let chime = Duration::from_secs(opt.idle_chime_secs.max(1));
+    let config = |i| CubeConfig {
+        color: color(opt, i),
+        idle: opt.idle,
+        chime,
+        light: if opt.metronome == Some(i) {
+            lights::Mode::Downbeat
+        } else if opt.dynamics {
+            lights::Mode::Dynamics
+        } else {
+            opt.light_mode
+        },
+        fixed_packets: opt.fixed_packets,
+        max_ops: opt.max_ops,
+        offset: tune::offset(&opt.tunings, i),
+        reconnect: opt.reconnect,
+        retry: retry::Policy {
+            retries: opt.retries,
+            max_skips: opt.max_skips,
+        },
+        timer: timer.clone(),
+    };
+    let drop_failed = opt.drop_failed || opt.failover;
+    let mut sinks: Vec<_> = cubes
+        .into_iter()
+        .map(|link| {
+            let config = config(link.index);
+            CubeSink::spawn(link, config, drop_failed)
+        })
+        .collect();
+    let mut joins = vec![];
+    for i in connected..connected + spares {
+        let (sink, join) = CubeSink::vacant(i, config(i), drop_failed);
+        sinks.push(sink);
+        joins.push((i, join));
+    }
+    if !joins.is_empty() {
+        info!("Keeping {} places for the cubes powered on later", spares);
+        tokio::spawn(hot_plug(opt, joins, known));
+    }
+    logged(opt, songs, router, events, sinks, status, timer).await
+}
+
+/// Searches for the cubes powered on later, handing each to the place kept for it.
+fn hot_plug(
+    opt: &Opt,
+    mut joins: Vec<(usize, tokio::sync::oneshot::Sender<Link>)>,
+    mut known: Vec<String>,
+) -> impl std::future::Future<Output = ()> {
+    let (wanted, min_rssi, tracer) = (opt.connect.cubes.clone(), opt.connect.min_rssi, tracer(opt));
+    let registry = cubes::Registry::local();
+    joins.reverse();
+    async move {
+        // Each search holds up the radio the cubes play through.
+        let mut interval = HOT_PLUG;
+        loop {
+            joins.retain(|(_, join)| !join.is_closed());
+            if joins.is_empty() {
+                return;
+            }
+            let found = tokio::spawn(async { Cube::search().all().await }).await;
+            for mut cube in found.into_iter().flatten().flatten() {
+                let id = cube.id().to_string();
+                let near = min_rssi.is_none_or(|min| cube.rssi() >= min);
+                let listed = wanted.is_empty() || wanted.iter().any(|c| registry.is(&id, c));
+                if known.contains(&id) || !near || !listed {
+                    continue;
+                }
+                let (index, join) = match joins.pop() {
+                    Some(join) => join,
+                    None => break,
+                };
+                if let Err(e) = cube.connect().await {
+                    warn!("Cube {} couldn't join: {:#}", id, e);
+                    joins.push((index, join));
+                    continue;
+                }
+                info!("Cube {} joined", registry.label(index, &id));
+                journal::record(
+                    "connect",
+                    vec![("cube", index.into()), ("id", id.as_str().into())],
+                );
+                known.push(id);
+                let _ = join.send(Link::new(index, cube, tracer.clone()));
+                interval = HOT_PLUG;
+            }
+            delay_for(interval).await;
+            interval = (interval * 2).min(HOT_PLUG_MAX);
+        }
+    }
+}
+
+/// Mixes the parts meant for the cubes not found into the others if asked, or else fails.
+fn collapse(
+    opt: &Opt,
+    router: &mut Router,
+    parts: &std::collections::BTreeSet<midi::Channel>,
+    cubes: usize,
+) -> Result<()> {
+    let missing: Vec<_> = parts.iter().filter(|p| **p as usize >= cubes).collect();
+    match missing.first() {
+        None => return Ok(()),
+        Some(p) if !opt.collapse => {
+            return Err(anyhow!(
+                "Part {} plays on cube {}, but only {} cubes are found; \
+                 give --collapse to mix it into the others",
+                p,
+                p,
+                cubes
+            ))
+            .context(Failure::Bluetooth);
+        }
+        Some(_) => {}
+    }
+    for m in router.collapse(missing.into_iter().copied()) {
+        warn!(
+            "Cube {} isn't found; mixing part {} into cube {}",
+            m.from, m.part, m.to
+        );
+    }
+    Ok(())
+}
+
+/// Routes the parts to the cubes, linking them as given.
+fn route(
+    opt: &Opt,
+    cubes: usize,
+    parts: std::collections::BTreeSet<midi::Channel>,
+) -> Result<Router> {
+    let mut router = Router::new(cubes, parts);
+    for m in &opt.links {
+        for part in router.mirror(m)? {
+            warn!(
+                "Part {} isn't played as cube {} mirrors cube {}",
+                part, m.follower, m.leader
+            );
+        }
+    }
+    Ok(router)
+}
+
+/// Cube events such as the button, with the index of the cube.
+type CubeEvents = stream::BoxStream<'static, (usize, CubeEvent)>;
+
+/// Schedules on the sinks, also logging and showing the sets if asked.
+async fn logged<S: SoundSink>(
+    opt: &Opt,
+    songs: Vec<Song>,
+    router: Router,
+    events: Vec<CubeEvents>,
+    sinks: Vec<S>,
+    status: Vec<tui::Cube>,
+    timer: Timer,
+) -> Result<()> {
+    let cubes = sinks.len();
+    let logs: Vec<Option<PlayLog>> = match &opt.play_log {
+        Some(path) => PlayLog::create(path, cubes)?
+            .into_iter()
+            .map(Some)
+            .collect(),
+        None => (0..cubes).map(|_| None).collect(),
+    };
+    let rolls: Vec<Option<Roll>> = if opt.piano_roll {
+        Roll::start(cubes).into_iter().map(Some).collect()
+    } else {
+        (0..cubes).map(|_| None).collect()
+    };
+    let tuis: Vec<Option<Tui>> = if opt.tui {
+        let segments: Vec<_> = songs.iter().flat_map(|s| s.segments.clone()).collect();
+        Tui::start(&segments, &router, status)
+            .into_iter()
+            .map(Some)
+            .collect()
+    } else {
+        (0..cubes).map(|_| None).collect()
+    };
+    let bars: Vec<Option<Progress>> = if opt.progress {
+        let segments = songs.iter().flat_map(|s| &s.segments);
+        let total = segments.map(midi::end).max().unwrap_or(0);
+        Progress::start(total, cubes)
+            .into_iter()
+            .map(Some)
+            .collect()
+    } else {
+        (0..cubes).map(|_| None).collect()
+    };
+    let lyrics: Vec<Option<Lyrics>> = if opt.lyrics {
+        let file = file(opt)?;
+        let midi_opts = midi_options(opt);
+        let lines = lyrics::lines(&midi::texts(file, &midi_opts).context(Failure::Parse)?);
+        if lines.is_empty() {
+            warn!("No lyrics in {}", file.display());
+        }
+        let (from, to) = section(opt, file, &midi_opts)?;
+        let lines = lyrics::cut(lines, from.map_or(0, |f| f.0), to.map(|t| t.0));
+        Lyrics::start(lines, cubes).into_iter().map(Some).collect()
+    } else {
+        (0..cubes).map(|_| None).collect()
+    };
+    let sinks = sinks
+        .into_iter()
+        .zip(logs)
+        .zip(rolls)
+        .zip(tuis)
+        .zip(bars)
+        .zip(lyrics)
+        .collect();
+    schedule(opt, songs, router, events, sinks, timer).await
+}
+
+/// Beats per bar and the length of a beat at the tempo and the time signature of the start
+/// of the song, or of 4/4 at 120 bpm for a plan.
+fn count_in(opt: &Opt, song: &Song) -> Result<(u64, Duration)> {
+    let (bpm, (beats, unit)) = match &song.file {
+        Some(file) => {
+            let midi_opts = midi_options(opt);
+            let bpm = midi::bpm(file, &midi_opts).context(Failure::Parse)?;
+            let signature = midi::signature(file, &midi_opts).context(Failure::Parse)?;
+            (bpm * opt.speed as f64 / 100.0, signature)
+        }
+        None => (120.0, (4, 4)),
+    };
+    let beat = 60.0 / bpm.max(1.0) * 4.0 / unit.max(1) as f64;
+    Ok((beats.max(1), Duration::from_secs_f64(beat)))
+}
+
+/// A click of the count-in, higher on the first beat of bars.
+fn click(accent: bool) -> PlaySet {
+    let note = if accent {
+        toio::Note::A6
+    } else {
+        toio::Note::E6
+    };
+    let mut set = PlaySet::new(0, 0);
+    set.plays = vec![midi::Play::new(0, 0, CLICK, note)];
+    set.len = CLICK;
+    set
+}
+
+/// Waits for Ctrl-C, ignoring the other signals.
+async fn interrupted(control: &mut Control) {
+    while control.next().await != Signal::Interrupt {}
+}
+
+/// How following the sync input went.
+enum Followed {
+    /// The timeline of the segment is moved to the input.
+    On,
+    /// The input went to the time of the song.
+    Located(Time),
+    /// The input stopped, to be silent until it goes on.
+    Halted,
+    /// Stopped before the time came.
+    Stopped(Next),
+}
+
+/// Moves the timeline of the segment from `offset` to when the sync input tells the time of
+/// the song comes, `early` before it at the latest.
+async fn follow(
+    clock: &mut clock::Clock,
+    at: Time,
+    offset: Time,
+    early: Duration,
+    start: &mut Instant,
+    wind: Option<Instant>,
+    control: &mut Control,
+) -> Result<Followed> {
+    let followed = future::select(
+        Box::pin(clock.when(at, early)),
+        Box::pin(interrupted(control)),
+    );
+    match before(wind, followed).await {
+        Some(future::Either::Left((cue, _))) => match cue? {
+            clock::Cue::At(due) => {
+                let since = Duration::from_millis(at - offset);
+                *start = due.checked_sub(since).unwrap_or(due);
+                Ok(Followed::On)
+            }
+            clock::Cue::Locate(to) => Ok(Followed::Located(to)),
+            clock::Cue::Halt => Ok(Followed::Halted),
+        },
+        Some(future::Either::Right(_)) => Ok(Followed::Stopped(Next::Interrupt)),
+        None => Ok(Followed::Stopped(Next::Wind)),
+    }
+}
+
+/// Plays the songs until the end or until stopped, telling how it stopped.
+async fn playback<S: SoundSink>(
+    opt: &Opt,
+    mut songs: Vec<Song>,
+    mut router: Router,
+    events: Vec<CubeEvents>,
+    sinks: &mut [S],
+    fade: Duration,
+    timer: &Timer,
+) -> Result<Next> {
+    // Taken from the start, the count-in and the wait before it included.
+    let mut control = Control::new();
+    control.interrupt();
+    let mut clock = match (&opt.midi_clock, &opt.mtc) {
+        (Some(path), _) => {
+            let tempos = midi::tempos(file(opt)?, &midi_options(opt)).context(Failure::Parse)?;
+            let clock = clock::Clock::midi(&jam::device(path)?, clock::Tempos(tempos))?;
+            info!("Following the MIDI clock; start the sequencer to play");
+            Some(clock)
+        }
+        (None, Some(chase)) => {
+            let clock = clock::Clock::mtc(&jam::device(&chase.path)?, chase.zero)?;
+            info!("Chasing the MIDI time code; start the sequencer to play");
+            Some(clock)
+        }
+        (None, None) => match &opt.mmc {
+            Some(chase) => Some(clock::Clock::mmc(&jam::device(&chase.path)?, chase.zero)?),
+            None => None,
+        },
+    };
+    match opt.count_in {
+        _ if opt.midi_clock.is_some() || opt.mtc.is_some() => {}
+        Some(bars) => {
+            let (beats, beat) = match songs.first() {
+                Some(song) => count_in(opt, song)?,
+                None => return Ok(Next::Quit),
+            };
+            info!("Counting in {} bars of {} beats", bars, beats);
+            let start = Instant::now();
+            for i in 0..=bars * beats {
+                let tick = Box::pin(timer.until(start + beat * i as u32));
+                if let future::Either::Right(_) =
+                    future::select(tick, Box::pin(interrupted(&mut control))).await
+                {
+                    return Ok(Next::Interrupt);
+                }
+                if let (true, Some(sink)) = (i < bars * beats, sinks.first_mut()) {
+                    sink.play(click(i % beats == 0)).await?;
+                }
+            }
+            for sink in sinks.iter_mut() {
+                sink.restart().await?;
+            }
+        }
+        None => {
+            info!("Start playing in 3 seconds...");
+            let delay = Box::pin(delay_for(Duration::from_secs(3)));
+            if let future::Either::Right(_) =
+                future::select(delay, Box::pin(interrupted(&mut control))).await
+            {
+                return Ok(Next::Interrupt);
+            }
+        }
+    }
+    info!("Started");
+
+    if opt.tui || opt.keys {
+        control.keys();
+        if !opt.tui {
+            info!("Press space to pause and resume, s to skip the segment and q to quit");
+        }
+    } else if opt.panic_key {
+        control.key();
+        info!("Press Enter to silence every cube, and again to resume");
+    }
+    if let Some(addr) = opt.panic_http {
+        control.http(addr).await?;
+    }
+    if let Some(addr) = opt.panic_osc {
+        control.osc(addr).await?;
+    }
+    if opt.watch {
+        let file = file(opt)?;
+        info!("Watching {} for changes", file.display());
+        control.watch(file.to_path_buf());
+    }
+
+    let lookahead = Duration::from_millis(opt.lookahead);
+    let wind = opt
+        .max_duration
+        .map(|l| Instant::now() + Duration::from_secs(l) - fade);
+
+    // Without cubes to press the button on, the segments go on by themselves.
+    let button = !events.is_empty();
+    let mut cube_events = stream::select_all(events);
+    let mut next = Next::Play(Duration::from_secs(0));
+    let mut playing = Playing::new();
+    // The loop between the points and the shuffle go on forever unless repeated a number
+    // of times, as long as there's something to play.
+    let passes = match opt.repeat {
+        _ if songs.iter().flat_map(|s| &s.segments).all(|s| s.is_empty()) => Some(1),
+        Some(Repeat(passes)) => passes,
+        None if opt.loop_between.is_empty() && !opt.shuffle => Some(1),
+        None => None,
+    };
+    let mut pass = 1;
+    let mut order: Vec<_> = (0..songs.len()).collect();
+    let mut seed = playlist::seed();
+    // Songs of a playlist go on one after another on the same clock, without the silence
+    // before the first note or after the last one.
+    let gapless = songs.len() > 1;
+    let mut spliced = None;
+    // The rest of a song converted while it's played comes on as it's ready.
+    let mut rest = songs.first_mut().and_then(|s| s.rest.take());
+    'passes: loop {
+        if opt.shuffle {
+            playlist::shuffle(&mut order, &mut seed);
+        }
+        for (n, song) in order.iter().map(|i| &songs[*i]).enumerate() {
+            if pass > 1 || n > 0 {
+                for sink in sinks.iter_mut() {
+                    sink.restart().await?;
+                }
+            }
+            if pass > 1 && n == 0 {
+                info!("Playing again, pass {}", pass);
+            }
+            if songs.len() > 1 {
+                info!("Playing {} ({}/{})", song.name, n + 1, songs.len());
+            }
+            let segments = &song.segments;
+            let total = segments.len();
+            'play: for (i, events) in segments.iter().cloned().enumerate() {
+                // The first segment keeps the leading silence of the song, unless spliced.
+                let offset = match events.keys().next() {
+                    Some((at, _)) if i > 0 || spliced.is_some() => *at,
+                    _ => 0,
+                };
+                let events = router.skewed(events);
+                // The sync input may go to another time of the song to play on from.
+                let replay = clock.as_ref().map(|_| events.clone());
+                let name = Path::new(&song.name)
+                    .file_name()
+                    .map_or(song.name.clone(), |n| n.to_string_lossy().into_owned());
+                span::section(Some(format!("{} {}/{}", name, i + 1, total)));
+
+                if i > 0 {
+                    if opt.idle.enabled() {
+                        for sink in sinks.iter_mut() {
+                            sink.idle().await?;
+                        }
+                    }
+                    // A skip goes on without the button.
+                    if button && next != Next::Skip {
+                        info!(
+                            "Segment {}/{} done. Press the button on a cube to continue...",
+                            i, total
+                        );
+                        let pressed = future::select(
+                            Box::pin(wait_button(&mut cube_events)),
+                            Box::pin(interrupted(&mut control)),
+                        );
+                        match before(wind, pressed).await {
+                            Some(future::Either::Left((pressed, _))) => pressed?,
+                            Some(future::Either::Right(_)) => {
+                                next = Next::Interrupt;
+                                break 'play;
+                            }
+                            None => {
+                                next = Next::Wind;
+                                break 'play;
+                            }
+                        }
+                    }
+                    info!("Playing segment {}/{}", i + 1, total);
+                }
+
+                next = Next::Play(Duration::from_secs(0));
+                let mut start = spliced.take().unwrap_or_else(Instant::now);
+                if let Some(clock) = clock.as_mut() {
+                    clock.begin(offset);
+                }
+                let mut drift = drift::Drift::new(start);
+                let mut last_at = 0;
+                // The time in the song told to the event log last.
+                let mut position = None;
+                let mut end = 0;
+                let last = gapless && i + 1 == total;
+                let last_note = midi::last_note(&events);
+                let mut events = events.into_iter();
+                'sets: loop {
+                    let event = match events.next() {
+                        Some(event) => Some(event),
+                        None => match rest.as_mut().map(|r| r.plans.recv()) {
+                            Some(plan) => match plan.await {
+                                Some(plan) => {
+                                    let plan = plan.context(Failure::Parse)?;
+                                    events = router.skewed(plan).into_iter();
+                                    continue;
+                                }
+                                None => None,
+                            },
+                            None => None,
+                        },
+                    };
+                    // The sync input tells when the set comes, or the end of the segment.
+                    if let (Some(clock), Some(replay)) = (clock.as_mut(), &replay) {
+                        let (at, early) = match &event {
+                            Some(((at, _), _)) => (*at, lookahead),
+                            None => (end.max(offset), Duration::from_secs(0)),
+                        };
+                        while event.is_none() || last_at != at || position.is_none() {
+                            let followed =
+                                follow(clock, at, offset, early, &mut start, wind, &mut control);
+                            match followed.await? {
+                                Followed::On => break,
+                                Followed::Halted => {
+                                    info!("The sync input stopped; silent until it goes on");
+                                    playing.clear();
+                                    for sink in sinks.iter_mut() {
+                                        sink.silence().await?;
+                                    }
+                                }
+                                Followed::Located(to) => {
+                                    info!("Playing on from {} by the sync input", Offset(to));
+                                    playing.clear();
+                                    for sink in sinks.iter_mut() {
+                                        sink.silence().await?;
+                                    }
+                                    events = section::seek(replay, to).into_iter();
+                                    position = None;
+                                    last_at = Time::MAX;
+                                    continue 'sets;
+                                }
+                                Followed::Stopped(stop) => {
+                                    next = stop;
+                                    break 'play;
+                                }
+                            }
+                        }
+                    }
+                    let ((at, _), playset) = match event {
+                        Some(event) => event,
+                        None => break,
+                    };
+                    debug!("At {}: {:?}", at, playset);
+
+                    if last_at != at {
+                        // Sent ahead, the set is on its way while the cube plays the last one.
+                        let at = start + Duration::from_millis(at - offset) - lookahead;
+                        next = wait(at, timer, wind, &mut control, sinks, &mut playing).await?;
+                        match next {
+                            Next::Play(paused) => {
+                                start += paused;
+                                if let Some(clock) = clock.as_mut() {
+                                    clock.delay(paused);
+                                }
+                                let now = Instant::now();
+                                drift.record(now.saturating_duration_since(at + paused));
+                                match drift.correction(now) {
+                                    Some(drift::Correction::Later(c)) => {
+                                        start += c;
+                                        info!(
+                                            "The play sets went out late; moving the rest {:?} later",
+                                            c
+                                        );
+                                    }
+                                    Some(drift::Correction::Earlier(c)) => {
+                                        start -= c;
+                                        info!(
+                                            "The play sets go out on time; moving the rest {:?} back earlier",
+                                            c
+                                        );
+                                    }
+                                    None => {}
+                                }
+                            }
+                            Next::Skip => break,
+                            Next::Wind | Next::Quit | Next::Reload | Next::Interrupt => break 'play,
+                        }
+                    }
+                    if position != Some(at) {
+                        position = Some(at);
+                        journal::record(
+                            "song-position",
+                            vec![
+                                ("song", song.name.as_str().into()),
+                                ("segment", (i + 1).into()),
+                                ("at", at.into()),
+                            ],
+                        );
+                    }
+                    last_at = at;
+                    let due = start + Duration::from_millis(at - offset);
+
+                    if let Some(threshold) = opt.reassign_battery {
+                        rebalance(&mut router, &mut cube_events, sinks, threshold).await?;
+                    }
+
+                    let now = Instant::now();
+                    playing.retain(|(_, set, sent)| now < *sent + Duration::from_millis(set.len));
+                    let sent = due.max(now);
+                    for (follower, interval) in router.mirrors(playset.ch) {
+                        if let Some(sink) = sinks.get_mut(follower) {
+                            end = end.max(playset.at + playset.len);
+                            let set = playset.transposed(interval);
+                            playing.push((follower, set.clone(), sent));
+                            sink.play_at(set, due).await?;
+                            if opt.failover && sink.lost() {
+                                fail_over(&mut router, follower);
+                            }
+                        }
+                    }
+                    // A lost cube hands the set on to the cube taking over its parts.
+                    while let Some(cube) = router.cube(playset.ch).filter(|c| *c < sinks.len()) {
+                        end = end.max(playset.at + playset.len);
+                        // Only a cube playing a part taken over has another part to mix.
+                        let set = if router.shared(cube) {
+                            route::mix_in(&mut playing, cube, &playset, opt.unit, Instant::now())
+                        } else {
+                            playset.clone()
+                        };
+                        playing.push((cube, set.clone(), sent));
+                        sinks[cube].play_at(set, due).await?;
+                        if !opt.failover || !sinks[cube].lost() {
+                            break;
+                        }
+                        fail_over(&mut router, cube);
+                    }
+                }
+                if next != Next::Skip {
+                    let end = if last { end.min(last_note) } else { end };
+                    // The loop goes round after the whole section, silent to its end or not.
+                    let end = match opt.loop_between[..] {
+                        [a, b] if i + 1 == total => b.0 - a.0,
+                        _ => end,
+                    };
+                    let end = start + Duration::from_millis(end.saturating_sub(offset));
+                    next = wait(end, timer, wind, &mut control, sinks, &mut playing).await?;
+                    if let (true, Next::Play(paused)) = (last, next) {
+                        spliced = Some(end + paused);
+                    }
+                }
+                match next {
+                    Next::Play(_) => {}
+                    Next::Skip => {
+                        info!("Skipped segment {}/{}", i + 1, total);
+                        playing.clear();
+                        for sink in sinks.iter_mut() {
+                            sink.silence().await?;
+                        }
+                    }
+                    Next::Wind | Next::Quit | Next::Reload | Next::Interrupt => break 'play,
+                }
+            }
+            match next {
+                Next::Wind | Next::Quit | Next::Interrupt => break 'passes,
+                Next::Reload => break,
+                Next::Play(_) | Next::Skip => {}
+            }
+        }
+        if next != Next::Reload && passes.is_some_and(|p| pass >= p) {
+            if !opt.watch {
+                break;
+            }
+            info!("Waiting for the file to change...");
+            let changed = async {
+                loop {
+                    match control.next().await {
+                        Signal::Reload => return Next::Reload,
+                        Signal::Quit => return Next::Quit,
+                        Signal::Interrupt => return Next::Interrupt,
+                        _ => {}
+                    }
+                }
+            };
+            next = before(wind, changed).await.unwrap_or(Next::Wind);
+            if next != Next::Reload {
+                break;
+            }
+        }
+        if next == Next::Reload {
+            playing.clear();
+            for sink in sinks.iter_mut() {
+                sink.restart().await?;
+            }
+            // The connections stay, going on with the song as it was if it's broken.
+            match plan(opt) {
+                Ok(segments) => {
+                    info!("Reloaded the file; playing from the start");
+                    songs = vec![Song::of(file(opt)?, segments)];
+                }
+                Err(e) => error!("Couldn't reload the file: {:?}", e),
+            }
+            pass = 1;
+            continue;
+        }
+        pass += 1;
+    }
+    Ok(next)
+}
+
+/// Sends each play set to the sinks of its cubes when it's due.
+async fn schedule<S: SoundSink>(
+    opt: &Opt,
+    songs: Vec<Song>,
+    router: Router,
+    events: Vec<CubeEvents>,
+    mut sinks: Vec<S>,
+    timer: Timer,
+) -> Result<()> {
+    let limit = opt.max_duration.map(Duration::from_secs);
+    let fade = limit.map(|l| l.min(WIND_DOWN)).unwrap_or(WIND_DOWN);
+    let next = playback(opt, songs, router, events, &mut sinks, fade, &timer).await;
+    span::section(None);
+    let next = match next {
+        Ok(next) => next,
+        Err(e) => {
+            // Whatever failed, the other cubes don't go on sounding.
+            for sink in &mut sinks {
+                let _ = sink.wind_down(Duration::from_secs(0)).await;
+            }
+            for sink in sinks {
+                let _ = sink.finish().await;
+            }
+            return Err(e);
+        }
+    };
+
+    if next == Next::Wind {
+        info!("Time limit reached. Winding down...");
+        for sink in &mut sinks {
+            sink.wind_down(fade).await?;
+        }
+        for sink in sinks {
+            sink.finish().await?;
+        }
+        info!("Done");
+        return Ok(());
+    }
+    if next == Next::Interrupt {
+        info!("Interrupted. Turning off the cubes...");
+        for sink in &mut sinks {
+            sink.wind_down(Duration::from_secs(0)).await?;
+        }
+        for sink in sinks {
+            sink.finish().await?;
+        }
+        info!("Done");
+        return Ok(());
+    }
+    if next == Next::Quit {
+        info!("Quitting...");
+        for sink in &mut sinks {
+            sink.silence().await?;
+        }
+    }
+
+    info!("Shutting down in 3 seconds...");
+    delay_for(Duration::from_secs(3)).await;
+    for sink in sinks {
+        sink.finish().await?;
+    }
+    info!("Done");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let rule = |s: &str| s.parse::<Rule>().unwrap();
+
+        let numbered = rule("2=0,3");
+        assert_eq!(
+            (numbered.as_ch, numbered.chs, numbered.cube),
+            (2, vec![0, 3], None)
+        );
+
+        let mut rules = vec![rule("bass=1"), rule("0=2"), rule("d1:5e:01=4")];
+        assert_eq!(rules[0].cube.as_deref(), Some("bass"));
+        assert_eq!(rules[2].cube.as_deref(), Some("d1:5e:01"));
+        assert_eq!(rules[2].chs, vec![4]);
+        // The cubes by name take the lowest numbers free.
+        number(&mut rules);
+        let numbers: Vec<_> = rules.iter().map(|r| r.as_ch).collect();
+        assert_eq!(numbers, vec![1, 0, 2]);
+        assert_eq!(rules[0].to_string(), "bass=1");
+
+        assert!("bass cube=1".parse::<Rule>().is_err());
+        assert!("=1".parse::<Rule>().is_err());
+        assert!("1".parse::<Rule>().is_err());
+
+        // A cube by a name neither registered nor found fails to be arranged.
+        let drums = rule("drums=1");
+        let mut registry = cubes::Registry::default();
+        registry.register("bass", "d1:5e:01").unwrap();
+        let found = || vec![("d1:5e:01".to_string(), 'a'), ("c0:ff:02".to_string(), 'b')];
+        fn targets(r: &Rule) -> Vec<(usize, &str)> {
+            vec![(r.as_ch as usize, r.cube.as_deref().unwrap())]
+        }
+        assert_eq!(
+            cubes::arrange(found(), &targets(&rules[0]), &registry).unwrap(),
+            vec!['b', 'a']
+        );
+        assert_eq!(
+            cubes::arrange(found(), &targets(&drums), &registry)
+                .unwrap_err()
+                .to_string(),
+            "Cube drums isn't found"
+        );
+    }
+
+    #[test]
+    fn metronome() {
+        let mut events = midi::Plan::new();
+        events.insert((0, 0), PlaySet::new(0, 0));
+        assert_eq!(metronome_part(&events, 1).unwrap(), 1);
+        assert_eq!(
+            metronome_part(&events, 0).unwrap_err().to_string(),
+            "Cube 0 plays part 0; give it no part with --rule to keep it for the metronome"
+        );
+
+        // A set per bar, clicking on every beat with a rest until the next one.
+        let beats = [(0, true), (500, false), (1000, true), (1030, false)];
+        let plan = super::metronome(&beats, 1);
+        assert_eq!(plan.keys().collect::<Vec<_>>(), vec![&(0, 1), &(1000, 1)]);
+        let first = &plan[&(0, 1)];
+        assert_eq!(first.len, 1000);
+        assert_eq!(
+            first.plays,
+            vec![
+                midi::Play::new(1, 0, CLICK, toio::Note::A6),
+                midi::Play::new(1, CLICK, 500 - CLICK, toio::Note::NoSound),
+                midi::Play::new(1, 500, CLICK, toio::Note::E6),
+                midi::Play::new(1, 500 + CLICK, 500 - CLICK, toio::Note::NoSound),
+            ]
+        );
+
+        // A click is cut short by the next beat, and the last lasts a click with a rest.
+        let last = &plan[&(1000, 1)];
+        assert_eq!(last.len, 30 + CLICK * 2);
+        assert_eq!(
+            last.plays,
+            vec![
+                midi::Play::new(1, 1000, 30, toio::Note::A6),
+                midi::Play::new(1, 1030, CLICK, toio::Note::E6),
+                midi::Play::new(1, 1030 + CLICK, CLICK, toio::Note::NoSound),
+            ]
+        );
+    }
+
+    #[test]
+    fn layer_channels() {
+        assert_eq!(layer_channel("3").unwrap(), 3);
+        assert_eq!(layer_channel("0:3").unwrap(), 3);
+        assert_eq!(layer_channel("2:10").unwrap(), 2010);
+        assert_eq!(
+            layer_channel("1:1000").unwrap_err().to_string(),
+            "Channel 1000 is out of range"
+        );
+        assert_eq!(
+            layer_channel("66:1").unwrap_err().to_string(),
+            "File 66 is out of range"
+        );
+        assert_eq!(
+            layer_channel("65:600").unwrap_err().to_string(),
+            "File 65 is out of range"
+        );
+        assert!(layer_channel("a:1").is_err());
+    }
 }
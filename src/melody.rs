@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use toio::Note;
+
+use crate::midi::{Channel, EventMap, Play, Time};
+
+/// Gaps up to this length in msec don't break a phrase.
+const PHRASE_GAP: Time = 1000;
+
+/// How likely a channel carries the melody, each factor from 0 to 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Score {
+    pub ch: Channel,
+    /// Average pitch relative to the highest channel.
+    pub pitch: f64,
+    /// Notes per second relative to the busiest channel.
+    pub density: f64,
+    /// Longest continuous activity relative to the whole song.
+    pub activity: f64,
+}
+
+impl Score {
+    pub fn total(&self) -> f64 {
+        self.pitch * 0.5 + self.density * 0.2 + self.activity * 0.3
+    }
+}
+
+struct Stats {
+    pitch: f64,
+    density: f64,
+    activity: Time,
+}
+
+fn stats(plays: &[&Play]) -> Stats {
+    let pitch = plays.iter().map(|p| p.note as u8 as f64).sum::<f64>() / plays.len() as f64;
+
+    let start = plays.first().map(|p| p.at).unwrap_or(0);
+    let end = plays.iter().map(|p| p.at + p.len).max().unwrap_or(0);
+    let density = plays.len() as f64 * 1000.0 / (end - start).max(1) as f64;
+
+    let mut activity = 0;
+    let mut phrase = (start, start);
+    for p in plays {
+        if p.at > phrase.1 + PHRASE_GAP {
+            phrase = (p.at, p.at);
+        }
+        phrase.1 = phrase.1.max(p.at + p.len);
+        activity = activity.max(phrase.1 - phrase.0);
+    }
+
+    Stats {
+        pitch,
+        density,
+        activity,
+    }
+}
+
+/// Scores every channel, the most likely melody first.
+pub fn scores(plays: &EventMap) -> Vec<Score> {
+    let mut chs: BTreeMap<Channel, Vec<&Play>> = BTreeMap::new();
+    for ((_, ch), ps) in plays {
+        for p in ps.iter().filter(|p| p.note != Note::NoSound && p.len > 0) {
+            chs.entry(*ch).or_default().push(p);
+        }
+    }
+
+    let stats: Vec<_> = chs.iter().map(|(ch, ps)| (*ch, stats(ps))).collect();
+    let len = plays
+        .values()
+        .flatten()
+        .map(|p| p.at + p.len)
+        .max()
+        .unwrap_or(0)
+        .max(1) as f64;
+    let max = |f: fn(&Stats) -> f64| {
+        stats
+            .iter()
+            .map(|(_, s)| f(s))
+            .fold(f64::MIN_POSITIVE, f64::max)
+    };
+    let max_pitch = max(|s| s.pitch);
+    let max_density = max(|s| s.density);
+
+    let mut scores: Vec<_> = stats
+        .iter()
+        .map(|(ch, s)| Score {
+            ch: *ch,
+            pitch: s.pitch / max_pitch,
+            density: s.density / max_density,
+            activity: s.activity as f64 / len,
+        })
+        .collect();
+    scores.sort_by(|a, b| b.total().partial_cmp(&a.total()).unwrap());
+    scores
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn plays(ch: Channel, notes: &[(Time, Time, Note)]) -> Vec<((Time, Channel), Vec<Play>)> {
+        notes
+            .iter()
+            .map(|(at, len, n)| ((*at, ch), vec![Play::new(ch, *at, *len, *n)]))
+            .collect()
+    }
+
+    #[test]
+    fn melody_first() {
+        let mut map = EventMap::new();
+        // Bass: low and sparse.
+        map.extend(plays(
+            0,
+            &[
+                (0, 1000, Note::C2),
+                (4000, 1000, Note::G2),
+                (8000, 1000, Note::C2),
+            ],
+        ));
+        // Melody: high and continuous.
+        let melody: Vec<_> = (0..40)
+            .map(|i| (i * 250, 250, if i % 2 == 0 { Note::C5 } else { Note::E5 }))
+            .collect();
+        map.extend(plays(1, &melody));
+
+        let scores = scores(&map);
+        assert_eq!(scores.len(), 2);
+        assert_eq!(scores[0].ch, 1);
+        assert!((scores[0].pitch - 1.0).abs() < 1e-9);
+        assert!((scores[0].activity - 10000.0 / 10000.0).abs() < 1e-9);
+        assert!(scores[1].activity < 0.2);
+    }
+}
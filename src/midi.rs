@@ -1,24 +1,46 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use derive_new::new;
 use ghakuf::{messages::*, reader::*};
 use log::*;
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     convert::TryInto,
     path::Path,
+    time::Duration,
 };
-use toio::Note;
+use toio::{Note, SoundOp};
 
-pub type EventMap = BTreeMap<(Time, Channel), Play>;
-pub type Channel = u8;
+use crate::{
+    smf,
+    song::{self, Song},
+    stats::{self, Stats},
+    unsupported::{Feature, Ignored, DRUMS},
+};
+
+/// Plays by their start; plays starting together on a channel are kept in order.
+pub type EventMap = BTreeMap<(Time, Channel), Vec<Play>>;
+pub type Plan = BTreeMap<(Time, Channel), PlaySet>;
+pub type Channel = u16;
 pub type Time = u64;
 
+/// The maximum number of sound operations the cube accepts at once.
+pub const MAX_OPS: usize = 59;
+/// The maximum duration of a sound operation in msec.
+pub const MAX_OP_LEN: Time = 2550;
+/// The resolution of durations in sound operations in msec.
+pub const OP_LEN_UNIT: Time = 10;
+/// The velocity of notes without one, such as the notes of tests.
+const DEFAULT_VELOCITY: u8 = 100;
+
 #[derive(Clone, Debug, PartialEq, Eq, new)]
 pub struct Play {
     pub ch: Channel,
     pub at: Time,
     pub len: Time,
     pub note: Note,
+    /// Velocity of the note on.
+    #[new(value = "DEFAULT_VELOCITY")]
+    pub vel: u8,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, new)]
@@ -29,6 +51,163 @@ pub struct PlaySet {
     pub len: Time,
     #[new(default)]
     pub plays: Vec<Play>,
+    /// How many times the plays are repeated on the cube.
+    #[new(value = "1")]
+    pub repeat: u8,
+}
+
+impl PlaySet {
+    /// Returns the play set transposed by semitones, folding notes out of the range of
+    /// the cube by octaves.
+    pub fn transposed(&self, interval: i8) -> PlaySet {
+        let mut set = self.clone();
+        for play in &mut set.plays {
+            if play.note != Note::NoSound {
+                let mut n = play.note as i32 + interval as i32;
+                while n < 0 {
+                    n += 12;
+                }
+                while n > Note::G10 as i32 {
+                    n -= 12;
+                }
+                play.note = (n as u8).try_into().unwrap();
+            }
+        }
+        set
+    }
+
+    /// Converts into the chunks of sound operations that the cube accepts at once.
+    ///
+    /// The chunks are meant to be played back-to-back, each `repeat` times.
+    pub fn chunks(&self) -> Vec<Vec<SoundOp>> {
+        self.chunks_of(MAX_OPS)
+    }
+
+    /// Converts into the chunks of at most `size` sound operations.
+    ///
+    /// Repeated plays are kept in whole chunks so that the cube repeats them as is.
+    pub fn chunks_of(&self, size: usize) -> Vec<Vec<SoundOp>> {
+        let size = if self.repeat > 1 {
+            MAX_OPS
+        } else {
+            size.clamp(1, MAX_OPS)
+        };
+        let mut chunks = vec![];
+        let mut chunk = vec![];
+
+        for play in &self.plays {
+            let mut rem = play.len;
+            while rem > 0 {
+                let len = rem.min(MAX_OP_LEN);
+                chunk.push(SoundOp::new(play.note, Duration::from_millis(len)));
+                if chunk.len() == size {
+                    chunks.push(std::mem::take(&mut chunk));
+                }
+                rem -= len;
+            }
+        }
+
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+
+        chunks
+    }
+
+    /// Returns the plays as the cube sounds them, repetitions unrolled.
+    pub fn unrolled(&self) -> Vec<Play> {
+        let repeat = self.repeat.max(1) as Time;
+        let pattern = self.len / repeat;
+        (0..repeat)
+            .flat_map(|r| {
+                self.plays.iter().map(move |p| {
+                    let mut p = p.clone();
+                    p.at += r * pattern;
+                    p
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the plays sounding from `from` msec after the start for `len` msec.
+    ///
+    /// For repeated plays, the window is taken in the current repetition and cut at
+    /// its end.
+    pub fn window(&self, from: Time, len: Time) -> PlaySet {
+        let from = match self.len / self.repeat.max(1) as Time {
+            pattern if self.repeat > 1 && pattern > 0 => from % pattern,
+            _ => from,
+        };
+        let (start, end) = (self.at + from, self.at + from + len);
+
+        let mut set = PlaySet::new(self.ch, start);
+        set.plays = self
+            .plays
+            .iter()
+            .filter(|p| p.at < end && p.at + p.len > start)
+            .map(|p| {
+                let mut p = p.clone();
+                let (at, to) = (p.at.max(start), (p.at + p.len).min(end));
+                p.at = at;
+                p.len = to - at;
+                p
+            })
+            .collect();
+        set.len = set.plays.iter().map(|p| p.len).sum();
+        set
+    }
+}
+
+impl PlaySet {
+    /// Mixes in the plays of another set sounding meanwhile, taking turns every `unit` msec
+    /// among the notes sounding together as the rules mix channels into a cube.
+    ///
+    /// The mixed set is of the channel of `other`, without repetitions.
+    pub fn mixed(&self, other: &PlaySet, unit: u64) -> PlaySet {
+        let start = self.at.min(other.at);
+        let plays: Vec<_> = self
+            .unrolled()
+            .into_iter()
+            .chain(other.unrolled())
+            .filter(|p| p.note != Note::NoSound && p.len > 0)
+            .collect();
+        let end = plays.iter().map(|p| p.at + p.len).max().unwrap_or(start);
+
+        let mut set = PlaySet::new(other.ch, start);
+        for at in (start..end).step_by(OP_LEN_UNIT as usize) {
+            let len = OP_LEN_UNIT.min(end - at);
+            let on: Vec<_> = plays
+                .iter()
+                .filter(|p| p.at <= at && at < p.at + p.len)
+                .collect();
+            let (note, vel) = match on.len() {
+                0 => (Note::NoSound, DEFAULT_VELOCITY),
+                n => {
+                    let p = on[((at - start) / unit.max(1)) as usize % n];
+                    (p.note, p.vel)
+                }
+            };
+            match set.plays.last_mut() {
+                Some(last) if last.note == note && last.vel == vel => last.len += len,
+                _ => {
+                    let mut play = Play::new(other.ch, at, len, note);
+                    play.vel = vel;
+                    set.plays.push(play);
+                }
+            }
+        }
+        set.len = end - start;
+        set
+    }
+
+    /// Returns the plays sounding from `from` msec after the start to the end,
+    /// the repetitions unrolled.
+    pub fn rest(&self, from: Time) -> PlaySet {
+        let mut set = self.clone();
+        set.plays = self.unrolled();
+        set.repeat = 1;
+        set.window(from, self.len.saturating_sub(from))
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, new)]
@@ -36,12 +215,15 @@ pub enum Event {
     Start(Start),
     Stop(Stop),
     Tempo(Tempo),
+    Text(Text),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, new)]
 pub struct Start {
     ch: Channel,
     note: Note,
+    #[new(value = "DEFAULT_VELOCITY")]
+    vel: u8,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, new)]
@@ -55,14 +237,208 @@ pub struct Tempo {
     tempo: u64,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, new)]
+pub struct Text {
+    kind: MarkKind,
+    text: String,
+}
+
+/// Kinds of the text in the song.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarkKind {
+    Marker,
+    CuePoint,
+    Lyric,
+    Text,
+}
+
+impl std::fmt::Display for MarkKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MarkKind::Marker => write!(f, "marker"),
+            MarkKind::CuePoint => write!(f, "cue point"),
+            MarkKind::Lyric => write!(f, "lyric"),
+            MarkKind::Text => write!(f, "text"),
+        }
+    }
+}
+
+/// A text in the song such as a marker, at the time in msec.
+#[derive(Clone, Debug, PartialEq, Eq, new)]
+pub struct Mark {
+    pub at: Time,
+    pub kind: MarkKind,
+    pub text: String,
+}
+
+/// How gaps between notes are handled on merge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Gap {
+    /// Fill gaps with silence.
+    #[default]
+    Rest,
+    /// Extend the previous note over the gap.
+    Legato,
+    /// Shorten every note by the given msec.
+    Staccato(Time),
+}
+
+impl std::str::FromStr for Gap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut iter = s.splitn(2, ':');
+        match (iter.next(), iter.next()) {
+            (Some("rest"), None) => Ok(Gap::Rest),
+            (Some("legato"), None) => Ok(Gap::Legato),
+            (Some("staccato"), None) => Ok(Gap::Staccato(50)),
+            (Some("staccato"), Some(len)) => {
+                Ok(Gap::Staccato(len.parse().map_err(|_| {
+                    anyhow!("Invalid staccato length: {}", len)
+                })?))
+            }
+            _ => Err(anyhow!(
+                "Invalid gap strategy: {} (expected rest, legato or staccato[:msec])",
+                s
+            )),
+        }
+    }
+}
+
+/// What the channels of the plan are made of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum GroupBy {
+    /// MIDI channels for format 0 files, otherwise tracks.
+    #[default]
+    Auto,
+    /// Tracks of the file.
+    Track,
+    /// MIDI channels of the notes, splitting the voices of format 0 files.
+    Channel,
+}
+
+impl std::str::FromStr for GroupBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(GroupBy::Auto),
+            "track" => Ok(GroupBy::Track),
+            "channel" => Ok(GroupBy::Channel),
+            _ => Err(anyhow!(
+                "Invalid grouping: {} (expected auto, track or channel)",
+                s
+            )),
+        }
+    }
+}
+
+/// The reader of MIDI files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Parser {
+    /// The reader of ghakuf.
+    #[default]
+    Ghakuf,
+    /// The built-in reader, which tells where a file is broken and skips unknown chunks.
+    Builtin,
+}
+
+impl std::str::FromStr for Parser {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ghakuf" => Ok(Parser::Ghakuf),
+            "builtin" => Ok(Parser::Builtin),
+            _ => Err(anyhow!(
+                "Invalid parser: {} (expected ghakuf or builtin)",
+                s
+            )),
+        }
+    }
+}
+
+/// Options applied on converting MIDI files.
 #[derive(Clone, Debug, Default, new)]
+pub struct Options {
+    /// Channels to drop.
+    #[new(default)]
+    pub mute: Vec<Channel>,
+    /// If not empty, only these channels are kept.
+    #[new(default)]
+    pub solo: Vec<Channel>,
+    /// How to handle gaps between notes.
+    #[new(default)]
+    pub gap: Gap,
+    /// The maximum number of sound operations in a play set.
+    #[new(value = "MAX_OPS")]
+    pub max_ops: usize,
+    /// The maximum duration of a sound operation in msec.
+    #[new(value = "MAX_OP_LEN")]
+    pub max_op_len: Time,
+    /// What the channels are made of.
+    #[new(default)]
+    pub group_by: GroupBy,
+    /// Playback speed in percent of the tempo of the file.
+    #[new(value = "100")]
+    pub speed: u64,
+    /// The only track played of format 2 files, which otherwise play their tracks in turn.
+    #[new(default)]
+    pub sequence: Option<Channel>,
+    /// The reader of the file.
+    #[new(default)]
+    pub parser: Parser,
+    /// Plays what could be read of broken files instead of failing.
+    #[new(default)]
+    pub lenient: bool,
+}
+
+impl Options {
+    /// Checks the limits against the protocol.
+    pub fn validate(&self) -> Result<()> {
+        if self.max_ops == 0 || self.max_ops > MAX_OPS {
+            return Err(anyhow!(
+                "The maximum number of operations must be from 1 to {}",
+                MAX_OPS
+            ));
+        }
+        if self.speed == 0 {
+            return Err(anyhow!("Speed must be non-zero"));
+        }
+        if self.max_op_len < OP_LEN_UNIT || self.max_op_len > MAX_OP_LEN {
+            return Err(anyhow!(
+                "The maximum operation length must be from {} to {} msec",
+                OP_LEN_UNIT,
+                MAX_OP_LEN
+            ));
+        }
+        Ok(())
+    }
+
+    fn allows(&self, ch: Channel) -> bool {
+        !self.mute.contains(&ch) && (self.solo.is_empty() || self.solo.contains(&ch))
+    }
+}
+
+#[derive(Clone, Debug, new)]
 struct Raw {
     #[new(default)]
     at: Time,
+    /// Velocity of the next note on.
+    #[new(value = "DEFAULT_VELOCITY")]
+    vel: u8,
+    /// Playback speed in percent.
+    #[new(value = "100")]
+    speed: u64,
+    /// Whether tracks follow one another instead of starting together.
+    #[new(default)]
+    sequential: bool,
+    /// Notes held on each channel.
     #[new(default)]
-    notes: HashMap<Note, Time>,
+    notes: HashMap<Channel, HashMap<Note, (Time, u8)>>,
+    /// Events by their tick; events at the same tick are kept in order.
     #[new(default)]
-    events: BTreeMap<(Time, Channel), Event>,
+    events: BTreeMap<(Time, Channel), Vec<Event>>,
 }
 
 impl Raw {
@@ -70,6 +446,10 @@ impl Raw {
         self.at += delta;
     }
 
+    fn push(&mut self, ch: Channel, event: Event) {
+        self.events.entry((self.at, ch)).or_default().push(event);
+    }
+
     fn on(&mut self, ch: Channel, delta: Time, note: Note) {
         self.update(delta);
         self.onoff(ch, note, true);
@@ -82,254 +462,411 @@ impl Raw {
 
     fn tempo(&mut self, ch: Channel, delta: Time, tempo: u64) {
         self.update(delta);
-        self.events
-            .insert((self.at, ch), Event::Tempo(Tempo::new(ch, tempo)));
+        self.push(ch, Event::Tempo(Tempo::new(ch, tempo)));
+    }
+
+    fn text(&mut self, ch: Channel, delta: Time, kind: MarkKind, text: String) {
+        self.update(delta);
+        self.push(ch, Event::Text(Text::new(kind, text)));
     }
 
-    fn end(&mut self, ch: Channel) {
-        if !self.notes.is_empty() {
-            self.events
-                .insert((self.at, ch), Event::Stop(Stop::new(ch)));
+    /// Ends the track, stopping the notes still held.
+    fn end(&mut self) {
+        let mut held: Vec<_> = self
+            .notes
+            .iter()
+            .filter(|(_, notes)| !notes.is_empty())
+            .map(|(ch, _)| *ch)
+            .collect();
+        held.sort_unstable();
+        for ch in held {
+            self.push(ch, Event::Stop(Stop::new(ch)));
         }
         self.notes.clear();
-        self.at = 0;
+        if !self.sequential {
+            self.at = 0;
+        }
     }
 
     fn onoff(&mut self, ch: Channel, note: Note, on: bool) {
-        let old = self.note();
+        let old = self.note(ch);
+        let notes = self.notes.entry(ch).or_default();
         if on {
-            self.notes.insert(note, self.at);
+            notes.insert(note, (self.at, self.vel));
         } else {
-            self.notes.remove(&note);
+            notes.remove(&note);
         }
-        let new = self.note();
+        let new = self.note(ch);
 
         if old != new {
-            if let Some(_) = old {
-                self.events
-                    .insert((self.at, ch), Event::Stop(Stop::new(ch)));
+            if old.is_some() {
+                self.push(ch, Event::Stop(Stop::new(ch)));
             }
-            if let Some(note) = new {
-                self.events
-                    .insert((self.at, ch), Event::Start(Start::new(ch, note)));
+            if let Some((note, vel)) = new {
+                let mut start = Start::new(ch, note);
+                start.vel = vel;
+                self.push(ch, Event::Start(start));
             }
         }
     }
 
-    fn note(&self) -> Option<Note> {
+    /// Returns the number of notes held on the channel.
+    fn held(&self, ch: Channel) -> usize {
+        self.notes.get(&ch).map_or(0, |notes| notes.len())
+    }
+
+    /// Returns the latest note held on the channel with its velocity.
+    fn note(&self, ch: Channel) -> Option<(Note, u8)> {
         self.notes
+            .get(&ch)?
             .iter()
-            .max_by(|p, q| p.1.cmp(q.1))
-            .map(|(k, _)| *k)
+            .max_by(|p, q| (p.1).0.cmp(&(q.1).0))
+            .map(|(k, (_, vel))| (*k, *vel))
     }
 
-    fn tempoed(&self, time_base: u64) -> Tempoed {
-        let mut tempo = 500000;
-        let mut events = BTreeMap::new();
+    /// Returns the tempo changes as (tick, msec, tempo).
+    fn tempo_map(&self, time_base: u64) -> Vec<(Time, Time, u64)> {
+        let mut map = vec![];
+        let mut tempo = self.scaled(500000);
         let mut old_tempo_at = 0;
         let mut new_tempo_at = 0;
-        let mut new_at = 0;
-        let mut notes = HashMap::new();
-
-        for ((at, _), event) in &self.events {
-            new_at = ((at - old_tempo_at) * tempo / 1000 / time_base + new_tempo_at) / 10 * 10;
 
-            match event {
-                Event::Start(s) => {
-                    if let Some((start_at, note)) = notes.remove(&s.ch) {
-                        events.insert(
-                            (start_at, s.ch),
-                            Play::new(s.ch, start_at, new_at - start_at, note),
-                        );
-                    }
-                    notes.insert(s.ch, (new_at, s.note));
-                }
-                Event::Stop(s) => {
-                    if let Some((start_at, note)) = notes.remove(&s.ch) {
-                        events.insert(
-                            (start_at, s.ch),
-                            Play::new(s.ch, start_at, new_at - start_at, note),
-                        );
-                    }
-                }
-                Event::Tempo(t) => {
-                    old_tempo_at = *at;
-                    new_tempo_at = new_at;
-                    tempo = t.tempo;
-                }
+        for ((at, _), event) in self.iter() {
+            if let Event::Tempo(t) = event {
+                new_tempo_at =
+                    ((at - old_tempo_at) * tempo / 1000 / time_base + new_tempo_at) / 10 * 10;
+                old_tempo_at = *at;
+                tempo = self.scaled(t.tempo);
+                map.push((old_tempo_at, new_tempo_at, tempo));
             }
         }
 
-        for (ch, (start_at, note)) in notes {
-            events.insert(
-                (start_at, ch),
-                Play::new(ch, start_at, new_at - start_at, note),
-            );
+        map
+    }
+
+    /// Applies the playback speed to the tempo in usec per beat.
+    fn scaled(&self, tempo: u64) -> u64 {
+        tempo * 100 / self.speed.max(1)
+    }
+
+    /// Iterates over the events in order.
+    fn iter(&self) -> impl Iterator<Item = (&(Time, Channel), &Event)> {
+        self.events
+            .iter()
+            .flat_map(|(key, events)| events.iter().map(move |e| (key, e)))
+    }
+
+    fn tempoed(&self, time_base: u64) -> Tempoed {
+        let map = self.tempo_map(time_base);
+        let initial = self.scaled(500000);
+        // The msec of the tick, after the last tempo change before it.
+        let ms = |at: Time| {
+            let (tick, ms, tempo) = match map.partition_point(|(tick, _, _)| *tick <= at) {
+                0 => (0, 0, initial),
+                i => map[i - 1],
+            };
+            ((at - tick) * tempo / 1000 / time_base + ms) / 10 * 10
+        };
+        let end = self.events.keys().next_back().map_or(0, |(at, _)| ms(*at));
+
+        let mut channels: BTreeMap<Channel, Vec<(Time, &Event)>> = BTreeMap::new();
+        let mut marks = vec![];
+        for ((at, ch), event) in self.iter() {
+            match event {
+                Event::Text(t) => marks.push(Mark::new(ms(*at), t.kind, t.text.clone())),
+                Event::Tempo(_) => {}
+                _ => channels.entry(*ch).or_default().push((*at, event)),
+            }
         }
 
-        Tempoed(events)
+        // The channels change tempo together but are played apart.
+        let plays = parallel(channels.into_iter().collect(), |(ch, events)| {
+            let mut plays = EventMap::new();
+            let mut note = None;
+            let mut play = |start_at, end_at: Time, (n, vel)| {
+                let mut play = Play::new(ch, start_at, end_at - start_at, n);
+                play.vel = vel;
+                plays.entry((start_at, ch)).or_default().push(play);
+            };
+            for (at, event) in events {
+                let new_at = ms(at);
+                if let Some((start_at, n, vel)) = note.take() {
+                    play(start_at, new_at, (n, vel));
+                }
+                if let Event::Start(s) = event {
+                    note = Some((new_at, s.note, s.vel));
+                }
+            }
+            // Held to the end of the song.
+            if let Some((start_at, n, vel)) = note {
+                play(start_at, end, (n, vel));
+            }
+            plays
+        });
+        let events = plays.into_iter().flatten().collect();
+        Tempoed(events, marks)
     }
 }
 
+/// The plays of each channel, with the texts in the song in the order of time.
 #[derive(Clone, Debug, PartialEq, Eq, new)]
-struct Tempoed(EventMap);
+struct Tempoed(EventMap, Vec<Mark>);
 
-fn mix(mixed: &mut EventMap, orig: &EventMap, unit: u64, as_ch: u8, chs: &[u8]) {
+/// Mixes the channels into `as_ch` from `from` up to `to`, or to the end without it,
+/// going on with the plays started before `from`.
+fn mix(
+    mixed: &mut EventMap,
+    orig: &EventMap,
+    unit: u64,
+    (as_ch, chs): (Channel, &[Channel]),
+    from: Time,
+    to: Option<Time>,
+) {
     if chs.len() == 1 {
-        for ((at, ch), play) in orig {
+        for ((at, ch), plays) in orig {
             if chs.contains(ch) {
-                let mut p = play.clone();
-                p.ch = as_ch;
-                mixed.insert((*at, as_ch), p);
+                for play in plays {
+                    let mut p = play.clone();
+                    p.ch = as_ch;
+                    mixed.entry((*at, as_ch)).or_default().push(p);
+                }
             }
         }
         return;
     }
 
-    let mut on: Vec<Play> = vec![];
-    let mut last = None::<Play>;
-    let mut iter = orig.iter().peekable();
-
-    for at in 0.. {
-        if !on.is_empty() {
-            let mut play = on[(at / unit) as usize % on.len()].clone();
-            play.at = at;
-            play.len = 1;
-
-            if let Some(mut l) = last.take() {
-                // If the item is same as the previous one, merge.
-                if l.ch == play.ch && l.at + l.len == play.at && l.note == play.note {
-                    l.len += 1;
-                    last = Some(l);
-                } else {
-                    l.ch = as_ch;
-                    mixed.insert((l.at, as_ch), l);
-                    last = Some(play);
-                }
-            } else {
-                last = Some(play);
-            }
+    // When each play is mixed from and to: the plays are taken in one a msec at most,
+    // as they come, and each is mixed for a msec at least.
+    let mut spans: Vec<(Time, Time, &Play)> = orig
+        .range(..(from, 0))
+        .filter(|((_, ch), _)| chs.contains(ch))
+        .flat_map(|(_, plays)| plays)
+        .map(|p| (from, (p.at + p.len).max(from) + 1, p))
+        .collect();
+    let mut taken = None::<Time>;
+    let plays = orig
+        .range((from, 0)..)
+        .flat_map(|(key, plays)| plays.iter().map(move |p| (key, p)));
+    for ((at, ch), play) in plays {
+        let t = taken.map_or(from, |t| t + 1).max(*at);
+        if to.is_some_and(|to| t >= to) {
+            break;
         }
+        taken = Some(t);
+        if chs.contains(ch) {
+            spans.push((t + 1, (play.at + play.len).max(t + 1) + 1, play));
+        }
+    }
+    // Mixed for a msec after the last play is taken in.
+    let end = to.unwrap_or_else(|| taken.map_or(from, |t| t + 1) + 1);
 
-        on.retain(|play| at < play.at + play.len);
-
-        let play = match iter.peek() {
-            Some(((play_at, ch), play)) if *play_at <= at => {
-                if !chs.contains(ch) {
-                    iter.next();
-                    continue;
-                } else {
-                    play
-                }
+    let mut last = None::<Play>;
+    let mut emit = |play: &Play, at: Time, len: Time| {
+        if let Some(l) = last.as_mut() {
+            // If the item is same as the previous one, merge.
+            if l.ch == play.ch && l.at + l.len == at && l.note == play.note {
+                l.len += len;
+                return;
             }
-            Some(_) => continue,
-            None => break,
-        };
-
-        on.push((*play).clone());
-
-        iter.next();
+        }
+        if let Some(mut l) = last.take() {
+            l.ch = as_ch;
+            mixed.entry((l.at, as_ch)).or_default().push(l);
+        }
+        let mut play = play.clone();
+        play.at = at;
+        play.len = len;
+        last = Some(play);
+    };
+
+    // The plays sounding only change at the bounds, and the one played in between at
+    // each unit.
+    let mut bounds: Vec<Time> = spans
+        .iter()
+        .flat_map(|(start, stop, _)| [*start, *stop])
+        .chain([from, end])
+        .filter(|at| (from..=end).contains(at))
+        .collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let mut on: Vec<&(Time, Time, &Play)> = vec![];
+    let mut spans = spans.iter().peekable();
+    for bound in bounds.windows(2) {
+        let (start, stop) = (bound[0], bound[1]);
+        on.retain(|(_, until, _)| start < *until);
+        while let Some(span) = spans.next_if(|(since, _, _)| *since <= start) {
+            on.push(span);
+        }
+        let mut at = start;
+        while !on.is_empty() && at < stop {
+            let len = ((at / unit + 1) * unit).min(stop) - at;
+            emit(on[(at / unit) as usize % on.len()].2, at, len);
+            at += len;
+        }
     }
 
     if let Some(mut l) = last.take() {
         l.ch = as_ch;
-        mixed.insert((l.at, as_ch), l);
+        mixed.entry((l.at, as_ch)).or_default().push(l);
     }
 }
 
 impl Tempoed {
-    fn mixed(&self, unit: u64, rules: &[(u8, Vec<u8>)]) -> Tempoed {
-        let mut mixed = BTreeMap::new();
-
-        for (as_ch, chs) in rules {
-            mix(&mut mixed, &self.0, unit, *as_ch, &chs);
+    /// Warns about notes that can't be played as written.
+    fn check(&self, song: &Song, max_op_len: Time) {
+        for play in self.0.values().flatten() {
+            if play.len == 0 {
+                warn!(
+                    "{} is shorter than {} msec and dropped",
+                    song.describe(play.ch, play.at, play.note),
+                    OP_LEN_UNIT
+                );
+            } else if play.len > max_op_len && play.len % max_op_len < 50 {
+                warn!(
+                    "{} is split into {} msec operations, leaving a {} msec fragment",
+                    song.describe(play.ch, play.at, play.note),
+                    max_op_len,
+                    play.len % max_op_len
+                );
+            }
         }
-
-        Tempoed(mixed)
     }
 
-    fn merged(&self, size: usize, maxlen: Time) -> Merged {
-        let mut merged = BTreeMap::new();
-        let mut chs = HashMap::new();
-
-        for ((at, _), play) in &self.0 {
-            let mut play = play.clone();
-            let mut rem = play.len;
-
-            while rem > 0 {
-                play.len = rem.min(maxlen);
-
-                // Operations on gaps
-                {
-                    enum Op {
-                        Flush,
-                        Fill(Time, Time),
-                        None,
-                    }
+    fn filtered(self, opts: &Options) -> Tempoed {
+        Tempoed(
+            self.0
+                .into_iter()
+                .filter(|((_, ch), _)| opts.allows(*ch))
+                .collect(),
+            self.1,
+        )
+    }
 
-                    let set = chs
-                        .entry(play.ch)
-                        .or_insert_with(|| PlaySet::new(play.ch, *at));
-
-                    let op = if let Some(last) = set.plays.last() {
-                        if last.at + last.len + maxlen < play.at {
-                            // Gap is longer than maxlen, flush.
-                            Op::Flush
-                        } else if last.at + last.len < play.at {
-                            // Gap is shorter than maxlen, fill.
-                            Op::Fill(last.at + last.len, play.at - (last.at + last.len))
-                        } else {
-                            // No gap.
-                            Op::None
-                        }
-                    } else {
-                        // No last.
-                        Op::None
-                    };
+    /// Shortens each note for staccato before it's mixed or split, leaving the gaps to
+    /// the merge. Notes no longer than the gap are dropped, and empty ones left alone.
+    fn shortened(self, gap: Gap) -> Tempoed {
+        let len = match gap {
+            Gap::Staccato(len) => len,
+            _ => return self,
+        };
+        let mut events = self.0;
+        for plays in events.values_mut() {
+            plays.retain_mut(|play| match play.len {
+                0 => true,
+                l if l <= len => false,
+                l => {
+                    play.len = (l - len).max(OP_LEN_UNIT);
+                    true
+                }
+            });
+        }
+        events.retain(|_, plays| !plays.is_empty());
+        Tempoed(events, self.1)
+    }
 
-                    match op {
-                        Op::Flush => {
-                            merged.insert((set.at, set.ch), set.clone());
+    fn mixed(&self, unit: u64, rules: &[(Channel, Vec<Channel>)]) -> Tempoed {
+        // The rules are mixed apart.
+        let parts = parallel(rules.iter().collect(), |(as_ch, chs)| {
+            let mut mixed = EventMap::new();
+            mix(&mut mixed, &self.0, unit, (*as_ch, chs), 0, None);
+            mixed
+        });
+
+        let mut mixed = EventMap::new();
+        for part in parts {
+            for (key, plays) in part {
+                mixed.entry(key).or_default().extend(plays);
+            }
+        }
+        Tempoed(mixed, self.1.clone())
+    }
 
-                            let ch = set.ch;
-                            chs.remove(&ch);
-                        }
-                        Op::Fill(at, len) => {
-                            set.plays.push(Play::new(play.ch, at, len, Note::NoSound));
-                            if set.plays.len() == size {
-                                merged.insert((set.at, set.ch), set.clone());
+    fn merged(&self, size: usize, maxlen: Time, gap: Gap) -> Merged {
+        let mut channels: BTreeMap<Channel, Vec<(&Time, &Play)>> = BTreeMap::new();
+        for ((at, ch), plays) in &self.0 {
+            channels
+                .entry(*ch)
+                .or_default()
+                .extend(plays.iter().map(|p| (at, p)));
+        }
+        // The channels are merged apart.
+        let sets = parallel(channels.into_values().collect(), |plays| {
+            merge(plays, size, maxlen, gap)
+        });
+        Merged(sets.into_iter().flatten().collect())
+    }
+}
 
-                                let ch = set.ch;
-                                chs.remove(&ch);
+/// Merges the plays of a channel into play sets of up to `size` operations.
+fn merge(plays: Vec<(&Time, &Play)>, size: usize, maxlen: Time, gap: Gap) -> Plan {
+    let mut merged = BTreeMap::new();
+    // The set being filled, put in the plan when it's full or a long gap comes.
+    let mut open = None::<PlaySet>;
+    let mut close = |set: PlaySet| merged.insert((set.at, set.ch), set);
+
+    for (at, play) in plays {
+        let mut play = play.clone();
+        let mut rem = play.len;
+
+        while rem > 0 {
+            play.len = rem.min(maxlen);
+
+            // Operations on gaps
+            if let Some(mut set) = open.take() {
+                let end = set.plays.last().map(|last| last.at + last.len);
+                match end {
+                    // Gap is longer than maxlen, flush.
+                    Some(end) if end + maxlen < play.at => {
+                        close(set);
+                    }
+                    // Gap is shorter than maxlen, fill.
+                    Some(end) if end < play.at => {
+                        let len = play.at - end;
+                        match set.plays.last_mut() {
+                            Some(last)
+                                if gap == Gap::Legato
+                                    && last.note != Note::NoSound
+                                    && last.len + len <= maxlen =>
+                            {
+                                last.len += len;
+                                set.len += len;
+                                open = Some(set);
+                            }
+                            _ => {
+                                set.plays.push(Play::new(play.ch, end, len, Note::NoSound));
+                                if set.plays.len() == size {
+                                    close(set);
+                                } else {
+                                    open = Some(set);
+                                }
                             }
                         }
-                        Op::None => {}
                     }
+                    // No gap, or no last.
+                    _ => open = Some(set),
                 }
+            }
 
-                let set = chs
-                    .entry(play.ch)
-                    .or_insert_with(|| PlaySet::new(play.ch, *at));
-                set.len += play.len;
-                set.plays.push(play.clone());
-                if set.plays.len() == size {
-                    merged.insert((set.at, set.ch), set.clone());
-
-                    let ch = set.ch;
-                    chs.remove(&ch);
-                }
-
-                play.at += play.len;
-                rem -= play.len;
+            let mut set = open.take().unwrap_or_else(|| PlaySet::new(play.ch, *at));
+            set.len += play.len;
+            set.plays.push(play.clone());
+            if set.plays.len() == size {
+                close(set);
+            } else {
+                open = Some(set);
             }
-        }
 
-        for (_, set) in chs {
-            merged.insert((set.at, set.ch), set.clone());
+            play.at += play.len;
+            rem -= play.len;
         }
+    }
 
-        Merged(merged)
+    if let Some(set) = open {
+        close(set);
     }
+
+    merged
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, new)]
@@ -340,32 +877,180 @@ struct Processor {
     #[new(default)]
     time_base: u64,
     #[new(default)]
-    ch: u8,
-    #[new(default)]
+    ch: Channel,
+    #[new(value = "Raw::new()")]
     raw: Raw,
+    #[new(default)]
+    song: Song,
+    group_by: GroupBy,
+    #[new(default)]
+    sequence: Option<Channel>,
+    /// The number of sequences of format 2 files.
+    #[new(default)]
+    sequences: Option<Channel>,
+    #[new(default)]
+    ignored: Ignored,
+    #[new(default)]
+    parts: BTreeMap<Channel, stats::Part>,
 }
 
 impl Processor {
-    fn finalize(&self, size: usize, maxlen: Time) -> Merged {
-        self.raw.tempoed(self.time_base).merged(size, maxlen)
+    /// Fails if the sequence selected isn't in the file.
+    fn checked(self) -> Result<Self> {
+        match (self.sequence, self.sequences) {
+            (Some(s), Some(n)) if s == 0 || s > n => Err(anyhow!(
+                "Sequence {} is out of range; the file has {} sequences from 1",
+                s,
+                n
+            )),
+            _ => Ok(self),
+        }
+    }
+
+    fn ignored_report(&self, opts: &Options) -> Vec<String> {
+        self.ignored.report(&self.song, |ch| opts.allows(ch))
+    }
+
+    fn stats(&self, opts: &Options) -> Stats {
+        let mut parts = self.parts.clone();
+        for play in self.tempoed(opts).0.values().flatten() {
+            if play.note != Note::NoSound {
+                parts.entry(play.ch).or_default().sound(play.at, play.len);
+            }
+        }
+
+        let tempos = self
+            .raw
+            .tempo_map(self.time_base)
+            .into_iter()
+            .map(|(tick, ms, tempo)| {
+                let (bar, beat) = self.song.bar_beat(tick);
+                let bpm = 60_000_000.0 / tempo.max(1) as f64;
+                (format!("{}:{}", bar, beat), ms, bpm)
+            })
+            .collect();
+
+        Stats {
+            parts: parts
+                .into_iter()
+                .filter(|(ch, _)| opts.allows(*ch))
+                .map(|(ch, part)| (ch, self.song.track(ch), part))
+                .collect(),
+            tempos,
+        }
+    }
+
+    /// The song with its tempo map at the speed.
+    fn timed_song(&self) -> Song {
+        let mut song = self.song.clone();
+        song.tempos = self.raw.tempo_map(self.time_base);
+        // Until the first tempo change, the default tempo plays at the speed too.
+        if song.tempos.first().is_none_or(|(tick, _, _)| *tick > 0) {
+            song.tempos.insert(0, (0, 0, self.raw.scaled(500000)));
+        }
+        song
+    }
+
+    fn tempoed(&self, opts: &Options) -> Tempoed {
+        let song = self.timed_song();
+
+        let tempoed = self.raw.tempoed(self.time_base).filtered(opts);
+        tempoed.check(&song, opts.max_op_len);
+        tempoed
+    }
+
+    fn finalize(&self, opts: &Options, size: usize, maxlen: Time) -> Merged {
+        self.tempoed(opts)
+            .shortened(opts.gap)
+            .merged(size, maxlen, opts.gap)
     }
 
     fn finalize_mixed(
         &self,
+        opts: &Options,
         size: usize,
         maxlen: Time,
         unit: u64,
-        rules: &[(u8, Vec<u8>)],
+        rules: &[(Channel, Vec<Channel>)],
     ) -> Merged {
-        self.raw
-            .tempoed(self.time_base)
+        self.tempoed(opts)
+            .shortened(opts.gap)
             .mixed(unit, rules)
-            .merged(size, maxlen)
+            .merged(size, maxlen, opts.gap)
+    }
+}
+
+/// Converts the MIDI note number into the note of the cube, raising notes below its range by octaves.
+pub fn cube_note(note: u8) -> Note {
+    let mut n = note;
+    while n < 12 {
+        n += 12;
+    }
+    (n - 12).try_into().unwrap()
+}
+
+impl Processor {
+    /// Returns true if the events of the current track are ignored.
+    fn skipped(&self) -> bool {
+        matches!(self.sequence, Some(s) if s != self.ch)
+    }
+
+    /// Returns the channel of the plan for the MIDI channel of the event.
+    fn part(&self, ch: u8) -> Channel {
+        match self.group_by {
+            GroupBy::Channel => ch as Channel,
+            _ => self.ch,
+        }
+    }
+
+    /// Converts the MIDI note number, raising notes below the range of the cube by octaves.
+    ///
+    /// Warns about the raised notes when they start.
+    fn note(&mut self, delta: u32, part: Channel, note: u8, start: bool) -> Note {
+        let converted = cube_note(note);
+
+        if note < 12 && start {
+            self.ignore(Feature::OutOfRange, part, delta);
+            warn!(
+                "{} on {} is below the range of the cube; raised to {}",
+                song::midi_note_name(note),
+                self.song.position(part, self.raw.at + delta as u64),
+                song::note_name(converted)
+            );
+        }
+
+        converted
+    }
+
+    fn ignore(&mut self, feature: Feature, part: Channel, delta: u32) {
+        self.ignored.push(feature, part, self.raw.at + delta as u64);
     }
 }
 
 impl Handler for Processor {
-    fn header(&mut self, _format: u16, _track: u16, time_base: u16) {
+    fn header(&mut self, format: u16, track: u16, time_base: u16) {
+        if format == 2 {
+            self.sequences = Some(track as Channel);
+            match self.sequence {
+                Some(s) => info!("Playing sequence {} of {}", s, track),
+                None => {
+                    info!("Playing {} sequences one after another", track);
+                    self.raw.sequential = true;
+                }
+            }
+        } else if self.sequence.take().is_some() {
+            warn!("Only format 2 files have sequences; playing the whole file");
+        }
+
+        if self.group_by == GroupBy::Auto {
+            // Format 0 files have every voice in a single track, and so do the
+            // sequences of format 2 files mostly.
+            self.group_by = if format != 1 {
+                GroupBy::Channel
+            } else {
+                GroupBy::Track
+            };
+        }
         debug!("time_base: {:04x} {}", time_base, time_base);
 
         if time_base & 0x8000 > 0 {
@@ -374,6 +1059,7 @@ impl Handler for Processor {
         } else {
             self.time_base = time_base as u64;
         }
+        self.song.time_base = self.time_base;
     }
 
     fn meta_event(&mut self, delta: u32, event: &MetaEvent, data: &Vec<u8>) {
@@ -381,6 +1067,9 @@ impl Handler for Processor {
             "{}: delta time: {:>4}, meta event: {}",
             self.ch, delta, event
         );
+        if self.skipped() {
+            return;
+        }
 
         match event {
             MetaEvent::SetTempo => {
@@ -391,6 +1080,44 @@ impl Handler for Processor {
                 }
                 self.raw.tempo(self.ch, delta as u64, tempo);
             }
+            MetaEvent::SequenceOrTrackName => {
+                let name = String::from_utf8_lossy(data).trim().to_string();
+                // Names of tracks don't tell the voices on channels.
+                if !name.is_empty() && self.group_by == GroupBy::Track {
+                    self.song.names.insert(self.ch, name);
+                }
+                self.raw.update(delta as u64);
+            }
+            MetaEvent::InstrumentName => {
+                let name = String::from_utf8_lossy(data).trim().to_string();
+                if !name.is_empty() && self.group_by == GroupBy::Track {
+                    self.parts.entry(self.ch).or_default().name = Some(name);
+                }
+                self.raw.update(delta as u64);
+            }
+            MetaEvent::Marker | MetaEvent::CuePoint => {
+                let kind = match event {
+                    MetaEvent::Marker => MarkKind::Marker,
+                    _ => MarkKind::CuePoint,
+                };
+                let text = String::from_utf8_lossy(data).trim().to_string();
+                self.raw.text(self.ch, delta as u64, kind, text);
+            }
+            // The spaces and line breaks of lyrics join the syllables.
+            MetaEvent::Lyric | MetaEvent::TextEvent => {
+                let kind = match event {
+                    MetaEvent::Lyric => MarkKind::Lyric,
+                    _ => MarkKind::Text,
+                };
+                let text = String::from_utf8_lossy(data).to_string();
+                self.raw.text(self.ch, delta as u64, kind, text);
+            }
+            MetaEvent::TimeSignature if data.len() >= 2 => {
+                self.raw.update(delta as u64);
+                self.song
+                    .signatures
+                    .push((self.raw.at, data[0] as u64, 1 << data[1].min(6)));
+            }
             _ => {
                 self.raw.update(delta as u64);
             }
@@ -402,30 +1129,54 @@ impl Handler for Processor {
             "{}: delta time: {:>4}, MIDI event: {}",
             self.ch, delta, event
         );
+        if self.skipped() {
+            return;
+        }
 
         match event {
-            MidiEvent::NoteOn {
-                ch: _,
-                note,
-                velocity,
-            } => {
+            MidiEvent::NoteOn { ch, note, velocity } => {
+                let part = self.part(*ch);
+                if *ch == DRUMS && *velocity > 0 {
+                    self.ignore(Feature::Drums, part, delta);
+                }
+                let number = *note;
+                let note = self.note(delta, part, number, *velocity > 0);
                 if *velocity > 0 {
-                    self.raw
-                        .on(self.ch, delta as u64, (*note - 12).try_into().unwrap());
+                    self.raw.vel = *velocity;
+                    self.raw.on(part, delta as u64, note);
+                    let held = self.raw.held(part);
+                    self.parts.entry(part).or_default().on(number, held);
                 } else {
-                    self.raw
-                        .off(self.ch, delta as u64, (*note - 12).try_into().unwrap());
+                    self.raw.off(part, delta as u64, note);
                 }
             }
             MidiEvent::NoteOff {
-                ch: _,
+                ch,
                 note,
                 velocity: _,
             } => {
-                self.raw
-                    .off(self.ch, delta as u64, (*note - 12).try_into().unwrap());
+                let part = self.part(*ch);
+                let note = self.note(delta, part, *note, false);
+                self.raw.off(part, delta as u64, note);
             }
-            _ => {
+            e => {
+                let ignored = match e {
+                    MidiEvent::PitchBendChange { ch, .. } => Some((Feature::PitchBend, *ch)),
+                    MidiEvent::ControlChange { ch, .. } => Some((Feature::ControlChange, *ch)),
+                    MidiEvent::ProgramChange { ch, .. } => Some((Feature::ProgramChange, *ch)),
+                    MidiEvent::PolyphonicKeyPressure { ch, .. }
+                    | MidiEvent::ChannelPressure { ch, .. } => Some((Feature::Aftertouch, *ch)),
+                    _ => None,
+                };
+                if let Some((feature, ch)) = ignored {
+                    let part = self.part(ch);
+                    self.ignore(feature, part, delta);
+                }
+                if let MidiEvent::ProgramChange { ch, program } = e {
+                    let part = self.part(*ch);
+                    let part = self.parts.entry(part).or_default();
+                    part.program = part.program.or(Some(*program));
+                }
                 self.raw.update(delta as u64);
             }
         }
@@ -433,36 +1184,481 @@ impl Handler for Processor {
 
     fn sys_ex_event(&mut self, delta: u32, _event: &SysExEvent, _data: &Vec<u8>) {
         debug!("{}: ex event: {:>4}", self.ch, delta);
-        self.raw.update(delta as u64);
+        if !self.skipped() {
+            self.ignore(Feature::SysEx, self.ch, delta);
+            self.raw.update(delta as u64);
+        }
     }
 
     fn track_change(&mut self) {
-        self.raw.end(self.ch);
+        self.raw.end();
         self.ch += 1;
     }
 }
 
-fn proc<P: AsRef<Path>>(p: P) -> Result<Processor> {
-    let mut proc = Processor::new();
-    let mut reader = Reader::new(&mut proc, p.as_ref()).map_err(|e| anyhow!("{}", e))?;
-    let _ = reader.read();
-    Ok(proc)
+fn proc<P: AsRef<Path>>(p: P, opts: &Options) -> Result<Processor> {
+    let mut proc = Processor::new(opts.group_by);
+    proc.raw.speed = opts.speed;
+    proc.sequence = opts.sequence;
+
+    match opts.parser {
+        Parser::Ghakuf => {
+            let mut reader = Reader::new(&mut proc, p.as_ref()).map_err(|e| anyhow!("{}", e))?;
+            let res = reader.read().map_err(|e| e.to_string());
+
+            if let Err(e) = res {
+                // ghakuf doesn't tell where; locate the error with the built-in reader.
+                let e = match std::fs::read(p.as_ref()).map(|d| smf::check(&d)) {
+                    Ok(Err(located)) => anyhow!("{} ({})", e, located),
+                    _ => anyhow!("{} in track {}", e, proc.ch),
+                };
+                if !opts.lenient {
+                    return Err(e);
+                }
+                warn!("{}; playing the tracks read so far", e);
+            }
+        }
+        Parser::Builtin => {
+            let data = std::fs::read(p.as_ref())
+                .context(format!("Couldn't read {}", p.as_ref().display()))?;
+            smf::read(&data, &mut proc, opts.lenient)?;
+        }
+    }
+    proc.checked()
 }
 
-pub fn load<P: AsRef<Path>>(p: P) -> Result<BTreeMap<(Time, Channel), PlaySet>> {
-    Ok(proc(p)?.finalize(59, 2550).0)
+pub fn load<P: AsRef<Path>>(p: P, opts: &Options) -> Result<Plan> {
+    Ok(proc(p, opts)?
+        .finalize(opts, opts.max_ops, opts.max_op_len)
+        .0)
 }
 
-pub fn load_mixed<P: AsRef<Path>>(
-    p: P,
-    unit: u64,
-    rules: &[(u8, Vec<u8>)],
-) -> Result<BTreeMap<(Time, Channel), PlaySet>> {
-    Ok(proc(p)?.finalize_mixed(59, 2550, unit, rules).0)
+/// Describes the MIDI features of the played parts that the cubes can't play.
+pub fn ignored<P: AsRef<Path>>(p: P, opts: &Options) -> Result<Vec<String>> {
+    Ok(proc(p, opts)?.ignored_report(opts))
 }
 
-#[cfg(test)]
-mod test {
+/// Analyzes the notes of each part and the tempo changes.
+pub fn stats<P: AsRef<Path>>(p: P, opts: &Options) -> Result<Stats> {
+    Ok(proc(p, opts)?.stats(opts))
+}
+
+/// What `list` tells of a file, read once.
+pub struct Listing {
+    /// The parts played, in order.
+    pub parts: Vec<Channel>,
+    /// The plays of each part before they are merged into play sets.
+    pub plays: EventMap,
+    pub stats: Stats,
+    pub ignored: Vec<String>,
+}
+
+/// Reads the file for `list`.
+pub fn listing<P: AsRef<Path>>(p: P, opts: &Options) -> Result<Listing> {
+    let proc = proc(p, opts)?;
+    let plan = proc.finalize(opts, opts.max_ops, opts.max_op_len).0;
+    let mut parts: Vec<_> = plan.keys().map(|(_, ch)| *ch).collect();
+    parts.sort();
+    parts.dedup();
+
+    Ok(Listing {
+        parts,
+        plays: proc.tempoed(opts).0,
+        stats: proc.stats(opts),
+        ignored: proc.ignored_report(opts),
+    })
+}
+
+/// Returns the initial tempo of the file in bpm.
+pub fn bpm<P: AsRef<Path>>(p: P, opts: &Options) -> Result<f64> {
+    let proc = proc(p, opts)?;
+    let tempo = proc
+        .raw
+        .iter()
+        .find_map(|(_, e)| match e {
+            Event::Tempo(t) => Some(t.tempo),
+            _ => None,
+        })
+        .unwrap_or(500000);
+    Ok(60_000_000.0 / tempo.max(1) as f64)
+}
+
+/// Returns the tempo map of the file at the speed, as (quarter notes, msec, msec a quarter
+/// note) from the start, in the msec the notes are played at.
+pub fn tempos<P: AsRef<Path>>(p: P, opts: &Options) -> Result<Vec<(f64, f64, f64)>> {
+    let proc = proc(p, opts)?;
+    let time_base = proc.time_base.max(1) as f64;
+    let mut map = vec![(0.0, 0.0, proc.raw.scaled(500000) as f64 / 1000.0)];
+    for (tick, ms, tempo) in proc.raw.tempo_map(proc.time_base) {
+        let change = (tick as f64 / time_base, ms as f64, tempo as f64 / 1000.0);
+        match tick {
+            0 => map[0] = change,
+            _ => map.push(change),
+        }
+    }
+    Ok(map)
+}
+
+/// Reads the texts in the song such as markers and lyrics in the order of time.
+pub fn texts<P: AsRef<Path>>(p: P, opts: &Options) -> Result<Vec<Mark>> {
+    let proc = proc(p, opts)?;
+    Ok(proc.raw.tempoed(proc.time_base).1)
+}
+
+/// Reads the markers and the cue points in the order of time.
+pub fn marks<P: AsRef<Path>>(p: P, opts: &Options) -> Result<Vec<Mark>> {
+    let mut marks = texts(p, opts)?;
+    marks.retain(|m| matches!(m.kind, MarkKind::Marker | MarkKind::CuePoint));
+    Ok(marks)
+}
+
+/// Returns the time signature at the start of the file.
+pub fn signature<P: AsRef<Path>>(p: P, opts: &Options) -> Result<(u64, u64)> {
+    Ok(proc(p, opts)?.song.signature())
+}
+
+/// Returns the beats of the file before `end` msec as (msec, whether it starts a bar).
+pub fn beats<P: AsRef<Path>>(p: P, opts: &Options, end: Time) -> Result<Vec<(Time, bool)>> {
+    Ok(proc(p, opts)?.timed_song().beats(end))
+}
+
+/// Loads the plays of each channel before they are merged into play sets.
+pub fn load_plays<P: AsRef<Path>>(p: P, opts: &Options) -> Result<EventMap> {
+    Ok(proc(p, opts)?.tempoed(opts).0)
+}
+
+pub fn load_mixed<P: AsRef<Path>>(
+    p: P,
+    opts: &Options,
+    unit: u64,
+    rules: &[(Channel, Vec<Channel>)],
+) -> Result<Plan> {
+    Ok(proc(p, opts)?
+        .finalize_mixed(opts, opts.max_ops, opts.max_op_len, unit, rules)
+        .0)
+}
+
+/// Converts a file a window of time after another, so that the first can be played
+/// while the others are converted.
+pub struct Windows {
+    tempoed: Tempoed,
+    opts: Options,
+    unit: u64,
+    rules: Vec<(Channel, Vec<Channel>)>,
+    len: Time,
+    at: Time,
+    end: Time,
+    /// The longest play, to find those sounding in a window.
+    longest: Time,
+}
+
+impl Windows {
+    fn new(
+        tempoed: Tempoed,
+        opts: &Options,
+        unit: u64,
+        rules: &[(Channel, Vec<Channel>)],
+        len: Time,
+    ) -> Self {
+        let plays = tempoed.0.values().flatten();
+        let end = plays.clone().map(|p| p.at + p.len).max().unwrap_or(0);
+        let longest = plays.map(|p| p.len).max().unwrap_or(0);
+        Self {
+            tempoed,
+            opts: opts.clone(),
+            unit,
+            rules: rules.to_vec(),
+            len,
+            at: 0,
+            end,
+            longest,
+        }
+    }
+
+    /// The parts the plans are made of.
+    pub fn parts(&self) -> BTreeSet<Channel> {
+        if self.rules.is_empty() {
+            self.tempoed.0.keys().map(|(_, ch)| *ch).collect()
+        } else {
+            self.rules.iter().map(|(as_ch, _)| *as_ch).collect()
+        }
+    }
+
+    /// The plays sounding in the window, started before it or not.
+    fn sounding(&self, from: Time, to: Time) -> EventMap {
+        let since = from.saturating_sub(self.longest);
+        let mut sounding = EventMap::new();
+        for ((at, ch), plays) in self.tempoed.0.range((since, 0)..(to, 0)) {
+            let plays: Vec<_> = plays
+                .iter()
+                .filter(|p| p.at + p.len > from)
+                .cloned()
+                .collect();
+            if !plays.is_empty() {
+                sounding.insert((*at, *ch), plays);
+            }
+        }
+        sounding
+    }
+}
+
+impl Iterator for Windows {
+    type Item = Plan;
+
+    fn next(&mut self) -> Option<Plan> {
+        if self.at >= self.end {
+            return None;
+        }
+        let (from, to) = (self.at, self.at + self.len);
+        self.at = to;
+        let starting: EventMap = self
+            .tempoed
+            .0
+            .range((from, 0)..(to, 0))
+            .map(|(k, v)| (*k, v.clone()))
+            .collect();
+        let events = if self.rules.is_empty() {
+            starting
+        } else {
+            let sounding = self.sounding(from, to);
+            let to = Some(to).filter(|to| *to < self.end);
+            let mut mixed = EventMap::new();
+            for (as_ch, chs) in &self.rules {
+                // A part of a channel takes the plays as they are, by their start.
+                let orig = if chs.len() == 1 { &starting } else { &sounding };
+                mix(&mut mixed, orig, self.unit, (*as_ch, chs), from, to);
+            }
+            mixed
+        };
+        let opts = &self.opts;
+        Some(
+            Tempoed(events, vec![])
+                .merged(opts.max_ops, opts.max_op_len, opts.gap)
+                .0,
+        )
+    }
+}
+
+/// Reads the file to convert it a window of `len` msec after another, mixed by the rules
+/// if any.
+pub fn windows<P: AsRef<Path>>(
+    p: P,
+    opts: &Options,
+    unit: u64,
+    rules: &[(Channel, Vec<Channel>)],
+    len: Time,
+) -> Result<Windows> {
+    let tempoed = proc(p, opts)?.tempoed(opts).shortened(opts.gap);
+    Ok(Windows::new(tempoed, opts, unit, rules, len))
+}
+
+/// Maps the items on as many threads as there are cores, keeping them in order.
+fn parallel<T: Send, R: Send>(items: Vec<T>, f: impl Fn(T) -> R + Sync) -> Vec<R> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    parallel_on(threads, items, f)
+}
+
+fn parallel_on<T: Send, R: Send>(
+    threads: usize,
+    items: Vec<T>,
+    f: impl Fn(T) -> R + Sync,
+) -> Vec<R> {
+    if threads < 2 || items.len() < 2 {
+        return items.into_iter().map(f).collect();
+    }
+    let per = items.len().div_ceil(threads);
+    let mut items = items.into_iter().peekable();
+    let mut chunks = vec![];
+    while items.peek().is_some() {
+        chunks.push(items.by_ref().take(per).collect::<Vec<_>>());
+    }
+    let f = &f;
+    std::thread::scope(|s| {
+        let threads: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| s.spawn(move || chunk.into_iter().map(f).collect::<Vec<_>>()))
+            .collect();
+        threads
+            .into_iter()
+            .flat_map(|t| t.join().expect("A conversion thread panicked"))
+            .collect()
+    })
+}
+
+/// The channels of the layered files are numbered apart by this.
+pub const LAYER: Channel = 1000;
+
+/// Puts the channels of each file onto one timeline, numbering the channel `ch` of the
+/// file `i` as `i * LAYER + ch`. Fails if a channel can't be numbered so.
+fn layered(files: Vec<Tempoed>) -> Result<Tempoed> {
+    let mut events = EventMap::new();
+    let mut marks = vec![];
+    for (i, file) in files.into_iter().enumerate() {
+        for ((at, ch), plays) in file.0 {
+            if ch >= LAYER {
+                return Err(anyhow!("Channel {} of file {} is out of range", ch, i));
+            }
+            let ch = i
+                .try_into()
+                .ok()
+                .and_then(|i: Channel| i.checked_mul(LAYER))
+                .and_then(|i| i.checked_add(ch))
+                .ok_or_else(|| anyhow!("Too many files to layer; file {} is out of range", i))?;
+            let plays = plays.into_iter().map(|p| Play { ch, ..p });
+            events.entry((at, ch)).or_default().extend(plays);
+        }
+        marks.extend(file.1);
+    }
+    marks.sort_by_key(|m| m.at);
+    Ok(Tempoed(events, marks))
+}
+
+/// Loads the files layered onto one timeline, mixed by the rules on the channels of
+/// the files numbered as in [`layered`].
+pub fn load_layered<P: AsRef<Path>>(
+    files: &[P],
+    opts: &Options,
+    unit: u64,
+    rules: &[(Channel, Vec<Channel>)],
+) -> Result<Plan> {
+    // The tracks muted or soloed are numbered as in the layers.
+    let each = Options {
+        mute: vec![],
+        solo: vec![],
+        ..opts.clone()
+    };
+    let files = files
+        .iter()
+        .map(|p| Ok(proc(p, &each)?.tempoed(&each).shortened(opts.gap)))
+        .collect::<Result<_>>()?;
+    Ok(layered(files)?
+        .filtered(opts)
+        .mixed(unit, rules)
+        .merged(opts.max_ops, opts.max_op_len, opts.gap)
+        .0)
+}
+
+/// Returns the end of the last play set of the plan in msec.
+pub fn end(plan: &Plan) -> Time {
+    plan.values().map(|s| s.at + s.len).max().unwrap_or(0)
+}
+
+/// Returns the end of the last note of the plan in msec, leaving out the rests after it.
+pub fn last_note(plan: &Plan) -> Time {
+    plan.values()
+        .flat_map(|s| s.unrolled())
+        .filter(|p| p.note != Note::NoSound)
+        .map(|p| p.at + p.len)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Splits the plan into segments of at least `len` msec.
+///
+/// A segment is only cut where every channel is silent, so that no play set
+/// is interrupted by the pause between segments.
+pub fn segments(plan: Plan, len: Time) -> Vec<Plan> {
+    let mut segs = vec![];
+    let mut seg = BTreeMap::new();
+    let mut seg_at = 0;
+    let mut end = 0;
+
+    for ((at, ch), set) in plan {
+        if !seg.is_empty() && at >= end && at - seg_at >= len {
+            segs.push(std::mem::take(&mut seg));
+            seg_at = at;
+        }
+        end = end.max(set.at + set.len);
+        seg.insert((at, ch), set);
+    }
+
+    if !seg.is_empty() {
+        segs.push(seg);
+    }
+
+    segs
+}
+
+/// Returns how many times the pattern of the given length repeats from the start.
+fn repeats(plays: &[Play], len: usize) -> usize {
+    let same = |a: &Play, b: &Play| a.note == b.note && a.len == b.len;
+    let pattern = &plays[..len];
+
+    plays
+        .chunks_exact(len)
+        .take(u8::MAX as usize)
+        .take_while(|c| c.iter().zip(pattern).all(|(a, b)| same(a, b)))
+        .count()
+}
+
+/// Folds repeated patterns into play sets using the repeat count of the cube.
+///
+/// A pattern is only folded when it covers more operations than a single play
+/// set can hold, so that folding never costs more writes than it saves.
+pub fn folded(plan: Plan, size: usize) -> Plan {
+    let mut runs: BTreeMap<Channel, Vec<Vec<Play>>> = BTreeMap::new();
+    let mut ends: HashMap<Channel, Time> = HashMap::new();
+
+    // Join the play sets played back-to-back.
+    for ((at, ch), set) in plan {
+        let run = runs.entry(ch).or_default();
+        match run.last_mut() {
+            Some(last) if set.repeat == 1 && ends.get(&ch) == Some(&at) => last.extend(set.plays),
+            _ => run.push(set.plays),
+        }
+        ends.insert(ch, set.at + set.len);
+    }
+
+    let mut folded = BTreeMap::new();
+    let mut insert = |set: PlaySet| {
+        folded.insert((set.at, set.ch), set);
+    };
+
+    for (ch, runs) in runs {
+        for plays in runs {
+            let mut set: Option<PlaySet> = None;
+            let mut i = 0;
+
+            while i < plays.len() {
+                let rest = &plays[i..];
+                let best = (1..=size.min(rest.len() / 2))
+                    .map(|len| (len, repeats(rest, len)))
+                    .filter(|(len, r)| *r >= 2 && len * r > size)
+                    .max_by_key(|(len, r)| (len * r, std::cmp::Reverse(*len)));
+
+                if let Some((len, r)) = best {
+                    if let Some(set) = set.take() {
+                        insert(set);
+                    }
+                    let mut fold = PlaySet::new(ch, rest[0].at);
+                    fold.plays = rest[..len].to_vec();
+                    fold.len = fold.plays.iter().map(|p| p.len).sum::<Time>() * r as Time;
+                    fold.repeat = r as u8;
+                    insert(fold);
+                    i += len * r;
+                    continue;
+                }
+
+                let s = set.get_or_insert_with(|| PlaySet::new(ch, rest[0].at));
+                s.len += rest[0].len;
+                s.plays.push(rest[0].clone());
+                if s.plays.len() == size {
+                    insert(set.take().unwrap());
+                }
+                i += 1;
+            }
+
+            if let Some(set) = set {
+                insert(set);
+            }
+        }
+    }
+
+    folded
+}
+
+#[cfg(test)]
+mod test {
     use super::*;
 
     #[test]
@@ -475,12 +1671,14 @@ mod test {
         r.off(0, 0, Note::D3);
         r.off(0, 0, Note::E3);
 
-        let es: Vec<_> = r.events.into_iter().map(|((at, _), v)| (at, v)).collect();
+        let es: Vec<_> = r.iter().map(|((at, _), v)| (*at, v.clone())).collect();
         assert_eq!(
             es,
             vec![
                 (100u64, Event::Start(Start::new(0, Note::C3))),
+                (300u64, Event::Stop(Stop::new(0))),
                 (300u64, Event::Start(Start::new(0, Note::D3))),
+                (400u64, Event::Stop(Stop::new(0))),
                 (400u64, Event::Start(Start::new(0, Note::E3))),
                 (700u64, Event::Stop(Stop::new(0)))
             ]
@@ -501,7 +1699,10 @@ mod test {
         // 500msec / 100 = 5msec <=> 1
         let t = r.tempoed(100);
 
-        let es: Vec<_> = t.0.into_iter().map(|((at, _), v)| (at, v)).collect();
+        let es: Vec<_> =
+            t.0.into_iter()
+                .flat_map(|((at, _), v)| v.into_iter().map(move |p| (at, p)))
+                .collect();
         assert_eq!(
             es,
             vec![
@@ -512,8 +1713,327 @@ mod test {
         );
     }
 
+    #[test]
+    fn simultaneous() {
+        // Note off, tempo change and note on on the same tick of the same track.
+        let mut r = Raw::new();
+        r.tempo(0, 0, 500000);
+        r.on(0, 0, Note::C3);
+        r.off(0, 100, Note::C3);
+        r.tempo(0, 0, 250000);
+        r.on(0, 0, Note::D3);
+        r.off(0, 100, Note::D3);
+
+        let plays: Vec<_> = r.tempoed(100).0.into_values().flatten().collect();
+        assert_eq!(
+            plays,
+            vec![
+                Play::new(0, 0, 500, Note::C3),
+                Play::new(0, 500, 250, Note::D3)
+            ]
+        );
+    }
+
+    #[test]
+    fn channels() {
+        // Voices on different channels of a single track.
+        let mut r = Raw::new();
+        r.on(0, 0, Note::C3);
+        r.on(9, 0, Note::E3);
+        r.off(0, 100, Note::C3);
+        r.off(9, 0, Note::E3);
+        r.end();
+
+        let plays: Vec<_> = r.tempoed(100).0.into_values().flatten().collect();
+        assert_eq!(
+            plays,
+            vec![
+                Play::new(0, 0, 500, Note::C3),
+                Play::new(9, 0, 500, Note::E3)
+            ]
+        );
+
+        assert_eq!("channel".parse::<GroupBy>().unwrap(), GroupBy::Channel);
+        assert_eq!("track".parse::<GroupBy>().unwrap(), GroupBy::Track);
+        assert_eq!("auto".parse::<GroupBy>().unwrap(), GroupBy::Auto);
+        assert!("voice".parse::<GroupBy>().is_err());
+    }
+
+    #[test]
+    fn groups() {
+        let path =
+            std::env::temp_dir().join(format!("toio-midi-groups-{}.mid", std::process::id()));
+        let chs = |tracks: usize, format: u8, parser| -> Vec<Channel> {
+            let mut tracks: Vec<_> = (0..tracks)
+                .map(|i| smf::Track::new(i.to_string()))
+                .collect();
+            tracks.last_mut().unwrap().notes = vec![(0, 480, 60, 100)];
+            smf::write(&path, 480, 500_000, &tracks).unwrap();
+            let mut data = std::fs::read(&path).unwrap();
+            data[9] = format;
+            std::fs::write(&path, data).unwrap();
+
+            let mut opts = Options::new();
+            opts.parser = parser;
+            let chs = proc(&path, &opts)
+                .unwrap()
+                .tempoed(&opts)
+                .0
+                .into_keys()
+                .map(|(_, ch)| ch)
+                .collect();
+            std::fs::remove_file(&path).unwrap();
+            chs
+        };
+
+        for parser in [Parser::Ghakuf, Parser::Builtin] {
+            // Tracks count from 1, beyond the 255th track.
+            assert_eq!(chs(300, 1, parser), vec![300]);
+            // Format 0 files are split by MIDI channel.
+            assert_eq!(chs(1, 0, parser), vec![0]);
+        }
+    }
+
+    #[test]
+    fn polyphony() {
+        let mut p = Processor::new(GroupBy::Track);
+        p.header(1, 1, 480);
+        for (delta, note, velocity) in [(0, 60, 100), (0, 64, 100), (240, 60, 0), (0, 67, 90)] {
+            p.midi_event(
+                delta,
+                &MidiEvent::NoteOn {
+                    ch: 0,
+                    note,
+                    velocity,
+                },
+            );
+        }
+        p.midi_event(0, &MidiEvent::ProgramChange { ch: 0, program: 40 });
+        let part = &p.parts[&0];
+        assert_eq!(
+            (part.notes, part.range, part.polyphony),
+            (3, Some((60, 67)), 2)
+        );
+        assert_eq!(part.instrument().unwrap(), "Violin");
+
+        p.meta_event(0, &MetaEvent::InstrumentName, &b"Solo Violin".to_vec());
+        assert_eq!(p.parts[&0].instrument().unwrap(), "Solo Violin");
+    }
+
+    #[test]
+    fn ignored() {
+        let mut p = Processor::new(GroupBy::Channel);
+        p.header(0, 1, 480);
+        p.midi_event(0, &MidiEvent::PitchBendChange { ch: 1, data: 100 });
+        p.midi_event(
+            480,
+            &MidiEvent::NoteOn {
+                ch: DRUMS,
+                note: 36,
+                velocity: 100,
+            },
+        );
+        p.midi_event(
+            0,
+            &MidiEvent::NoteOn {
+                ch: 1,
+                note: 5,
+                velocity: 100,
+            },
+        );
+        p.sys_ex_event(480, &SysExEvent::new(0xf0), &vec![]);
+
+        assert_eq!(
+            p.ignored.report(&p.song, |ch| ch != DRUMS as Channel),
+            vec![
+                "1 pitch bends: track 1 at 1:1",
+                "1 system exclusive messages: track 0 at 1:3",
+                "1 notes raised into the range of the cube: track 1 at 1:2",
+            ]
+        );
+    }
+
+    #[test]
+    fn layered() {
+        let file = |ch, note| {
+            let mut events = EventMap::new();
+            events.insert((100, ch), vec![Play::new(ch, 100, 200, note)]);
+            Tempoed(events, vec![])
+        };
+        let t = super::layered(vec![
+            file(1, Note::C4),
+            file(1, Note::E4),
+            file(9, Note::G4),
+        ])
+        .unwrap();
+        let plays: Vec<_> = t.0.into_values().flatten().collect();
+        assert_eq!(
+            plays,
+            vec![
+                Play::new(1, 100, 200, Note::C4),
+                Play::new(1001, 100, 200, Note::E4),
+                Play::new(2009, 100, 200, Note::G4),
+            ]
+        );
+
+        let err = |files| super::layered(files).unwrap_err().to_string();
+        assert_eq!(
+            err(vec![file(1000, Note::C4)]),
+            "Channel 1000 of file 0 is out of range"
+        );
+        let mut files: Vec<_> = (0..66).map(|_| Tempoed(EventMap::new(), vec![])).collect();
+        files.push(file(1, Note::C4));
+        assert_eq!(
+            err(files),
+            "Too many files to layer; file 66 is out of range"
+        );
+    }
+
+    #[test]
+    fn marks() {
+        let mut p = Processor::new(GroupBy::Track);
+        p.header(1, 1, 480);
+        p.meta_event(0, &MetaEvent::SetTempo, &vec![0x07, 0xa1, 0x20]);
+        p.meta_event(960, &MetaEvent::Marker, &b"Verse 1".to_vec());
+        p.meta_event(480, &MetaEvent::CuePoint, &b" Solo ".to_vec());
+        p.meta_event(0, &MetaEvent::Lyric, &b"la \r".to_vec());
+        let marks = p.raw.tempoed(p.time_base).1;
+        assert_eq!(
+            marks,
+            vec![
+                Mark::new(1000, MarkKind::Marker, "Verse 1".into()),
+                Mark::new(1500, MarkKind::CuePoint, "Solo".into()),
+                Mark::new(1500, MarkKind::Lyric, "la \r".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn speed() {
+        let mut r = Raw::new();
+        r.speed = 200;
+        r.tempo(0, 0, 500000);
+        r.on(0, 100, Note::C3);
+        r.off(0, 100, Note::C3);
+
+        let plays: Vec<_> = r.tempoed(100).0.into_values().flatten().collect();
+        assert_eq!(plays, vec![Play::new(0, 250, 250, Note::C3)]);
+        assert_eq!(r.tempo_map(100), vec![(0, 0, 250000)]);
+
+        // Without a tempo event, the beats of the default tempo are at the speed too.
+        let mut p = Processor::new(GroupBy::Track);
+        p.raw.speed = 200;
+        p.header(1, 1, 480);
+        assert_eq!(
+            p.timed_song().beats(1000),
+            vec![(0, true), (250, false), (500, false), (750, false)]
+        );
+    }
+
+    #[test]
+    fn beats() {
+        let song = |speed: u64| {
+            let mut p = Processor::new(GroupBy::Track);
+            p.raw.speed = speed;
+            p.header(1, 1, 480);
+            p.meta_event(0, &MetaEvent::SetTempo, &vec![0x07, 0xa1, 0x20]);
+            p.meta_event(0, &MetaEvent::TimeSignature, &vec![3, 2, 24, 8]);
+            // Twice as fast from the second bar, which changes into 2/4 after two beats.
+            p.meta_event(1440, &MetaEvent::SetTempo, &vec![0x03, 0xd0, 0x90]);
+            p.meta_event(960, &MetaEvent::TimeSignature, &vec![2, 2, 24, 8]);
+            p.timed_song()
+        };
+        let beats = vec![
+            (0, true),
+            (500, false),
+            (1000, false),
+            (1500, true),
+            (1750, false),
+            (2000, true),
+            (2250, false),
+            (2500, true),
+        ];
+        assert_eq!(song(100).beats(2600), beats);
+
+        // Every beat comes sooner at the speed.
+        let fast: Vec<_> = beats.iter().map(|&(at, bar)| (at / 2, bar)).collect();
+        assert_eq!(song(200).beats(1300), fast);
+    }
+
+    #[test]
+    fn sequences() {
+        let starts = |sequence: Option<Channel>| -> Vec<(Time, Channel)> {
+            let mut p = Processor::new(GroupBy::Track);
+            p.sequence = sequence;
+            p.header(2, 2, 480);
+            for _ in 0..2 {
+                // The readers change the track at the start of every track.
+                p.track_change();
+                let on = MidiEvent::NoteOn {
+                    ch: 0,
+                    note: 60,
+                    velocity: 100,
+                };
+                let off = MidiEvent::NoteOff {
+                    ch: 0,
+                    note: 60,
+                    velocity: 0,
+                };
+                p.midi_event(0, &on);
+                p.midi_event(960, &off);
+            }
+            p.tempoed(&Options::new()).0.into_keys().collect()
+        };
+
+        // The sequences play in turn, or only the selected one is played.
+        assert_eq!(starts(None), vec![(0, 1), (1000, 2)]);
+        assert_eq!(starts(Some(2)), vec![(0, 2)]);
+
+        let checked = |sequence| {
+            let mut p = Processor::new(GroupBy::Track);
+            p.sequence = Some(sequence);
+            p.header(2, 2, 480);
+            p.checked().err().map(|e| e.to_string())
+        };
+        assert_eq!(checked(2), None);
+        assert_eq!(
+            checked(3).unwrap(),
+            "Sequence 3 is out of range; the file has 2 sequences from 1"
+        );
+        assert!(checked(0).is_some());
+    }
+
+    #[test]
+    fn velocity() {
+        let mut r = Raw::new();
+        r.tempo(0, 0, 500000);
+        r.vel = 40;
+        r.on(0, 0, Note::C3);
+        r.vel = 120;
+        r.on(0, 480, Note::E3);
+        r.off(0, 480, Note::E3);
+        r.off(0, 480, Note::C3);
+        r.end();
+
+        // The note sounding again keeps its own velocity.
+        let vels: Vec<_> = r
+            .tempoed(480)
+            .0
+            .values()
+            .flatten()
+            .map(|p| (p.note, p.vel))
+            .collect();
+        assert_eq!(vels, vec![(Note::C3, 40), (Note::E3, 120), (Note::C3, 40)]);
+    }
+
     fn p(ch: Channel, at: Time, len: Time, plays: Vec<Play>) -> PlaySet {
-        PlaySet { ch, at, len, plays }
+        PlaySet {
+            ch,
+            at,
+            len,
+            plays,
+            repeat: 1,
+        }
     }
 
     #[test]
@@ -529,12 +2049,12 @@ mod test {
             r.off(i, 300, Note::C3);
             r.off(i, 0, Note::D3);
             r.off(i, 0, Note::E3);
-            r.end(i);
+            r.end();
         }
 
         // 1 = 5msec
         // Max is large enough
-        let t = r.tempoed(100).merged(1000, 2500);
+        let t = r.tempoed(100).merged(1000, 2500, Gap::Rest);
 
         let es: Vec<_> = t.0.into_iter().map(|((at, _), v)| (at, v)).collect();
         assert_eq!(
@@ -556,4 +2076,422 @@ mod test {
                 .collect::<Vec<_>>()
         );
     }
+
+    #[test]
+    fn merged_gap() {
+        let mut r = Raw::new();
+        r.on(0, 100, Note::C3);
+        r.off(0, 100, Note::C3);
+        r.on(0, 100, Note::D3);
+        r.off(0, 100, Note::D3);
+
+        // 1 = 5msec
+        let plays = |gap| -> Vec<_> {
+            r.tempoed(100)
+                .shortened(gap)
+                .merged(1000, 2500, gap)
+                .0
+                .into_values()
+                .flat_map(|s| s.plays)
+                .collect()
+        };
+
+        assert_eq!(
+            plays(Gap::Rest),
+            vec![
+                Play::new(0, 500, 500, Note::C3),
+                Play::new(0, 1000, 500, Note::NoSound),
+                Play::new(0, 1500, 500, Note::D3),
+            ]
+        );
+        assert_eq!(
+            plays(Gap::Legato),
+            vec![
+                Play::new(0, 500, 1000, Note::C3),
+                Play::new(0, 1500, 500, Note::D3),
+            ]
+        );
+        assert_eq!(
+            plays(Gap::Staccato(100)),
+            vec![
+                Play::new(0, 500, 400, Note::C3),
+                Play::new(0, 900, 600, Note::NoSound),
+                Play::new(0, 1500, 400, Note::D3),
+            ]
+        );
+
+        // A long note split on merge, or mixed with another, is shortened at its end only.
+        let mut events = EventMap::new();
+        events.insert((0, 0), vec![Play::new(0, 0, 3000, Note::C3)]);
+        events.insert((0, 1), vec![Play::new(1, 0, 200, Note::E4)]);
+        events.insert((2900, 1), vec![Play::new(1, 2900, 200, Note::G4)]);
+        let shortened = Tempoed(events, vec![]).shortened(Gap::Staccato(100));
+        let plays: Vec<_> = shortened
+            .merged(1000, 2500, Gap::Staccato(100))
+            .0
+            .into_values()
+            .flat_map(|s| s.plays)
+            .filter(|p| p.ch == 0)
+            .collect();
+        assert_eq!(
+            plays,
+            vec![
+                Play::new(0, 0, 2500, Note::C3),
+                Play::new(0, 2500, 400, Note::C3),
+            ]
+        );
+        let rests: Vec<_> = shortened
+            .mixed(20, &[(5, vec![0, 1])])
+            .merged(1000, 2500, Gap::Staccato(100))
+            .0
+            .into_values()
+            .flat_map(|s| s.plays)
+            .filter(|p| p.note == Note::NoSound)
+            .map(|p| (p.at, p.len))
+            .collect();
+        assert_eq!(rests, vec![]);
+
+        // Notes the gap takes entirely are dropped rather than sounded, and empty ones kept.
+        let mut events = EventMap::new();
+        events.insert((0, 0), vec![Play::new(0, 0, 80, Note::C3)]);
+        events.insert((100, 0), vec![Play::new(0, 100, 0, Note::D3)]);
+        events.insert((200, 0), vec![Play::new(0, 200, 105, Note::E3)]);
+        let plays: Vec<_> = Tempoed(events, vec![])
+            .shortened(Gap::Staccato(100))
+            .0
+            .into_values()
+            .flatten()
+            .collect();
+        assert_eq!(
+            plays,
+            vec![
+                Play::new(0, 100, 0, Note::D3),
+                Play::new(0, 200, OP_LEN_UNIT, Note::E3),
+            ]
+        );
+    }
+
+    #[test]
+    fn gap_from_str() {
+        assert_eq!("rest".parse::<Gap>().unwrap(), Gap::Rest);
+        assert_eq!("legato".parse::<Gap>().unwrap(), Gap::Legato);
+        assert_eq!("staccato".parse::<Gap>().unwrap(), Gap::Staccato(50));
+        assert_eq!("staccato:80".parse::<Gap>().unwrap(), Gap::Staccato(80));
+        assert!("staccato:x".parse::<Gap>().is_err());
+        assert!("pizzicato".parse::<Gap>().is_err());
+    }
+
+    #[test]
+    fn chunks() {
+        let mut plays: Vec<_> = (0..60)
+            .map(|i| Play::new(0, i * 10, 10, Note::C3))
+            .collect();
+        plays.push(Play::new(0, 600, 3000, Note::D3));
+        let set = p(0, 0, 3600, plays);
+
+        let chunks: Vec<Vec<_>> = set
+            .chunks()
+            .into_iter()
+            .map(|c| {
+                c.into_iter()
+                    .map(|op| (op.note, op.duration.as_millis()))
+                    .collect()
+            })
+            .collect();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), MAX_OPS);
+        assert_eq!(
+            chunks[1],
+            vec![(Note::C3, 10), (Note::D3, 2550), (Note::D3, 450)]
+        );
+
+        let sizes =
+            |set: &PlaySet| -> Vec<usize> { set.chunks_of(16).iter().map(|c| c.len()).collect() };
+        assert_eq!(sizes(&set), vec![16, 16, 16, 14]);
+        // Repeated plays aren't split.
+        let mut set = set;
+        set.repeat = 2;
+        assert_eq!(sizes(&set), vec![59, 3]);
+    }
+
+    #[test]
+    fn folded() {
+        // A riff of 3 notes repeated 30 times, then an ending note.
+        let riff = [Note::C3, Note::E3, Note::G3];
+        let mut plays: Vec<_> = (0..90)
+            .map(|i| Play::new(0, i * 100, 100, riff[i as usize % 3]))
+            .collect();
+        plays.push(Play::new(0, 9000, 500, Note::C4));
+
+        let mut plan = Plan::new();
+        for (i, c) in plays.chunks(MAX_OPS).enumerate() {
+            let at = i as Time * MAX_OPS as Time * 100;
+            let len = c.iter().map(|p| p.len).sum();
+            plan.insert((at, 0), p(0, at, len, c.to_vec()));
+        }
+        let plan = super::folded(plan, MAX_OPS);
+
+        let sets: Vec<_> = plan.values().collect();
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0].repeat, 30);
+        assert_eq!(sets[0].plays.len(), 3);
+        assert_eq!(sets[0].len, 9000);
+        assert_eq!(sets[1], &p(0, 9000, 500, vec![plays[90].clone()]));
+
+        // Short repeats are left as they are.
+        let plays: Vec<_> = (0..8)
+            .map(|i| Play::new(1, i * 100, 100, Note::C3))
+            .collect();
+        let mut plan = Plan::new();
+        plan.insert((0, 1), p(1, 0, 800, plays));
+        assert_eq!(super::folded(plan.clone(), MAX_OPS), plan);
+    }
+
+    #[test]
+    fn transposed() {
+        let set = p(
+            0,
+            0,
+            300,
+            vec![
+                Play::new(0, 0, 100, Note::C3),
+                Play::new(0, 100, 100, Note::NoSound),
+                Play::new(0, 200, 100, Note::C10),
+            ],
+        );
+        let notes: Vec<_> = set.transposed(12).plays.iter().map(|p| p.note).collect();
+        assert_eq!(notes, vec![Note::C4, Note::NoSound, Note::C10]);
+        assert_eq!(set.transposed(-48).plays[0].note, Note::C0);
+    }
+
+    #[test]
+    fn window() {
+        let set = p(
+            0,
+            1000,
+            300,
+            vec![
+                Play::new(0, 1000, 100, Note::C3),
+                Play::new(0, 1100, 100, Note::NoSound),
+                Play::new(0, 1200, 100, Note::C4),
+            ],
+        );
+        let w = set.window(50, 200);
+        assert_eq!(
+            w.plays,
+            vec![
+                Play::new(0, 1050, 50, Note::C3),
+                Play::new(0, 1100, 100, Note::NoSound),
+                Play::new(0, 1200, 50, Note::C4),
+            ]
+        );
+        assert_eq!((w.at, w.len), (1050, 200));
+        assert!(set.window(300, 100).plays.is_empty());
+
+        // In the second repetition.
+        let mut folded = set.clone();
+        folded.len = 900;
+        folded.repeat = 3;
+        assert_eq!(
+            folded.window(420, 100).plays,
+            vec![
+                Play::new(0, 1120, 80, Note::NoSound),
+                Play::new(0, 1200, 20, Note::C4)
+            ]
+        );
+
+        // The rest goes on over the repetitions.
+        let rest = folded.rest(750);
+        assert_eq!(
+            rest.plays,
+            vec![
+                Play::new(0, 1750, 50, Note::NoSound),
+                Play::new(0, 1800, 100, Note::C4),
+            ]
+        );
+        assert_eq!((rest.at, rest.len, rest.repeat), (1750, 150, 1));
+        assert!(folded.rest(900).plays.is_empty());
+    }
+
+    #[test]
+    fn mixed() {
+        let a = p(0, 1000, 100, vec![Play::new(0, 1000, 100, Note::C3)]);
+        let b = p(1, 1050, 100, vec![Play::new(1, 1050, 100, Note::E4)]);
+        let mixed = a.mixed(&b, 20);
+        assert_eq!(
+            mixed.plays,
+            vec![
+                Play::new(1, 1000, 60, Note::C3),
+                Play::new(1, 1060, 20, Note::E4),
+                Play::new(1, 1080, 20, Note::C3),
+                Play::new(1, 1100, 50, Note::E4),
+            ]
+        );
+        assert_eq!((mixed.ch, mixed.at, mixed.len), (1, 1000, 150));
+    }
+
+    #[test]
+    fn windows() {
+        let mut events = EventMap::new();
+        events.insert((0, 0), vec![Play::new(0, 0, 1500, Note::C3)]);
+        events.insert((900, 1), vec![Play::new(1, 900, 300, Note::E4)]);
+        events.insert((1600, 1), vec![Play::new(1, 1600, 200, Note::G4)]);
+        let tempoed = Tempoed(events, vec![]);
+        // The note sounding at each msec.
+        let sounding = |plans: &[Plan]| -> Vec<(Time, Note)> {
+            let mut notes: Vec<_> = plans
+                .iter()
+                .flat_map(|plan| plan.values())
+                .flat_map(|set| &set.plays)
+                .filter(|p| p.note != Note::NoSound)
+                .flat_map(|p| (p.at..p.at + p.len).map(move |t| (t, p.note)))
+                .collect();
+            notes.sort_by_key(|(t, _)| *t);
+            notes
+        };
+        let opts = Options::new();
+
+        let windows = Windows::new(tempoed.clone(), &opts, 20, &[], 1000);
+        assert_eq!(windows.parts().into_iter().collect::<Vec<_>>(), vec![0, 1]);
+        let plans: Vec<_> = windows.collect();
+        assert_eq!(plans.len(), 2);
+        let whole = tempoed.merged(MAX_OPS, MAX_OP_LEN, Gap::Rest).0;
+        assert_eq!(sounding(&plans), sounding(&[whole]));
+
+        let rules = [(5, vec![0, 1])];
+        let windows = Windows::new(tempoed.clone(), &opts, 20, &rules, 1000);
+        assert_eq!(windows.parts().into_iter().collect::<Vec<_>>(), vec![5]);
+        let plans: Vec<_> = windows.collect();
+        let whole = tempoed
+            .mixed(20, &rules)
+            .merged(MAX_OPS, MAX_OP_LEN, Gap::Rest)
+            .0;
+        assert_eq!(sounding(&plans), sounding(&[whole]));
+    }
+
+    #[test]
+    fn mix() {
+        let mut orig = EventMap::new();
+        for play in [
+            Play::new(0, 0, 100, Note::C4),
+            Play::new(1, 40, 40, Note::E4),
+            Play::new(3, 40, 40, Note::A4),
+            Play::new(2, 200, 10, Note::G4),
+        ] {
+            orig.entry((play.at, play.ch)).or_default().push(play);
+        }
+        let mut mixed = EventMap::new();
+        super::mix(&mut mixed, &orig, 20, (5, &[0, 1, 2]), 0, None);
+
+        // Taken in a msec late, one at a time, and turned at each unit.
+        let plays: Vec<_> = mixed
+            .into_values()
+            .flatten()
+            .map(|p| (p.ch, p.at, p.len, p.note))
+            .collect();
+        assert_eq!(
+            plays,
+            vec![
+                (5, 1, 59, Note::C4),
+                (5, 60, 20, Note::E4),
+                (5, 80, 21, Note::C4),
+                (5, 201, 1, Note::G4),
+            ]
+        );
+    }
+
+    #[test]
+    fn parallel() {
+        let squares = parallel_on(3, (0..10).collect(), |i: u64| i * i);
+        assert_eq!(squares, (0..10).map(|i| i * i).collect::<Vec<_>>());
+        assert_eq!(parallel_on(3, vec![], |i: u64| i), vec![]);
+    }
+
+    #[test]
+    fn validate() {
+        let mut opts = Options::new();
+        assert!(opts.validate().is_ok());
+        opts.max_ops = 60;
+        assert!(opts.validate().is_err());
+        opts.max_ops = 10;
+        opts.max_op_len = 2560;
+        assert!(opts.validate().is_err());
+        opts.max_op_len = 5;
+        assert!(opts.validate().is_err());
+        opts.max_op_len = 500;
+        assert!(opts.validate().is_ok());
+    }
+
+    #[test]
+    fn filtered() {
+        let mut r = Raw::new();
+        for i in 0..4 {
+            r.on(i, 100, Note::C3);
+            r.off(i, 100, Note::C3);
+            r.end();
+        }
+        let chs = |opts: &Options| -> Vec<_> {
+            r.tempoed(100)
+                .filtered(opts)
+                .0
+                .into_keys()
+                .map(|(_, ch)| ch)
+                .collect()
+        };
+
+        let mut opts = Options::new();
+        assert_eq!(chs(&opts), vec![0, 1, 2, 3]);
+        opts.mute = vec![1, 3];
+        assert_eq!(chs(&opts), vec![0, 2]);
+        opts.solo = vec![0, 1];
+        assert_eq!(chs(&opts), vec![0]);
+    }
+
+    #[test]
+    fn last_note() {
+        let mut plan = BTreeMap::new();
+        plan.insert(
+            (1000, 0),
+            p(
+                0,
+                1000,
+                600,
+                vec![
+                    Play::new(0, 1000, 100, Note::C4),
+                    Play::new(0, 1100, 200, Note::NoSound),
+                ],
+            ),
+        );
+        plan.insert(
+            (1000, 1),
+            p(1, 1000, 200, vec![Play::new(1, 1000, 200, Note::NoSound)]),
+        );
+        plan.get_mut(&(1000, 0)).unwrap().repeat = 2;
+        assert_eq!(end(&plan), 1600);
+        assert_eq!(super::last_note(&plan), 1400);
+    }
+
+    #[test]
+    fn segments() {
+        let mut plan = BTreeMap::new();
+        plan.insert((0, 0), p(0, 0, 1000, vec![]));
+        plan.insert((500, 1), p(1, 500, 1000, vec![]));
+        plan.insert((1000, 0), p(0, 1000, 1000, vec![]));
+        plan.insert((2000, 0), p(0, 2000, 500, vec![]));
+        plan.insert((2000, 1), p(1, 2000, 500, vec![]));
+        plan.insert((2500, 0), p(0, 2500, 500, vec![]));
+
+        let segs: Vec<Vec<_>> = super::segments(plan, 1000)
+            .into_iter()
+            .map(|s| s.into_keys().collect())
+            .collect();
+        assert_eq!(
+            segs,
+            vec![
+                vec![(0, 0), (500, 1), (1000, 0)],
+                vec![(2000, 0), (2000, 1), (2500, 0)],
+            ]
+        );
+    }
 }
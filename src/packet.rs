@@ -0,0 +1,89 @@
+use log::*;
+use tokio::time::Duration;
+
+use crate::midi::MAX_OPS;
+
+/// The smallest packet in sound operations.
+const MIN_SIZE: usize = 8;
+/// Below this latency, the link has room for smaller packets.
+const FAST: Duration = Duration::from_millis(40);
+/// Above this latency, the link is congested and packets are made maximal.
+const SLOW: Duration = Duration::from_millis(120);
+
+/// Number of sound operations sent to a cube at once, adapted to the latency of sends.
+///
+/// Small packets let the cube follow the schedule closely while the link is fast;
+/// full packets cut the number of writes when it's congested.
+#[derive(Clone, Debug)]
+pub struct PacketSize {
+    index: usize,
+    size: usize,
+    max: usize,
+    latency: Option<Duration>,
+}
+
+impl PacketSize {
+    /// Starts with full packets of at most `max` operations.
+    pub fn new(index: usize, max: usize) -> Self {
+        let max = max.clamp(1, MAX_OPS);
+        Self {
+            index,
+            size: max,
+            max,
+            latency: None,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Updates the size with the latency of a send.
+    pub fn record(&mut self, latency: Duration) {
+        // Moving average to ride out single slow writes.
+        let avg = match self.latency {
+            Some(avg) => (avg * 3 + latency) / 4,
+            None => latency,
+        };
+        self.latency = Some(avg);
+
+        let size = if avg >= SLOW {
+            self.max
+        } else if avg < FAST {
+            (self.size / 2).max(MIN_SIZE.min(self.max))
+        } else {
+            self.size
+        };
+        if size != self.size {
+            debug!(
+                "Cube {} sends {} operations at once (latency {:?})",
+                self.index, size, avg
+            );
+            self.size = size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn adapts() {
+        let mut p = PacketSize::new(0, MAX_OPS);
+        assert_eq!(p.size(), MAX_OPS);
+
+        for _ in 0..3 {
+            p.record(Duration::from_millis(20));
+        }
+        assert_eq!(p.size(), MIN_SIZE);
+
+        // A single slow write isn't congestion.
+        p.record(Duration::from_millis(200));
+        assert_eq!(p.size(), MIN_SIZE);
+        p.record(Duration::from_millis(300));
+        assert_eq!(p.size(), MAX_OPS);
+
+        assert_eq!(PacketSize::new(0, 4).size(), 4);
+    }
+}
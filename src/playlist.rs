@@ -0,0 +1,121 @@
+//! Playlists of MIDI files played one after another.
+
+use anyhow::{anyhow, Context, Result};
+use derive_new::new;
+use std::path::{Path, PathBuf};
+
+use crate::Rule;
+
+/// A file of the playlist with the rules to play it with, if any of its own.
+#[derive(Clone, Debug, new)]
+pub struct Entry {
+    pub file: PathBuf,
+    pub rules: Vec<Rule>,
+}
+
+/// Reads the playlist file.
+pub fn load(path: &Path) -> Result<Vec<Entry>> {
+    let text = std::fs::read_to_string(path)
+        .context(format!("Couldn't read playlist {}", path.display()))?;
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    parse(&text, base).context(format!("Invalid playlist {}", path.display()))
+}
+
+/// Parses a playlist of a file per line relative to `base`, like `.m3u`.
+///
+/// Lines starting with `#` are comments, except `#rules 0=1 1=2,3` giving the rules
+/// of the next file.
+pub fn parse(text: &str, base: &Path) -> Result<Vec<Entry>> {
+    let mut entries = vec![];
+    let mut rules = vec![];
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if let Some(list) = line.strip_prefix("#rules") {
+            rules = list
+                .split_whitespace()
+                .map(|r| r.parse().context(format!("line {}", n + 1)))
+                .collect::<Result<_>>()?;
+        } else if !line.is_empty() && !line.starts_with('#') {
+            entries.push(Entry::new(base.join(line), std::mem::take(&mut rules)));
+        }
+    }
+    if !rules.is_empty() {
+        return Err(anyhow!("#rules at the end isn't followed by a file"));
+    }
+    Ok(entries)
+}
+
+/// Lists the MIDI files in the directory in the order of the names.
+pub fn scan(dir: &Path) -> Result<Vec<Entry>> {
+    let read = || format!("Couldn't read directory {}", dir.display());
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir).context(read())? {
+        let path = entry.context(read())?.path();
+        let ext = path.extension().and_then(|e| e.to_str());
+        if ext.is_some_and(|e| e.eq_ignore_ascii_case("mid") || e.eq_ignore_ascii_case("midi")) {
+            files.push(path);
+        }
+    }
+    if files.is_empty() {
+        return Err(anyhow!("No MIDI file in {}", dir.display()));
+    }
+    files.sort();
+    Ok(files.into_iter().map(|f| Entry::new(f, vec![])).collect())
+}
+
+/// A seed of shuffling from the clock.
+pub fn seed() -> u64 {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+    now.map_or(0, |d| d.as_nanos() as u64) | 1
+}
+
+/// Shuffles the items in place, moving the seed on with xorshift.
+pub fn shuffle<T>(items: &mut [T], seed: &mut u64) {
+    for i in (1..items.len()).rev() {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        items.swap(i, (*seed % (i as u64 + 1)) as usize);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let text = "#EXTM3U\n\
+                    # Encore\n\
+                    battle.mid\n\
+                    #rules 0=1 1=2,3\n\
+                    \n\
+                    /music/field.mid\n";
+        let entries = super::parse(text, Path::new("songs")).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].file, Path::new("songs/battle.mid"));
+        assert!(entries[0].rules.is_empty());
+        assert_eq!(entries[1].file, Path::new("/music/field.mid"));
+        let rules: Vec<_> = entries[1].rules.iter().map(|r| r.to_string()).collect();
+        assert_eq!(rules, vec!["0=1", "1=2,3"]);
+
+        assert!(super::parse("#rules x\nbattle.mid\n", Path::new("")).is_err());
+        assert!(super::parse("battle.mid\n#rules 0=1\n", Path::new("")).is_err());
+    }
+
+    #[test]
+    fn shuffle() {
+        let mut items: Vec<_> = (0..10).collect();
+        let mut seed = 42;
+        super::shuffle(&mut items, &mut seed);
+        assert_ne!(items, (0..10).collect::<Vec<_>>());
+        let mut sorted = items.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..10).collect::<Vec<_>>());
+
+        // Another round goes on from the seed moved on.
+        let shuffled = items.clone();
+        super::shuffle(&mut items, &mut seed);
+        assert_ne!(items, shuffled);
+    }
+}
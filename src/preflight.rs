@@ -0,0 +1,193 @@
+use anyhow::{anyhow, Result};
+use log::*;
+use std::{collections::BTreeSet, path::Path};
+use toio::{Cube, Note, SoundOp};
+use tokio::time::{delay_for, delay_until, Duration, Instant};
+
+use crate::{
+    link::Link,
+    midi::{self, Plan},
+    section::Offset,
+    show, Opt,
+};
+
+/// Counts the failed checks while printing the result of each.
+#[derive(Default)]
+pub struct Report {
+    pub failed: usize,
+}
+
+impl Report {
+    pub fn check<T>(&mut self, name: &str, res: Result<(T, String)>) -> Option<T> {
+        match res {
+            Ok((v, detail)) => {
+                info!("[PASS] {}: {}", name, detail);
+                Some(v)
+            }
+            Err(e) => {
+                error!("[FAIL] {}: {:#}", name, e);
+                self.failed += 1;
+                None
+            }
+        }
+    }
+
+    fn skip(&self, name: &str) {
+        warn!("[SKIP] {}", name);
+    }
+
+    /// Tells what to do about the check failed last.
+    pub fn advise(&self, hint: &str) {
+        info!("       {}", hint);
+    }
+}
+
+fn config(path: &Path, opt: &mut Opt, given: &[&str]) -> Result<((), String)> {
+    show::load(path, opt, given)?;
+    crate::demo(opt)?;
+    crate::number(&mut opt.rules);
+    crate::validate(opt)?;
+    crate::auto_assign(opt)?;
+    Ok(((), format!("{} rules", opt.rules.len())))
+}
+
+fn convert(opt: &Opt) -> Result<(Vec<Plan>, String)> {
+    let segments = crate::plan(opt)?;
+
+    let sets: usize = segments.iter().map(|s| s.len()).sum();
+    let end = segments.iter().map(midi::end).max().unwrap_or(0);
+    let detail = format!(
+        "{} play sets in {} segments, {:.0}",
+        sets,
+        segments.len(),
+        Offset(end)
+    );
+    Ok((segments, detail))
+}
+
+pub async fn adapter() -> Result<(Vec<Cube>, String)> {
+    // The BLE backend panics on unsupported platforms; report it as a failure.
+    let cubes = tokio::spawn(async { Cube::search().all().await })
+        .await
+        .map_err(|e| anyhow!("Bluetooth is unavailable: {}", e))??;
+
+    if cubes.is_empty() {
+        return Err(anyhow!("No cube found"));
+    }
+    let detail = format!("{} cubes found", cubes.len());
+    Ok((cubes, detail))
+}
+
+async fn selftest(link: &mut Link) -> Result<((), String)> {
+    link.cube()?.connect().await?;
+    let version = link.cube()?.version().await?;
+    let battery = link.cube()?.battery().await?;
+    link.light_on(255, 255, 255).await?;
+    link.play(1, &[SoundOp::new(Note::C5, Duration::from_millis(200))])
+        .await?;
+    delay_for(Duration::from_millis(300)).await;
+    link.light_off().await?;
+
+    Ok(((), format!("firmware {}, battery {}%", version, battery)))
+}
+
+fn assignment(segments: &[Plan], cubes: usize) -> Result<((), String)> {
+    let parts: BTreeSet<_> = segments
+        .iter()
+        .flat_map(|s| s.keys().map(|(_, ch)| *ch))
+        .collect();
+    let missing: Vec<_> = parts.iter().filter(|p| **p as usize >= cubes).collect();
+
+    if missing.is_empty() {
+        Ok(((), format!("{} parts on {} cubes", parts.len(), cubes)))
+    } else {
+        Err(anyhow!("No cube plays parts {:?}", missing))
+    }
+}
+
+/// Walks the schedule in real time without sending anything, measuring how late
+/// the scheduler wakes up.
+async fn dry_run(segments: &[Plan], limit: Duration) -> Result<((), String)> {
+    let limit = limit.as_millis() as u64;
+    let start = Instant::now();
+    let mut max = Duration::from_millis(0);
+    let mut count = 0;
+
+    for at in segments
+        .iter()
+        .flat_map(|s| s.keys().map(|(at, _)| *at))
+        .take_while(|at| *at <= limit)
+    {
+        let target = start + Duration::from_millis(at);
+        delay_until(target).await;
+        max = max.max(Instant::now() - target);
+        count += 1;
+    }
+
+    if max > Duration::from_millis(50) {
+        Err(anyhow!("Scheduler was late by up to {:?}", max))
+    } else {
+        Ok((
+            (),
+            format!(
+                "{} play sets in the first {} sec, late by up to {:?}",
+                count,
+                limit / 1000,
+                max
+            ),
+        ))
+    }
+}
+
+/// Runs all the checks for the show, failing if any of them fails.
+///
+/// The options in `given` were given on the command line, winning over the show.
+pub async fn run(path: &Path, mut opt: Opt, given: &[&str], limit: Duration) -> Result<()> {
+    let mut report = Report::default();
+
+    let segments = match report.check("Show file", config(path, &mut opt, given)) {
+        Some(()) => report.check("Conversion", convert(&opt)),
+        None => {
+            report.skip("Conversion");
+            None
+        }
+    };
+
+    if let Some(cubes) = report.check("Bluetooth adapter", adapter().await) {
+        let tracer = crate::tracer(&opt);
+        let mut links: Vec<_> = cubes
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| Link::new(i, c, tracer.clone()))
+            .collect();
+
+        for link in &mut links {
+            let name = format!("Cube {} self-test", link.index);
+            report.check(&name, selftest(link).await);
+        }
+
+        match &segments {
+            Some(segments) => {
+                report.check("Cube assignment", assignment(segments, links.len()));
+            }
+            None => report.skip("Cube assignment"),
+        }
+    } else {
+        report.skip("Cube self-test");
+        report.skip("Cube assignment");
+    }
+
+    match &segments {
+        Some(segments) => {
+            report.check("Dry-run", dry_run(segments, limit).await);
+        }
+        None => report.skip("Dry-run"),
+    }
+
+    if report.failed > 0 {
+        Err(anyhow!("Preflight failed: {} checks failed", report.failed))
+    } else {
+        info!("Preflight passed");
+        Ok(())
+    }
+}
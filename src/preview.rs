@@ -0,0 +1,112 @@
+//! Previews of the plan without cubes, rendered into a WAV file.
+
+use anyhow::{Context, Result};
+use std::{collections::BTreeSet, path::Path};
+use toio::Note;
+
+use crate::midi::{self, Plan, Time};
+
+/// Samples per second.
+const RATE: u32 = 22050;
+/// Attack and release of each note in samples, not to click.
+const EDGE: usize = RATE as usize / 200;
+
+/// Frequency of the note in Hz, the A4 at 440 Hz.
+fn frequency(note: Note) -> f64 {
+    440.0 * 2f64.powf((note as i32 - Note::A4 as i32) as f64 / 12.0)
+}
+
+/// Synthesizes the plan with a square wave per part, like the buzzers of the cubes.
+fn render(plan: &Plan) -> Vec<i16> {
+    let end = midi::end(plan);
+    let parts = plan
+        .values()
+        .map(|s| s.ch)
+        .collect::<BTreeSet<_>>()
+        .len()
+        .max(1);
+    let sample = |ms: Time| (ms * RATE as Time / 1000) as usize;
+
+    let mut mix = vec![0f64; sample(end)];
+    for play in plan.values().flat_map(|s| s.unrolled()) {
+        if play.note == Note::NoSound {
+            continue;
+        }
+        let (from, to) = (sample(play.at), sample(play.at + play.len).min(mix.len()));
+        let period = RATE as f64 / frequency(play.note);
+        let volume = play.vel as f64 / 127.0 / parts as f64;
+        let len = to.saturating_sub(from);
+        for i in 0..len {
+            let edge = i.min(len - 1 - i).min(EDGE) as f64 / EDGE as f64;
+            let wave = if (i as f64 % period) < period / 2.0 {
+                1.0
+            } else {
+                -1.0
+            };
+            mix[from + i] += wave * volume * edge;
+        }
+    }
+
+    mix.into_iter()
+        .map(|v| (v.clamp(-1.0, 1.0) * 0.5 * i16::MAX as f64) as i16)
+        .collect()
+}
+
+/// Encodes the samples as a mono 16-bit PCM WAV file.
+fn wav(samples: &[i16]) -> Vec<u8> {
+    let data = (samples.len() * 2) as u32;
+    let mut out = Vec::with_capacity(44 + data as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data).to_le_bytes());
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    out.extend_from_slice(&1u16.to_le_bytes()); // mono
+    out.extend_from_slice(&RATE.to_le_bytes());
+    out.extend_from_slice(&(RATE * 2).to_le_bytes());
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data.to_le_bytes());
+    for s in samples {
+        out.extend_from_slice(&s.to_le_bytes());
+    }
+    out
+}
+
+/// Renders the plan into the WAV file.
+pub fn write(plan: &Plan, path: &Path) -> Result<()> {
+    std::fs::write(path, wav(&render(plan))).context(format!("Couldn't write {}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::midi::{Play, PlaySet};
+
+    #[test]
+    fn render() {
+        assert_eq!(frequency(Note::A4), 440.0);
+        assert!((frequency(Note::A5) - 880.0).abs() < 1e-9);
+
+        let mut set = PlaySet::new(0, 0);
+        set.plays = vec![
+            Play::new(0, 0, 100, Note::A4),
+            Play::new(0, 100, 100, Note::NoSound),
+        ];
+        set.len = 200;
+        let plan: Plan = vec![((0, 0), set)].into_iter().collect();
+
+        let samples = super::render(&plan);
+        assert_eq!(samples.len(), RATE as usize / 5);
+        // Sound, then silence.
+        assert!(samples[EDGE..RATE as usize / 10 - EDGE]
+            .iter()
+            .all(|s| s.abs() > 10000));
+        assert!(samples[RATE as usize / 10..].iter().all(|s| *s == 0));
+
+        let data = wav(&samples);
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(data.len(), 44 + samples.len() * 2);
+    }
+}
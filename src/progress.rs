@@ -0,0 +1,156 @@
+//! Progress bar of the position in the song, redrawn on a line of the terminal.
+
+use anyhow::Result;
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    midi::{PlaySet, Time},
+    section::Offset,
+    sink::SoundSink,
+};
+
+/// Interval of redrawing the bar.
+const REFRESH: Duration = Duration::from_millis(500);
+/// Width of the bar.
+const WIDTH: usize = 30;
+
+struct State {
+    /// Length of the song in msec.
+    total: Time,
+    /// The position in the song and when it was reached, kept while silenced.
+    position: Option<(Time, Instant)>,
+    silenced: bool,
+}
+
+impl State {
+    fn position(&self, now: Instant) -> Time {
+        match self.position {
+            Some((at, _)) if self.silenced => at,
+            Some((at, since)) => at + now.saturating_duration_since(since).as_millis() as Time,
+            None => 0,
+        }
+        .min(self.total)
+    }
+}
+
+/// Moves the bar with the sets sent to the cubes.
+pub struct Progress {
+    state: Arc<Mutex<State>>,
+}
+
+impl Progress {
+    /// Starts drawing the bar of the song of `total` msec on stderr, a sink per cube.
+    pub fn start(total: Time, cubes: usize) -> Vec<Progress> {
+        let state = Arc::new(Mutex::new(State {
+            total,
+            position: None,
+            silenced: false,
+        }));
+
+        let shared = state.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(REFRESH);
+            while Arc::strong_count(&shared) > 1 {
+                ticks.tick().await;
+                let s = shared.lock().unwrap();
+                eprint!("\r{}", bar(s.position(Instant::now()), s.total));
+                let _ = std::io::stderr().flush();
+            }
+            eprintln!();
+        });
+
+        (0..cubes)
+            .map(|_| Progress {
+                state: state.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Draws the bar such as `[######........] 0:12 / 1:05  ETA 0:53`.
+fn bar(position: Time, total: Time) -> String {
+    let done = (position as usize * WIDTH / total.max(1) as usize).min(WIDTH);
+    format!(
+        "[{}{}] {:.0} / {:.0}  ETA {:.0}",
+        "#".repeat(done),
+        ".".repeat(WIDTH - done),
+        Offset(position),
+        Offset(total),
+        Offset(total.saturating_sub(position))
+    )
+}
+
+impl SoundSink for Progress {
+    async fn play(&mut self, set: PlaySet) -> Result<()> {
+        let mut s = self.state.lock().unwrap();
+        let at = s.position.map_or(set.at, |(at, _)| at.max(set.at));
+        s.position = Some((at, Instant::now()));
+        s.silenced = false;
+        Ok(())
+    }
+
+    async fn silence(&mut self) -> Result<()> {
+        let mut s = self.state.lock().unwrap();
+        if !s.silenced {
+            let now = Instant::now();
+            s.position = Some((s.position(now), now));
+            s.silenced = true;
+        }
+        Ok(())
+    }
+
+    async fn restart(&mut self) -> Result<()> {
+        self.state.lock().unwrap().position = None;
+        Ok(())
+    }
+
+    /// Stops the bar until the next segment.
+    async fn idle(&mut self) -> Result<()> {
+        self.silence().await
+    }
+
+    async fn resume(&mut self) -> Result<()> {
+        let mut s = self.state.lock().unwrap();
+        s.position = s.position.map(|(at, _)| (at, Instant::now()));
+        s.silenced = false;
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn progress() {
+        assert_eq!(
+            bar(15_000, 60_000),
+            "[#######.......................] 0:15 / 1:00  ETA 0:45"
+        );
+        assert_eq!(
+            bar(0, 0),
+            format!("[{}] 0:00 / 0:00  ETA 0:00", ".".repeat(30))
+        );
+
+        let start = Instant::now();
+        let mut s = State {
+            total: 60_000,
+            position: Some((1000, start)),
+            silenced: false,
+        };
+        assert_eq!(s.position(start + Duration::from_millis(500)), 1500);
+        s.silenced = true;
+        assert_eq!(s.position(start + Duration::from_millis(500)), 1000);
+        assert_eq!(s.position(start + Duration::from_secs(120)), 1000);
+        s.silenced = false;
+        assert_eq!(s.position(start + Duration::from_secs(120)), 60_000);
+    }
+}
@@ -0,0 +1,103 @@
+//! The report of how the play sets went out to each cube against their times, telling
+//! if the stutters came from the host falling behind or the cubes taking long.
+
+use tokio::time::Duration;
+
+/// How late the play sets of a cube were written, and how long the writes took.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Timing {
+    sets: u32,
+    late: Duration,
+    most_late: Duration,
+    writes: u32,
+    write: Duration,
+    longest_write: Duration,
+    skipped: usize,
+    dropped: usize,
+}
+
+impl Timing {
+    /// Records the first write of a play set, which was due the lateness before.
+    pub fn sent(&mut self, late: Duration) {
+        self.sets += 1;
+        self.late += late;
+        self.most_late = self.most_late.max(late);
+    }
+
+    /// Records how long a write took to be acknowledged.
+    pub fn written(&mut self, took: Duration) {
+        self.writes += 1;
+        self.write += took;
+        self.longest_write = self.longest_write.max(took);
+    }
+
+    /// Records a play set skipped as the writes failed.
+    pub fn skip(&mut self) {
+        self.skipped += 1;
+    }
+
+    /// Records a play set dropped as the cube fell behind.
+    pub fn drop_set(&mut self) {
+        self.dropped += 1;
+    }
+
+    /// How many play sets the cube dropped.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+impl std::fmt::Display for Timing {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.sets == 0 {
+            write!(f, "no play sets")?;
+        } else {
+            write!(
+                f,
+                "{} play sets, late {:.1} ms on average and {:.1} ms at most",
+                self.sets,
+                ms(self.late / self.sets),
+                ms(self.most_late)
+            )?;
+        }
+        if self.writes > 0 {
+            write!(
+                f,
+                ", writes taking {:.1} ms on average and {:.1} ms at most",
+                ms(self.write / self.writes),
+                ms(self.longest_write)
+            )?;
+        }
+        write!(f, ", {} skipped", self.skipped)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timing() {
+        let mut timing = Timing::default();
+        assert_eq!(timing.to_string(), "no play sets, 0 skipped");
+
+        let ms = Duration::from_millis;
+        timing.sent(ms(2));
+        timing.sent(ms(10));
+        for took in &[30, 45, 60] {
+            timing.written(ms(*took));
+        }
+        timing.skip();
+        timing.drop_set();
+        assert_eq!(timing.dropped(), 1);
+        assert_eq!(
+            timing.to_string(),
+            "2 play sets, late 6.0 ms on average and 10.0 ms at most, \
+             writes taking 45.0 ms on average and 60.0 ms at most, 1 skipped"
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Retries of the writes that fail on a cube for a moment.
+
+use anyhow::Result;
+use log::*;
+use toio::SoundOp;
+use tokio::time::{delay_for, Duration, Instant};
+
+use crate::link::Link;
+
+/// The wait before the first retry, doubled on each of the others.
+const BACKOFF: Duration = Duration::from_millis(20);
+/// The longest wait before a retry.
+const MAX_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How the failed writes of the sounds to a cube are handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// How many times a failed write is tried again.
+    pub retries: u32,
+    /// How many play sets in a row may be skipped before the cube fails.
+    pub max_skips: usize,
+}
+
+impl Policy {
+    /// The wait before the retry, counted from 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        (BACKOFF * 2u32.saturating_pow(retry.min(16))).min(MAX_BACKOFF)
+    }
+
+    /// Writes the sounds, trying again with backoff as long as retries are left and the
+    /// play set isn't over by `until`, not to hold up the cube for a set played too late.
+    pub async fn play(
+        &self,
+        link: &mut Link,
+        repeat: usize,
+        ops: &[SoundOp],
+        until: Instant,
+    ) -> Result<()> {
+        let mut retry = 0;
+        loop {
+            match link.play(repeat, ops).await {
+                Err(e) if retry < self.retries && Instant::now() + self.backoff(retry) < until => {
+                    let wait = self.backoff(retry);
+                    debug!(
+                        "Cube {} failed to play: {:#}; trying again in {:?}",
+                        link.index, e, wait
+                    );
+                    delay_for(wait).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Tells if the play set can be skipped after the given number in a row.
+    pub fn skips(&self, skipped: usize) -> bool {
+        skipped < self.max_skips
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn policy() {
+        let policy = Policy {
+            retries: 3,
+            max_skips: 2,
+        };
+        let ms = Duration::from_millis;
+        assert_eq!(policy.backoff(0), ms(20));
+        assert_eq!(policy.backoff(2), ms(80));
+        assert_eq!(policy.backoff(10), MAX_BACKOFF);
+        assert_eq!(policy.backoff(u32::MAX), MAX_BACKOFF);
+
+        assert!(policy.skips(0));
+        assert!(policy.skips(1));
+        assert!(!policy.skips(2));
+        assert!(!Policy::default().skips(0));
+    }
+}
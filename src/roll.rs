@@ -0,0 +1,168 @@
+//! Scrolling piano roll in the terminal of what each cube should be playing.
+
+use anyhow::Result;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use toio::Note;
+
+use crate::{
+    midi::{PlaySet, Time},
+    section::Offset,
+    sink::SoundSink,
+    song::note_name,
+};
+
+/// Time per row of the roll.
+const STEP: Duration = Duration::from_millis(200);
+/// Width of the progress bar of each cube.
+const BAR: usize = 10;
+
+#[derive(Default)]
+struct State {
+    /// When the first set started, from which the rows are timed.
+    start: Option<Instant>,
+    /// The set each cube plays with when it started.
+    lanes: Vec<Option<(Instant, PlaySet)>>,
+}
+
+/// Shows the sets a cube is sent in its lane of the roll.
+pub struct Roll {
+    cube: usize,
+    state: Arc<Mutex<State>>,
+}
+
+impl Roll {
+    /// Starts drawing the roll with a lane per cube until the lanes are dropped.
+    pub fn start(cubes: usize) -> Vec<Roll> {
+        let state = Arc::new(Mutex::new(State {
+            start: None,
+            lanes: vec![None; cubes],
+        }));
+
+        let shared = state.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(STEP);
+            while Arc::strong_count(&shared) > 1 {
+                ticks.tick().await;
+                let now = Instant::now();
+                let s = shared.lock().unwrap();
+                if let Some(row) = row(&s, now) {
+                    println!("{}", row);
+                }
+            }
+        });
+
+        (0..cubes)
+            .map(|cube| Roll {
+                cube,
+                state: state.clone(),
+            })
+            .collect()
+    }
+
+    fn set(&self, lane: Option<(Instant, PlaySet)>) {
+        let mut s = self.state.lock().unwrap();
+        if s.start.is_none() {
+            s.start = lane.as_ref().map(|(at, _)| *at);
+        }
+        s.lanes[self.cube] = lane;
+    }
+}
+
+/// Draws the row of the time, unless no cube is playing.
+fn row(s: &State, now: Instant) -> Option<String> {
+    let lanes: Vec<_> = s
+        .lanes
+        .iter()
+        .enumerate()
+        .map(|(cube, lane)| {
+            let lane = lane
+                .as_ref()
+                .map(|(at, set)| (set, now.duration_since(*at).as_millis() as Time))
+                .filter(|(set, elapsed)| *elapsed < set.len);
+            match lane {
+                Some((set, elapsed)) => (true, cell(cube, set, elapsed)),
+                None => (false, format!("{} {:<4} {}", cube, "", " ".repeat(BAR))),
+            }
+        })
+        .collect();
+    if !lanes.iter().any(|(playing, _)| *playing) {
+        return None;
+    }
+
+    let ms = now.duration_since(s.start?).as_millis() as Time;
+    let cells: Vec<_> = lanes.into_iter().map(|(_, cell)| cell).collect();
+    Some(format!("{} | {} |", Offset(ms), cells.join(" | ")))
+}
+
+/// Draws a cube playing the set, with the note sounding and the progress through the set.
+fn cell(cube: usize, set: &PlaySet, elapsed: Time) -> String {
+    let at = set.at + elapsed;
+    let note = set
+        .unrolled()
+        .into_iter()
+        .find(|p| p.at <= at && at < p.at + p.len)
+        .map(|p| p.note)
+        .unwrap_or(Note::NoSound);
+    let note = match note {
+        Note::NoSound => "-".into(),
+        n => note_name(n),
+    };
+    let done = (elapsed as usize * BAR / set.len.max(1) as usize).min(BAR);
+    format!(
+        "{} {:<4} {}{}",
+        cube,
+        note,
+        "#".repeat(done),
+        ".".repeat(BAR - done)
+    )
+}
+
+impl SoundSink for Roll {
+    async fn play(&mut self, set: PlaySet) -> Result<()> {
+        self.set(Some((Instant::now(), set)));
+        Ok(())
+    }
+
+    async fn silence(&mut self) -> Result<()> {
+        self.set(None);
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::midi::Play;
+
+    #[test]
+    fn roll() {
+        let mut set = PlaySet::new(1, 1000);
+        set.plays = vec![
+            Play::new(1, 1000, 500, Note::A4),
+            Play::new(1, 1500, 500, Note::NoSound),
+        ];
+        set.len = 1000;
+        assert_eq!(cell(0, &set, 200), "0 A4   ##........");
+        assert_eq!(cell(1, &set, 700), "1 -    #######...");
+
+        let start = Instant::now();
+        let mut s = State {
+            start: Some(start),
+            lanes: vec![Some((start, set)), None],
+        };
+        let now = start + Duration::from_millis(200);
+        assert_eq!(
+            row(&s, now).unwrap(),
+            "0:00.2 | 0 A4   ##........ | 1                 |"
+        );
+        s.lanes[0] = None;
+        assert_eq!(row(&s, now), None);
+    }
+}
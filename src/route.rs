@@ -0,0 +1,476 @@
+use anyhow::{anyhow, Error, Result};
+use derive_new::new;
+use std::collections::{BTreeMap, BTreeSet};
+use tokio::time::Instant;
+
+use crate::midi::{Channel, Plan, PlaySet, Time};
+
+/// The parts a cube can play at most to take over the part of a weak cube, mixed into
+/// its own.
+const LIGHT_LOAD: usize = 1;
+
+/// The sets sent to the cubes as (cube, set, when it was sent).
+pub type Playing = Vec<(usize, PlaySet, Instant)>;
+
+/// A part moved from one cube to another.
+#[derive(Clone, Debug, PartialEq, Eq, new)]
+pub struct Move {
+    pub part: Channel,
+    pub from: usize,
+    pub to: usize,
+}
+
+/// A cube mirroring the part of another cube, transposed by semitones.
+#[derive(Clone, Debug, PartialEq, Eq, new)]
+pub struct Mirror {
+    pub follower: usize,
+    pub leader: usize,
+    pub interval: i8,
+}
+
+impl std::str::FromStr for Mirror {
+    type Err = Error;
+
+    /// Parses `cube2=cube0:+12`, where the `cube` prefixes and the interval are optional.
+    fn from_str(s: &str) -> Result<Self> {
+        let err = || anyhow!("Invalid link: {} (expected cubeN=cubeM[:interval])", s);
+        let cube = |c: &str| c.trim_start_matches("cube").parse().map_err(|_| err());
+
+        let mut iter = s.splitn(2, '=');
+        let follower = cube(iter.next().ok_or_else(err)?)?;
+        let mut iter = iter.next().ok_or_else(err)?.splitn(2, ':');
+        let leader = cube(iter.next().ok_or_else(err)?)?;
+        let interval = match iter.next() {
+            Some(i) => i.trim_start_matches('+').parse().map_err(|_| err())?,
+            None => 0,
+        };
+
+        if follower == leader {
+            return Err(anyhow!("Cube {} can't mirror itself", follower));
+        }
+        Ok(Mirror::new(follower, leader, interval))
+    }
+}
+
+/// Routes parts (channels of the plan) to cubes.
+#[derive(Clone, Debug)]
+pub struct Router {
+    route: BTreeMap<Channel, usize>,
+    battery: Vec<Option<usize>>,
+    /// Delays from sending a command to the sound of each cube in msec.
+    latency: Vec<Time>,
+    parked: BTreeSet<usize>,
+    /// Parts mirrored by cubes as (part, follower, interval).
+    mirrors: Vec<(Channel, usize, i8)>,
+}
+
+impl Router {
+    /// Creates a router which plays part `i` on cube `i`.
+    pub fn new<I: IntoIterator<Item = Channel>>(cubes: usize, parts: I) -> Self {
+        Self {
+            route: parts
+                .into_iter()
+                .filter(|p| (*p as usize) < cubes)
+                .map(|p| (p, p as usize))
+                .collect(),
+            battery: vec![None; cubes],
+            latency: vec![0; cubes],
+            parked: BTreeSet::new(),
+            mirrors: vec![],
+        }
+    }
+
+    /// Makes the follower mirror the parts now on the leader, wherever they move later.
+    ///
+    /// Returns the parts of the follower, which are no longer played.
+    pub fn mirror(&mut self, m: &Mirror) -> Result<Vec<Channel>> {
+        for cube in &[m.follower, m.leader] {
+            if *cube >= self.battery.len() {
+                return Err(anyhow!("No cube {} to link", cube));
+            }
+        }
+
+        let parts = |cube| -> Vec<Channel> {
+            self.route
+                .iter()
+                .filter(|(_, c)| **c == cube)
+                .map(|(p, _)| *p)
+                .collect()
+        };
+        let (leads, dropped) = (parts(m.leader), parts(m.follower));
+
+        for part in &dropped {
+            self.route.remove(part);
+        }
+        for part in leads {
+            self.mirrors.push((part, m.follower, m.interval));
+        }
+        Ok(dropped)
+    }
+
+    /// Returns the cubes mirroring the part with their intervals.
+    pub fn mirrors(&self, part: Channel) -> Vec<(usize, i8)> {
+        self.mirrors
+            .iter()
+            .filter(|(p, c, _)| *p == part && !self.parked.contains(c))
+            .map(|(_, c, i)| (*c, *i))
+            .collect()
+    }
+
+    fn follower(&self, cube: usize) -> bool {
+        self.mirrors.iter().any(|(_, c, _)| *c == cube)
+    }
+
+    pub fn cube(&self, part: Channel) -> Option<usize> {
+        self.route.get(&part).cloned()
+    }
+
+    pub fn battery(&mut self, cube: usize, level: usize) {
+        if let Some(b) = self.battery.get_mut(cube) {
+            *b = Some(level);
+        }
+    }
+
+    pub fn latency(&mut self, cube: usize, ms: Time) {
+        if let Some(l) = self.latency.get_mut(cube) {
+            *l = ms;
+        }
+    }
+
+    /// Delays the sets of each cube by how much sooner it sounds than the slowest cube,
+    /// so that all the cubes sound together.
+    pub fn skewed(&self, plan: Plan) -> Plan {
+        let slowest = self.latency.iter().max().copied().unwrap_or(0);
+        if slowest == 0 {
+            return plan;
+        }
+        plan.into_iter()
+            .map(|((at, ch), set)| {
+                let delay = self.cube(ch).map_or(0, |c| slowest - self.latency[c]);
+                ((at + delay, ch), set)
+            })
+            .collect()
+    }
+
+    fn load(&self, cube: usize) -> usize {
+        self.route.values().filter(|c| **c == cube).count()
+    }
+
+    /// Returns true if the cube plays more than one part, having taken over another.
+    pub fn shared(&self, cube: usize) -> bool {
+        self.load(cube) > 1
+    }
+
+    fn weak(&self, cube: usize, threshold: usize) -> bool {
+        matches!(self.battery[cube], Some(b) if b < threshold)
+    }
+
+    /// Moves the parts of cubes whose battery is below `threshold` to the least loaded of
+    /// the idle or lightly loaded cubes, the healthiest first, to be mixed into its part.
+    ///
+    /// Returns the moves and the cubes that became parked.
+    pub fn rebalance(&mut self, threshold: usize) -> (Vec<Move>, Vec<usize>) {
+        let mut moves = vec![];
+        let mut parked = vec![];
+
+        for cube in 0..self.battery.len() {
+            if self.parked.contains(&cube) || !self.weak(cube, threshold) {
+                continue;
+            }
+
+            let parts: Vec<_> = self
+                .route
+                .iter()
+                .filter(|(_, c)| **c == cube)
+                .map(|(p, _)| *p)
+                .collect();
+
+            let target = (0..self.battery.len())
+                .filter(|c| {
+                    *c != cube
+                        && !self.parked.contains(c)
+                        && !self.weak(*c, threshold)
+                        && !self.follower(*c)
+                        && self.load(*c) <= LIGHT_LOAD
+                })
+                .min_by_key(|c| {
+                    (
+                        self.load(*c),
+                        std::cmp::Reverse(self.battery[*c].unwrap_or(0)),
+                        *c,
+                    )
+                });
+
+            let target = match (parts.is_empty(), target) {
+                (true, _) => None,
+                (false, Some(t)) => Some(t),
+                // Nobody can take over the part; keep playing as long as possible.
+                (false, None) => continue,
+            };
+
+            for part in parts {
+                if let Some(to) = target {
+                    self.route.insert(part, to);
+                    moves.push(Move::new(part, cube, to));
+                }
+            }
+            self.parked.insert(cube);
+            parked.push(cube);
+        }
+
+        (moves, parked)
+    }
+
+    /// Routes the parts of no cube each to the least loaded cube, to be mixed into its part.
+    ///
+    /// Returns the moves from the cubes the parts were meant for.
+    pub fn collapse<I: IntoIterator<Item = Channel>>(&mut self, parts: I) -> Vec<Move> {
+        let mut moves = vec![];
+        for part in parts {
+            if self.route.contains_key(&part) {
+                continue;
+            }
+            let target = (0..self.battery.len())
+                .filter(|c| !self.parked.contains(c) && !self.follower(*c))
+                .min_by_key(|c| self.load(*c));
+            if let Some(to) = target {
+                self.route.insert(part, to);
+                moves.push(Move::new(part, part as usize, to));
+            }
+        }
+        moves
+    }
+
+    /// Moves the parts of the lost cube to the least loaded cube playing on, parking it.
+    ///
+    /// Returns the moves and the parts no longer played as no cube is left for them.
+    pub fn fail(&mut self, cube: usize) -> (Vec<Move>, Vec<Channel>) {
+        self.parked.insert(cube);
+        let target = (0..self.battery.len())
+            .filter(|c| !self.parked.contains(c) && !self.follower(*c))
+            .min_by_key(|c| {
+                (
+                    self.load(*c),
+                    std::cmp::Reverse(self.battery[*c].unwrap_or(0)),
+                )
+            });
+
+        let parts: Vec<_> = self
+            .route
+            .iter()
+            .filter(|(_, c)| **c == cube)
+            .map(|(p, _)| *p)
+            .collect();
+        let mut moves = vec![];
+        for part in &parts {
+            match target {
+                Some(to) => {
+                    self.route.insert(*part, to);
+                    moves.push(Move::new(*part, cube, to));
+                }
+                None => {
+                    self.route.remove(part);
+                }
+            }
+        }
+        let dropped = if target.is_some() { vec![] } else { parts };
+        (moves, dropped)
+    }
+}
+
+/// Mixes the set into what's still sounding at `now` of another part on the cube, which
+/// the set cuts short otherwise.
+pub fn mix_in(
+    playing: &mut Playing,
+    cube: usize,
+    set: &PlaySet,
+    unit: u64,
+    now: Instant,
+) -> PlaySet {
+    let sounding = playing
+        .iter()
+        .rposition(|(c, p, _)| *c == cube && p.ch != set.ch);
+    let rest = sounding.map(|i| {
+        let (_, p, sent) = playing.remove(i);
+        p.rest(now.saturating_duration_since(sent).as_millis() as Time)
+    });
+    match rest {
+        Some(rest) if rest.plays.iter().any(|p| p.note != toio::Note::NoSound) => {
+            playing.retain(|(c, _, _)| *c != cube);
+            rest.mixed(set, unit)
+        }
+        _ => set.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::midi::Play;
+    use toio::Note;
+    use tokio::time::Duration;
+
+    #[test]
+    fn mix_in() {
+        let set = |ch, at, len, note| {
+            let mut set = PlaySet::new(ch, at);
+            set.len = len;
+            set.plays = vec![Play::new(ch, at, len, note)];
+            set
+        };
+        let sent = Instant::now();
+        let now = sent + Duration::from_millis(400);
+
+        // The part taken over cuts in while the cube's own is sounding; they take turns.
+        let mut playing = vec![
+            (0, set(1, 0, 1000, Note::C4)),
+            (1, set(2, 0, 1000, Note::G4)),
+        ]
+        .into_iter()
+        .map(|(c, p)| (c, p, sent))
+        .collect();
+        let mixed = super::mix_in(&mut playing, 0, &set(0, 400, 200, Note::E4), 100, now);
+        assert_eq!(
+            mixed.plays,
+            vec![
+                Play::new(0, 400, 100, Note::C4),
+                Play::new(0, 500, 100, Note::E4),
+                Play::new(0, 600, 400, Note::C4),
+            ]
+        );
+        let cubes: Vec<_> = playing.iter().map(|(c, _, _)| *c).collect();
+        assert_eq!(cubes, vec![1]);
+
+        // Nothing is left to mix once the cube's own set has ended.
+        let mut playing = vec![(0, set(1, 0, 300, Note::C4), sent)];
+        let next = set(0, 400, 200, Note::E4);
+        assert_eq!(super::mix_in(&mut playing, 0, &next, 100, now), next);
+    }
+
+    #[test]
+    fn rebalance() {
+        let mut r = Router::new(4, vec![0, 1, 2]);
+        r.battery(0, 10);
+        r.battery(1, 80);
+        r.battery(2, 90);
+        r.battery(3, 50);
+
+        // Cube 3 is idle, so it takes over although cube 2 is healthier.
+        assert_eq!(r.rebalance(20), (vec![Move::new(0, 0, 3)], vec![0]));
+        assert_eq!(r.cube(0), Some(3));
+
+        // Nothing changes for the already parked cube.
+        assert_eq!(r.rebalance(20), (vec![], vec![]));
+
+        // The cubes left play a part each; the healthiest mixes in the part.
+        r.battery(3, 15);
+        assert_eq!(r.rebalance(20), (vec![Move::new(0, 3, 2)], vec![3]));
+        assert_eq!(r.cube(0), Some(2));
+        assert!(r.shared(2));
+
+        // The healthiest one among the idle cubes is picked.
+        let mut r = Router::new(5, vec![0, 1]);
+        r.battery(0, 10);
+        r.battery(1, 95);
+        r.battery(2, 60);
+        r.battery(3, 80);
+        assert_eq!(r.rebalance(20), (vec![Move::new(0, 0, 3)], vec![0]));
+    }
+
+    #[test]
+    fn rebalance_busy() {
+        // Every healthy cube plays a part; the healthiest one takes over the weak one's.
+        let mut r = Router::new(3, vec![0, 1, 2]);
+        r.battery(0, 10);
+        r.battery(1, 70);
+        r.battery(2, 90);
+        assert_eq!(r.rebalance(20), (vec![Move::new(0, 0, 2)], vec![0]));
+        assert_eq!(r.cube(0), Some(2));
+        assert_eq!(r.cube(1), Some(1));
+
+        // A cube playing two parts already is loaded too much to take over.
+        r.battery(1, 10);
+        assert_eq!(r.rebalance(20), (vec![], vec![]));
+        assert_eq!(r.cube(1), Some(1));
+
+        // A cube without a part is parked all the same.
+        let mut r = Router::new(3, vec![0, 1]);
+        r.battery(2, 10);
+        assert_eq!(r.rebalance(20), (vec![], vec![2]));
+    }
+
+    #[test]
+    fn skewed() {
+        let mut r = Router::new(2, vec![0, 1]);
+        let plan: Plan = (0..2)
+            .map(|ch| ((100, ch), crate::midi::PlaySet::new(ch, 100)))
+            .collect();
+        assert_eq!(r.skewed(plan.clone()), plan);
+
+        r.latency(0, 40);
+        r.latency(1, 10);
+        let keys: Vec<_> = r.skewed(plan).into_keys().collect();
+        assert_eq!(keys, vec![(100, 0), (130, 1)]);
+    }
+
+    #[test]
+    fn collapse() {
+        let mut r = Router::new(2, vec![0, 1, 2, 3, 4]);
+        assert_eq!(r.cube(3), None);
+        assert_eq!(
+            r.collapse(vec![1, 2, 3, 4]),
+            vec![Move::new(2, 2, 0), Move::new(3, 3, 1), Move::new(4, 4, 0)]
+        );
+        assert_eq!(r.cube(3), Some(1));
+        assert!(r.shared(0) && r.shared(1));
+    }
+
+    #[test]
+    fn fail() {
+        let mut r = Router::new(3, vec![0, 1, 2, 3]);
+        r.route.insert(3, 2);
+        assert!(!r.shared(1));
+        assert_eq!(r.fail(0), (vec![Move::new(0, 0, 1)], vec![]));
+        assert_eq!(r.cube(0), Some(1));
+        assert!(r.shared(1));
+
+        // The cube left plays all the parts.
+        assert_eq!(
+            r.fail(1),
+            (vec![Move::new(0, 1, 2), Move::new(1, 1, 2)], vec![])
+        );
+        assert_eq!(r.fail(2), (vec![], vec![0, 1, 2, 3]));
+        assert_eq!(r.cube(2), None);
+    }
+
+    #[test]
+    fn mirror() {
+        assert_eq!(
+            "cube2=cube0:+12".parse::<Mirror>().unwrap(),
+            Mirror::new(2, 0, 12)
+        );
+        assert_eq!("3=1:-7".parse::<Mirror>().unwrap(), Mirror::new(3, 1, -7));
+        assert_eq!("3=1".parse::<Mirror>().unwrap(), Mirror::new(3, 1, 0));
+        assert!("cube1=cube1:+12".parse::<Mirror>().is_err());
+        assert!("cube1:+12".parse::<Mirror>().is_err());
+
+        let mut r = Router::new(4, vec![0, 1, 2]);
+        assert!(r.mirror(&Mirror::new(4, 0, 12)).is_err());
+        assert_eq!(r.mirror(&Mirror::new(2, 0, 12)).unwrap(), vec![2]);
+        assert_eq!(r.cube(2), None);
+        assert_eq!(r.mirrors(0), vec![(2, 12)]);
+
+        // The mirror follows the part, and the follower doesn't take over parts.
+        r.battery(0, 10);
+        r.battery(1, 80);
+        r.battery(2, 90);
+        r.battery(3, 50);
+        assert_eq!(r.rebalance(20), (vec![Move::new(0, 0, 3)], vec![0]));
+        assert_eq!(r.mirrors(0), vec![(2, 12)]);
+
+        // A parked follower stops mirroring.
+        r.battery(2, 10);
+        assert_eq!(r.rebalance(20), (vec![], vec![2]));
+        assert!(r.mirrors(0).is_empty());
+    }
+}
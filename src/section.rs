@@ -0,0 +1,193 @@
+//! Sections of the song to play only a passage of it.
+
+use anyhow::{anyhow, Error, Result};
+
+use crate::midi::{Plan, Time};
+
+/// A position in the song in msec.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Offset(pub Time);
+
+impl std::str::FromStr for Offset {
+    type Err = Error;
+
+    /// Parses `1m30s`, `90s`, `1:30`, `1:30.5`, `500ms` or `90` in seconds.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid time: {} (expected such as 1m30s or 1:30)", s);
+        let secs = |v: &str| -> Result<f64> {
+            match v.parse::<f64>() {
+                Ok(v) if v >= 0.0 && v.is_finite() => Ok(v),
+                _ => Err(invalid()),
+            }
+        };
+
+        let ms = if s.is_empty() {
+            return Err(invalid());
+        } else if let Some((m, sec)) = s.split_once(':') {
+            secs(m)?.trunc() * 60000.0 + secs(sec)? * 1000.0
+        } else if let Some(ms) = s.strip_suffix("ms") {
+            secs(ms)?
+        } else {
+            let (m, rest) = match s.split_once('m') {
+                Some((m, rest)) => (secs(m)?.trunc(), rest),
+                None => (0.0, s),
+            };
+            let rest = rest.strip_suffix('s').unwrap_or(rest);
+            let sec = if rest.is_empty() { 0.0 } else { secs(rest)? };
+            m * 60000.0 + sec * 1000.0
+        };
+        Ok(Offset(ms.round() as Time))
+    }
+}
+
+impl std::fmt::Display for Offset {
+    /// Formats as `m:ss.s`, or with as many decimals as the precision given, such as
+    /// `m:ss.sss` for `{:.3}` and `m:ss` for `{:.0}`.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (min, ms) = (self.0 / 60000, self.0 % 60000);
+        match f.precision().unwrap_or(1).min(3) {
+            0 => write!(f, "{}:{:02}", min, ms / 1000),
+            p => write!(
+                f,
+                "{}:{:02}.{:0p$}",
+                min,
+                ms / 1000,
+                ms % 1000 / 10u64.pow(3 - p as u32),
+                p = p
+            ),
+        }
+    }
+}
+
+/// How many times to play the song, or `None` to play it forever.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Repeat(pub Option<usize>);
+
+impl std::str::FromStr for Repeat {
+    type Err = Error;
+
+    /// Parses a count or `infinite`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "infinite" | "inf" => Ok(Repeat(None)),
+            s => s
+                .parse()
+                .map(|n| Repeat(Some(n)))
+                .map_err(|_| anyhow!("Invalid repeat: {} (expected a count or infinite)", s)),
+        }
+    }
+}
+
+/// Cuts the plan to the sets sounding from `from` until `to`, trimming the sets over
+/// the boundaries, and moves it to start at `from`.
+pub fn cut(plan: Plan, from: Time, to: Option<Time>) -> Plan {
+    let to = to.unwrap_or(Time::MAX);
+    let mut cut = Plan::new();
+    for (_, set) in plan {
+        let end = set.at + set.len;
+        if end <= from || set.at >= to {
+            continue;
+        }
+        let mut set = if set.at < from || end > to {
+            let start = set.at.max(from);
+            let rest = set.rest(start - set.at);
+            rest.window(0, end.min(to) - start)
+        } else {
+            set
+        };
+        if set.plays.is_empty() {
+            continue;
+        }
+
+        set.at -= from;
+        for play in &mut set.plays {
+            play.at -= from;
+        }
+        cut.insert((set.at, set.ch), set);
+    }
+    cut
+}
+
+/// The sets sounding from `from` on where they are, trimming the set over it, to play the
+/// song on from there.
+pub fn seek(plan: &Plan, from: Time) -> Plan {
+    plan.values()
+        .filter(|set| set.at + set.len > from)
+        .map(|set| {
+            if set.at < from {
+                set.rest(from - set.at)
+            } else {
+                set.clone()
+            }
+        })
+        .filter(|set| !set.plays.is_empty())
+        .map(|set| ((set.at, set.ch), set))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::midi::{Play, PlaySet};
+    use toio::Note;
+
+    #[test]
+    fn offset() {
+        let ms = |s: &str| s.parse::<Offset>().unwrap().0;
+        assert_eq!(ms("1m30s"), 90_000);
+        assert_eq!(ms("2m"), 120_000);
+        assert_eq!(ms("90s"), 90_000);
+        assert_eq!(ms("1:30.5"), 90_500);
+        assert_eq!(ms("500ms"), 500);
+        assert_eq!(ms("1.5"), 1500);
+        assert!("".parse::<Offset>().is_err());
+        assert!("1h".parse::<Offset>().is_err());
+        assert!("-1s".parse::<Offset>().is_err());
+        assert_eq!(Offset(90_500).to_string(), "1:30.5");
+        assert_eq!(Offset(83_500).to_string(), "1:23.5");
+        assert_eq!(format!("{:.3}", Offset(83_500)), "1:23.500");
+        assert_eq!(format!("{:.0}", Offset(59_999)), "0:59");
+
+        assert_eq!("3".parse::<Repeat>().unwrap(), Repeat(Some(3)));
+        assert_eq!("infinite".parse::<Repeat>().unwrap(), Repeat(None));
+        assert!("twice".parse::<Repeat>().is_err());
+    }
+
+    #[test]
+    fn cut() {
+        let set = |at, notes: &[Note]| {
+            let mut set = PlaySet::new(0, at);
+            set.plays = notes
+                .iter()
+                .enumerate()
+                .map(|(i, n)| Play::new(0, at + i as Time * 100, 100, *n))
+                .collect();
+            set.len = notes.len() as Time * 100;
+            ((at, 0), set)
+        };
+        let plan: Plan = vec![
+            set(0, &[Note::C4, Note::D4]),
+            set(200, &[Note::E4, Note::F4, Note::G4]),
+            set(600, &[Note::A4]),
+        ]
+        .into_iter()
+        .collect();
+
+        let seek = super::seek(&plan, 250);
+        assert_eq!(seek.keys().collect::<Vec<_>>(), vec![&(250, 0), &(600, 0)]);
+        assert_eq!(seek[&(250, 0)].plays[0], Play::new(0, 250, 50, Note::E4));
+
+        let cut = super::cut(plan, 250, Some(450));
+        let sets: Vec<_> = cut.values().collect();
+        assert_eq!(sets.len(), 1);
+        assert_eq!((sets[0].at, sets[0].len), (0, 200));
+        assert_eq!(
+            sets[0].plays,
+            vec![
+                Play::new(0, 0, 50, Note::E4),
+                Play::new(0, 50, 100, Note::F4),
+                Play::new(0, 150, 50, Note::G4),
+            ]
+        );
+    }
+}
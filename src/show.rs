@@ -0,0 +1,484 @@
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use structopt::clap::ArgMatches;
+
+use crate::{
+    config::{self, Item, Pos, Table, Value},
+    midi::Channel,
+    section::Repeat,
+    Opt,
+};
+
+/// How a key of show files sets the options, from the item and the directory of the show.
+type Set = fn(&Item, &Path, &mut Opt) -> Result<()>;
+
+/// The keys of show files, named after the long options except `layers` for `--file`,
+/// with how to set each.
+const KEYS: &[(&str, Set)] = &[
+    ("file", |i, base, opt| {
+        put(&mut opt.file, path(i, base).map(Some))
+    }),
+    ("layers", |i, base, opt| {
+        put(
+            &mut opt.layers,
+            i.array()?.iter().map(|i| path(i, base)).collect(),
+        )
+    }),
+    ("playlist", |i, base, opt| {
+        put(&mut opt.playlist, path(i, base).map(Some))
+    }),
+    ("dir", |i, base, opt| {
+        put(&mut opt.dir, path(i, base).map(Some))
+    }),
+    ("shuffle", |i, _, opt| put(&mut opt.shuffle, i.bool())),
+    ("watch", |i, _, opt| put(&mut opt.watch, i.bool())),
+    ("rules", |i, _, opt| put(&mut opt.rules, each(i))),
+    ("cubes", |i, _, opt| {
+        put(
+            &mut opt.connect.cubes,
+            i.array()?.iter().map(|i| Ok(i.str()?.into())).collect(),
+        )
+    }),
+    ("speed", |i, _, opt| put(&mut opt.speed, i.uint())),
+    ("link", |i, _, opt| put(&mut opt.links, each(i))),
+    ("tuning", |i, _, opt| put(&mut opt.tunings, each(i))),
+    ("offset", |i, _, opt| put(&mut opt.latencies, each(i))),
+    ("panic-key", |i, _, opt| put(&mut opt.panic_key, i.bool())),
+    ("panic-http", |i, _, opt| {
+        put(&mut opt.panic_http, parsed(i).map(Some))
+    }),
+    ("panic-osc", |i, _, opt| {
+        put(&mut opt.panic_osc, parsed(i).map(Some))
+    }),
+    ("demo", |i, _, opt| {
+        put(&mut opt.demo, i.str().map(|s| Some(s.into())))
+    }),
+    ("tap-tempo", |i, _, opt| put(&mut opt.tap_tempo, i.bool())),
+    ("unit", |i, _, opt| put(&mut opt.unit, i.uint())),
+    ("segment", |i, _, opt| {
+        put(&mut opt.segment, i.float().map(Some))
+    }),
+    ("from", |i, _, opt| put(&mut opt.from, parsed(i).map(Some))),
+    ("to", |i, _, opt| put(&mut opt.to, parsed(i).map(Some))),
+    ("from-marker", |i, _, opt| {
+        put(&mut opt.from_marker, i.str().map(|s| Some(s.into())))
+    }),
+    ("lyrics", |i, _, opt| put(&mut opt.lyrics, i.bool())),
+    ("count-in", |i, _, opt| {
+        put(&mut opt.count_in, i.uint().map(Some))
+    }),
+    ("metronome", |i, _, opt| {
+        put(&mut opt.metronome, i.uint().map(Some))
+    }),
+    ("fade-out", |i, _, opt| {
+        put(&mut opt.fade_out, i.float().map(Some))
+    }),
+    ("loop-between", |i, _, opt| {
+        put(&mut opt.loop_between, each(i))
+    }),
+    ("repeat", |i, _, opt| {
+        let repeat = match i.value {
+            Value::Int(_) => Repeat(Some(i.uint()?)),
+            _ => parsed(i)?,
+        };
+        put(&mut opt.repeat, Ok(Some(repeat)))
+    }),
+    ("min-battery", |i, _, opt| {
+        put(&mut opt.min_battery, i.uint().map(Some))
+    }),
+    ("reassign-battery", |i, _, opt| {
+        put(&mut opt.reassign_battery, i.uint().map(Some))
+    }),
+    ("require-cubes", |i, _, opt| {
+        put(&mut opt.connect.require_cubes, i.uint().map(Some))
+    }),
+    ("search-timeout", |i, _, opt| {
+        put(&mut opt.connect.search_timeout, parsed(i))
+    }),
+    ("max-cubes", |i, _, opt| {
+        put(&mut opt.connect.max_cubes, i.uint().map(Some))
+    }),
+    ("min-rssi", |i, _, opt| {
+        put(&mut opt.connect.min_rssi, i.int().map(|n| Some(n as i32)))
+    }),
+    ("reconnect", |i, _, opt| put(&mut opt.reconnect, i.bool())),
+    ("hot-plug", |i, _, opt| put(&mut opt.hot_plug, i.uint())),
+    ("collapse", |i, _, opt| put(&mut opt.collapse, i.bool())),
+    ("failover", |i, _, opt| put(&mut opt.failover, i.bool())),
+    ("drop-failed", |i, _, opt| {
+        put(&mut opt.drop_failed, i.bool())
+    }),
+    ("mute", |i, _, opt| put(&mut opt.mute, channels(i))),
+    ("solo", |i, _, opt| put(&mut opt.solo, channels(i))),
+    ("gap", |i, _, opt| put(&mut opt.gap, parsed(i))),
+    ("trace-ble", |i, _, opt| put(&mut opt.trace.ble, i.bool())),
+    ("trace", |i, base, opt| {
+        put(&mut opt.trace.file, path(i, base).map(Some))
+    }),
+    ("event-log", |i, base, opt| {
+        put(&mut opt.event_log, path(i, base).map(Some))
+    }),
+    ("log-cube", |i, _, opt| {
+        put(&mut opt.log_cube, i.uint().map(Some))
+    }),
+    ("max-ops", |i, _, opt| put(&mut opt.max_ops, i.uint())),
+    ("max-op-len", |i, _, opt| put(&mut opt.max_op_len, i.uint())),
+    ("strict", |i, _, opt| put(&mut opt.strict, i.bool())),
+    ("lenient", |i, _, opt| put(&mut opt.read.lenient, i.bool())),
+    ("max-warnings", |i, _, opt| {
+        put(&mut opt.max_warnings, i.uint().map(Some))
+    }),
+    ("parser", |i, _, opt| put(&mut opt.read.parser, parsed(i))),
+    ("sequence", |i, _, opt| {
+        put(&mut opt.sequence, i.uint().map(Some))
+    }),
+    ("format", |i, _, opt| put(&mut opt.read.format, parsed(i))),
+    ("group-by", |i, _, opt| {
+        put(&mut opt.read.group_by, parsed(i))
+    }),
+    ("auto-assign", |i, _, opt| {
+        put(&mut opt.auto_assign, i.bool())
+    }),
+    ("dynamics", |i, _, opt| put(&mut opt.dynamics, i.bool())),
+    ("light-mode", |i, _, opt| {
+        put(&mut opt.light_mode, parsed(i))
+    }),
+    ("fold-repeats", |i, _, opt| {
+        put(&mut opt.fold_repeats, i.bool())
+    }),
+    ("idle", |i, _, opt| put(&mut opt.idle, parsed(i))),
+    ("idle-chime-secs", |i, _, opt| {
+        put(&mut opt.idle_chime_secs, i.uint())
+    }),
+    ("fixed-packets", |i, _, opt| {
+        put(&mut opt.fixed_packets, i.bool())
+    }),
+    ("cache", |i, _, opt| put(&mut opt.cache, i.bool())),
+    ("progressive", |i, _, opt| {
+        put(&mut opt.progressive, i.bool())
+    }),
+    ("precise-timing", |i, _, opt| {
+        put(&mut opt.precise_timing, i.bool())
+    }),
+    ("retries", |i, _, opt| put(&mut opt.retries, i.uint())),
+    ("max-skips", |i, _, opt| put(&mut opt.max_skips, i.uint())),
+    ("lookahead", |i, _, opt| put(&mut opt.lookahead, i.uint())),
+    ("midi-clock", |i, _, opt| {
+        put(&mut opt.midi_clock, i.str().map(|s| Some(s.into())))
+    }),
+    ("mtc", |i, _, opt| put(&mut opt.mtc, parsed(i).map(Some))),
+    ("mmc", |i, _, opt| put(&mut opt.mmc, parsed(i).map(Some))),
+    ("export-toio", |i, base, opt| {
+        put(&mut opt.export_toio, path(i, base).map(Some))
+    }),
+    ("export-midi", |i, base, opt| {
+        put(&mut opt.export_midi, path(i, base).map(Some))
+    }),
+    ("preview-midi", |i, _, opt| {
+        put(&mut opt.preview_midi, i.str().map(|s| Some(s.into())))
+    }),
+    ("simulate", |i, _, opt| put(&mut opt.simulate, i.bool())),
+    ("simulate-beep", |i, _, opt| {
+        put(&mut opt.simulate_beep, i.bool())
+    }),
+    ("play-log", |i, base, opt| {
+        put(&mut opt.play_log, path(i, base).map(Some))
+    }),
+    ("piano-roll", |i, _, opt| put(&mut opt.piano_roll, i.bool())),
+    ("tui", |i, _, opt| put(&mut opt.tui, i.bool())),
+    ("keys", |i, _, opt| put(&mut opt.keys, i.bool())),
+    ("progress", |i, _, opt| put(&mut opt.progress, i.bool())),
+    ("dry-run", |i, _, opt| put(&mut opt.dry_run, i.bool())),
+    ("max-duration", |i, _, opt| {
+        put(&mut opt.max_duration, i.uint().map(Some))
+    }),
+    // Taken by `profiled`.
+    ("profiles", |i, _, _| i.table().map(|_| ())),
+];
+
+/// Returns true if the file looks like a show file.
+pub fn is_show(path: &Path) -> bool {
+    path.extension().map(|e| e == "toml").unwrap_or(false)
+}
+
+/// The keys of the settings given on the command line, before a command or after it.
+///
+/// It goes through the keys of show files, looking `layers` up as the argument `layer`.
+pub fn given(matches: &ArgMatches) -> Vec<&'static str> {
+    let (_, sub) = matches.subcommand();
+    let given = |name: &str| {
+        [Some(matches), sub]
+            .iter()
+            .flatten()
+            .any(|m| m.occurrences_of(name) > 0)
+    };
+    KEYS.iter()
+        .map(|(key, _)| *key)
+        .filter(|key| match *key {
+            // The file given is the show itself.
+            "file" => false,
+            "layers" => given("layer"),
+            key => given(key),
+        })
+        .collect()
+}
+
+/// Loads the show file, with the options given on the command line in `given` winning
+/// over its settings.
+///
+/// The MIDI file in the show is relative to the directory of the show file.
+pub fn load(path: &Path, opt: &mut Opt, given: &[&str]) -> Result<()> {
+    let text = std::fs::read_to_string(path)
+        .context(format!("Couldn't read show file {}", path.display()))?;
+    let table = config::parse(&text).context(format!("Invalid show file {}", path.display()))?;
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+    apply(&table, base, opt, given).context(format!("Invalid show file {}", path.display()))
+}
+
+/// Reads the channels, or `file:channel` of the layered files as strings.
+fn channels(item: &Item) -> Result<Vec<Channel>> {
+    item.array()?
+        .iter()
+        .map(|i| match i.str() {
+            Ok(s) => crate::layer_channel(s).map_err(|e| i.error(e)),
+            Err(_) => i.uint(),
+        })
+        .collect()
+}
+
+/// Returns the known key closest to the misspelt one.
+fn suggest(key: &str) -> Option<&'static str> {
+    // Edit distance between the keys.
+    let distance = |a: &str, b: &str| {
+        let b: Vec<_> = b.chars().collect();
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for (i, ca) in a.chars().enumerate() {
+            let mut prev = row[0];
+            row[0] = i + 1;
+            for (j, cb) in b.iter().enumerate() {
+                let next = (row[j] + 1)
+                    .min(row[j + 1] + 1)
+                    .min(prev + (ca != *cb) as usize);
+                prev = row[j + 1];
+                row[j + 1] = next;
+            }
+        }
+        row[b.len()]
+    };
+    KEYS.iter()
+        .map(|(k, _)| (distance(key, k), *k))
+        .filter(|(d, k)| *d <= 2.max(k.len() / 4))
+        .min()
+        .map(|(_, k)| k)
+}
+
+fn set(key: &str, item: &Item, base: &Path, opt: &mut Opt) -> Result<()> {
+    match KEYS.iter().find(|(k, _)| *k == key) {
+        Some((_, set)) => set(item, base, opt),
+        None => {
+            let msg = match suggest(key) {
+                Some(k) => format!("unknown key '{}' (did you mean '{}'?)", key, k),
+                None => format!("unknown key '{}'", key),
+            };
+            Err(item.error(msg))
+        }
+    }
+}
+
+/// Sets the field to the value read.
+fn put<T>(field: &mut T, value: Result<T>) -> Result<()> {
+    *field = value?;
+    Ok(())
+}
+
+/// Reads the string of the item as a `T`.
+fn parsed<T: FromStr>(item: &Item) -> Result<T>
+where
+    T::Err: fmt::Display,
+{
+    item.str()?.parse().map_err(|e| item.error(e))
+}
+
+/// Reads the strings of the array as `T`s.
+fn each<T: FromStr>(item: &Item) -> Result<Vec<T>>
+where
+    T::Err: fmt::Display,
+{
+    item.array()?.iter().map(parsed).collect()
+}
+
+/// Reads a path relative to the directory of the show.
+fn path(item: &Item, base: &Path) -> Result<PathBuf> {
+    Ok(base.join(item.str()?))
+}
+
+/// Finds the settings that contradict each other, pointing at the later one.
+fn conflicts(table: &Table, opt: &Opt) -> Vec<(Pos, anyhow::Error)> {
+    crate::conflicts(opt)
+        .into_iter()
+        .filter_map(|c| {
+            let item = table.get(c.key)?;
+            let item = match c.item {
+                Some(i) => item.array().ok()?.get(i)?,
+                None => item,
+            };
+            Some((item.pos, item.error(c.msg)))
+        })
+        .collect()
+}
+
+/// The settings with those of the profile laid over them, if a profile is chosen.
+fn profiled(table: &Table, profile: Option<&str>) -> Result<Table> {
+    let name = match profile {
+        Some(name) => name,
+        None => return Ok(table.clone()),
+    };
+    let mut table = table.clone();
+    let profiles = match table.remove("profiles") {
+        Some(profiles) => profiles,
+        None => return Err(anyhow!("no profiles for profile '{}'", name)),
+    };
+    let profiles = profiles.table()?;
+    let settings = match profiles.get(name) {
+        Some(settings) => settings.table()?,
+        None => {
+            let names: Vec<_> = profiles.keys().map(|k| k.as_str()).collect();
+            return Err(anyhow!(
+                "no profile named '{}'; the profiles are {}",
+                name,
+                names.join(", ")
+            ));
+        }
+    };
+    table.extend(settings.clone());
+    Ok(table)
+}
+
+/// Applies the settings, reporting every invalid one at once in the order of the file.
+fn apply(table: &Table, base: &Path, opt: &mut Opt, given: &[&str]) -> Result<()> {
+    let table = &profiled(table, opt.profile.as_deref())?;
+    if !table.contains_key("file") && !table.contains_key("demo") {
+        return Err(anyhow!("missing key 'file'"));
+    }
+
+    let mut errors = vec![];
+    for (key, item) in table {
+        if given.contains(&key.as_str()) {
+            continue;
+        }
+        if let Err(e) = set(key, item, base, opt) {
+            errors.push((item.pos, e));
+        }
+    }
+    if errors.is_empty() {
+        errors = conflicts(table, opt);
+    }
+
+    errors.sort_by_key(|(pos, _)| *pos);
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0).1),
+        n => {
+            let errors: Vec<_> = errors.iter().map(|(_, e)| e.to_string()).collect();
+            Err(anyhow!("{} errors\n{}", n, errors.join("\n")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn apply(text: &str) -> Result<Opt> {
+        let mut opt = Opt::from_iter(&["toio-midi"]);
+        super::apply(&config::parse(text)?, Path::new(""), &mut opt, &[])?;
+        Ok(opt)
+    }
+
+    #[test]
+    fn keys() {
+        for (key, _) in KEYS {
+            let err = apply(&format!("file = 'a.mid'\n{} = []", key))
+                .err()
+                .map(|e| e.to_string())
+                .unwrap_or_default();
+            assert!(!err.contains("unknown key"), "{}", key);
+        }
+        assert_eq!(suggest("sped"), Some("speed"));
+        assert_eq!(suggest("light_mode"), Some("light-mode"));
+        assert_eq!(suggest("volume"), None);
+    }
+
+    #[test]
+    fn given() {
+        let args = ["toio-midi", "--speed", "80", "--file", "b.mid"];
+        let given = super::given(&Opt::clap().get_matches_from(args));
+        assert_eq!(given, vec!["layers", "speed"]);
+
+        let mut opt = Opt::from_iter(&args);
+        let table = config::parse("file = 'a.mid'\nspeed = 120\nunit = 20").unwrap();
+        super::apply(&table, Path::new(""), &mut opt, &given).unwrap();
+        assert_eq!((opt.speed, opt.unit), (80, 20));
+    }
+
+    #[test]
+    fn errors() {
+        let err = |s: &str| apply(s).err().unwrap().to_string();
+
+        assert_eq!(
+            err("file = 'a.mid'\nspeeed = 120\nunit = 'x'"),
+            concat!(
+                "2 errors\n",
+                "line 2, column 10: unknown key 'speeed' (did you mean 'speed'?)\n",
+                "line 3, column 8: expected an integer, found a string"
+            )
+        );
+        assert_eq!(
+            err("file = 'a.mid'\nrules = ['0=1', '1=2', '0=3']"),
+            "line 2, column 24: rule '0=3' plays on cube 0 as well as rule '0=1'; merge them into one rule"
+        );
+        assert_eq!(
+            err("file = 'a.mid'\nmute = [2]\nsolo = [2, 3]"),
+            "line 3, column 8: track 2 is both muted and soloed"
+        );
+        assert!(apply("file = 'a.mid'\nrules = ['0=1', '1=1']").is_ok());
+        let opt = apply("file = 'a.mid'\nmute = [2, '1:10']").unwrap();
+        assert_eq!(opt.mute, vec![2, 1010]);
+    }
+
+    #[test]
+    fn profiles() {
+        let text = "file = 'a.mid'\n\
+                    speed = 90\n\
+                    rules = ['0=1']\n\
+                    [profiles.classroom]\n\
+                    speed = 70\n\
+                    [profiles.stage]\n\
+                    rules = ['0=1', '1=2']\n\
+                    light-mode = 'note-follow'\n";
+        let profile = |name: Option<&str>| -> Result<Opt> {
+            let mut opt = Opt::from_iter(&["toio-midi"]);
+            opt.profile = name.map(|n| n.into());
+            super::apply(&config::parse(text)?, Path::new(""), &mut opt, &[])?;
+            Ok(opt)
+        };
+
+        let opt = profile(None).unwrap();
+        assert_eq!((opt.speed, opt.rules.len()), (90, 1));
+        let opt = profile(Some("classroom")).unwrap();
+        assert_eq!((opt.speed, opt.rules.len()), (70, 1));
+        let opt = profile(Some("stage")).unwrap();
+        assert_eq!((opt.speed, opt.rules.len()), (90, 2));
+        assert_eq!(opt.light_mode, crate::lights::Mode::NoteFollow);
+        assert_eq!(
+            profile(Some("hall")).err().unwrap().to_string(),
+            "no profile named 'hall'; the profiles are classroom, stage"
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! Virtual cubes logging the messages they receive, to play without hardware.
+
+use log::*;
+use std::time::Instant;
+use toio::proto::{self, Light, Message, Sound};
+
+use crate::song::note_name;
+
+/// Receives the messages of the virtual cubes, timed from the same start.
+#[derive(Clone, Copy, Debug)]
+pub struct Sim {
+    start: Instant,
+    beep: bool,
+}
+
+impl Sim {
+    /// Rings the terminal bell on each sound program if `beep` is set.
+    pub fn new(beep: bool) -> Self {
+        Self {
+            start: Instant::now(),
+            beep,
+        }
+    }
+
+    /// Logs the message received by the cube.
+    pub fn receive(&self, index: usize, msg: &Message) {
+        let at = self.start.elapsed().as_millis();
+        match msg {
+            Message::Sound(Sound::Play(_)) => {
+                info!("Cube {} at {} ms: {}", index, at, describe(msg));
+                if self.beep {
+                    eprint!("\x07");
+                }
+            }
+            Message::Sound(Sound::Stop) => info!("Cube {} at {} ms: {}", index, at, describe(msg)),
+            msg => debug!("Cube {} at {} ms: {}", index, at, describe(msg)),
+        }
+    }
+}
+
+/// Describes the message such as `play x1: C4 250ms` or `light #ff0000`.
+pub fn describe(msg: &Message) -> String {
+    match msg {
+        Message::Sound(Sound::Play(p)) => format!("play x{}: {}", p.repeat, ops(&p.ops)),
+        Message::Sound(Sound::Stop) => "stop".to_string(),
+        Message::Light(Light::On(l)) => {
+            format!("light #{:02x}{:02x}{:02x}", l.red, l.green, l.blue)
+        }
+        Message::Light(Light::Ctrl(l)) => {
+            format!("light program of {} ops x{}", l.num, l.repeat)
+        }
+        msg => format!("{:?}", msg),
+    }
+}
+
+/// Describes the sound operations such as `C4 250ms, rest 100ms`.
+fn ops(ops: &[proto::SoundOp]) -> String {
+    ops.iter()
+        .map(|op| format!("{} {}ms", note_name(op.note), op.duration as u32 * 10))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use toio::Note;
+
+    #[test]
+    fn describe() {
+        assert_eq!(
+            ops(&[
+                proto::SoundOp::new(25, Note::C4, 255),
+                proto::SoundOp::new(10, Note::NoSound, 255)
+            ]),
+            "C4 250ms, rest 100ms"
+        );
+        assert_eq!(super::describe(&Message::Sound(Sound::Stop)), "stop");
+        assert_eq!(
+            super::describe(&Message::Light(Light::On(proto::LightOn::new(
+                0, 255, 0, 16
+            )))),
+            "light #ff0010"
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! Targets the scheduler plays the sets on, a sink per cube.
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use toio::Note;
+
+use crate::{
+    midi::{PlaySet, Time},
+    smf,
+    song::note_name,
+};
+
+/// Plays the sets the scheduler sends to a cube as they're due.
+///
+/// Only `play` and `finish` are required; the others are for what real cubes do
+/// beyond playing.
+pub trait SoundSink {
+    /// Starts playing the set, returning without waiting for it to end.
+    async fn play(&mut self, set: PlaySet) -> Result<()>;
+
+    /// Plays the set at the time, given ahead of it so that the sink can get it ready.
+    ///
+    /// The sinks that don't keep time play it at once.
+    async fn play_at(&mut self, set: PlaySet, _due: tokio::time::Instant) -> Result<()> {
+        self.play(set).await
+    }
+
+    /// Stops what's playing at once, on the panic button.
+    async fn silence(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Goes on after the silence, with the sets sent from then.
+    async fn resume(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Waits for the next segment.
+    async fn idle(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Gets ready to play the song from the start again.
+    async fn restart(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stops playing for good, as the battery is low.
+    async fn park(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Fades out within the time, as the time limit comes.
+    async fn wind_down(&mut self, _fade: Duration) -> Result<()> {
+        self.silence().await
+    }
+
+    /// Tells if the cube is lost for good, so that its parts can go to another.
+    fn lost(&self) -> bool {
+        false
+    }
+
+    /// Waits for what's left to finish.
+    async fn finish(self) -> Result<()>;
+}
+
+/// Plays on both, such as a cube while logging the sets.
+impl<A: SoundSink, B: SoundSink> SoundSink for (A, B) {
+    async fn play(&mut self, set: PlaySet) -> Result<()> {
+        self.0.play(set.clone()).await?;
+        self.1.play(set).await
+    }
+
+    async fn play_at(&mut self, set: PlaySet, due: tokio::time::Instant) -> Result<()> {
+        self.0.play_at(set.clone(), due).await?;
+        self.1.play_at(set, due).await
+    }
+
+    async fn silence(&mut self) -> Result<()> {
+        self.0.silence().await?;
+        self.1.silence().await
+    }
+
+    async fn resume(&mut self) -> Result<()> {
+        self.0.resume().await?;
+        self.1.resume().await
+    }
+
+    async fn idle(&mut self) -> Result<()> {
+        self.0.idle().await?;
+        self.1.idle().await
+    }
+
+    async fn restart(&mut self) -> Result<()> {
+        self.0.restart().await?;
+        self.1.restart().await
+    }
+
+    async fn park(&mut self) -> Result<()> {
+        self.0.park().await?;
+        self.1.park().await
+    }
+
+    async fn wind_down(&mut self, fade: Duration) -> Result<()> {
+        self.0.wind_down(fade).await?;
+        self.1.wind_down(fade).await
+    }
+
+    fn lost(&self) -> bool {
+        self.0.lost() || self.1.lost()
+    }
+
+    async fn finish(self) -> Result<()> {
+        self.0.finish().await?;
+        self.1.finish().await
+    }
+}
+
+/// Plays on the sink if there's one, such as a log asked for.
+impl<S: SoundSink> SoundSink for Option<S> {
+    async fn play(&mut self, set: PlaySet) -> Result<()> {
+        match self {
+            Some(sink) => sink.play(set).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn play_at(&mut self, set: PlaySet, due: tokio::time::Instant) -> Result<()> {
+        match self {
+            Some(sink) => sink.play_at(set, due).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn silence(&mut self) -> Result<()> {
+        match self {
+            Some(sink) => sink.silence().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn resume(&mut self) -> Result<()> {
+        match self {
+            Some(sink) => sink.resume().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn idle(&mut self) -> Result<()> {
+        match self {
+            Some(sink) => sink.idle().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn restart(&mut self) -> Result<()> {
+        match self {
+            Some(sink) => sink.restart().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn park(&mut self) -> Result<()> {
+        match self {
+            Some(sink) => sink.park().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn wind_down(&mut self, fade: Duration) -> Result<()> {
+        match self {
+            Some(sink) => sink.wind_down(fade).await,
+            None => Ok(()),
+        }
+    }
+
+    fn lost(&self) -> bool {
+        self.as_ref().is_some_and(|sink| sink.lost())
+    }
+
+    async fn finish(self) -> Result<()> {
+        match self {
+            Some(sink) => sink.finish().await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// A MIDI message due at the time, unless silenced after it's queued.
+type Queued = (Instant, usize, [u8; 3]);
+
+/// Plays the sets of a cube on a channel of the raw MIDI output such as
+/// `/dev/snd/midiC1D0`, for example to hear them through a softsynth.
+pub struct MidiOut {
+    ch: u8,
+    tx: mpsc::Sender<Queued>,
+    /// Bumped on silence to drop the queued messages.
+    generation: Arc<AtomicUsize>,
+    // Writes to the device block; keep them and the timing on a thread.
+    thread: JoinHandle<Result<()>>,
+}
+
+impl MidiOut {
+    /// Opens the output shared by the sinks of the cubes, a channel each.
+    pub fn open(path: &Path, cubes: usize) -> Result<Vec<MidiOut>> {
+        let out = std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .context(format!("Couldn't open MIDI output {}", path.display()))?;
+        let out = Arc::new(Mutex::new(out));
+        Ok((0..cubes)
+            .map(|i| Self::spawn(smf::channel(i), out.clone(), path.to_path_buf()))
+            .collect())
+    }
+
+    fn spawn(ch: u8, out: Arc<Mutex<File>>, path: PathBuf) -> Self {
+        let (tx, rx) = mpsc::channel::<Queued>();
+        let generation = Arc::new(AtomicUsize::new(0));
+        let current = generation.clone();
+        let thread = std::thread::spawn(move || {
+            for (at, generation, message) in rx {
+                if generation != current.load(Ordering::SeqCst) {
+                    continue;
+                }
+                if let Some(wait) = at.checked_duration_since(Instant::now()) {
+                    std::thread::sleep(wait);
+                }
+                if generation != current.load(Ordering::SeqCst) {
+                    continue;
+                }
+                out.lock()
+                    .unwrap()
+                    .write_all(&message)
+                    .context(format!("Couldn't write to {}", path.display()))?;
+            }
+            Ok(())
+        });
+        Self {
+            ch,
+            tx,
+            generation,
+            thread,
+        }
+    }
+
+    fn send(&self, at: Instant, message: [u8; 3]) -> Result<()> {
+        self.tx
+            .send((at, self.generation.load(Ordering::SeqCst), message))
+            .map_err(|_| anyhow!("The MIDI output on channel {} stopped", self.ch + 1))
+    }
+
+    fn notes_off(&self) -> Result<()> {
+        self.send(Instant::now(), [0xb0 | self.ch, 123, 0])
+    }
+}
+
+/// MIDI messages of the set on the channel as (msec from the start of the set, bytes).
+fn messages(set: &PlaySet, ch: u8) -> Vec<(Time, [u8; 3])> {
+    let mut messages = vec![];
+    for play in set.unrolled() {
+        if play.note == Note::NoSound {
+            continue;
+        }
+        let at = play.at - set.at;
+        let note = (play.note as u8 + 12).min(127);
+        messages.push((at, [0x90 | ch, note, play.vel.clamp(1, 127)]));
+        messages.push((at + play.len, [0x80 | ch, note, 0]));
+    }
+    // Note offs go before note ons at the same time.
+    messages.sort_by_key(|(at, m)| (*at, m[0] & 0xf0 == 0x90));
+    messages
+}
+
+impl SoundSink for MidiOut {
+    async fn play(&mut self, set: PlaySet) -> Result<()> {
+        self.play_at(set, tokio::time::Instant::now()).await
+    }
+
+    async fn play_at(&mut self, set: PlaySet, due: tokio::time::Instant) -> Result<()> {
+        let start = due.into_std().max(Instant::now());
+        for (at, message) in messages(&set, self.ch) {
+            self.send(start + Duration::from_millis(at), message)?;
+        }
+        Ok(())
+    }
+
+    async fn silence(&mut self) -> Result<()> {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.notes_off()
+    }
+
+    async fn finish(self) -> Result<()> {
+        self.notes_off()?;
+        drop(self.tx);
+        let thread = self.thread;
+        tokio::task::spawn_blocking(move || thread.join())
+            .await?
+            .map_err(|_| anyhow!("The MIDI output panicked"))?
+    }
+}
+
+/// Records the sets sent to a cube into a file shared with the other cubes,
+/// a line per set such as `1200 cube 0 part 2 x1: C4 600ms, rest 600ms`.
+pub struct PlayLog {
+    cube: usize,
+    out: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl PlayLog {
+    pub fn create(path: &Path, cubes: usize) -> Result<Vec<PlayLog>> {
+        let out = File::create(path).context(format!("Couldn't write {}", path.display()))?;
+        let out = Arc::new(Mutex::new(BufWriter::new(out)));
+        Ok((0..cubes)
+            .map(|cube| PlayLog {
+                cube,
+                out: out.clone(),
+            })
+            .collect())
+    }
+}
+
+/// Describes the set sent to the cube, at its time in the plan.
+fn line(cube: usize, set: &PlaySet) -> String {
+    let plays: Vec<_> = set
+        .plays
+        .iter()
+        .map(|p| format!("{} {}ms", note_name(p.note), p.len))
+        .collect();
+    format!(
+        "{} cube {} part {} x{}: {}",
+        set.at,
+        cube,
+        set.ch,
+        set.repeat,
+        plays.join(", ")
+    )
+}
+
+impl SoundSink for PlayLog {
+    async fn play(&mut self, set: PlaySet) -> Result<()> {
+        writeln!(self.out.lock().unwrap(), "{}", line(self.cube, &set))?;
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<()> {
+        self.out.lock().unwrap().flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::midi::Play;
+
+    #[test]
+    fn sinks() {
+        let mut set = PlaySet::new(3, 1000);
+        set.plays = vec![
+            Play::new(3, 1000, 50, Note::A4),
+            Play::new(3, 1050, 50, Note::NoSound),
+        ];
+        set.len = 200;
+        set.repeat = 2;
+
+        assert_eq!(
+            messages(&set, 1),
+            vec![
+                (0, [0x91, 69, 100]),
+                (50, [0x81, 69, 0]),
+                (100, [0x91, 69, 100]),
+                (150, [0x81, 69, 0]),
+            ]
+        );
+        assert_eq!(line(0, &set), "1000 cube 0 part 3 x2: A4 50ms, rest 50ms");
+    }
+}
@@ -0,0 +1,442 @@
+use anyhow::{anyhow, Context, Result};
+use derive_new::new;
+use ghakuf::{
+    messages::{Message, MetaEvent, MidiEvent, MidiEventBuilder, SysExEvent},
+    reader::Handler,
+    writer::Writer,
+};
+use log::*;
+use std::path::Path;
+
+use crate::unsupported::DRUMS;
+
+/// Reads bytes of a chunk, failing at its end.
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).cloned()
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(len)?;
+        let bytes = self.data.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.bytes(2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let b = self.bytes(4)?;
+        Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Reads a variable-length quantity of up to 4 bytes.
+    fn vlq(&mut self) -> Option<u32> {
+        let mut v = 0u32;
+        for _ in 0..4 {
+            let b = self.u8()?;
+            v = (v << 7) | (b & 0x7f) as u32;
+            if b & 0x80 == 0 {
+                return Some(v);
+            }
+        }
+        None
+    }
+}
+
+/// Fails with the message, or only warns about it if lenient.
+fn recover(lenient: bool, msg: String) -> Result<()> {
+    if lenient {
+        warn!("{}", msg);
+        Ok(())
+    } else {
+        Err(anyhow!(msg))
+    }
+}
+
+/// Reads a standard MIDI file, calling the handler like the reader of ghakuf.
+///
+/// A broken file fails with the byte offset of the error, unless lenient, when it
+/// clamps chunks running past the end of the file and skips the rest of a track
+/// at a broken event instead. Unknown chunks are always skipped.
+pub fn read<H: Handler>(data: &[u8], handler: &mut H, lenient: bool) -> Result<()> {
+    let mut file = Cursor::new(data);
+
+    if file.bytes(4) != Some(b"MThd") {
+        return Err(anyhow!("Not a standard MIDI file"));
+    }
+    let len = file.u32().ok_or_else(|| anyhow!("Truncated header"))? as usize;
+    let mut header = Cursor::new(file.bytes(len).ok_or_else(|| anyhow!("Truncated header"))?);
+    match (header.u16(), header.u16(), header.u16()) {
+        (Some(format), Some(tracks), Some(time_base)) => handler.header(format, tracks, time_base),
+        _ => return Err(anyhow!("Truncated header")),
+    }
+
+    while !file.done() {
+        let at = file.pos;
+        let (tag, len) = match (file.bytes(4), file.u32()) {
+            (Some(tag), Some(len)) => (tag, len as usize),
+            _ => {
+                return recover(
+                    lenient,
+                    format!("Broken chunk header at byte {}; ignoring the rest", at),
+                )
+            }
+        };
+
+        let rest = data.len() - file.pos;
+        if len > rest {
+            recover(
+                lenient,
+                format!(
+                    "Chunk at byte {} runs {} bytes past the end of the file",
+                    at,
+                    len - rest
+                ),
+            )?;
+        }
+        let base = file.pos;
+        let chunk = file.bytes(len.min(rest)).unwrap_or(&[]);
+
+        if tag == b"MTrk" {
+            handler.track_change();
+            track(chunk, base, handler, lenient)?;
+        } else {
+            warn!(
+                "Skipping unknown chunk {:?} at byte {}",
+                String::from_utf8_lossy(tag),
+                at
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the file strictly, returning the first error.
+pub fn check(data: &[u8]) -> Result<()> {
+    read(data, &mut Ignore, false)
+}
+
+/// Handler ignoring everything.
+struct Ignore;
+
+impl Handler for Ignore {
+    fn header(&mut self, _format: u16, _track: u16, _time_base: u16) {}
+    fn meta_event(&mut self, _delta: u32, _event: &MetaEvent, _data: &Vec<u8>) {}
+    fn midi_event(&mut self, _delta: u32, _event: &MidiEvent) {}
+    fn sys_ex_event(&mut self, _delta: u32, _event: &SysExEvent, _data: &Vec<u8>) {}
+    fn track_change(&mut self) {}
+}
+
+fn track<H: Handler>(chunk: &[u8], base: usize, handler: &mut H, lenient: bool) -> Result<()> {
+    let mut track = Cursor::new(chunk);
+    let mut running = None;
+
+    while !track.done() {
+        let at = base + track.pos;
+        if event(&mut track, &mut running, handler).is_none() {
+            return recover(
+                lenient,
+                format!(
+                    "Broken event at byte {}; skipping the rest of the track",
+                    at
+                ),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn event<H: Handler>(track: &mut Cursor, running: &mut Option<u8>, handler: &mut H) -> Option<()> {
+    let delta = track.vlq()?;
+    let status = match track.peek()? {
+        b if b >= 0x80 => {
+            track.u8();
+            b
+        }
+        _ => (*running)?,
+    };
+
+    match status {
+        0xff => {
+            let kind = track.u8()?;
+            let len = track.vlq()? as usize;
+            let data = track.bytes(len)?.to_vec();
+            handler.meta_event(delta, &MetaEvent::new(kind), &data);
+        }
+        0xf0 | 0xf7 => {
+            let len = track.vlq()? as usize;
+            let data = track.bytes(len)?.to_vec();
+            handler.sys_ex_event(delta, &SysExEvent::new(status), &data);
+            *running = None;
+        }
+        0x80..=0xef => {
+            let mut builder = MidiEventBuilder::new(status);
+            while builder.shortage() > 0 {
+                builder.push(track.u8()?);
+            }
+            handler.midi_event(delta, &builder.build());
+            *running = Some(status);
+        }
+        _ => return None,
+    }
+
+    Some(())
+}
+
+/// A track to write.
+#[derive(Clone, Debug, new)]
+pub struct Track {
+    pub name: String,
+    #[new(default)]
+    pub program: Option<u8>,
+    /// Notes as (tick, length in ticks, MIDI note number, velocity).
+    #[new(default)]
+    pub notes: Vec<(u32, u32, u8, u8)>,
+}
+
+/// MIDI channel of the nth part, skipping the drums.
+pub fn channel(index: usize) -> u8 {
+    (if index >= DRUMS as usize {
+        index + 1
+    } else {
+        index
+    } % 16) as u8
+}
+
+fn meta(delta_time: u32, event: MetaEvent, data: Vec<u8>) -> Message {
+    Message::MetaEvent {
+        delta_time,
+        event,
+        data,
+    }
+}
+
+/// Writes the tracks into a standard MIDI file of format 1 at the tempo in microseconds
+/// per quarter note.
+///
+/// The tempo goes in the first track, and each track gets its own channel, skipping
+/// the drums.
+pub fn write(path: &Path, time_base: u16, tempo: u32, tracks: &[Track]) -> Result<()> {
+    let mut messages = vec![];
+    for (i, track) in tracks.iter().enumerate() {
+        let ch = channel(i);
+        if i > 0 {
+            messages.push(Message::TrackChange);
+        }
+        if i == 0 {
+            messages.push(meta(
+                0,
+                MetaEvent::SetTempo,
+                tempo.to_be_bytes()[1..].to_vec(),
+            ));
+        }
+        messages.push(meta(
+            0,
+            MetaEvent::SequenceOrTrackName,
+            track.name.as_bytes().to_vec(),
+        ));
+        if let Some(program) = track.program {
+            messages.push(Message::MidiEvent {
+                delta_time: 0,
+                event: MidiEvent::ProgramChange { ch, program },
+            });
+        }
+
+        // Note offs go before note ons at the same tick.
+        let mut events: Vec<_> = track
+            .notes
+            .iter()
+            .flat_map(|&(at, len, note, vel)| vec![(at, 1, note, vel), (at + len, 0, note, 0)])
+            .collect();
+        events.sort_by_key(|(at, on, _, _)| (*at, *on));
+
+        let mut last = 0;
+        for (at, on, note, velocity) in events {
+            let event = if on == 1 {
+                MidiEvent::NoteOn { ch, note, velocity }
+            } else {
+                MidiEvent::NoteOff { ch, note, velocity }
+            };
+            messages.push(Message::MidiEvent {
+                delta_time: at - last,
+                event,
+            });
+            last = at;
+        }
+        messages.push(meta(0, MetaEvent::EndOfTrack, vec![]));
+    }
+
+    let mut writer = Writer::new();
+    writer.format(1).time_base(time_base);
+    for message in &messages {
+        writer.push(message);
+    }
+    writer
+        .write(path)
+        .context(format!("Couldn't write {}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Default)]
+    struct Log(Vec<String>);
+
+    impl Handler for Log {
+        fn header(&mut self, format: u16, track: u16, time_base: u16) {
+            self.0
+                .push(format!("header {} {} {}", format, track, time_base));
+        }
+
+        fn meta_event(&mut self, delta: u32, event: &MetaEvent, data: &Vec<u8>) {
+            self.0.push(format!("{} meta {} {:?}", delta, event, data));
+        }
+
+        fn midi_event(&mut self, delta: u32, event: &MidiEvent) {
+            self.0.push(format!("{} midi {}", delta, event));
+        }
+
+        fn sys_ex_event(&mut self, delta: u32, _event: &SysExEvent, data: &Vec<u8>) {
+            self.0.push(format!("{} sysex {:?}", delta, data));
+        }
+
+        fn track_change(&mut self) {
+            self.0.push("track".into());
+        }
+    }
+
+    fn smf(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut data = b"MThd\0\0\0\x06\0\x01\0\x01\x01\xe0".to_vec();
+        for (tag, body) in chunks {
+            data.extend_from_slice(*tag);
+            data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            data.extend_from_slice(body);
+        }
+        data
+    }
+
+    #[test]
+    fn read() {
+        let data = smf(&[
+            (b"XFIH", b"junk"),
+            (
+                b"MTrk",
+                &[
+                    0x00, 0x90, 60, 100, // note on
+                    0x83, 0x60, 60, 0, // running status with a 2-byte delta
+                    0x00, 0xff, 0x2f, 0x00, // end of track
+                ],
+            ),
+        ]);
+
+        let mut log = Log::default();
+        super::read(&data, &mut log, false).unwrap();
+        let expected = {
+            let mut log = Log::default();
+            log.header(1, 1, 480);
+            log.track_change();
+            log.midi_event(
+                0,
+                &MidiEvent::NoteOn {
+                    ch: 0,
+                    note: 60,
+                    velocity: 100,
+                },
+            );
+            log.midi_event(
+                480,
+                &MidiEvent::NoteOn {
+                    ch: 0,
+                    note: 60,
+                    velocity: 0,
+                },
+            );
+            log.meta_event(0, &MetaEvent::EndOfTrack, &vec![]);
+            log.0
+        };
+        assert_eq!(log.0, expected);
+    }
+
+    #[test]
+    fn broken() {
+        assert!(super::read(b"RIFF", &mut Log::default(), true).is_err());
+
+        // A track running past the end of the file, broken in the middle of an event.
+        let mut data = smf(&[(b"MTrk", &[0x00, 0x90, 60, 100, 0x10, 0x90])]);
+        data[21] = 0xff;
+
+        let e = check(&data).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Chunk at byte 14 runs 249 bytes past the end of the file"
+        );
+        data[21] = 6;
+        let e = check(&data).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Broken event at byte 26; skipping the rest of the track"
+        );
+
+        let mut log = Log::default();
+        data[21] = 0xff;
+        super::read(&data, &mut log, true).unwrap();
+        assert_eq!(log.0.len(), 3);
+    }
+
+    #[test]
+    fn write() {
+        let path = std::env::temp_dir().join(format!("toio-midi-test-{}.mid", std::process::id()));
+        let mut track = Track::new("Bass".into());
+        track.program = Some(33);
+        track.notes = vec![(0, 480, 36, 100), (480, 240, 43, 90)];
+        super::write(&path, 480, 500_000, &[Track::new("Melody".into()), track]).unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut log = Log::default();
+        super::read(&data, &mut log, false).unwrap();
+        assert_eq!(
+            log.0,
+            vec![
+                "header 1 2 480",
+                "track",
+                "0 meta SetTempo [7, 161, 32]",
+                "0 meta SequenceOrTrackName [77, 101, 108, 111, 100, 121]",
+                "0 meta EndOfTrack []",
+                "track",
+                "0 meta SequenceOrTrackName [66, 97, 115, 115]",
+                "0 midi (ProgramChange{ch: 1, program: 33})",
+                "0 midi (NoteOn{ch: 1, note: 36, velocity: 100})",
+                "480 midi (NoteOff{ch: 1, note: 36, velocity: 0})",
+                "0 midi (NoteOn{ch: 1, note: 43, velocity: 90})",
+                "240 midi (NoteOff{ch: 1, note: 43, velocity: 0})",
+                "0 meta EndOfTrack []",
+            ]
+        );
+    }
+}
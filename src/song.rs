@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use toio::Note;
+
+use crate::midi::{Channel, Time};
+
+const NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Names of the programs of General MIDI.
+const PROGRAMS: [&str; 128] = [
+    "Acoustic Grand Piano",
+    "Bright Acoustic Piano",
+    "Electric Grand Piano",
+    "Honky-tonk Piano",
+    "Electric Piano 1",
+    "Electric Piano 2",
+    "Harpsichord",
+    "Clavinet",
+    "Celesta",
+    "Glockenspiel",
+    "Music Box",
+    "Vibraphone",
+    "Marimba",
+    "Xylophone",
+    "Tubular Bells",
+    "Dulcimer",
+    "Drawbar Organ",
+    "Percussive Organ",
+    "Rock Organ",
+    "Church Organ",
+    "Reed Organ",
+    "Accordion",
+    "Harmonica",
+    "Tango Accordion",
+    "Acoustic Guitar (nylon)",
+    "Acoustic Guitar (steel)",
+    "Electric Guitar (jazz)",
+    "Electric Guitar (clean)",
+    "Electric Guitar (muted)",
+    "Overdriven Guitar",
+    "Distortion Guitar",
+    "Guitar Harmonics",
+    "Acoustic Bass",
+    "Electric Bass (finger)",
+    "Electric Bass (pick)",
+    "Fretless Bass",
+    "Slap Bass 1",
+    "Slap Bass 2",
+    "Synth Bass 1",
+    "Synth Bass 2",
+    "Violin",
+    "Viola",
+    "Cello",
+    "Contrabass",
+    "Tremolo Strings",
+    "Pizzicato Strings",
+    "Orchestral Harp",
+    "Timpani",
+    "String Ensemble 1",
+    "String Ensemble 2",
+    "Synth Strings 1",
+    "Synth Strings 2",
+    "Choir Aahs",
+    "Voice Oohs",
+    "Synth Voice",
+    "Orchestra Hit",
+    "Trumpet",
+    "Trombone",
+    "Tuba",
+    "Muted Trumpet",
+    "French Horn",
+    "Brass Section",
+    "Synth Brass 1",
+    "Synth Brass 2",
+    "Soprano Sax",
+    "Alto Sax",
+    "Tenor Sax",
+    "Baritone Sax",
+    "Oboe",
+    "English Horn",
+    "Bassoon",
+    "Clarinet",
+    "Piccolo",
+    "Flute",
+    "Recorder",
+    "Pan Flute",
+    "Blown Bottle",
+    "Shakuhachi",
+    "Whistle",
+    "Ocarina",
+    "Lead 1 (square)",
+    "Lead 2 (sawtooth)",
+    "Lead 3 (calliope)",
+    "Lead 4 (chiff)",
+    "Lead 5 (charang)",
+    "Lead 6 (voice)",
+    "Lead 7 (fifths)",
+    "Lead 8 (bass + lead)",
+    "Pad 1 (new age)",
+    "Pad 2 (warm)",
+    "Pad 3 (polysynth)",
+    "Pad 4 (choir)",
+    "Pad 5 (bowed)",
+    "Pad 6 (metallic)",
+    "Pad 7 (halo)",
+    "Pad 8 (sweep)",
+    "FX 1 (rain)",
+    "FX 2 (soundtrack)",
+    "FX 3 (crystal)",
+    "FX 4 (atmosphere)",
+    "FX 5 (brightness)",
+    "FX 6 (goblins)",
+    "FX 7 (echoes)",
+    "FX 8 (sci-fi)",
+    "Sitar",
+    "Banjo",
+    "Shamisen",
+    "Koto",
+    "Kalimba",
+    "Bagpipe",
+    "Fiddle",
+    "Shanai",
+    "Tinkle Bell",
+    "Agogo",
+    "Steel Drums",
+    "Woodblock",
+    "Taiko Drum",
+    "Melodic Tom",
+    "Synth Drum",
+    "Reverse Cymbal",
+    "Guitar Fret Noise",
+    "Breath Noise",
+    "Seashore",
+    "Bird Tweet",
+    "Telephone Ring",
+    "Helicopter",
+    "Applause",
+    "Gunshot",
+];
+
+/// Returns the name of the General MIDI program such as `Acoustic Bass` for 32.
+pub fn program_name(program: u8) -> &'static str {
+    PROGRAMS[(program & 0x7f) as usize]
+}
+
+/// Returns the pitch name of the MIDI note number such as `C#4` for 61.
+pub fn midi_note_name(note: u8) -> String {
+    format!("{}{}", NAMES[(note % 12) as usize], note as i32 / 12 - 1)
+}
+
+/// Returns the pitch name of the note such as `C#4`.
+pub fn note_name(note: Note) -> String {
+    match note {
+        Note::NoSound => "rest".into(),
+        n => {
+            let n = n as u8;
+            format!("{}{}", NAMES[(n % 12) as usize], n / 12)
+        }
+    }
+}
+
+/// Musical context of the song used to describe positions to users.
+#[derive(Clone, Debug, Default)]
+pub struct Song {
+    pub time_base: u64,
+    /// Track names.
+    pub names: HashMap<Channel, String>,
+    /// Time signatures as (tick, numerator, denominator).
+    pub signatures: Vec<(Time, u64, u64)>,
+    /// Tempo changes as (tick, msec, tempo).
+    pub tempos: Vec<(Time, Time, u64)>,
+}
+
+impl Song {
+    /// Converts msec into ticks.
+    pub fn tick(&self, at: Time) -> Time {
+        let (tick, ms, tempo) = self
+            .tempos
+            .iter()
+            .take_while(|(_, ms, _)| *ms <= at)
+            .last()
+            .cloned()
+            .unwrap_or((0, 0, 500000));
+        tick + (at - ms) * 1000 * self.time_base.max(1) / tempo.max(1)
+    }
+
+    /// Converts ticks into msec.
+    pub fn ms(&self, tick: Time) -> Time {
+        let (from, ms, tempo) = self
+            .tempos
+            .iter()
+            .take_while(|(at, _, _)| *at <= tick)
+            .last()
+            .cloned()
+            .unwrap_or((0, 0, 500000));
+        ms + (tick - from) * tempo / 1000 / self.time_base.max(1)
+    }
+
+    /// Returns the beats before `end` msec as (msec, whether it starts a bar).
+    pub fn beats(&self, end: Time) -> Vec<(Time, bool)> {
+        let time_base = self.time_base.max(1);
+        let mut signatures = self.signatures.iter().peekable();
+        let (mut num, mut den) = (4, 4);
+        let mut beats = vec![];
+        let mut tick = 0;
+        let mut beat = 0;
+        loop {
+            // A new time signature starts a bar on the next beat.
+            while let Some((_, n, d)) = signatures.next_if(|(at, _, _)| *at <= tick) {
+                num = (*n).max(1);
+                den = (*d).max(1);
+                beat = 0;
+            }
+            let at = self.ms(tick);
+            if at >= end {
+                return beats;
+            }
+            beats.push((at, beat == 0));
+            beat = (beat + 1) % num;
+            tick += (time_base * 4 / den).max(1);
+        }
+    }
+
+    /// Returns 1-based bar and beat of the tick.
+    pub fn bar_beat(&self, tick: Time) -> (u64, u64) {
+        let time_base = self.time_base.max(1);
+        let bar_len = |num: u64, den: u64| (time_base * 4 * num / den.max(1)).max(1);
+
+        let mut bars = 0;
+        let mut from = 0;
+        let (mut num, mut den) = (4, 4);
+        for (at, n, d) in &self.signatures {
+            if *at > tick {
+                break;
+            }
+            let len = bar_len(num, den);
+            bars += (at - from).div_ceil(len);
+            from = *at;
+            num = *n;
+            den = *d;
+        }
+
+        let len = bar_len(num, den);
+        let beat = (tick - from) % len / (time_base * 4 / den.max(1)).max(1);
+        (bars + (tick - from) / len + 1, beat + 1)
+    }
+
+    /// Returns the time signature at the start, 4/4 unless given.
+    pub fn signature(&self) -> (u64, u64) {
+        self.signatures
+            .iter()
+            .find(|(at, _, _)| *at == 0)
+            .map_or((4, 4), |(_, num, den)| (*num, *den))
+    }
+
+    pub fn track(&self, ch: Channel) -> String {
+        match self.names.get(&ch) {
+            Some(name) => format!("track {} \"{}\"", ch, name),
+            None => format!("track {}", ch),
+        }
+    }
+
+    /// Describes the position such as `track 2 "Bass" at 12:3`.
+    pub fn position(&self, ch: Channel, tick: Time) -> String {
+        let (bar, beat) = self.bar_beat(tick);
+        format!("{} at {}:{}", self.track(ch), bar, beat)
+    }
+
+    /// Describes the note such as `C#4 on track 2 "Bass" at 12:3`.
+    pub fn describe(&self, ch: Channel, at: Time, note: Note) -> String {
+        format!(
+            "{} on {}",
+            note_name(note),
+            self.position(ch, self.tick(at))
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(note_name(Note::C0), "C0");
+        assert_eq!(note_name(Note::CS4), "C#4");
+        assert_eq!(note_name(Note::B9), "B9");
+        assert_eq!(note_name(Note::NoSound), "rest");
+        assert_eq!(midi_note_name(61), "C#4");
+        assert_eq!(midi_note_name(11), "B-1");
+        assert_eq!(program_name(0), "Acoustic Grand Piano");
+        assert_eq!(program_name(127), "Gunshot");
+    }
+
+    #[test]
+    fn position() {
+        let mut s = Song {
+            time_base: 480,
+            // 120 bpm: 1 beat = 500 msec, then 60 bpm from the 3rd bar.
+            tempos: vec![(0, 0, 500000), (3840, 4000, 1000000)],
+            // 4/4, then 3/4 from the 3rd bar.
+            signatures: vec![(0, 4, 4), (3840, 3, 4)],
+            ..Default::default()
+        };
+        s.names.insert(2, "Bass".into());
+
+        assert_eq!(s.tick(1000), 960);
+        assert_eq!(s.tick(5000), 4320);
+        assert_eq!(s.bar_beat(0), (1, 1));
+        assert_eq!(s.bar_beat(960), (1, 3));
+        assert_eq!(s.bar_beat(3840), (3, 1));
+        assert_eq!(s.bar_beat(3840 + 480 * 4), (4, 2));
+        assert_eq!(
+            s.describe(2, 5000, Note::E3),
+            "E3 on track 2 \"Bass\" at 3:2"
+        );
+        assert_eq!(s.position(1, 0), "track 1 at 1:1");
+        assert_eq!(s.ms(960), 1000);
+        assert_eq!(s.ms(4320), 5000);
+        let beats = s.beats(6000);
+        assert_eq!(beats.len(), 10);
+        assert_eq!(&beats[..2], &[(0, true), (500, false)]);
+        assert_eq!(&beats[7..], &[(3500, false), (4000, true), (5000, false)]);
+        assert_eq!(s.signature(), (4, 4));
+        s.signatures[0] = (0, 6, 8);
+        assert_eq!(s.signature(), (6, 8));
+        s.signatures.remove(0);
+        assert_eq!(s.signature(), (4, 4));
+    }
+}
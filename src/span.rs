@@ -0,0 +1,81 @@
+//! Spans of the logs, telling which cube and which section of the song each line is about
+//! so that the lines of a cube can be told apart or kept alone.
+
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+tokio::task_local! {
+    /// The cube the task plays on.
+    static CUBE: usize;
+}
+
+/// The section of the song being played.
+static SECTION: Mutex<Option<String>> = Mutex::new(None);
+/// The only cube logged, if not `usize::MAX`.
+static ONLY: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Runs the task of the cube in its span.
+pub async fn cube<F: Future>(index: usize, f: F) -> F::Output {
+    CUBE.scope(index, f).await
+}
+
+/// Enters the section of the song, such as `twinkle.mid 1/2`, or leaves it.
+pub fn section(name: Option<String>) {
+    *SECTION.lock().unwrap() = name;
+}
+
+/// Logs only the lines outside of the spans of the other cubes.
+pub fn only(cube: usize) {
+    ONLY.store(cube, Ordering::Relaxed);
+}
+
+/// Tells if the lines logged now are hidden, being about another cube.
+pub fn hidden() -> bool {
+    let only = ONLY.load(Ordering::Relaxed);
+    only != usize::MAX && CUBE.try_with(|c| *c != only).unwrap_or(false)
+}
+
+/// The spans of the lines logged now, such as `cube=1 section="twinkle.mid 1/2"`.
+pub fn current() -> String {
+    let cube = CUBE.try_with(|c| format!("cube={}", c)).ok();
+    let section = SECTION
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|s| format!("section={:?}", s));
+    cube.into_iter()
+        .chain(section)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn spans() {
+        assert_eq!(current(), "");
+        assert_eq!(block_on(cube(2, async { current() })), "cube=2");
+        section(Some("twinkle.mid 1/2".into()));
+        assert_eq!(
+            block_on(cube(2, async { current() })),
+            "cube=2 section=\"twinkle.mid 1/2\""
+        );
+        assert_eq!(current(), "section=\"twinkle.mid 1/2\"");
+        section(None);
+
+        assert!(!hidden());
+        only(1);
+        assert!(block_on(cube(2, async { hidden() })));
+        assert!(!block_on(cube(1, async { hidden() })));
+        assert!(!hidden());
+        ONLY.store(usize::MAX, Ordering::Relaxed);
+    }
+}
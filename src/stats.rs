@@ -0,0 +1,191 @@
+use crate::{
+    json::Json,
+    midi::{Channel, Time},
+    section::Offset,
+};
+
+/// Statistics of the notes of a part as written in the file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Part {
+    /// Number of notes.
+    pub notes: usize,
+    /// Lowest and highest MIDI note numbers.
+    pub range: Option<(u8, u8)>,
+    /// Most notes held at once.
+    pub polyphony: usize,
+    /// Time of the first sound and the end of the last in msec.
+    pub span: Option<(Time, Time)>,
+    /// Instrument name in the file.
+    pub name: Option<String>,
+    /// The first program of General MIDI.
+    pub program: Option<u8>,
+}
+
+impl Part {
+    /// Counts a note starting while `held` notes including it are held.
+    pub fn on(&mut self, note: u8, held: usize) {
+        self.notes += 1;
+        self.range = Some(match self.range {
+            Some((low, high)) => (low.min(note), high.max(note)),
+            None => (note, note),
+        });
+        self.polyphony = self.polyphony.max(held);
+    }
+
+    /// Describes the range such as `C2-G4`.
+    pub fn range_name(&self) -> Option<String> {
+        self.range.map(|(low, high)| {
+            format!(
+                "{}-{}",
+                crate::song::midi_note_name(low),
+                crate::song::midi_note_name(high)
+            )
+        })
+    }
+
+    /// Describes the instrument by its name, or by the program otherwise.
+    pub fn instrument(&self) -> Option<String> {
+        self.name
+            .clone()
+            .or_else(|| self.program.map(|p| crate::song::program_name(p).into()))
+    }
+
+    /// Describes the part of the channel in JSON.
+    pub fn json(&self, ch: Channel, name: &str) -> Json {
+        Json::object(vec![
+            ("part", ch.into()),
+            ("name", name.into()),
+            ("instrument", self.instrument().into()),
+            ("program", self.program.into()),
+            ("notes", self.notes.into()),
+            ("low", self.range.map(|(low, _)| low).into()),
+            ("high", self.range.map(|(_, high)| high).into()),
+            ("range", self.range_name().into()),
+            ("polyphony", self.polyphony.into()),
+            ("startMs", self.span.map(|(start, _)| start).into()),
+            ("endMs", self.span.map(|(_, end)| end).into()),
+        ])
+    }
+
+    /// Extends the span over a sound.
+    pub fn sound(&mut self, at: Time, len: Time) {
+        self.span = Some(match self.span {
+            Some((start, end)) => (start.min(at), end.max(at + len)),
+            None => (at, at + len),
+        });
+    }
+}
+
+/// Statistics of a file.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Parts with their descriptions such as `track 2 "Bass"`.
+    pub parts: Vec<(Channel, String, Part)>,
+    /// Tempo changes as (position such as `12:3`, msec, bpm).
+    pub tempos: Vec<(String, Time, f64)>,
+}
+
+impl Stats {
+    pub fn json(&self) -> Json {
+        let parts = self
+            .parts
+            .iter()
+            .map(|(ch, name, part)| part.json(*ch, name))
+            .collect();
+        let tempos = self
+            .tempos
+            .iter()
+            .map(|(pos, ms, bpm)| {
+                Json::object(vec![
+                    ("position", pos.as_str().into()),
+                    ("ms", (*ms).into()),
+                    ("bpm", (*bpm).into()),
+                ])
+            })
+            .collect();
+        Json::object(vec![
+            ("parts", Json::Array(parts)),
+            ("tempos", Json::Array(tempos)),
+        ])
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:<28} {:>6} {:>9} {:>5} {:>9} {:>9}  Instrument",
+            "Part", "Notes", "Range", "Poly", "Start", "Length"
+        )?;
+        for (_, name, p) in &self.parts {
+            let range = p.range_name().unwrap_or_else(|| "-".into());
+            let (start, len) = match p.span {
+                Some((start, end)) => (Offset(start).to_string(), Offset(end - start).to_string()),
+                None => ("-".into(), "-".into()),
+            };
+            writeln!(
+                f,
+                "{:<28} {:>6} {:>9} {:>5} {:>9} {:>9}  {}",
+                name,
+                p.notes,
+                range,
+                p.polyphony,
+                start,
+                len,
+                p.instrument().unwrap_or_else(|| "-".into())
+            )?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Tempo")?;
+        if self.tempos.is_empty() {
+            writeln!(f, "  120 bpm (default)")?;
+        }
+        for (pos, ms, bpm) in &self.tempos {
+            writeln!(
+                f,
+                "  {:>7} {:>9} {:>7.1} bpm",
+                pos,
+                Offset(*ms).to_string(),
+                bpm
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn part() {
+        let mut p = Part::default();
+        p.on(60, 1);
+        p.on(48, 3);
+        p.on(72, 2);
+        p.sound(500, 1000);
+        p.sound(0, 100);
+        assert_eq!(
+            p,
+            Part {
+                notes: 3,
+                range: Some((48, 72)),
+                polyphony: 3,
+                span: Some((0, 1500)),
+                ..Default::default()
+            }
+        );
+        assert_eq!(p.range_name().unwrap(), "C3-C5");
+
+        assert_eq!(p.instrument(), None);
+        p.program = Some(33);
+        assert_eq!(p.instrument().unwrap(), "Electric Bass (finger)");
+        p.name = Some("Bass".into());
+        assert_eq!(p.instrument().unwrap(), "Bass");
+        assert_eq!(
+            p.json(2, "track 2").to_string(),
+            r#"{"part":2,"name":"track 2","instrument":"Bass","program":33,"notes":3,"low":48,"high":72,"range":"C3-C5","polyphony":3,"startMs":0,"endMs":1500}"#
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Generated patterns of notes for testing the timing at its limits without a MIDI file.
+
+use anyhow::{anyhow, Error, Result};
+use derive_new::new;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    midi::Time,
+    smf::{self, Track},
+};
+
+/// Ticks per quarter note of the pattern, at a tempo making a tick a msec.
+const TIME_BASE: u16 = 1000;
+/// Microseconds per quarter note of the pattern.
+const TEMPO: u32 = 1_000_000;
+/// The notes picked from, C4 to B5.
+const NOTES: std::ops::Range<u8> = 60..84;
+
+/// The lengths of the notes in msec, from the first to the last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lengths(Time, Time);
+
+impl std::str::FromStr for Lengths {
+    type Err = Error;
+
+    /// Parses `100`, or a range such as `20-200` to pick from.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            anyhow!(
+                "Invalid note length: {} (expected such as 100 or 20-200)",
+                s
+            )
+        };
+        let (min, max) = s.split_once('-').unwrap_or((s, s));
+        let (min, max) = (
+            min.parse().map_err(|_| invalid())?,
+            max.parse().map_err(|_| invalid())?,
+        );
+        if min == 0 || min > max {
+            return Err(invalid());
+        }
+        Ok(Lengths(min, max))
+    }
+}
+
+/// The pattern generated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, new)]
+pub struct Pattern {
+    /// Parts, each on its own channel.
+    parts: usize,
+    /// Notes a second on each part.
+    density: u64,
+    lengths: Lengths,
+    secs: u64,
+    /// The same seed gives the same notes.
+    seed: u64,
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} parts of {} notes a second of {}-{} msec for {} seconds",
+            self.parts, self.density, self.lengths.0, self.lengths.1, self.secs
+        )
+    }
+}
+
+/// The xorshift generator of the notes, not to depend on a crate for the randomness.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Zero stays zero on the shifts.
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number from the range including both ends.
+    fn pick(&mut self, min: u64, max: u64) -> u64 {
+        min + self.next() % (max - min + 1)
+    }
+}
+
+impl Pattern {
+    fn validate(&self) -> Result<()> {
+        if self.parts == 0 || self.parts > 15 {
+            return Err(anyhow!("The number of parts must be from 1 to 15"));
+        }
+        if self.density == 0 || self.density > 1000 {
+            return Err(anyhow!("The density must be from 1 to 1000 notes a second"));
+        }
+        if self.secs == 0 {
+            return Err(anyhow!("The pattern must be 1 second at least"));
+        }
+        Ok(())
+    }
+
+    /// The notes of the part as (msec, length, MIDI note number, velocity), all the parts
+    /// starting together; a note is cut at the next one.
+    fn notes(&self, rng: &mut Rng) -> Vec<(u32, u32, u8, u8)> {
+        let step = 1000 / self.density;
+        let Lengths(min, max) = self.lengths;
+        (0..self.secs * 1000)
+            .step_by(step as usize)
+            .map(|at| {
+                let len = rng.pick(min, max).min(step);
+                let note = rng.pick(NOTES.start as u64, NOTES.end as u64 - 1);
+                (at as u32, len as u32, note as u8, 100)
+            })
+            .collect()
+    }
+}
+
+/// Writes the pattern into a MIDI file in the directory, named after the process not to
+/// clash with another run.
+///
+/// Returns the file and the number of parts, which are on tracks 1 and later.
+pub fn write(pattern: &Pattern, dir: &Path) -> Result<(PathBuf, usize)> {
+    pattern.validate()?;
+    let mut rng = Rng::new(pattern.seed);
+    let tracks: Vec<_> = (0..pattern.parts)
+        .map(|i| {
+            let mut track = Track::new(format!("Stress {}", i + 1));
+            track.notes = pattern.notes(&mut rng);
+            track
+        })
+        .collect();
+
+    let path = dir.join(format!("toio-midi-stress-{}.mid", std::process::id()));
+    smf::write(&path, TIME_BASE, TEMPO, &tracks)?;
+    Ok((path, tracks.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pattern() {
+        assert_eq!("100".parse::<Lengths>().unwrap(), Lengths(100, 100));
+        assert_eq!("20-200".parse::<Lengths>().unwrap(), Lengths(20, 200));
+        assert!("200-20".parse::<Lengths>().is_err());
+        assert!("0".parse::<Lengths>().is_err());
+        assert!("x-20".parse::<Lengths>().is_err());
+
+        let pattern = Pattern::new(2, 8, Lengths(50, 300), 2, 7);
+        let notes = pattern.notes(&mut Rng::new(7));
+        assert_eq!(notes.len(), 16);
+        assert_eq!(notes[1].0, 125);
+        for (_, len, note, _) in &notes {
+            assert!((50..=125).contains(len));
+            assert!(NOTES.contains(note));
+        }
+        assert_eq!(pattern.notes(&mut Rng::new(7)), notes);
+        assert_ne!(pattern.notes(&mut Rng::new(8)), notes);
+
+        let dir = std::env::temp_dir().join(format!("toio-midi-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(write(&Pattern::new(0, 8, Lengths(50, 50), 2, 1), &dir).is_err());
+        assert!(write(&Pattern::new(2, 0, Lengths(50, 50), 2, 1), &dir).is_err());
+        let (path, parts) = write(&pattern, &dir).unwrap();
+        assert_eq!(parts, 2);
+        assert!(path.starts_with(&dir));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+use anyhow::{anyhow, Result};
+use log::*;
+use std::io::BufRead;
+use tokio::time::Instant;
+
+/// Taps needed to set the tempo.
+pub const TAPS: usize = 4;
+/// The range of tempos accepted in bpm.
+const RANGE: (f64, f64) = (20.0, 400.0);
+
+/// Returns the tempo in bpm from the times of taps on beats.
+fn bpm(taps: &[Instant]) -> Option<f64> {
+    let (first, last) = (taps.first()?, taps.last()?);
+    let beats = taps.len().checked_sub(1).filter(|b| *b > 0)?;
+    let secs = (*last - *first).as_secs_f64();
+    if secs > 0.0 {
+        Some(60.0 * beats as f64 / secs)
+    } else {
+        None
+    }
+}
+
+/// Reads taps on the Enter key from the standard input, returning the tempo in bpm.
+pub async fn tempo() -> Result<f64> {
+    info!("Tap the Enter key {} times on the beat...", TAPS);
+
+    let taps = tokio::task::spawn_blocking(|| {
+        let stdin = std::io::stdin();
+        let mut taps = vec![];
+        for line in stdin.lock().lines() {
+            line?;
+            taps.push(Instant::now());
+            if taps.len() == TAPS {
+                break;
+            }
+        }
+        Ok::<_, std::io::Error>(taps)
+    })
+    .await??;
+
+    if taps.len() < TAPS {
+        return Err(anyhow!("Input closed while tapping the tempo"));
+    }
+    match bpm(&taps) {
+        Some(bpm) if bpm >= RANGE.0 && bpm <= RANGE.1 => Ok(bpm),
+        _ => Err(anyhow!(
+            "Tapped tempo must be from {} to {} bpm",
+            RANGE.0,
+            RANGE.1
+        )),
+    }
+}
+
+/// Converts the tapped tempo into the speed in percent of the tempo of the file.
+pub fn speed(tapped: f64, file: f64) -> u64 {
+    (tapped / file * 100.0).round().max(1.0) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::Duration;
+
+    #[test]
+    fn bpm_of_taps() {
+        let start = Instant::now();
+        let taps: Vec<_> = (0..4)
+            .map(|i| start + Duration::from_millis(i * 500))
+            .collect();
+        assert!((bpm(&taps).unwrap() - 120.0).abs() < 1e-6);
+        assert_eq!(bpm(&taps[..1]), None);
+        assert_eq!(bpm(&[start, start]), None);
+
+        assert_eq!(speed(90.0, 120.0), 75);
+        assert_eq!(speed(0.1, 120.0), 1);
+    }
+}
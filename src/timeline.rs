@@ -0,0 +1,82 @@
+//! Human-readable timeline of what each cube is sent, for dry runs.
+
+use crate::{
+    export,
+    midi::{Plan, Time},
+    route::Router,
+    section::Offset,
+    song::note_name,
+};
+
+/// Lists the batches of sound operations sent to the cubes in the order of time,
+/// a line per batch such as `0:01.200 cube 0 part 2 x1 batch 1/2 (59 ops, 14.750s): ...`.
+///
+/// The batches are of the most operations a cube accepts at once, which the cubes send
+/// when the latency of BLE allows it.
+pub fn lines(plan: &Plan, router: &Router) -> Vec<String> {
+    let mut lines = vec![];
+    for set in plan.values() {
+        for (cube, set) in export::targets(set, router) {
+            let chunks = set.chunks();
+            let mut at = set.at;
+            for (i, chunk) in chunks.iter().enumerate() {
+                let len: Time = chunk.iter().map(|op| op.duration.as_millis() as Time).sum();
+                let ops: Vec<_> = chunk
+                    .iter()
+                    .map(|op| format!("{} {}ms", note_name(op.note), op.duration.as_millis()))
+                    .collect();
+                lines.push((
+                    (at, cube),
+                    format!(
+                        "{:.3} cube {} part {} x{} batch {}/{} ({} ops, {:.3}s): {}",
+                        Offset(at),
+                        cube,
+                        set.ch,
+                        set.repeat,
+                        i + 1,
+                        chunks.len(),
+                        chunk.len(),
+                        len as f64 / 1000.0,
+                        ops.join(", ")
+                    ),
+                ));
+                at += len * set.repeat as Time;
+            }
+        }
+    }
+    lines.sort_by_key(|(key, _)| *key);
+    lines.into_iter().map(|(_, line)| line).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        midi::{Play, PlaySet},
+        route::Mirror,
+    };
+    use toio::Note;
+
+    #[test]
+    fn timeline() {
+        let mut set = PlaySet::new(0, 1200);
+        set.plays = vec![
+            Play::new(0, 1200, 600, Note::C4),
+            Play::new(0, 1800, 600, Note::NoSound),
+        ];
+        set.len = 1200;
+        let plan: Plan = vec![((1200, 0), set)].into_iter().collect();
+        let mut router = Router::new(2, vec![0]);
+        router
+            .mirror(&"cube1=cube0:+12".parse::<Mirror>().unwrap())
+            .unwrap();
+
+        assert_eq!(
+            lines(&plan, &router),
+            vec![
+                "0:01.200 cube 0 part 0 x1 batch 1/1 (2 ops, 1.200s): C4 600ms, rest 600ms",
+                "0:01.200 cube 1 part 0 x1 batch 1/1 (2 ops, 1.200s): C5 600ms, rest 600ms",
+            ]
+        );
+    }
+}
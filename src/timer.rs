@@ -0,0 +1,107 @@
+//! Waits for the times of the play sets, optionally on a thread of its own that sleeps
+//! and then spins to be closer to them than the timers of tokio.
+
+use std::sync::mpsc::{self, RecvTimeoutError};
+use tokio::{
+    sync::oneshot,
+    time::{delay_until, Duration, Instant},
+};
+
+/// How long before the time the thread stops sleeping and spins.
+const SPIN: Duration = Duration::from_millis(2);
+
+/// A time waited for and who waits for it.
+type Wake = (std::time::Instant, oneshot::Sender<()>);
+
+/// Waits for times with the timers of tokio, or on the timing thread if it's started.
+#[derive(Clone, Default)]
+pub struct Timer {
+    tx: Option<mpsc::Sender<Wake>>,
+}
+
+impl Timer {
+    /// Starts the timing thread, which runs until every timer is gone.
+    pub fn precise() -> Self {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || run(rx));
+        Self { tx: Some(tx) }
+    }
+
+    /// Waits until the time.
+    pub async fn until(&self, at: Instant) {
+        if let Some(tx) = &self.tx {
+            let (done, woken) = oneshot::channel();
+            if tx.send((at.into_std(), done)).is_ok() && woken.await.is_ok() {
+                return;
+            }
+        }
+        delay_until(at).await
+    }
+}
+
+/// Wakes those waiting at their times, sleeping until just before the earliest and
+/// spinning for the rest.
+fn run(rx: mpsc::Receiver<Wake>) {
+    let mut pending: Vec<Wake> = vec![];
+    let mut closed = false;
+    loop {
+        // Those who gave up waiting aren't woken.
+        pending.retain(|(_, done)| !done.is_closed());
+        let next = pending.iter().map(|(at, _)| *at).min();
+        let now = std::time::Instant::now();
+        let wake = match next {
+            None if closed => return,
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            Some(at) if at <= now + SPIN => Err(RecvTimeoutError::Timeout),
+            Some(at) if closed => {
+                std::thread::sleep(at - SPIN - now);
+                Err(RecvTimeoutError::Timeout)
+            }
+            Some(at) => rx.recv_timeout(at - SPIN - now),
+        };
+        match wake {
+            Ok(wake) => {
+                pending.push(wake);
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                closed = true;
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        let at = match next {
+            Some(at) => at,
+            None => continue,
+        };
+        while std::time::Instant::now() < at {
+            std::hint::spin_loop();
+        }
+        let now = std::time::Instant::now();
+        let (due, rest) = pending.drain(..).partition(|(at, _)| *at <= now);
+        pending = rest;
+        for (_, done) in due {
+            let _ = done.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn precise() {
+        let timer = Timer::precise();
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        futures::executor::block_on(async {
+            timer.until(start + ms(5)).await;
+            assert!(start.elapsed() >= ms(5));
+            // Past times are woken at once.
+            timer.until(start).await;
+        });
+        assert!(start.elapsed() < ms(500));
+    }
+}
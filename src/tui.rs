@@ -0,0 +1,258 @@
+//! Full-screen status of the playback in the terminal, redrawn with ANSI escapes.
+
+use anyhow::Result;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    export,
+    midi::{self, Channel, Plan, PlaySet, Time},
+    route::Router,
+    section::Offset,
+    sink::SoundSink,
+};
+
+/// Interval of redrawing the screen.
+const REFRESH: Duration = Duration::from_millis(250);
+/// Sets shown ahead for each cube.
+const UPCOMING: usize = 3;
+
+/// Status of a cube shown on the screen.
+#[derive(Clone, Debug)]
+pub struct Cube {
+    /// How the cube is connected, such as `connected` or `simulated`.
+    pub state: String,
+    pub battery: Option<usize>,
+    /// The sets the cube is sent in the order of time, as (index in the song, at).
+    sets: Vec<(usize, Time)>,
+    /// The set the cube is playing, as the index in `sets`.
+    current: Option<usize>,
+}
+
+impl Cube {
+    pub fn new(state: &str, battery: Option<usize>) -> Self {
+        Self {
+            state: state.into(),
+            battery,
+            sets: vec![],
+            current: None,
+        }
+    }
+}
+
+struct Screen {
+    cubes: Vec<Cube>,
+    /// Sets of the whole song as (segment, part, at, len, notes).
+    sets: Vec<(usize, Channel, Time, Time, usize)>,
+    segments: usize,
+    /// The segment playing.
+    segment: usize,
+    /// End of the song in msec.
+    total: Time,
+    /// The position in the song and when it was reached.
+    position: Option<(Time, Instant)>,
+    started: Option<Instant>,
+    silenced: bool,
+}
+
+/// Updates the screen with the sets sent to a cube.
+pub struct Tui {
+    cube: usize,
+    screen: Arc<Mutex<Screen>>,
+}
+
+impl Screen {
+    fn new(segments: &[Plan], router: &Router, mut cubes: Vec<Cube>) -> Self {
+        let mut sets = vec![];
+        for (segment, plan) in segments.iter().enumerate() {
+            for set in plan.values() {
+                for (cube, set) in export::targets(set, router) {
+                    if let Some(c) = cubes.get_mut(cube) {
+                        c.sets.push((sets.len(), set.at));
+                    }
+                }
+                let notes = set.plays.iter().filter(|p| p.note != toio::Note::NoSound);
+                sets.push((segment, set.ch, set.at, set.len, notes.count()));
+            }
+        }
+        for cube in &mut cubes {
+            cube.sets.sort_by_key(|(_, at)| *at);
+        }
+        let total = segments.iter().map(midi::end).max().unwrap_or(0);
+
+        Self {
+            cubes,
+            sets,
+            segments: segments.len(),
+            segment: 0,
+            total,
+            position: None,
+            started: None,
+            silenced: false,
+        }
+    }
+}
+
+impl Tui {
+    /// Starts redrawing the screen with a row per cube until the returned sinks are dropped.
+    pub fn start(segments: &[Plan], router: &Router, cubes: Vec<Cube>) -> Vec<Tui> {
+        let count = cubes.len();
+        let screen = Arc::new(Mutex::new(Screen::new(segments, router, cubes)));
+
+        let shared = screen.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(REFRESH);
+            while Arc::strong_count(&shared) > 1 {
+                ticks.tick().await;
+                let screen = draw(&shared.lock().unwrap(), Instant::now());
+                print!("\x1b[H\x1b[2J{}", screen);
+            }
+        });
+
+        (0..count)
+            .map(|cube| Tui {
+                cube,
+                screen: screen.clone(),
+            })
+            .collect()
+    }
+}
+
+impl Screen {
+    /// The position in the song at the time, which stays while silenced.
+    fn position(&self, now: Instant) -> Time {
+        match self.position {
+            Some((at, _)) if self.silenced => at,
+            Some((at, since)) => at + now.saturating_duration_since(since).as_millis() as Time,
+            None => 0,
+        }
+    }
+}
+
+/// Draws the screen at the time.
+fn draw(s: &Screen, now: Instant) -> String {
+    let position = s.position(now);
+    let state = match (s.started, s.silenced) {
+        (None, _) => "waiting",
+        (Some(_), true) => "paused",
+        (Some(_), false) => "playing",
+    };
+
+    let mut out = format!(
+        "toio-midi  {} / {}  segment {}/{}  [{}]\n\n",
+        Offset(position),
+        Offset(s.total),
+        s.segment + 1,
+        s.segments,
+        state
+    );
+    out += &format!("{:<5} {:<14} {:>7}  Upcoming\n", "Cube", "State", "Battery");
+    for (i, cube) in s.cubes.iter().enumerate() {
+        let from = cube.current.map_or(0, |c| c + 1);
+        let upcoming: Vec<_> = cube
+            .sets
+            .iter()
+            .skip(from)
+            .take(UPCOMING)
+            .map(|(set, _)| {
+                let (_, part, at, _, notes) = s.sets[*set];
+                format!("{} part {} ({} notes)", Offset(at), part, notes)
+            })
+            .collect();
+        let battery = cube.battery.map_or("-".to_string(), |b| format!("{}%", b));
+        out += &format!(
+            "{:<5} {:<14} {:>7}  {}\n",
+            i,
+            cube.state,
+            battery,
+            upcoming.join(", ")
+        );
+    }
+    out += "\nKeys: space pause/resume, s skip the segment, q quit\n";
+    out
+}
+
+impl SoundSink for Tui {
+    async fn play(&mut self, set: PlaySet) -> Result<()> {
+        let mut s = self.screen.lock().unwrap();
+        let now = Instant::now();
+        s.started.get_or_insert(now);
+        s.silenced = false;
+        s.position = Some((set.at, now));
+        let index = s.sets.iter().position(|x| x.1 == set.ch && x.2 == set.at);
+        if let Some(i) = index {
+            s.segment = s.sets[i].0;
+        }
+        let cube = &mut s.cubes[self.cube];
+        cube.current = cube.sets.iter().position(|(i, _)| Some(*i) == index);
+        Ok(())
+    }
+
+    async fn silence(&mut self) -> Result<()> {
+        let mut s = self.screen.lock().unwrap();
+        if !s.silenced {
+            let now = Instant::now();
+            s.position = Some((s.position(now), now));
+            s.silenced = true;
+        }
+        Ok(())
+    }
+
+    async fn resume(&mut self) -> Result<()> {
+        let mut s = self.screen.lock().unwrap();
+        s.position = s.position.map(|(at, _)| (at, Instant::now()));
+        s.silenced = false;
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::midi::Play;
+    use toio::Note;
+
+    #[test]
+    fn screen() {
+        let set = |ch, at| {
+            let mut set = PlaySet::new(ch, at);
+            set.plays = vec![Play::new(ch, at, 500, Note::A4)];
+            set.len = 500;
+            ((at, ch), set)
+        };
+        let plan: Plan = vec![set(0, 0), set(1, 0), set(0, 1000)]
+            .into_iter()
+            .collect();
+        let router = Router::new(2, vec![0, 1]);
+        let mut screen = Screen::new(
+            &[plan],
+            &router,
+            vec![
+                Cube::new("connected", Some(80)),
+                Cube::new("simulated", None),
+            ],
+        );
+
+        let start = Instant::now();
+        let text = draw(&screen, start);
+        assert!(text.starts_with("toio-midi  0:00.0 / 0:01.5  segment 1/1  [waiting]"));
+        assert!(text.contains(
+            "0     connected          80%  0:00.0 part 0 (1 notes), 0:01.0 part 0 (1 notes)\n"
+        ));
+        assert!(text.contains("1     simulated            -  0:00.0 part 1 (1 notes)\n"));
+
+        // Playing the first set of cube 0.
+        screen.started = Some(start);
+        screen.position = Some((0, start));
+        screen.cubes[0].current = Some(0);
+        let text = draw(&screen, start + Duration::from_millis(200));
+        assert!(text.starts_with("toio-midi  0:00.2 / 0:01.5  segment 1/1  [playing]"));
+        assert!(text.contains("0     connected          80%  0:01.0 part 0 (1 notes)\n"));
+    }
+}
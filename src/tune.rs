@@ -0,0 +1,179 @@
+use anyhow::{anyhow, Context, Error, Result};
+use log::*;
+use std::{convert::TryInto, io::BufRead};
+use toio::{Cube, Note, SoundOp};
+use tokio::time::{delay_for, Duration};
+
+use crate::{link::Link, Opt};
+
+/// The reference note, A at 440 Hz.
+const REFERENCE: Note = Note::A4;
+/// How long the reference note sounds.
+const LEN: Duration = Duration::from_millis(1000);
+
+/// Offset of the notes played by a cube in semitones, to correct its pitch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tuning {
+    pub cube: usize,
+    pub offset: i8,
+}
+
+impl std::str::FromStr for Tuning {
+    type Err = Error;
+
+    /// Parses `cube1:-12`, where the `cube` prefix is optional.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid tuning: {} (expected such as cube1:-12)", s);
+        let mut iter = s.splitn(2, ':');
+        let cube = iter.next().ok_or_else(invalid)?;
+        let offset = iter.next().ok_or_else(invalid)?;
+
+        let cube = cube.strip_prefix("cube").unwrap_or(cube);
+        let offset = offset.strip_prefix('+').unwrap_or(offset);
+        Ok(Tuning {
+            cube: cube.parse().map_err(|_| invalid())?,
+            offset: offset.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl std::fmt::Display for Tuning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "cube{}:{:+}", self.cube, self.offset)
+    }
+}
+
+/// Returns the offset of the cube in semitones.
+pub fn offset(tunings: &[Tuning], cube: usize) -> i8 {
+    tunings
+        .iter()
+        .filter(|t| t.cube == cube)
+        .map(|t| t.offset)
+        .sum()
+}
+
+/// Converts the answer to which octave a cube sounded into its correction.
+fn correction(answer: &str) -> Option<i8> {
+    match answer.trim() {
+        "" | "s" | "same" => Some(0),
+        "h" | "higher" => Some(-12),
+        "l" | "lower" => Some(12),
+        _ => None,
+    }
+}
+
+async fn ask() -> Result<String> {
+    Ok(tokio::task::spawn_blocking(|| {
+        let stdin = std::io::stdin();
+        let line = stdin.lock().lines().next();
+        line.unwrap_or_else(|| Ok(String::new()))
+    })
+    .await??)
+}
+
+async fn reference(link: &mut Link, offset: i8) -> Result<()> {
+    let note = (REFERENCE as i32 + offset as i32).clamp(0, Note::G10 as i32) as u8;
+    let note = note.try_into().unwrap();
+    link.play(1, &[SoundOp::new(note, LEN)]).await
+}
+
+/// Plays the reference note on every cube and compares each cube by ear with cube 0,
+/// suggesting the tunings that correct octave mismatches.
+pub async fn run(opt: &Opt) -> Result<()> {
+    let cubes = Cube::search().all().await?;
+    if cubes.is_empty() {
+        return Err(anyhow!("No cube found"));
+    }
+
+    let tracer = crate::tracer(opt);
+    let mut links = vec![];
+    let mut versions = vec![];
+    for (i, cube) in cubes.into_iter().enumerate() {
+        let mut link = Link::new(i, cube, tracer.clone());
+        link.cube()?
+            .connect()
+            .await
+            .context(format!("Couldn't connect to cube {}", i))?;
+        let version = link.cube()?.version().await?;
+        info!("Cube {}: protocol version {}", i, version);
+        versions.push(version);
+        links.push(link);
+    }
+    if versions.iter().any(|v| *v != versions[0]) {
+        warn!("Cubes run different firmware; their octaves may not match");
+    }
+
+    let mut offsets: Vec<_> = (0..links.len()).map(|i| offset(&opt.tunings, i)).collect();
+
+    info!(
+        "All cubes play {} together...",
+        crate::song::note_name(REFERENCE)
+    );
+    for link in &mut links {
+        reference(link, offsets[link.index]).await?;
+    }
+    delay_for(LEN).await;
+
+    for i in 1..links.len() {
+        loop {
+            info!(
+                "Cube 0, then cube {}. Was cube {} the same, higher or lower? [Enter/h/l]",
+                i, i
+            );
+            reference(&mut links[0], offsets[0]).await?;
+            delay_for(LEN).await;
+            reference(&mut links[i], offsets[i]).await?;
+            delay_for(LEN).await;
+
+            match correction(&ask().await?) {
+                Some(0) => break,
+                Some(c) => {
+                    warn!("Cube {} is off by an octave; correcting by {:+}", i, c);
+                    offsets[i] = offsets[i].saturating_add(c);
+                    break;
+                }
+                None => warn!("Answer with Enter, h or l"),
+            }
+        }
+    }
+
+    let tunings: Vec<_> = offsets
+        .into_iter()
+        .enumerate()
+        .filter(|(_, offset)| *offset != 0)
+        .map(|(cube, offset)| format!("--tuning {}", Tuning { cube, offset }))
+        .collect();
+    if tunings.is_empty() {
+        info!("All cubes are in tune");
+    } else {
+        info!("Play with {}", tunings.join(" "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tuning() {
+        let t: Tuning = "cube2:-12".parse().unwrap();
+        assert_eq!(
+            t,
+            Tuning {
+                cube: 2,
+                offset: -12
+            }
+        );
+        assert_eq!("1:+12".parse::<Tuning>().unwrap().offset, 12);
+        assert_eq!(t.to_string(), "cube2:-12");
+        assert!("cube2".parse::<Tuning>().is_err());
+        assert!("cubex:12".parse::<Tuning>().is_err());
+
+        assert_eq!(offset(&[t], 2), -12);
+        assert_eq!(offset(&[t], 0), 0);
+        assert_eq!(correction("h\n"), Some(-12));
+        assert_eq!(correction(""), Some(0));
+        assert_eq!(correction("x"), None);
+    }
+}
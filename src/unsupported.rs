@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    midi::{Channel, Time},
+    song::Song,
+};
+
+/// Positions shown for each feature.
+const SHOWN: usize = 3;
+
+/// The drum channel of General MIDI, counted from 0.
+pub const DRUMS: u8 = 9;
+
+/// MIDI features the cube can't play.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Feature {
+    PitchBend,
+    ControlChange,
+    ProgramChange,
+    Aftertouch,
+    SysEx,
+    Drums,
+    OutOfRange,
+}
+
+impl std::fmt::Display for Feature {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Feature::PitchBend => write!(f, "pitch bends"),
+            Feature::ControlChange => write!(f, "control changes"),
+            Feature::ProgramChange => write!(f, "program changes"),
+            Feature::Aftertouch => write!(f, "aftertouch messages"),
+            Feature::SysEx => write!(f, "system exclusive messages"),
+            Feature::Drums => write!(f, "drum notes played as pitches"),
+            Feature::OutOfRange => write!(f, "notes raised into the range of the cube"),
+        }
+    }
+}
+
+/// Features ignored or approximated on conversion, with the positions as (part, tick)
+/// where they appear.
+#[derive(Clone, Debug, Default)]
+pub struct Ignored(BTreeMap<Feature, Vec<(Channel, Time)>>);
+
+impl Ignored {
+    pub fn push(&mut self, feature: Feature, ch: Channel, tick: Time) {
+        self.0.entry(feature).or_default().push((ch, tick));
+    }
+
+    /// Describes each feature found in the parts such as
+    /// `4 pitch bends: track 2 at 3:1, track 2 at 3:2, track 3 at 8:1 and 1 more`.
+    pub fn report<F: Fn(Channel) -> bool>(&self, song: &Song, allows: F) -> Vec<String> {
+        self.0
+            .iter()
+            .filter_map(|(feature, at)| {
+                let at: Vec<_> = at.iter().filter(|(ch, _)| allows(*ch)).collect();
+                if at.is_empty() {
+                    return None;
+                }
+                let shown: Vec<_> = at
+                    .iter()
+                    .take(SHOWN)
+                    .map(|(ch, tick)| song.position(*ch, *tick))
+                    .collect();
+                let mut line = format!("{} {}: {}", at.len(), feature, shown.join(", "));
+                if at.len() > SHOWN {
+                    line += &format!(" and {} more", at.len() - SHOWN);
+                }
+                Some(line)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let mut ignored = Ignored::default();
+        for tick in 0..4 {
+            ignored.push(Feature::PitchBend, 2, tick * 480);
+        }
+        ignored.push(Feature::SysEx, 0, 0);
+        ignored.push(Feature::Drums, 3, 1920);
+
+        let song = Song {
+            time_base: 480,
+            ..Default::default()
+        };
+        assert_eq!(
+            ignored.report(&song, |ch| ch != 0),
+            vec![
+                "4 pitch bends: track 2 at 1:1, track 2 at 1:2, track 2 at 1:3 and 1 more",
+                "1 drum notes played as pitches: track 3 at 2:1",
+            ]
+        );
+    }
+}